@@ -0,0 +1,293 @@
+//! Load generator for the proxy.
+//!
+//! Runs a fake X server on a free display, starts rustywin in front of
+//! it and then drives many concurrent clients through the proxy. Every
+//! client sends batches of requests followed by a GetInputFocus, the way
+//! XSync does. The fake server answers the GetInputFocus with a checksum
+//! of everything it received since the previous one, so any byte that
+//! the proxy loses, duplicates or reorders fails the run.
+//!
+//! Usage: cargo run --example soak -- [clients] [batches] [rustywin]
+//!
+//! The proxy has to be built first; it defaults to target/debug/rustywin.
+
+extern crate byteorder;
+extern crate nix;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use nix::sys::socket::{socketpair, AddressFamily, SockFlag, SockType};
+
+const SOCKET_DIR: &str = "/tmp/.X11-unix";
+
+const INTERN_ATOM: u8 = 16;
+const CHANGE_PROPERTY: u8 = 18;
+const GET_PROPERTY: u8 = 20;
+const GET_INPUT_FOCUS: u8 = 43;
+
+/// FNV-1a, good enough to notice corruption.
+fn checksum(hash: u32, data: &[u8]) -> u32 {
+    data.iter()
+        .fold(hash, |h, &b| (h ^ u32::from(b)).wrapping_mul(16_777_619))
+}
+
+const CHECKSUM_INIT: u32 = 2_166_136_261;
+
+fn reply(sequence: u16, extra: &[u8], word: u32) -> Vec<u8> {
+    let mut reply = vec![0; 32];
+    reply[0] = 1;
+    LittleEndian::write_u16(&mut reply[2..4], sequence);
+    LittleEndian::write_u32(&mut reply[4..8], extra.len() as u32 / 4);
+    LittleEndian::write_u32(&mut reply[8..12], word);
+    reply.extend(extra);
+    reply
+}
+
+/// One connection to the fake server. Only LSB first clients.
+fn serve(mut stream: UnixStream) -> io::Result<()> {
+    let mut setup = [0; 12];
+    stream.read_exact(&mut setup)?;
+    let mut setup_reply = vec![1, 0, 11, 0, 0, 0, 2, 0];
+    setup_reply.extend(&[0; 8]);
+    stream.write_all(&setup_reply)?;
+
+    let mut sequence: u16 = 0;
+    let mut hash = CHECKSUM_INIT;
+    let mut header = [0; 4];
+    loop {
+        match stream.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+        let length = 4 * LittleEndian::read_u16(&header[2..4]) as usize;
+        let mut request = header.to_vec();
+        request.resize(length.max(4), 0);
+        stream.read_exact(&mut request[4..])?;
+        sequence = sequence.wrapping_add(1);
+
+        let answer = match request[0] {
+            INTERN_ATOM => Some(reply(sequence, &[], 0x100)),
+            GET_PROPERTY => Some(reply(sequence, &[], 0)),
+            GET_INPUT_FOCUS => {
+                let answer = reply(sequence, &[], hash);
+                hash = CHECKSUM_INIT;
+                Some(answer)
+            }
+            _ => None,
+        };
+        if request[0] != GET_INPUT_FOCUS {
+            hash = checksum(hash, &request);
+        }
+        if let Some(answer) = answer {
+            stream.write_all(&answer)?;
+        }
+    }
+}
+
+/// Something that looks like a toolkit starting up: atoms, properties
+/// of varying size and property reads.
+fn batch(client: usize, round: usize) -> (Vec<u8>, usize) {
+    let mut requests = Vec::new();
+    let mut replies = 0;
+    for i in 0..8 {
+        let name = format!("_SOAK_{}_{}_{}", client, round, i);
+        let padded = (name.len() + 3) & !3;
+        let mut intern = vec![INTERN_ATOM, 0, 0, 0, 0, 0, 0, 0];
+        LittleEndian::write_u16(&mut intern[2..4], (2 + padded / 4) as u16);
+        LittleEndian::write_u16(&mut intern[4..6], name.len() as u16);
+        intern.extend(name.as_bytes());
+        intern.resize(8 + padded, 0);
+        requests.extend(intern);
+        replies += 1;
+
+        let data_length = (client * 7 + round * 13 + i * 131) % 2048;
+        let padded = (data_length + 3) & !3;
+        let mut change = vec![0; 24];
+        change[0] = CHANGE_PROPERTY;
+        LittleEndian::write_u16(&mut change[2..4], (6 + padded / 4) as u16);
+        LittleEndian::write_u32(&mut change[4..8], 0x200_0001);
+        LittleEndian::write_u32(&mut change[8..12], 0x100);
+        LittleEndian::write_u32(&mut change[12..16], 31);
+        change[16] = 8;
+        LittleEndian::write_u32(&mut change[20..24], data_length as u32);
+        change.extend((0..padded).map(|n| (n + client) as u8));
+        requests.extend(change);
+
+        let mut get = vec![0; 24];
+        get[0] = GET_PROPERTY;
+        LittleEndian::write_u16(&mut get[2..4], 6);
+        LittleEndian::write_u32(&mut get[4..8], 0x200_0001);
+        LittleEndian::write_u32(&mut get[8..12], 0x100);
+        LittleEndian::write_u32(&mut get[20..24], 1024);
+        requests.extend(get);
+        replies += 1;
+    }
+    (requests, replies)
+}
+
+fn read_message(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let mut message = vec![0; 32];
+    stream.read_exact(&mut message)?;
+    if message[0] == 1 {
+        let extra = 4 * LittleEndian::read_u32(&message[4..8]) as usize;
+        message.resize(32 + extra, 0);
+        stream.read_exact(&mut message[32..])?;
+    }
+    Ok(message)
+}
+
+fn run_client(
+    display: &str,
+    client: usize,
+    batches: usize,
+) -> io::Result<Vec<Duration>> {
+    let path = format!("{}/X{}", SOCKET_DIR, &display[1..]);
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    stream.write_all(b"l\x00\x0b\x00\x00\x00\x00\x00\x00\x00\x00\x00")?;
+    let mut setup_reply = [0; 8];
+    stream.read_exact(&mut setup_reply)?;
+    let extra = 4 * LittleEndian::read_u16(&setup_reply[6..8]) as usize;
+    stream.read_exact(&mut vec![0; extra])?;
+
+    let mut latencies = Vec::with_capacity(batches);
+    for round in 0..batches {
+        let (requests, replies) = batch(client, round);
+        let expected = checksum(CHECKSUM_INIT, &requests);
+        let start = Instant::now();
+        stream.write_all(&requests)?;
+        stream.write_all(&[GET_INPUT_FOCUS, 0, 1, 0])?;
+        for _ in 0..replies {
+            read_message(&mut stream)?;
+        }
+        let sync = read_message(&mut stream)?;
+        latencies.push(start.elapsed());
+        let received = LittleEndian::read_u32(&sync[8..12]);
+        if sync[0] != 1 || received != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("client {} batch {} arrived damaged", client, round),
+            ));
+        }
+    }
+    Ok(latencies)
+}
+
+fn free_display() -> usize {
+    (50..1000)
+        .find(|n| !Path::new(&format!("{}/X{}", SOCKET_DIR, n)).exists())
+        .expect("No free display number")
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let clients = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(200);
+    let batches = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(20);
+    let proxy = args
+        .get(3)
+        .cloned()
+        .unwrap_or_else(|| "target/debug/rustywin".to_string());
+
+    let display = free_display();
+    let server_path = format!("{}/X{}", SOCKET_DIR, display);
+    let listener = UnixListener::bind(&server_path).expect("Can't bind");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.expect("Accept failed");
+            thread::spawn(move || {
+                if let Err(e) = serve(stream) {
+                    eprintln!("Fake server connection failed: {}", e);
+                }
+            });
+        }
+    });
+
+    // rustywin tells us the display it listens on over the socketpair.
+    let (ours, theirs): (RawFd, RawFd) = socketpair(
+        AddressFamily::Unix,
+        SockType::SeqPacket,
+        None,
+        SockFlag::empty(),
+    )
+    .expect("Can't create socketpair");
+    let mut child = Command::new(&proxy)
+        .arg("--fd")
+        .arg(theirs.to_string())
+        .env("DISPLAY", format!(":{}", display))
+        .env(
+            "RUST_LOG",
+            env::var("RUST_LOG").unwrap_or("warn".to_string()),
+        )
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Can't start rustywin");
+    let mut control = unsafe { UnixStream::from_raw_fd(ours) };
+    let mut proxy_display = [0; 64];
+    let n = control.read(&mut proxy_display).expect("No display");
+    let proxy_display = String::from_utf8_lossy(&proxy_display[..n]);
+    println!("Proxy on {} in front of :{}", proxy_display, display);
+
+    let failures = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    let start = Instant::now();
+    for client in 0..clients {
+        let display = proxy_display.to_string();
+        let sender = sender.clone();
+        let failures = failures.clone();
+        thread::spawn(move || match run_client(&display, client, batches) {
+            Ok(latencies) => sender.send(latencies).unwrap(),
+            Err(e) => {
+                eprintln!("Client {}: {}", client, e);
+                failures.fetch_add(1, Ordering::SeqCst);
+                sender.send(Vec::new()).unwrap();
+            }
+        });
+    }
+    drop(sender);
+    let mut latencies: Vec<Duration> = receiver.iter().flatten().collect();
+    let elapsed = start.elapsed();
+
+    child.kill().ok();
+    child.wait().ok();
+    fs::remove_file(&server_path).ok();
+
+    latencies.sort();
+    println!(
+        "{} clients, {} batches in {:?}, {} failed",
+        clients,
+        latencies.len(),
+        elapsed,
+        failures.load(Ordering::SeqCst)
+    );
+    if !latencies.is_empty() {
+        println!(
+            "Batch latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99),
+            latencies[latencies.len() - 1]
+        );
+    }
+    if failures.load(Ordering::SeqCst) > 0 {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,170 @@
+//! Counting heap allocations on the filtering hot path, with the
+//! `alloc-audit` feature, to see which requests still allocate. Without
+//! the feature nothing is counted and there is nothing to report.
+
+#[cfg(all(feature = "alloc-audit", test))]
+pub use self::counting::count;
+#[cfg(feature = "alloc-audit")]
+pub use self::counting::{report, Span};
+#[cfg(not(feature = "alloc-audit"))]
+pub use self::disabled::{report, Span};
+
+#[cfg(feature = "alloc-audit")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use analyze;
+
+    /// A span per core opcode, extension requests going by their major
+    /// opcode, and one for passing requests on.
+    const KINDS: usize = 257;
+    const FORWARDING: usize = 256;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Spans that ended and the allocations made in them, by kind.
+    static SPANS: [AtomicUsize; KINDS] = [const { AtomicUsize::new(0) }; KINDS];
+    static SPAN_ALLOCATIONS: [AtomicUsize; KINDS] =
+        [const { AtomicUsize::new(0) }; KINDS];
+
+    struct Counting;
+
+    fn counted() {
+        // Threads being torn down have no counter left.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            counted();
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            counted();
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            counted();
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    /// Allocations made on this thread so far.
+    pub fn count() -> usize {
+        ALLOCATIONS.try_with(Cell::get).unwrap_or(0)
+    }
+
+    /// The allocations made on this thread while it's around are put
+    /// down to the request it was made for, or to passing requests on.
+    pub struct Span {
+        kind: usize,
+        start: usize,
+    }
+
+    impl Span {
+        fn new(kind: usize) -> Span {
+            Span {
+                kind,
+                start: count(),
+            }
+        }
+
+        pub fn request(opcode: u8) -> Span {
+            Span::new(opcode as usize)
+        }
+
+        pub fn forwarding() -> Span {
+            Span::new(FORWARDING)
+        }
+    }
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            let allocations = count() - self.start;
+            SPANS[self.kind].fetch_add(1, Ordering::Relaxed);
+            SPAN_ALLOCATIONS[self.kind]
+                .fetch_add(allocations, Ordering::Relaxed);
+        }
+    }
+
+    /// Log the allocations of every kind of span there was, most per
+    /// span first.
+    pub fn report() {
+        let mut kinds: Vec<(usize, usize, usize)> = (0..KINDS)
+            .map(|kind| {
+                let spans = SPANS[kind].load(Ordering::Relaxed);
+                let allocations =
+                    SPAN_ALLOCATIONS[kind].load(Ordering::Relaxed);
+                (kind, spans, allocations)
+            })
+            .filter(|&(_, spans, _)| spans > 0)
+            .collect();
+        kinds.sort_by(|a, b| (b.2 * a.1).cmp(&(a.2 * b.1)));
+        for (kind, spans, allocations) in kinds {
+            let name = if kind == FORWARDING {
+                "Forwarding".to_string()
+            } else {
+                analyze::opcode_name(kind as u8)
+            };
+            info!(
+                "{}: {} allocations in {} spans, {:.1} each",
+                name,
+                allocations,
+                spans,
+                allocations as f64 / spans as f64
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_span() {
+            let before = SPAN_ALLOCATIONS[FORWARDING].load(Ordering::Relaxed);
+            {
+                let _span = Span::forwarding();
+                let buffer: Vec<u8> = Vec::with_capacity(16);
+                assert_eq!(buffer.capacity(), 16);
+            }
+            let after = SPAN_ALLOCATIONS[FORWARDING].load(Ordering::Relaxed);
+            assert_eq!(after, before + 1);
+        }
+    }
+}
+
+#[cfg(not(feature = "alloc-audit"))]
+mod disabled {
+    pub struct Span;
+
+    impl Span {
+        pub fn request(_opcode: u8) -> Span {
+            Span
+        }
+
+        pub fn forwarding() -> Span {
+            Span
+        }
+    }
+
+    pub fn report() {}
+}
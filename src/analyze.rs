@@ -1,10 +1,22 @@
 use std::borrow::Cow;
+use std::fmt;
 use std::fmt::Debug;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
+use std::os::unix::io::RawFd;
 
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use enum_primitive::FromPrimitive;
-use nom::{le_i16, le_u16, le_u24, le_u32, le_u8, IResult, Needed};
+use log::Level;
+use nom::{le_u8, Endianness, IResult};
+
+use allocations;
+use hexdump;
+use metrics;
+use policy::{AtomDenial, Capability, GpuMode, Policy};
+use state::{ConnectionState, Resource, SentRequest};
+use xim;
 
 quick_error! {
     #[derive(Debug)]
@@ -15,47 +27,141 @@ quick_error! {
         InconsistentLength {
             description("Message length inconsistent")
         }
+        BadRequestLength(length: u64) {
+            description("Request length out of bounds")
+            display("Request length {} out of bounds", length)
+        }
+        Io(err: io::Error) {
+            from()
+            description(err.description())
+            display("I/O error: {}", err)
+        }
     }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Outcome {
     Allowed,
-    Denied,
+    /// Allowed because the policy grants the capability it needs.
+    Granted(Capability),
+    Denied(Capability),
+    /// Denied, but answered with a harmless made up reply where the
+    /// request has one, see `Policy::spoofs`.
+    Spoofed(Capability),
 }
 
 type ParseResult = Result<Outcome, ParseError>;
 
-pub fn analyze_file(filename: &str) -> ParseResult {
-    let mut f = File::open(filename).expect("File not found.");
-
-    let mut buffer = Vec::new();
+/// Analyze a dumpfile, or standard input if `filename` is "-".
+///
+/// The input is processed as it comes in, so this works on pipes.
+/// With `hexdump` every request is printed as an annotated hexdump.
+pub fn analyze_file(
+    filename: &str,
+    policy: &Policy,
+    hexdump: bool,
+) -> ParseResult {
+    let mut input: Box<dyn Read> = if filename == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(filename)?)
+    };
 
-    // read the whole file
-    f.read_to_end(&mut buffer).expect("Error reading dumpfile.");
+    let mut state = ConnectionState::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut offset = 0;
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ParseError::from(e)),
+        };
+        let mut framer = ClientFramer::new(&state, policy);
+        let complete = state
+            .client_stream
+            .complete(&buffer[0..read], |m| framer.frame(m));
+        analyze_buffer(&mut state, policy, &complete, hexdump, offset)?;
+        offset += complete.len();
+        framer.check()?;
+    }
 
-    analyze_buffer(&buffer)
+    let trailing = state.client_stream.pending();
+    if trailing > 0 {
+        warn!(
+            "Input ends with {} bytes of an incomplete request",
+            trailing
+        );
+    }
+    Ok(Outcome::Allowed)
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 struct Request<'a> {
     opcode: u8,
     datab: u8,
-    length: u32,
+    length: usize,
     data: &'a [u8],
 }
 
 enum_from_primitive! {
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 // enum with explicit discriminator
 enum Opcode {
+    CreateWindow = 0x1,
     ChangeWindowAttributes = 0x2,
+    DestroyWindow = 0x4,
+    ReparentWindow = 0x7,
+    ConfigureWindow = 0xC,
+    GetGeometry = 0xE,
+    QueryTree = 0xF,
     InternAtom = 0x10,
+    GetAtomName = 0x11,
     ChangeProperty = 0x12,
+    DeleteProperty = 0x13,
     GetProperty = 0x14,
+    ListProperties = 0x15,
+    SetSelectionOwner = 0x16,
+    GetSelectionOwner = 0x17,
+    ConvertSelection = 0x18,
+    SendEvent = 0x19,
+    GrabPointer = 0x1A,
     GrabButton = 0x1C,
+    GrabKeyboard = 0x1F,
+    UngrabKeyboard = 0x20,
+    GrabKey = 0x21,
+    UngrabKey = 0x22,
+    AllowEvents = 0x23,
+    GrabServer = 0x24,
+    UngrabServer = 0x25,
+    QueryPointer = 0x26,
+    GetMotionEvents = 0x27,
+    TranslateCoordinates = 0x28,
+    SetInputFocus = 0x2A,
+    CreatePixmap = 0x35,
+    FreePixmap = 0x36,
+    CreateGC = 0x37,
+    FreeGC = 0x3C,
+    CopyArea = 0x3E,
+    CopyPlane = 0x3F,
+    GetImage = 0x49,
     QueryExtension = 0x62,
+    ChangeKeyboardMapping = 0x64,
+    SetScreenSaver = 0x6B,
+    RotateProperties = 0x72,
+    ForceScreenSaver = 0x73,
+    SetPointerMapping = 0x74,
+    SetModifierMapping = 0x76,
+}
 }
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SetupRequest<'a> {
+    byte_order: Endianness,
+    major_version: u16,
+    minor_version: u16,
+    auth_name: &'a [u8],
+    auth_data: &'a [u8],
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -65,6 +171,11 @@ struct InternAtom<'a> {
     name: Cow<'a, str>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GetAtomName {
+    atom: u32,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 struct GetProperty {
     delete: bool,
@@ -92,11 +203,587 @@ struct ChangeProperty<'a> {
     data: &'a [u8],
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct DeleteProperty {
+    window: u32,
+    property: u32,
+}
+
+// Names the properties of a window, but doesn't read them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ListProperties {
+    window: u32,
+}
+
+// Moves the values of `properties` along by `delta`, the way
+// XRotateBuffers does with the cut buffers. The atoms are read in place.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RotateProperties<'a> {
+    window: u32,
+    delta: i16,
+    properties: &'a [u8],
+    e: Endianness,
+}
+
+impl<'a> RotateProperties<'a> {
+    fn properties(&self) -> impl Iterator<Item = u32> + '_ {
+        let e = self.e;
+        self.properties.chunks(4).map(move |atom| read_u32(atom, e))
+    }
+}
+
+const BAD_VALUE: u8 = 2;
+const BAD_ACCESS: u8 = 10;
+const BAD_LENGTH: u8 = 16;
+
+/// Predefined atom of the window manager hints.
+const WM_HINTS: u32 = 35;
+
+// XTEST
+// Lets a client generate input events as if they came from a real
+// device. Anything that can send FakeInput can type into and click
+// around in every other application.
+
+const XTEST_FAKE_INPUT: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FakeInput {
+    event_type: u8,
+    detail: u8,
+    time: u32,
+    root: u32,
+    root_x: i16,
+    root_y: i16,
+    device_id: u8,
+}
+
+// MIT-SHM
+// Lets client and server exchange images through shared memory instead
+// of the socket. Segments are attached by SysV id, passed along with the
+// request as a file descriptor, or created by the server and passed
+// back. ShmGetImage copies any drawable into a segment, like GetImage.
+
+const SHM_NAME: &str = "MIT-SHM";
+const SHM_ATTACH: u8 = 1;
+const SHM_DETACH: u8 = 2;
+const SHM_GET_IMAGE: u8 = 4;
+const SHM_ATTACH_FD: u8 = 6;
+const SHM_CREATE_SEGMENT: u8 = 7;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ShmGetImage {
+    drawable: u32,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    plane_mask: u32,
+    format: u8,
+    shmseg: u32,
+    offset: u32,
+}
+
+// XInputExtension
+// XI2 selects its events per device and window, apart from the core
+// event masks. Raw events report every key and button as it comes from
+// the device, are only delivered on the root window, and are what
+// keyloggers use now that the core ones are watched.
+
+pub const XI_NAME: &str = "XInputExtension";
+const XI_SELECT_EVENTS: u8 = 46;
+
+const XI_KEY_EVENTS: u64 = 1 << 2 | 1 << 3;
+pub const XI_RAW_KEY_EVENTS: u64 = 1 << 13 | 1 << 14;
+pub const XI_RAW_POINTER_EVENTS: u64 = 1 << 15 | 1 << 16 | 1 << 17;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct XISelectEvents {
+    window: u32,
+    /// Event types selected for any device.
+    events: u64,
+}
+
+// XFIXES
+// Mostly fixes for the core protocol, but also tells clients whenever a
+// selection changes hands, which is how clipboard snoopers know when to
+// look, and lets them hide the cursor or swap out the cursors of others.
+
+const XFIXES_NAME: &str = "XFIXES";
+const XFIXES_SELECT_SELECTION_INPUT: u8 = 2;
+const XFIXES_SET_CURSOR_NAME: u8 = 23;
+const XFIXES_CHANGE_CURSOR: u8 = 26;
+const XFIXES_CHANGE_CURSOR_BY_NAME: u8 = 27;
+const XFIXES_HIDE_CURSOR: u8 = 29;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SelectSelectionInput {
+    window: u32,
+    selection: u32,
+    event_mask: u32,
+}
+
+// XKEYBOARD
+// XKB's own way of changing the keymap and keyboard controls, apart from
+// the core requests, and of ringing the bell and setting the LEDs.
+// Everything else it has is queries about the keyboard state.
+
+const XKB_NAME: &str = "XKEYBOARD";
+const XKB_BELL: u8 = 3;
+const XKB_SET_CONTROLS: u8 = 7;
+const XKB_SET_MAP: u8 = 9;
+const XKB_SET_INDICATOR_MAP: u8 = 14;
+const XKB_SET_NAMED_INDICATOR: u8 = 16;
+
+// SHAPE
+// Sets the bounding region a window is drawn in, and the input region
+// that takes the pointer. A window whose input region leaves out most of
+// it lets clicks through to what's underneath, while still covering it.
+
+const SHAPE_NAME: &str = "SHAPE";
+const SHAPE_RECTANGLES: u8 = 1;
+const SHAPE_MASK: u8 = 2;
+const SHAPE_COMBINE: u8 = 3;
+const SHAPE_UNION: u8 = 1;
+const SHAPE_SUBTRACT: u8 = 3;
+const SHAPE_INPUT: u8 = 2;
+
+/// ShapeRectangles, ShapeMask or ShapeCombine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ShapeChange<'a> {
+    minor_opcode: u8,
+    operation: u8,
+    kind: u8,
+    window: u32,
+    /// The rectangles of ShapeRectangles, read in place.
+    rectangles: &'a [u8],
+    /// The pixmap of ShapeMask, or the window ShapeCombine takes the shape
+    /// of.
+    source: u32,
+    e: Endianness,
+}
+
+impl<'a> ShapeChange<'a> {
+    /// The width and height of every rectangle.
+    fn rectangle_sizes(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        let e = self.e;
+        self.rectangles.chunks(8).map(move |rectangle| {
+            (read_u16(&rectangle[4..6], e), read_u16(&rectangle[6..8], e))
+        })
+    }
+}
+
+// XC-MISC
+// Hands a long running client resource ids that are free again, once it
+// has gone through its range. They're from the same range, but get
+// reused, so the ones handed out are kept track of like new resources.
+
+const XC_MISC_NAME: &str = "XC-MISC";
+pub const XC_MISC_GET_XID_RANGE: u8 = 1;
+pub const XC_MISC_GET_XID_LIST: u8 = 2;
+
+// SYNC
+// Counters, alarms on them and fences, which are the client's resources
+// like its windows are. Counters are also set by other clients knowing
+// their ids, which is how the window manager and a client keep resizing
+// in step.
+
+const SYNC_NAME: &str = "SYNC";
+const SYNC_CREATE_COUNTER: u8 = 2;
+const SYNC_DESTROY_COUNTER: u8 = 6;
+const SYNC_CREATE_ALARM: u8 = 8;
+const SYNC_DESTROY_ALARM: u8 = 11;
+const SYNC_CREATE_FENCE: u8 = 14;
+const SYNC_DESTROY_FENCE: u8 = 17;
+
+// GLX, DRI3 and Present
+// What 3D clients draw with: GLX rendering commands, or buffers the client
+// renders into on the GPU, shared with DRI3 and shown with Present. DRI3
+// imports buffers and fences as file descriptors passed along.
+
+const GPU_EXTENSIONS: &[&str] = &["GLX", DRI3_NAME, "Present"];
+const DRI3_NAME: &str = "DRI3";
+const DRI3_PIXMAP_FROM_BUFFER: u8 = 2;
+const DRI3_FENCE_FROM_FD: u8 = 4;
+const DRI3_PIXMAP_FROM_BUFFERS: u8 = 7;
+const DRI3_IMPORT_SYNCOBJ: u8 = 10;
+
+// RECORD
+// Lets a client see every request, reply and input event that goes
+// through the server, which makes it a keylogger for the whole display.
+// Contexts say what to record, and enabling one starts the stream of
+// replies carrying the data, up to an EndOfData one.
+
+const RECORD_CREATE_CONTEXT: u8 = 1;
+const RECORD_ENABLE_CONTEXT: u8 = 5;
+const RECORD_END_OF_DATA: u8 = 5;
+
+// RANDR
+// Tells clients about the monitors making up a screen, and lets them
+// change the resolution and layout for everyone. Sizes are rewritten in
+// the replies when the policy fakes the screen size, see
+// server::fake_geometry_reply.
+
+pub const RANDR_NAME: &str = "RANDR";
+const RANDR_SET_SCREEN_CONFIG: u8 = 2;
+const RANDR_SET_SCREEN_SIZE: u8 = 7;
+const RANDR_SET_CRTC_CONFIG: u8 = 21;
+const RANDR_SET_CRTC_GAMMA: u8 = 24;
+const RANDR_SET_CRTC_TRANSFORM: u8 = 26;
+const RANDR_SET_PANNING: u8 = 29;
+const RANDR_SET_OUTPUT_PRIMARY: u8 = 31;
+const RANDR_SET_MONITOR: u8 = 43;
+const RANDR_DELETE_MONITOR: u8 = 44;
+/// The status to say a configuration change didn't happen.
+const RANDR_FAILED: u8 = 3;
+pub const RANDR_GET_SCREEN_INFO: u8 = 5;
+pub const RANDR_GET_SCREEN_SIZE_RANGE: u8 = 6;
+pub const RANDR_GET_SCREEN_RESOURCES: u8 = 8;
+pub const RANDR_GET_OUTPUT_INFO: u8 = 9;
+pub const RANDR_GET_CRTC_INFO: u8 = 20;
+pub const RANDR_GET_SCREEN_RESOURCES_CURRENT: u8 = 25;
+pub const RANDR_GET_MONITORS: u8 = 42;
+
+const RANDR_GEOMETRY_QUERIES: &[u8] = &[
+    RANDR_GET_SCREEN_INFO,
+    RANDR_GET_SCREEN_SIZE_RANGE,
+    RANDR_GET_SCREEN_RESOURCES,
+    RANDR_GET_OUTPUT_INFO,
+    RANDR_GET_CRTC_INFO,
+    RANDR_GET_SCREEN_RESOURCES_CURRENT,
+    RANDR_GET_MONITORS,
+];
+
+// MIT-SCREEN-SAVER
+// Lets a client provide the screen saver window, or keep the screen saver
+// from starting at all.
+
+const SCREENSAVER_SET_ATTRIBUTES: u8 = 3;
+const SCREENSAVER_UNSET_ATTRIBUTES: u8 = 4;
+const SCREENSAVER_SUSPEND: u8 = 5;
+
+// A timeout of zero disables the screen saver.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SetScreenSaver {
+    timeout: i16,
+    interval: i16,
+    prefer_blanking: u8,
+    allow_exposures: u8,
+}
+
+// Mode 0 resets the idle timer, 1 starts the screen saver right away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ForceScreenSaver {
+    mode: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SetSelectionOwner {
+    owner: u32,
+    selection: u32,
+    time: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GetSelectionOwner {
+    selection: u32,
+}
+
+// Asks the owner of the selection to store it in a property on the
+// requestor, which gets a SelectionNotify once that is done.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ConvertSelection {
+    requestor: u32,
+    selection: u32,
+    target: u32,
+    property: u32,
+    time: u32,
+}
+
+const SELECTION_NOTIFY: u8 = 31;
+const CLIENT_MESSAGE: u8 = 33;
+
+// Releases input frozen by a synchronous grab. The replay modes hand the
+// event that triggered a passive grab on to whoever would have got it
+// without the grab.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct AllowEvents {
+    mode: u8,
+    time: u32,
+}
+
+const ALLOW_ASYNC_POINTER: u8 = 0;
+const ALLOW_REPLAY_POINTER: u8 = 2;
+const ALLOW_ASYNC_KEYBOARD: u8 = 3;
+const ALLOW_REPLAY_KEYBOARD: u8 = 5;
+
+// The focus can also be None or PointerRoot, which take it away from
+// whoever has it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SetInputFocus {
+    revert_to: u8,
+    focus: u32,
+    time: u32,
+}
+
+// The mappings are global, changing them changes what keys and buttons
+// do for every client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ChangeKeyboardMapping<'a> {
+    first_keycode: u8,
+    keycode_count: u8,
+    keysyms_per_keycode: u8,
+    keysyms: &'a [u8],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SetModifierMapping<'a> {
+    keycodes_per_modifier: u8,
+    keycodes: &'a [u8],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SetPointerMapping<'a> {
+    map: &'a [u8],
+}
+
+// Events sent with SendEvent have the high bit of their code set, but
+// are otherwise hard to tell apart from real input by the receiver.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SendEvent<'a> {
+    propagate: u8,
+    destination: u32,
+    event_mask: u32,
+    event_code: u8,
+    event: &'a [u8],
+}
+
+/// A bitmask with one 32-bit value for every bit set, in bit order.
+/// Used for window attributes, GC components and the like. The values
+/// are read in place.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ValueList<'a> {
+    mask: u32,
+    values: &'a [u8],
+    e: Endianness,
+}
+
+impl<'a> ValueList<'a> {
+    fn get(&self, bit: u32) -> Option<u32> {
+        if self.mask & bit == 0 {
+            return None;
+        }
+        let index = (self.mask & (bit - 1)).count_ones() as usize;
+        let value = self.values.get(4 * index..4 * index + 4)?;
+        Some(read_u32(value, self.e))
+    }
+
+    /// Number of values.
+    fn len(&self) -> usize {
+        self.values.len() / 4
+    }
+}
+
+// Window attributes
+const CW_OVERRIDE_REDIRECT: u32 = 0x200;
+const CW_EVENT_MASK: u32 = 0x800;
+
+// Event masks
+const KEY_PRESS_MASK: u32 = 0x1;
+const KEY_RELEASE_MASK: u32 = 0x2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ChangeWindowAttributes<'a> {
+    window: u32,
+    attributes: ValueList<'a>,
+}
+
+// ConfigureWindow values
+const CONFIG_WINDOW_WIDTH: u32 = 0x4;
+const CONFIG_WINDOW_HEIGHT: u32 = 0x8;
+
+/// The mask is only 16 bits here, the value list otherwise the same.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ConfigureWindow<'a> {
+    window: u32,
+    values: ValueList<'a>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ReparentWindow {
+    window: u32,
+    parent: u32,
+    x: i16,
+    y: i16,
+}
+
+// GetImage formats
+const XY_PIXMAP: u8 = 1;
+
+/// Largest blank image we make up, in bytes.
+const MAX_BLANK_IMAGE: u64 = 64 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GetImage {
+    format: u8,
+    drawable: u32,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    plane_mask: u32,
+}
+
+// Copies part of one drawable to another, which is as good as GetImage
+// when the source isn't the client's own, and then draws it anywhere.
+// CopyPlane also has the bit plane to copy, which isn't needed here.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CopyArea {
+    src_drawable: u32,
+    dst_drawable: u32,
+    gc: u32,
+    src_x: i16,
+    src_y: i16,
+    dst_x: i16,
+    dst_y: i16,
+    width: u16,
+    height: u16,
+}
+
+// Lists the children of a window. On the root window that is every
+// top-level window on the screen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct QueryTree {
+    window: u32,
+}
+
+// Both tell where the pointer is relative to `window`, even if it is
+// over a window of another client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct QueryPointer {
+    window: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GetMotionEvents {
+    window: u32,
+    start: u32,
+    stop: u32,
+}
+
+// Where a window is in its parent and how big, or how big a pixmap is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GetGeometry {
+    drawable: u32,
+}
+
+// Takes a point in one window to another, which tells where either is
+// relative to the other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TranslateCoordinates {
+    src_window: u32,
+    dst_window: u32,
+    src_x: i16,
+    src_y: i16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CreateWindow<'a> {
+    depth: u8,
+    wid: u32,
+    parent: u32,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    border_width: u16,
+    class: u16,
+    visual: u32,
+    attributes: ValueList<'a>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CreatePixmap {
+    depth: u8,
+    pid: u32,
+    drawable: u32,
+    width: u16,
+    height: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CreateGC<'a> {
+    cid: u32,
+    drawable: u32,
+    components: ValueList<'a>,
+}
+
+/// DestroyWindow, FreePixmap and FreeGC, which only name the resource.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FreeResource {
+    id: u32,
+}
+
+// Pointer grabs redirect pointer events to the grabbing client, and
+// can confine the pointer to a window.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GrabPointer {
+    owner_events: u8,
+    grab_window: u32,
+    event_mask: u16,
+    pointer_mode: u8,
+    keyboard_mode: u8,
+    confine_to: u32,
+    cursor: u32,
+    time: u32,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 struct GrabButton {
     owner_events: u8,
-    window: u32,
-    // ignore
+    grab_window: u32,
+    event_mask: u16,
+    pointer_mode: u8,
+    keyboard_mode: u8,
+    confine_to: u32,
+    cursor: u32,
+    button: u8,
+    modifiers: u16,
+}
+
+// An active keyboard grab sends every key event to the grabbing client,
+// whichever window has the focus. A passive GrabKey on a root window
+// does the same for a single key combination.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GrabKeyboard {
+    owner_events: u8,
+    grab_window: u32,
+    time: u32,
+    pointer_mode: u8,
+    keyboard_mode: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct UngrabKeyboard {
+    time: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct GrabKey {
+    owner_events: u8,
+    grab_window: u32,
+    modifiers: u16,
+    key: u8,
+    pointer_mode: u8,
+    keyboard_mode: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct UngrabKey {
+    key: u8,
+    grab_window: u32,
+    modifiers: u16,
 }
 
 // Every request contains an 8-bit major opcode and a 16-bit length
@@ -121,54 +808,240 @@ struct GrabButton {
 // then an additional 32-bit field containing the actual length
 // (in 4-byte units) is inserted into the request, immediately following
 // the 16-bit length field.
+//
+// Only once the client has sent BigReqEnable, though. Before that a zero
+// length is just a bad request.
 
 // Good references:
 // https://github.com/boundary/wireshark/blob/master/epan/dissectors/packet-x11.c
 // https://cgit.freedesktop.org/xorg/app/xscope/tree/x11.h#n406
 
-named!(
-    request<&[u8], Request>,
-    alt!(
-        // Using BIGREQUEST -> length == 0
-        do_parse!(
-            opcode: le_u8
-            >> datab: le_u8
-            >> _length_zero: tag!(b"\x00\x00")
-            >> length_4b: le_u32
-            >> request: take!(length_4b * 4 - (4 + 2 + 1 + 1))
-            >> (Request {
-                    opcode: opcode,
-                    datab: datab,
-                    length: 4 * length_4b,
-                    data: request
-                })
-        )
-        |
-        // Normal request
-        do_parse!(
-            opcode: le_u8
-            >> datab: le_u8
-            >> length_4b: le_u16
-            >> request: take!(length_4b * 4 - (2 + 1 + 1))
-            >> (Request {
-                    opcode: opcode,
-                    datab: datab,
-                    length: 4 * length_4b as u32,
-                    data: request
-                })
+// Byte order
+// The first byte of the connection setup is 'B' (MSB first) or 'l'
+// (LSB first), and every 16 and 32-bit quantity the client sends after
+// that, including the rest of the setup, is in that byte order.
+
+pub fn read_u16(buffer: &[u8], e: Endianness) -> u16 {
+    match e {
+        Endianness::Big => BigEndian::read_u16(buffer),
+        Endianness::Little => LittleEndian::read_u16(buffer),
+    }
+}
+
+pub fn read_u32(buffer: &[u8], e: Endianness) -> u32 {
+    match e {
+        Endianness::Big => BigEndian::read_u32(buffer),
+        Endianness::Little => LittleEndian::read_u32(buffer),
+    }
+}
+
+pub fn write_u16(buffer: &mut [u8], n: u16, e: Endianness) {
+    match e {
+        Endianness::Big => BigEndian::write_u16(buffer, n),
+        Endianness::Little => LittleEndian::write_u16(buffer, n),
+    }
+}
+
+pub fn write_u32(buffer: &mut [u8], n: u32, e: Endianness) {
+    match e {
+        Endianness::Big => BigEndian::write_u32(buffer, n),
+        Endianness::Little => LittleEndian::write_u32(buffer, n),
+    }
+}
+
+/// Byte order announced by the connection setup at the start of `buffer`,
+/// or None if it doesn't look like one.
+fn setup_byte_order(buffer: &[u8]) -> Option<Endianness> {
+    match buffer.get(0..4) {
+        Some([b'B', _, 0, 11]) => Some(Endianness::Big),
+        Some([b'l', _, 11, 0]) => Some(Endianness::Little),
+        _ => None,
+    }
+}
+
+const BIG_REQUESTS_NAME: &str = "BIG-REQUESTS";
+const BIG_REQ_ENABLE: u8 = 0;
+
+/// Length of the request at the start of `buffer`, if enough of it is there.
+fn request_length(
+    buffer: &[u8],
+    e: Endianness,
+    big_requests: bool,
+) -> Option<usize> {
+    if buffer.len() < 4 {
+        return None;
+    }
+    let length = read_u16(&buffer[2..4], e) as usize;
+    if length != 0 || !big_requests {
+        return Some(4 * length);
+    }
+    // BIG-REQUESTS
+    if buffer.len() < 8 {
+        return None;
+    }
+    Some(4 * read_u32(&buffer[4..8], e) as usize)
+}
+
+/// Finds the message boundaries in client to server traffic, for use
+/// with a `Reassembler`.
+pub struct ClientFramer {
+    setup: bool,
+    byte_order: Endianness,
+    big_requests: bool,
+    /// Major opcode of BIG-REQUESTS, to catch BigReqEnable as it goes by.
+    big_requests_opcode: Option<u8>,
+    max_request_size: u64,
+    /// The length of a request that can't be right, once one comes. Its
+    /// end can't be trusted, so neither can anything after it.
+    bad_length: Option<u64>,
+}
+
+impl ClientFramer {
+    pub fn new(state: &ConnectionState, policy: &Policy) -> ClientFramer {
+        ClientFramer {
+            setup: !state.setup_done(),
+            byte_order: state.byte_order(),
+            big_requests: state.big_requests(),
+            big_requests_opcode: state.extension_opcode(BIG_REQUESTS_NAME),
+            max_request_size: policy.max_request_size(),
+            bad_length: None,
+        }
+    }
+
+    /// Whether the requests framed so far had lengths in bounds: no
+    /// shorter than their header, no longer than the policy allows.
+    pub fn check(&self) -> Result<(), ParseError> {
+        match self.bad_length {
+            Some(length) => Err(ParseError::BadRequestLength(length)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn frame(&mut self, buffer: &[u8]) -> Option<usize> {
+        if self.setup {
+            if buffer.len() < 4 {
+                return None;
+            }
+            match setup_byte_order(buffer) {
+                Some(byte_order) => {
+                    let (rest, _) = setup_request(buffer).ok()?;
+                    self.setup = false;
+                    self.byte_order = byte_order;
+                    return Some(buffer.len() - rest.len());
+                }
+                // Starts in the middle, see track_setup.
+                None => self.setup = false,
+            }
+        }
+        let length =
+            request_length(buffer, self.byte_order, self.big_requests)?;
+        // A BIG-REQUESTS length counts its own extra field.
+        let header = if read_u16(&buffer[2..4], self.byte_order) == 0 {
+            8
+        } else {
+            4
+        };
+        if length < header || length as u64 > self.max_request_size {
+            self.bad_length = Some(length as u64);
+            return None;
+        }
+        if Some(buffer[0]) == self.big_requests_opcode
+            && buffer[1] == BIG_REQ_ENABLE
+        {
+            self.big_requests = true;
+        }
+        Some(length)
+    }
+}
+
+/// Number of padding bytes needed to round `length` up to a multiple of 4.
+fn pad(length: u16) -> u16 {
+    (4 - length % 4) % 4
+}
+
+named!(setup_request<&[u8], SetupRequest<'_>>,
+    do_parse!(
+        byte_order: alt!(
+            value!(Endianness::Big, tag!(b"B"))
+            | value!(Endianness::Little, tag!(b"l"))
         )
+        >> _unused: le_u8
+        >> major_version: u16!(byte_order)
+        >> minor_version: u16!(byte_order)
+        >> name_length: u16!(byte_order)
+        >> data_length: u16!(byte_order)
+        >> _unused2: take!(2)
+        >> auth_name: take!(name_length)
+        >> _pad: take!(pad(name_length))
+        >> auth_data: take!(data_length)
+        >> _pad2: take!(pad(data_length))
+        >> (SetupRequest {
+               byte_order,
+               major_version,
+               minor_version,
+               auth_name,
+               auth_data,
+        })
     )
 );
 
-named!(intern_atom<&[u8], InternAtom>,
+// Using BIGREQUEST -> length == 0
+named_args!(
+    big_request<'a>(e: Endianness)<&'a [u8], Request<'a>>,
     do_parse!(
-        _opcode: le_u8
-        >> only_if_exists: le_u8
-        >> _length: le_u16
-        >> name_length: le_u16
-        >> _pad: le_u16
-        >> name: take!(name_length)
-        >> ( InternAtom {
+        opcode: le_u8
+        >> datab: le_u8
+        >> _length_zero: tag!(b"\x00\x00")
+        >> length_4b: verify!(u32!(e), |length| length >= 2)
+        >> request: take!(4 * length_4b as usize - (4 + 2 + 1 + 1))
+        >> (Request {
+                opcode: opcode,
+                datab: datab,
+                length: 4 * length_4b as usize,
+                data: request
+            })
+    )
+);
+
+named_args!(
+    plain_request<'a>(e: Endianness)<&'a [u8], Request<'a>>,
+    do_parse!(
+        opcode: le_u8
+        >> datab: le_u8
+        >> length_4b: verify!(u16!(e), |length| length >= 1)
+        >> request: take!(4 * length_4b as usize - (2 + 1 + 1))
+        >> (Request {
+                opcode: opcode,
+                datab: datab,
+                length: 4 * length_4b as usize,
+                data: request
+            })
+    )
+);
+
+/// The request at the start of `input`, with the BIG-REQUESTS form
+/// accepted only if `big_requests` has been enabled.
+fn request(
+    input: &[u8],
+    e: Endianness,
+    big_requests: bool,
+) -> IResult<&[u8], Request<'_>> {
+    if big_requests {
+        alt!(input, call!(big_request, e) | call!(plain_request, e))
+    } else {
+        plain_request(input, e)
+    }
+}
+
+named_args!(intern_atom<'a>(e: Endianness)<&'a [u8], InternAtom<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> only_if_exists: le_u8
+        >> _length: u16!(e)
+        >> name_length: u16!(e)
+        >> _pad: u16!(e)
+        >> name: take!(name_length)
+        >> ( InternAtom {
                 only_if_exists: only_if_exists == 1,
                 name_length: name_length,
                 name: String::from_utf8_lossy(name)
@@ -176,16 +1049,26 @@ named!(intern_atom<&[u8], InternAtom>,
     )
 );
 
-named!(getproperty<&[u8], GetProperty>,
+named_args!(getatomname(e: Endianness)<GetAtomName>,
+    do_parse!(
+        _opcode: le_u8
+        >> _pad: le_u8
+        >> _length: u16!(e)
+        >> atom: u32!(e)
+        >> ( GetAtomName { atom } )
+    )
+);
+
+named_args!(getproperty(e: Endianness)<GetProperty>,
     do_parse!(
         _opcode: le_u8
         >> delete: le_u8
-        >> _length: le_u16
-        >> window: le_u32
-        >> property: le_u32
-        >> atom_prop_type: le_u32
-        >> offset: le_u32
-        >> length: le_u32
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> property: u32!(e)
+        >> atom_prop_type: u32!(e)
+        >> offset: u32!(e)
+        >> length: u32!(e)
         >> ( GetProperty {
                 delete: delete == 1,
                 window: window,
@@ -197,13 +1080,14 @@ named!(getproperty<&[u8], GetProperty>,
     )
 );
 
-named!(queryextension<&[u8], QueryExtension>,
+named_args!(
+    queryextension<'a>(e: Endianness)<&'a [u8], QueryExtension<'a>>,
     do_parse!(
         _opcode: le_u8
         >> _dummy: le_u8
-        >> _length: le_u16
-        >> name_length: le_u16
-        >> _pad: le_u16
+        >> _length: u16!(e)
+        >> name_length: u16!(e)
+        >> _pad: u16!(e)
         >> name: take!(name_length)
         >> ( QueryExtension {
                 name_length: name_length,
@@ -212,18 +1096,55 @@ named!(queryextension<&[u8], QueryExtension>,
     )
 );
 
-named!(changeproperty<&[u8], ChangeProperty>,
+named_args!(deleteproperty(e: Endianness)<DeleteProperty>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> property: u32!(e)
+        >> (DeleteProperty { window, property })
+    )
+);
+
+named_args!(listproperties(e: Endianness)<ListProperties>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> (ListProperties { window })
+    )
+);
+
+named_args!(
+    rotateproperties<'a>(e: Endianness)<&'a [u8], RotateProperties<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> count: u16!(e)
+        >> delta: i16!(e)
+        >> properties: take!(4 * count as usize)
+        >> (RotateProperties { window, delta, properties, e })
+    )
+);
+
+named_args!(
+    changeproperty<'a>(e: Endianness)<&'a [u8], ChangeProperty<'a>>,
     do_parse!(
         _opcode: le_u8
         >> mode: le_u8
-        >> _length: le_u16
-        >> window: le_u32
-        >> property: le_u32
-        >> prop_type: le_u32
-        >> prop_format: le_u8
-        >> _pad: le_u24
-        >> data_length: le_u32
-        >> data: take!(data_length)
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> property: u32!(e)
+        >> prop_type: u32!(e)
+        >> prop_format: verify!(le_u8, |f| f == 8 || f == 16 || f == 32)
+        >> _pad: take!(3)
+        >> data_length: u32!(e)
+        // The length is in units of the format, not bytes.
+        >> data: take!(data_length as usize * usize::from(prop_format / 8))
         >> (ChangeProperty {
                mode: mode,
                window: window,
@@ -236,203 +1157,5261 @@ named!(changeproperty<&[u8], ChangeProperty>,
     )
 );
 
-named!(grabbutton<&[u8], GrabButton>,
+named_args!(value_list<'a>(e: Endianness)<&'a [u8], ValueList<'a>>,
+    do_parse!(
+        mask: u32!(e)
+        >> values: take!(4 * mask.count_ones() as usize)
+        >> (ValueList { mask, values, e })
+    )
+);
+
+named_args!(changewindowattributes<'a>(e: Endianness)<&'a [u8], ChangeWindowAttributes<'a>>,
     do_parse!(
         _opcode: le_u8
-        >> owner_events: le_u8
-        >> length: le_u16
-        >> window: le_u32
-        >> _data: take!(length - 4 + 2 + 2)
-        >> (GrabButton {
-               owner_events: owner_events,
-               window: window,
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> attributes: apply!(value_list, e)
+        >> (ChangeWindowAttributes { window, attributes })
+    )
+);
+
+named_args!(configurewindow<'a>(e: Endianness)<&'a [u8], ConfigureWindow<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> mask: u16!(e)
+        >> _pad: take!(2)
+        >> values: take!(4 * mask.count_ones() as usize)
+        >> (ConfigureWindow {
+               window,
+               values: ValueList { mask: u32::from(mask), values, e },
         })
     )
 );
 
-fn analyze_request_opcode(header: Request, data: &[u8]) -> ParseResult {
-    let opcode = Opcode::from_u8(header.opcode);
+named_args!(reparentwindow(e: Endianness)<ReparentWindow>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> parent: u32!(e)
+        >> x: i16!(e)
+        >> y: i16!(e)
+        >> (ReparentWindow { window, parent, x, y })
+    )
+);
 
-    let result = match opcode {
-        Some(Opcode::InternAtom) => {
-            let intern = intern_atom(data);
-            if intern.is_ok() {
-                println!("{:?}", intern.unwrap().1);
-            } else {
-                println!("{:?}", intern);
-            }
-            Ok(Outcome::Allowed)
-        }
-        Some(Opcode::GetProperty) => {
-            let getprop = getproperty(data);
-            if getprop.is_ok() {
-                println!("{:?}", getprop.unwrap().1);
-            } else {
-                println!("{:?}", getprop);
-            }
-            Ok(Outcome::Allowed)
-        }
-        Some(Opcode::QueryExtension) => {
-            let queryext = queryextension(data);
-            if queryext.is_ok() {
-                println!("{:?}", queryext.unwrap().1);
-            } else {
-                println!("{:?}", queryext);
-            }
-            Ok(Outcome::Allowed)
-        }
-        Some(Opcode::ChangeProperty) => {
-            let changeprop = changeproperty(data);
-            if changeprop.is_ok() {
-                println!("{:?}", changeprop.unwrap().1);
-            } else {
-                println!("{:?}", changeprop);
-            }
-            Ok(Outcome::Allowed)
-        }
-        Some(Opcode::GrabButton) => {
-            let grab = grabbutton(data);
-            if grab.is_ok() {
-                println!("{:?}", grab.unwrap().1);
-            } else {
-                println!("{:?}", grab);
-            }
-            Ok(Outcome::Allowed)
-        }
-        None => Ok(Outcome::Allowed),
-        _ => {
-            println!("{:?}", opcode);
-            Ok(Outcome::Allowed)
-        }
-    };
+named_args!(copyarea(e: Endianness)<CopyArea>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> src_drawable: u32!(e)
+        >> dst_drawable: u32!(e)
+        >> gc: u32!(e)
+        >> src_x: i16!(e)
+        >> src_y: i16!(e)
+        >> dst_x: i16!(e)
+        >> dst_y: i16!(e)
+        >> width: u16!(e)
+        >> height: u16!(e)
+        >> (CopyArea {
+               src_drawable,
+               dst_drawable,
+               gc,
+               src_x,
+               src_y,
+               dst_x,
+               dst_y,
+               width,
+               height,
+        })
+    )
+);
 
-    result
-}
+named_args!(getimage(e: Endianness)<GetImage>,
+    do_parse!(
+        _opcode: le_u8
+        >> format: le_u8
+        >> _length: u16!(e)
+        >> drawable: u32!(e)
+        >> x: i16!(e)
+        >> y: i16!(e)
+        >> width: u16!(e)
+        >> height: u16!(e)
+        >> plane_mask: u32!(e)
+        >> (GetImage {
+               format,
+               drawable,
+               x,
+               y,
+               width,
+               height,
+               plane_mask,
+        })
+    )
+);
 
-/// Filters the buffer with X commands. Returns two buffers,
-/// one with accepted and one with rejected requests.
-pub fn filter_buffer(buffer: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    let mut out_reject_buff = Vec::with_capacity(buffer.len());
-    let mut out_accept_buff = Vec::with_capacity(buffer.len());
-    let mut work_buffer = &buffer[0..buffer.len()];
+named_args!(querytree(e: Endianness)<QueryTree>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> (QueryTree { window })
+    )
+);
 
-    while buffer.len() > 0 {
-        let size = work_buffer.len();
-        println!("Buffer size={}", size);
+named_args!(querypointer(e: Endianness)<QueryPointer>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> (QueryPointer { window })
+    )
+);
 
-        // Parse request headers
-        let req = request(work_buffer);
-        if req.is_err() {
-            out_reject_buff.extend(&work_buffer[0..]);
-            break;
-        }
+named_args!(getgeometry(e: Endianness)<GetGeometry>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> drawable: u32!(e)
+        >> (GetGeometry { drawable })
+    )
+);
 
-        let (_, req_header) = req.unwrap();
-        println!("{:?}", req_header);
+named_args!(translatecoordinates(e: Endianness)<TranslateCoordinates>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> src_window: u32!(e)
+        >> dst_window: u32!(e)
+        >> src_x: i16!(e)
+        >> src_y: i16!(e)
+        >> (TranslateCoordinates { src_window, dst_window, src_x, src_y })
+    )
+);
 
-        if (req_header.length as usize) > size {
-            warn!(
-                "Packet size ({}) is smaller than header size ({})",
-                size, req_header.length
-            );
-            out_reject_buff.extend(&work_buffer[0..]);
-            break;
-        }
+named_args!(getmotionevents(e: Endianness)<GetMotionEvents>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> start: u32!(e)
+        >> stop: u32!(e)
+        >> (GetMotionEvents { window, start, stop })
+    )
+);
 
-        let decision = analyze_request_opcode(req_header, work_buffer);
-        println!("{:?}", decision);
-        match decision {
-            Ok(Outcome::Allowed) => {
-                out_accept_buff
-                    .extend(&work_buffer[0..req_header.length as usize]);
-            }
-            Ok(Outcome::Denied) => {
-                out_reject_buff
-                    .extend(&work_buffer[0..req_header.length as usize]);
-            }
-            Err(_) => {
-                out_accept_buff
-                    .extend(&work_buffer[0..req_header.length as usize]);
-            }
-        }
-        if decision.is_ok() {
-            println!("Skipping {} bytes...", req_header.length);
-            work_buffer = &work_buffer[req_header.length as usize..];
-        }
-    }
+named_args!(createwindow<'a>(e: Endianness)<&'a [u8], CreateWindow<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> depth: le_u8
+        >> _length: u16!(e)
+        >> wid: u32!(e)
+        >> parent: u32!(e)
+        >> x: i16!(e)
+        >> y: i16!(e)
+        >> width: u16!(e)
+        >> height: u16!(e)
+        >> border_width: u16!(e)
+        >> class: u16!(e)
+        >> visual: u32!(e)
+        >> attributes: apply!(value_list, e)
+        >> (CreateWindow {
+               depth,
+               wid,
+               parent,
+               x,
+               y,
+               width,
+               height,
+               border_width,
+               class,
+               visual,
+               attributes,
+        })
+    )
+);
 
-    println!(
-        "Accepted {} bytes, rejected {} bytes",
-        out_accept_buff.len(),
-        out_reject_buff.len(),
-    );
-    (out_accept_buff, out_reject_buff)
-}
+named_args!(createpixmap(e: Endianness)<CreatePixmap>,
+    do_parse!(
+        _opcode: le_u8
+        >> depth: le_u8
+        >> _length: u16!(e)
+        >> pid: u32!(e)
+        >> drawable: u32!(e)
+        >> width: u16!(e)
+        >> height: u16!(e)
+        >> (CreatePixmap {
+               depth,
+               pid,
+               drawable,
+               width,
+               height,
+        })
+    )
+);
 
-fn analyze_buffer(mut buffer: &[u8]) -> ParseResult {
-    while buffer.len() > 0 {
-        let size = buffer.len();
-        println!("Buffer size={}", size);
+named_args!(creategc<'a>(e: Endianness)<&'a [u8], CreateGC<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> cid: u32!(e)
+        >> drawable: u32!(e)
+        >> components: apply!(value_list, e)
+        >> (CreateGC { cid, drawable, components })
+    )
+);
 
-        // Parse request headers
-        let req = request(buffer);
+named_args!(free_resource(e: Endianness)<FreeResource>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> id: u32!(e)
+        >> (FreeResource { id })
+    )
+);
 
-        if req.is_ok() {
-            let (_, req_header) = req.unwrap();
-            println!("{:?}", req_header);
+// The fence comes after the drawable it's for.
+named_args!(sync_create_fence(e: Endianness)<u32>,
+    do_parse!(
+        _header: take!(8)
+        >> fence: u32!(e)
+        >> (fence)
+    )
+);
 
-            if (req_header.length as usize) > size {
-                warn!(
-                    "Packet size ({}) is smaller than header size ({})",
-                    size, req_header.length
-                );
-                return Err(ParseError::InconsistentLength);
-            }
+named_args!(grabpointer(e: Endianness)<GrabPointer>,
+    do_parse!(
+        _opcode: le_u8
+        >> owner_events: le_u8
+        >> _length: u16!(e)
+        >> grab_window: u32!(e)
+        >> event_mask: u16!(e)
+        >> pointer_mode: le_u8
+        >> keyboard_mode: le_u8
+        >> confine_to: u32!(e)
+        >> cursor: u32!(e)
+        >> time: u32!(e)
+        >> (GrabPointer {
+               owner_events,
+               grab_window,
+               event_mask,
+               pointer_mode,
+               keyboard_mode,
+               confine_to,
+               cursor,
+               time,
+        })
+    )
+);
 
-            let decision = analyze_request_opcode(req_header, buffer);
-            println!("{:?}", decision);
-            if decision.is_ok() {
-                println!("Skipping {} bytes...", req_header.length);
-                buffer = &buffer[req_header.length as usize..];
-            }
-        } else {
-            break;
-        }
-    }
+named_args!(grabbutton(e: Endianness)<GrabButton>,
+    do_parse!(
+        _opcode: le_u8
+        >> owner_events: le_u8
+        >> _length: u16!(e)
+        >> grab_window: u32!(e)
+        >> event_mask: u16!(e)
+        >> pointer_mode: le_u8
+        >> keyboard_mode: le_u8
+        >> confine_to: u32!(e)
+        >> cursor: u32!(e)
+        >> button: le_u8
+        >> _pad: le_u8
+        >> modifiers: u16!(e)
+        >> (GrabButton {
+               owner_events,
+               grab_window,
+               event_mask,
+               pointer_mode,
+               keyboard_mode,
+               confine_to,
+               cursor,
+               button,
+               modifiers,
+        })
+    )
+);
 
-    Ok(Outcome::Allowed)
-}
+named_args!(grabkeyboard(e: Endianness)<GrabKeyboard>,
+    do_parse!(
+        _opcode: le_u8
+        >> owner_events: le_u8
+        >> _length: u16!(e)
+        >> grab_window: u32!(e)
+        >> time: u32!(e)
+        >> pointer_mode: le_u8
+        >> keyboard_mode: le_u8
+        >> _pad: take!(2)
+        >> (GrabKeyboard {
+               owner_events,
+               grab_window,
+               time,
+               pointer_mode,
+               keyboard_mode,
+        })
+    )
+);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    const D_INTERNATOM: &'static [u8] = include_bytes!("../dumps/blocked.dmp");
+named_args!(ungrabkeyboard(e: Endianness)<UngrabKeyboard>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> time: u32!(e)
+        >> (UngrabKeyboard { time })
+    )
+);
 
-    #[test]
-    fn test_request() {
-        let req = request(D_INTERNATOM);
-        let req_header = req.unwrap().1;
-        assert_eq!(
-            req_header,
-            Request {
-                opcode: 16,
-                datab: 0,
-                length: 32,
-                data: &[
-                    21, 0, 64, 7, 95, 71, 84, 75, 95, 69, 68, 71, 69, 95, 67,
-                    79, 78, 83, 84, 82, 65, 73, 78, 84, 83, 47, 109, 111
-                ]
-            }
-        );
-        let ia = intern_atom(D_INTERNATOM);
-        let ia = ia.unwrap().1;
-        assert_eq!(
-            ia,
-            InternAtom {
-                only_if_exists: false,
-                name_length: 21,
-                name: std::borrow::Cow::from("_GTK_EDGE_CONSTRAINTS"),
-            },
-        );
+named_args!(grabkey(e: Endianness)<GrabKey>,
+    do_parse!(
+        _opcode: le_u8
+        >> owner_events: le_u8
+        >> _length: u16!(e)
+        >> grab_window: u32!(e)
+        >> modifiers: u16!(e)
+        >> key: le_u8
+        >> pointer_mode: le_u8
+        >> keyboard_mode: le_u8
+        >> _pad: take!(3)
+        >> (GrabKey {
+               owner_events,
+               grab_window,
+               modifiers,
+               key,
+               pointer_mode,
+               keyboard_mode,
+        })
+    )
+);
+
+named_args!(ungrabkey(e: Endianness)<UngrabKey>,
+    do_parse!(
+        _opcode: le_u8
+        >> key: le_u8
+        >> _length: u16!(e)
+        >> grab_window: u32!(e)
+        >> modifiers: u16!(e)
+        >> _pad: take!(2)
+        >> (UngrabKey {
+               key,
+               grab_window,
+               modifiers,
+        })
+    )
+);
+
+named_args!(setselectionowner(e: Endianness)<SetSelectionOwner>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> owner: u32!(e)
+        >> selection: u32!(e)
+        >> time: u32!(e)
+        >> (SetSelectionOwner {
+               owner,
+               selection,
+               time,
+        })
+    )
+);
+
+named_args!(getselectionowner(e: Endianness)<GetSelectionOwner>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> selection: u32!(e)
+        >> (GetSelectionOwner { selection })
+    )
+);
+
+named_args!(convertselection(e: Endianness)<ConvertSelection>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> requestor: u32!(e)
+        >> selection: u32!(e)
+        >> target: u32!(e)
+        >> property: u32!(e)
+        >> time: u32!(e)
+        >> (ConvertSelection {
+               requestor,
+               selection,
+               target,
+               property,
+               time,
+        })
+    )
+);
+
+named_args!(setscreensaver(e: Endianness)<SetScreenSaver>,
+    do_parse!(
+        _opcode: le_u8
+        >> _unused: le_u8
+        >> _length: u16!(e)
+        >> timeout: i16!(e)
+        >> interval: i16!(e)
+        >> prefer_blanking: le_u8
+        >> allow_exposures: le_u8
+        >> (SetScreenSaver {
+               timeout,
+               interval,
+               prefer_blanking,
+               allow_exposures,
+        })
+    )
+);
+
+named_args!(forcescreensaver(e: Endianness)<ForceScreenSaver>,
+    do_parse!(
+        _opcode: le_u8
+        >> mode: le_u8
+        >> _length: u16!(e)
+        >> (ForceScreenSaver { mode })
+    )
+);
+
+named_args!(allowevents(e: Endianness)<AllowEvents>,
+    do_parse!(
+        _opcode: le_u8
+        >> mode: le_u8
+        >> _length: u16!(e)
+        >> time: u32!(e)
+        >> (AllowEvents { mode, time })
+    )
+);
+
+named_args!(setinputfocus(e: Endianness)<SetInputFocus>,
+    do_parse!(
+        _opcode: le_u8
+        >> revert_to: le_u8
+        >> _length: u16!(e)
+        >> focus: u32!(e)
+        >> time: u32!(e)
+        >> (SetInputFocus {
+               revert_to,
+               focus,
+               time,
+        })
+    )
+);
+
+named_args!(changekeyboardmapping<'a>(e: Endianness)<&'a [u8], ChangeKeyboardMapping<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> keycode_count: le_u8
+        >> _length: u16!(e)
+        >> first_keycode: le_u8
+        >> keysyms_per_keycode: le_u8
+        >> _pad: take!(2)
+        >> keysyms: take!(
+            4 * keycode_count as usize * keysyms_per_keycode as usize
+        )
+        >> (ChangeKeyboardMapping {
+               first_keycode,
+               keycode_count,
+               keysyms_per_keycode,
+               keysyms,
+        })
+    )
+);
+
+named_args!(setmodifiermapping<'a>(e: Endianness)<&'a [u8], SetModifierMapping<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> keycodes_per_modifier: le_u8
+        >> _length: u16!(e)
+        >> keycodes: take!(8 * keycodes_per_modifier as usize)
+        >> (SetModifierMapping {
+               keycodes_per_modifier,
+               keycodes,
+        })
+    )
+);
+
+named_args!(setpointermapping<'a>(e: Endianness)<&'a [u8], SetPointerMapping<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> map_length: le_u8
+        >> _length: u16!(e)
+        >> map: take!(map_length)
+        >> (SetPointerMapping { map })
+    )
+);
+
+named_args!(sendevent<'a>(e: Endianness)<&'a [u8], SendEvent<'a>>,
+    do_parse!(
+        _opcode: le_u8
+        >> propagate: le_u8
+        >> _length: u16!(e)
+        >> destination: u32!(e)
+        >> event_mask: u32!(e)
+        >> event: peek!(take!(32))
+        >> event_code: le_u8
+        >> (SendEvent {
+               propagate,
+               destination,
+               event_mask,
+               event_code: event_code & 0x7F,
+               event,
+        })
+    )
+);
+
+// Attach, AttachFd, CreateSegment and Detach all start with the segment.
+named_args!(shm_segment(e: Endianness)<u32>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> _minor_opcode: le_u8
+        >> _length: u16!(e)
+        >> shmseg: u32!(e)
+        >> (shmseg)
+    )
+);
+
+named_args!(shm_get_image(e: Endianness)<ShmGetImage>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> _minor_opcode: le_u8
+        >> _length: u16!(e)
+        >> drawable: u32!(e)
+        >> x: i16!(e)
+        >> y: i16!(e)
+        >> width: u16!(e)
+        >> height: u16!(e)
+        >> plane_mask: u32!(e)
+        >> format: le_u8
+        >> _pad: take!(3)
+        >> shmseg: u32!(e)
+        >> offset: u32!(e)
+        >> (ShmGetImage {
+               drawable,
+               x,
+               y,
+               width,
+               height,
+               plane_mask,
+               format,
+               shmseg,
+               offset,
+        })
+    )
+);
+
+named_args!(
+    shape_rectangles<'a>(e: Endianness)<&'a [u8], ShapeChange<'a>>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> minor_opcode: le_u8
+        >> length: u16!(e)
+        >> operation: le_u8
+        >> kind: le_u8
+        >> _ordering: le_u8
+        >> _pad: le_u8
+        >> window: u32!(e)
+        >> _x: i16!(e)
+        >> _y: i16!(e)
+        >> rectangles: take!((4 * length as usize).saturating_sub(16) / 8 * 8)
+        >> (ShapeChange {
+               minor_opcode,
+               operation,
+               kind,
+               window,
+               rectangles,
+               source: 0,
+               e,
+        })
+    )
+);
+
+named_args!(shape_source<'a>(e: Endianness)<&'a [u8], ShapeChange<'a>>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> minor_opcode: le_u8
+        >> _length: u16!(e)
+        >> operation: le_u8
+        >> kind: le_u8
+        >> _source_kind: le_u8
+        >> _pad: le_u8
+        >> window: u32!(e)
+        >> _x: i16!(e)
+        >> _y: i16!(e)
+        >> source: u32!(e)
+        >> (ShapeChange {
+               minor_opcode,
+               operation,
+               kind,
+               window,
+               rectangles: &[],
+               source,
+               e,
+        })
+    )
+);
+
+named_args!(xi_event_mask(e: Endianness)<u64>,
+    do_parse!(
+        _device_id: u16!(e)
+        >> mask_length: u16!(e)
+        >> mask: count!(u32!(e), mask_length as usize)
+        >> (mask
+            .iter()
+            .take(2)
+            .enumerate()
+            .fold(0, |events, (i, &word)| events | u64::from(word) << (32 * i)))
+    )
+);
+
+named_args!(xi_select_events(e: Endianness)<XISelectEvents>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> _minor_opcode: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> mask_count: u16!(e)
+        >> _pad: take!(2)
+        >> masks: count!(call!(xi_event_mask, e), mask_count as usize)
+        >> (XISelectEvents {
+               window,
+               events: masks.iter().fold(0, |events, mask| events | mask),
+        })
+    )
+);
+
+named_args!(select_selection_input(e: Endianness)<SelectSelectionInput>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> _minor_opcode: le_u8
+        >> _length: u16!(e)
+        >> window: u32!(e)
+        >> selection: u32!(e)
+        >> event_mask: u32!(e)
+        >> (SelectSelectionInput {
+               window,
+               selection,
+               event_mask,
+        })
+    )
+);
+
+named_args!(xtest_fake_input(e: Endianness)<FakeInput>,
+    do_parse!(
+        _major_opcode: le_u8
+        >> _minor_opcode: le_u8
+        >> _length: u16!(e)
+        >> event_type: le_u8
+        >> detail: le_u8
+        >> _pad: take!(2)
+        >> time: u32!(e)
+        >> root: u32!(e)
+        >> _pad2: take!(8)
+        >> root_x: i16!(e)
+        >> root_y: i16!(e)
+        >> _pad3: take!(7)
+        >> device_id: le_u8
+        >> (FakeInput {
+               event_type,
+               detail,
+               time,
+               root,
+               root_x,
+               root_y,
+               device_id,
+        })
+    )
+);
+
+/// A request decoded as far as we know how to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DecodedRequest<'a> {
+    InternAtom(InternAtom<'a>),
+    GetAtomName(GetAtomName),
+    GetProperty(GetProperty),
+    SetSelectionOwner(SetSelectionOwner),
+    GetSelectionOwner(GetSelectionOwner),
+    ConvertSelection(ConvertSelection),
+    QueryExtension(QueryExtension<'a>),
+    ChangeProperty(ChangeProperty<'a>),
+    DeleteProperty(DeleteProperty),
+    ListProperties(ListProperties),
+    RotateProperties(RotateProperties<'a>),
+    CreateWindow(CreateWindow<'a>),
+    ConfigureWindow(ConfigureWindow<'a>),
+    ReparentWindow(ReparentWindow),
+    DestroyWindow(FreeResource),
+    QueryTree(QueryTree),
+    QueryPointer(QueryPointer),
+    GetMotionEvents(GetMotionEvents),
+    GetGeometry(GetGeometry),
+    TranslateCoordinates(TranslateCoordinates),
+    CreatePixmap(CreatePixmap),
+    FreePixmap(FreeResource),
+    CreateGC(CreateGC<'a>),
+    FreeGC(FreeResource),
+    CopyArea(CopyArea),
+    CopyPlane(CopyArea),
+    GetImage(GetImage),
+    ChangeWindowAttributes(ChangeWindowAttributes<'a>),
+    GrabPointer(GrabPointer),
+    GrabButton(GrabButton),
+    GrabKeyboard(GrabKeyboard),
+    UngrabKeyboard(UngrabKeyboard),
+    GrabKey(GrabKey),
+    UngrabKey(UngrabKey),
+    AllowEvents(AllowEvents),
+    // Neither takes any arguments.
+    GrabServer,
+    UngrabServer,
+    SetInputFocus(SetInputFocus),
+    ChangeKeyboardMapping(ChangeKeyboardMapping<'a>),
+    SetModifierMapping(SetModifierMapping<'a>),
+    SetPointerMapping(SetPointerMapping<'a>),
+    SendEvent(SendEvent<'a>),
+    XTestFakeInput(FakeInput),
+    /// A MIT-SHM request that gives the server a new segment.
+    ShmAttach {
+        minor_opcode: u8,
+        shmseg: u32,
+    },
+    ShmDetach {
+        shmseg: u32,
+    },
+    ShmGetImage(ShmGetImage),
+    ShapeChange(ShapeChange<'a>),
+    XISelectEvents(XISelectEvents),
+    SelectSelectionInput(SelectSelectionInput),
+    /// A XFIXES request about cursors, with the cursor or window it
+    /// names first.
+    XFixesCursor {
+        minor_opcode: u8,
+        id: u32,
+    },
+    /// A RANDR request that changes the display configuration.
+    RandrConfigure {
+        minor_opcode: u8,
+    },
+    /// A XKB request that changes the keyboard or makes it signal.
+    XkbChange {
+        minor_opcode: u8,
+    },
+    /// A SYNC request that creates or destroys a counter, alarm or fence.
+    SyncResource {
+        minor_opcode: u8,
+        id: u32,
+    },
+    /// A XC-MISC request for free resource ids.
+    XidQuery {
+        minor_opcode: u8,
+    },
+    /// A DRI3 request that imports a buffer or fence.
+    Dri3Import {
+        minor_opcode: u8,
+    },
+    /// A RECORD request that sets up or starts recording.
+    RecordContext {
+        minor_opcode: u8,
+    },
+    /// A MIT-SCREEN-SAVER request that changes how the screen saver works.
+    ScreenSaverControl {
+        minor_opcode: u8,
+    },
+    SetScreenSaver(SetScreenSaver),
+    ForceScreenSaver(ForceScreenSaver),
+    /// Known opcode without a parser for its fields.
+    Other(Opcode),
+    /// Request of an extension we don't decode.
+    Extension {
+        major_opcode: u8,
+        minor_opcode: u8,
+    },
+    Unknown(u8),
+}
+
+type DecodeResult<'a> = Result<DecodedRequest<'a>, nom::Err<&'a [u8]>>;
+
+/// An extension request we know how to decode. Extension requests carry
+/// the minor opcode in the data byte, and the decoder gets the whole
+/// request like the core ones do.
+struct ExtensionRequest {
+    extension: &'static str,
+    minor_opcode: u8,
+    name: &'static str,
+    decode: for<'a> fn(&'a [u8], Endianness) -> DecodeResult<'a>,
+    /// What a request that doesn't decode needs to go through, if it
+    /// doesn't fail open.
+    capability: Option<Capability>,
+}
+
+/// The extension requests that are decoded rather than passed on as
+/// `DecodedRequest::Extension`. New filters for extensions start here.
+const EXTENSION_REQUESTS: &[ExtensionRequest] = &[
+    ExtensionRequest {
+        extension: "XTEST",
+        minor_opcode: XTEST_FAKE_INPUT,
+        name: "FakeInput",
+        decode: decode_fake_input,
+        capability: Some(Capability::FakeInput),
+    },
+    ExtensionRequest {
+        extension: SHM_NAME,
+        minor_opcode: SHM_ATTACH,
+        name: "Attach",
+        decode: decode_shm_attach,
+        capability: Some(Capability::SharedMemory),
+    },
+    ExtensionRequest {
+        extension: SHM_NAME,
+        minor_opcode: SHM_DETACH,
+        name: "Detach",
+        decode: decode_shm_detach,
+        capability: Some(Capability::SharedMemory),
+    },
+    ExtensionRequest {
+        extension: SHM_NAME,
+        minor_opcode: SHM_GET_IMAGE,
+        name: "GetImage",
+        decode: decode_shm_get_image,
+        capability: Some(Capability::SharedMemory),
+    },
+    ExtensionRequest {
+        extension: SHM_NAME,
+        minor_opcode: SHM_ATTACH_FD,
+        name: "AttachFd",
+        decode: decode_shm_attach,
+        capability: Some(Capability::SharedMemory),
+    },
+    ExtensionRequest {
+        extension: SHM_NAME,
+        minor_opcode: SHM_CREATE_SEGMENT,
+        name: "CreateSegment",
+        decode: decode_shm_attach,
+        capability: Some(Capability::SharedMemory),
+    },
+    ExtensionRequest {
+        extension: XI_NAME,
+        minor_opcode: XI_SELECT_EVENTS,
+        name: "XISelectEvents",
+        decode: decode_xi_select_events,
+        capability: Some(Capability::SnoopKeys),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_SCREEN_CONFIG,
+        name: "SetScreenConfig",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_SCREEN_SIZE,
+        name: "SetScreenSize",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_CRTC_CONFIG,
+        name: "SetCrtcConfig",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_CRTC_GAMMA,
+        name: "SetCrtcGamma",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_CRTC_TRANSFORM,
+        name: "SetCrtcTransform",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_PANNING,
+        name: "SetPanning",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_OUTPUT_PRIMARY,
+        name: "SetOutputPrimary",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_SET_MONITOR,
+        name: "SetMonitor",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: RANDR_NAME,
+        minor_opcode: RANDR_DELETE_MONITOR,
+        name: "DeleteMonitor",
+        decode: decode_randr_configure,
+        capability: Some(Capability::ConfigureDisplay),
+    },
+    ExtensionRequest {
+        extension: XFIXES_NAME,
+        minor_opcode: XFIXES_SELECT_SELECTION_INPUT,
+        name: "SelectSelectionInput",
+        decode: decode_select_selection_input,
+        capability: Some(Capability::WatchSelections),
+    },
+    ExtensionRequest {
+        extension: XFIXES_NAME,
+        minor_opcode: XFIXES_SET_CURSOR_NAME,
+        name: "SetCursorName",
+        decode: decode_xfixes_cursor,
+        capability: Some(Capability::ChangeCursor),
+    },
+    ExtensionRequest {
+        extension: XFIXES_NAME,
+        minor_opcode: XFIXES_CHANGE_CURSOR,
+        name: "ChangeCursor",
+        decode: decode_xfixes_cursor,
+        capability: Some(Capability::ChangeCursor),
+    },
+    ExtensionRequest {
+        extension: XFIXES_NAME,
+        minor_opcode: XFIXES_CHANGE_CURSOR_BY_NAME,
+        name: "ChangeCursorByName",
+        decode: decode_xfixes_cursor,
+        capability: Some(Capability::ChangeCursor),
+    },
+    ExtensionRequest {
+        extension: XFIXES_NAME,
+        minor_opcode: XFIXES_HIDE_CURSOR,
+        name: "HideCursor",
+        decode: decode_xfixes_cursor,
+        capability: Some(Capability::ChangeCursor),
+    },
+    ExtensionRequest {
+        extension: XKB_NAME,
+        minor_opcode: XKB_BELL,
+        name: "Bell",
+        decode: decode_xkb_change,
+        capability: Some(Capability::KeyboardFeedback),
+    },
+    ExtensionRequest {
+        extension: XKB_NAME,
+        minor_opcode: XKB_SET_CONTROLS,
+        name: "SetControls",
+        decode: decode_xkb_change,
+        capability: Some(Capability::RemapInput),
+    },
+    ExtensionRequest {
+        extension: XKB_NAME,
+        minor_opcode: XKB_SET_MAP,
+        name: "SetMap",
+        decode: decode_xkb_change,
+        capability: Some(Capability::RemapInput),
+    },
+    ExtensionRequest {
+        extension: XKB_NAME,
+        minor_opcode: XKB_SET_INDICATOR_MAP,
+        name: "SetIndicatorMap",
+        decode: decode_xkb_change,
+        capability: Some(Capability::KeyboardFeedback),
+    },
+    ExtensionRequest {
+        extension: XKB_NAME,
+        minor_opcode: XKB_SET_NAMED_INDICATOR,
+        name: "SetNamedIndicator",
+        decode: decode_xkb_change,
+        capability: Some(Capability::KeyboardFeedback),
+    },
+    ExtensionRequest {
+        extension: SHAPE_NAME,
+        minor_opcode: SHAPE_RECTANGLES,
+        name: "Rectangles",
+        decode: decode_shape_rectangles,
+        capability: Some(Capability::ClickThrough),
+    },
+    ExtensionRequest {
+        extension: SHAPE_NAME,
+        minor_opcode: SHAPE_MASK,
+        name: "Mask",
+        decode: decode_shape_source,
+        capability: Some(Capability::ClickThrough),
+    },
+    ExtensionRequest {
+        extension: SHAPE_NAME,
+        minor_opcode: SHAPE_COMBINE,
+        name: "Combine",
+        decode: decode_shape_source,
+        capability: Some(Capability::ClickThrough),
+    },
+    ExtensionRequest {
+        extension: XC_MISC_NAME,
+        minor_opcode: XC_MISC_GET_XID_RANGE,
+        name: "GetXIDRange",
+        decode: decode_xid_query,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: XC_MISC_NAME,
+        minor_opcode: XC_MISC_GET_XID_LIST,
+        name: "GetXIDList",
+        decode: decode_xid_query,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: SYNC_NAME,
+        minor_opcode: SYNC_CREATE_COUNTER,
+        name: "CreateCounter",
+        decode: decode_sync_resource,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: SYNC_NAME,
+        minor_opcode: SYNC_DESTROY_COUNTER,
+        name: "DestroyCounter",
+        decode: decode_sync_resource,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: SYNC_NAME,
+        minor_opcode: SYNC_CREATE_ALARM,
+        name: "CreateAlarm",
+        decode: decode_sync_resource,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: SYNC_NAME,
+        minor_opcode: SYNC_DESTROY_ALARM,
+        name: "DestroyAlarm",
+        decode: decode_sync_resource,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: SYNC_NAME,
+        minor_opcode: SYNC_CREATE_FENCE,
+        name: "CreateFence",
+        decode: decode_sync_resource,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: SYNC_NAME,
+        minor_opcode: SYNC_DESTROY_FENCE,
+        name: "DestroyFence",
+        decode: decode_sync_resource,
+        capability: None,
+    },
+    ExtensionRequest {
+        extension: DRI3_NAME,
+        minor_opcode: DRI3_PIXMAP_FROM_BUFFER,
+        name: "PixmapFromBuffer",
+        decode: decode_dri3_import,
+        capability: Some(Capability::ImportBuffers),
+    },
+    ExtensionRequest {
+        extension: DRI3_NAME,
+        minor_opcode: DRI3_FENCE_FROM_FD,
+        name: "FenceFromFD",
+        decode: decode_dri3_import,
+        capability: Some(Capability::ImportBuffers),
+    },
+    ExtensionRequest {
+        extension: DRI3_NAME,
+        minor_opcode: DRI3_PIXMAP_FROM_BUFFERS,
+        name: "PixmapFromBuffers",
+        decode: decode_dri3_import,
+        capability: Some(Capability::ImportBuffers),
+    },
+    ExtensionRequest {
+        extension: DRI3_NAME,
+        minor_opcode: DRI3_IMPORT_SYNCOBJ,
+        name: "ImportSyncobj",
+        decode: decode_dri3_import,
+        capability: Some(Capability::ImportBuffers),
+    },
+    ExtensionRequest {
+        extension: "RECORD",
+        minor_opcode: RECORD_CREATE_CONTEXT,
+        name: "CreateContext",
+        decode: decode_record_context,
+        capability: Some(Capability::RecordInput),
+    },
+    ExtensionRequest {
+        extension: "RECORD",
+        minor_opcode: RECORD_ENABLE_CONTEXT,
+        name: "EnableContext",
+        decode: decode_record_context,
+        capability: Some(Capability::RecordInput),
+    },
+    ExtensionRequest {
+        extension: "MIT-SCREEN-SAVER",
+        minor_opcode: SCREENSAVER_SET_ATTRIBUTES,
+        name: "SetAttributes",
+        decode: decode_screensaver_control,
+        capability: Some(Capability::ScreenSaver),
+    },
+    ExtensionRequest {
+        extension: "MIT-SCREEN-SAVER",
+        minor_opcode: SCREENSAVER_UNSET_ATTRIBUTES,
+        name: "UnsetAttributes",
+        decode: decode_screensaver_control,
+        capability: Some(Capability::ScreenSaver),
+    },
+    ExtensionRequest {
+        extension: "MIT-SCREEN-SAVER",
+        minor_opcode: SCREENSAVER_SUSPEND,
+        name: "Suspend",
+        decode: decode_screensaver_control,
+        capability: Some(Capability::ScreenSaver),
+    },
+];
+
+fn decode_fake_input(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    xtest_fake_input(data, e)
+        .map(|(_, req)| DecodedRequest::XTestFakeInput(req))
+}
+
+fn decode_shm_attach(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    shm_segment(data, e).map(|(_, shmseg)| DecodedRequest::ShmAttach {
+        minor_opcode: data[1],
+        shmseg,
+    })
+}
+
+fn decode_shm_detach(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    shm_segment(data, e).map(|(_, shmseg)| DecodedRequest::ShmDetach { shmseg })
+}
+
+fn decode_shm_get_image(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    shm_get_image(data, e).map(|(_, req)| DecodedRequest::ShmGetImage(req))
+}
+
+fn decode_shape_rectangles(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    shape_rectangles(data, e).map(|(_, req)| DecodedRequest::ShapeChange(req))
+}
+
+fn decode_shape_source(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    shape_source(data, e).map(|(_, req)| DecodedRequest::ShapeChange(req))
+}
+
+fn decode_xi_select_events(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    xi_select_events(data, e)
+        .map(|(_, req)| DecodedRequest::XISelectEvents(req))
+}
+
+fn decode_select_selection_input(
+    data: &[u8],
+    e: Endianness,
+) -> DecodeResult<'_> {
+    select_selection_input(data, e)
+        .map(|(_, req)| DecodedRequest::SelectSelectionInput(req))
+}
+
+fn decode_xfixes_cursor(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    free_resource(data, e).map(|(_, req)| DecodedRequest::XFixesCursor {
+        minor_opcode: data[1],
+        id: req.id,
+    })
+}
+
+fn decode_randr_configure(data: &[u8], _e: Endianness) -> DecodeResult<'_> {
+    Ok(DecodedRequest::RandrConfigure {
+        minor_opcode: data[1],
+    })
+}
+
+fn decode_xkb_change(data: &[u8], _e: Endianness) -> DecodeResult<'_> {
+    Ok(DecodedRequest::XkbChange {
+        minor_opcode: data[1],
+    })
+}
+
+fn decode_sync_resource(data: &[u8], e: Endianness) -> DecodeResult<'_> {
+    let id = if data[1] == SYNC_CREATE_FENCE {
+        sync_create_fence(data, e)?.1
+    } else {
+        free_resource(data, e)?.1.id
+    };
+    Ok(DecodedRequest::SyncResource {
+        minor_opcode: data[1],
+        id,
+    })
+}
+
+fn decode_xid_query(data: &[u8], _e: Endianness) -> DecodeResult<'_> {
+    Ok(DecodedRequest::XidQuery {
+        minor_opcode: data[1],
+    })
+}
+
+fn decode_dri3_import(data: &[u8], _e: Endianness) -> DecodeResult<'_> {
+    Ok(DecodedRequest::Dri3Import {
+        minor_opcode: data[1],
+    })
+}
+
+fn decode_record_context(data: &[u8], _e: Endianness) -> DecodeResult<'_> {
+    Ok(DecodedRequest::RecordContext {
+        minor_opcode: data[1],
+    })
+}
+
+fn decode_screensaver_control(data: &[u8], _e: Endianness) -> DecodeResult<'_> {
+    Ok(DecodedRequest::ScreenSaverControl {
+        minor_opcode: data[1],
+    })
+}
+
+/// The extension `header` is a request of, and the entry for the request
+/// if there is one.
+fn extension_request<'s>(
+    state: &'s ConnectionState,
+    header: &Request,
+) -> Option<(&'s str, Option<&'static ExtensionRequest>)> {
+    let extension = state.extension(header.opcode)?;
+    let request = EXTENSION_REQUESTS.iter().find(|request| {
+        request.extension == extension && request.minor_opcode == header.datab
+    });
+    Some((extension, request))
+}
+
+/// How many file descriptors `request` passes along: one for the MIT-SHM
+/// and DRI3 requests that hand the server memory, a buffer or a fence,
+/// and as many as it says for the DRI3 ones with several buffers.
+fn passed_fds(
+    state: &ConnectionState,
+    header: &Request,
+    request: &[u8],
+) -> usize {
+    match extension_request(state, header) {
+        Some((SHM_NAME, _)) if header.datab == SHM_ATTACH_FD => 1,
+        Some((DRI3_NAME, _)) => match header.datab {
+            DRI3_PIXMAP_FROM_BUFFER
+            | DRI3_FENCE_FROM_FD
+            | DRI3_IMPORT_SYNCOBJ => 1,
+            DRI3_PIXMAP_FROM_BUFFERS => {
+                request.get(12).map_or(0, |&n| n as usize)
+            }
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
+
+fn decode_request<'a>(
+    state: &ConnectionState,
+    header: &Request,
+    data: &'a [u8],
+    e: Endianness,
+) -> DecodeResult<'a> {
+    macro_rules! decode {
+        ($parser:ident, $variant:ident) => {
+            $parser(data, e).map(|(_, req)| DecodedRequest::$variant(req))
+        };
+    }
+
+    // Extension major opcodes are assigned by the server.
+    if let Some((_, request)) = extension_request(state, header) {
+        return match request {
+            Some(request) => (request.decode)(data, e),
+            None => Ok(DecodedRequest::Extension {
+                major_opcode: header.opcode,
+                minor_opcode: header.datab,
+            }),
+        };
+    }
+
+    match Opcode::from_u8(header.opcode) {
+        Some(Opcode::InternAtom) => decode!(intern_atom, InternAtom),
+        Some(Opcode::GetAtomName) => decode!(getatomname, GetAtomName),
+        Some(Opcode::GetProperty) => decode!(getproperty, GetProperty),
+        Some(Opcode::SetSelectionOwner) => {
+            decode!(setselectionowner, SetSelectionOwner)
+        }
+        Some(Opcode::GetSelectionOwner) => {
+            decode!(getselectionowner, GetSelectionOwner)
+        }
+        Some(Opcode::ConvertSelection) => {
+            decode!(convertselection, ConvertSelection)
+        }
+        Some(Opcode::QueryExtension) => {
+            decode!(queryextension, QueryExtension)
+        }
+        Some(Opcode::ChangeProperty) => {
+            decode!(changeproperty, ChangeProperty)
+        }
+        Some(Opcode::DeleteProperty) => {
+            decode!(deleteproperty, DeleteProperty)
+        }
+        Some(Opcode::ListProperties) => {
+            decode!(listproperties, ListProperties)
+        }
+        Some(Opcode::RotateProperties) => {
+            decode!(rotateproperties, RotateProperties)
+        }
+        Some(Opcode::CreateWindow) => decode!(createwindow, CreateWindow),
+        Some(Opcode::DestroyWindow) => decode!(free_resource, DestroyWindow),
+        Some(Opcode::ConfigureWindow) => {
+            decode!(configurewindow, ConfigureWindow)
+        }
+        Some(Opcode::ReparentWindow) => {
+            decode!(reparentwindow, ReparentWindow)
+        }
+        Some(Opcode::QueryTree) => decode!(querytree, QueryTree),
+        Some(Opcode::QueryPointer) => decode!(querypointer, QueryPointer),
+        Some(Opcode::GetMotionEvents) => {
+            decode!(getmotionevents, GetMotionEvents)
+        }
+        Some(Opcode::GetGeometry) => decode!(getgeometry, GetGeometry),
+        Some(Opcode::TranslateCoordinates) => {
+            decode!(translatecoordinates, TranslateCoordinates)
+        }
+        Some(Opcode::CreatePixmap) => decode!(createpixmap, CreatePixmap),
+        Some(Opcode::FreePixmap) => decode!(free_resource, FreePixmap),
+        Some(Opcode::CreateGC) => decode!(creategc, CreateGC),
+        Some(Opcode::FreeGC) => decode!(free_resource, FreeGC),
+        Some(Opcode::CopyArea) => decode!(copyarea, CopyArea),
+        Some(Opcode::CopyPlane) => decode!(copyarea, CopyPlane),
+        Some(Opcode::GetImage) => decode!(getimage, GetImage),
+        Some(Opcode::ChangeWindowAttributes) => {
+            decode!(changewindowattributes, ChangeWindowAttributes)
+        }
+        Some(Opcode::GrabPointer) => decode!(grabpointer, GrabPointer),
+        Some(Opcode::GrabButton) => decode!(grabbutton, GrabButton),
+        Some(Opcode::SendEvent) => decode!(sendevent, SendEvent),
+        Some(Opcode::GrabKeyboard) => decode!(grabkeyboard, GrabKeyboard),
+        Some(Opcode::UngrabKeyboard) => {
+            decode!(ungrabkeyboard, UngrabKeyboard)
+        }
+        Some(Opcode::GrabKey) => decode!(grabkey, GrabKey),
+        Some(Opcode::UngrabKey) => decode!(ungrabkey, UngrabKey),
+        Some(Opcode::SetScreenSaver) => {
+            decode!(setscreensaver, SetScreenSaver)
+        }
+        Some(Opcode::ForceScreenSaver) => {
+            decode!(forcescreensaver, ForceScreenSaver)
+        }
+        Some(Opcode::AllowEvents) => decode!(allowevents, AllowEvents),
+        Some(Opcode::GrabServer) => Ok(DecodedRequest::GrabServer),
+        Some(Opcode::UngrabServer) => Ok(DecodedRequest::UngrabServer),
+        Some(Opcode::SetInputFocus) => decode!(setinputfocus, SetInputFocus),
+        Some(Opcode::ChangeKeyboardMapping) => {
+            decode!(changekeyboardmapping, ChangeKeyboardMapping)
+        }
+        Some(Opcode::SetModifierMapping) => {
+            decode!(setmodifiermapping, SetModifierMapping)
+        }
+        Some(Opcode::SetPointerMapping) => {
+            decode!(setpointermapping, SetPointerMapping)
+        }
+        // Every known opcode has a parser at the moment.
+        #[allow(unreachable_patterns)]
+        Some(opcode) => Ok(DecodedRequest::Other(opcode)),
+        None => Ok(DecodedRequest::Unknown(header.opcode)),
+    }
+}
+
+/// Bytes a request needs for what it decoded to, before padding, where
+/// that's determined by the fields.
+fn decoded_length(decoded: &DecodedRequest) -> Option<usize> {
+    let length = match *decoded {
+        DecodedRequest::InternAtom(ref intern) => {
+            8 + intern.name_length as usize
+        }
+        DecodedRequest::QueryExtension(ref query) => {
+            8 + query.name_length as usize
+        }
+        DecodedRequest::ChangeProperty(ref change) => 24 + change.data.len(),
+        DecodedRequest::RotateProperties(ref rotate) => {
+            12 + rotate.properties.len()
+        }
+        DecodedRequest::CreateWindow(ref create) => {
+            32 + 4 * create.attributes.len()
+        }
+        DecodedRequest::CreateGC(ref create) => {
+            16 + 4 * create.components.len()
+        }
+        DecodedRequest::ChangeWindowAttributes(ref change) => {
+            12 + 4 * change.attributes.len()
+        }
+        DecodedRequest::ConfigureWindow(ref configure) => {
+            12 + 4 * configure.values.len()
+        }
+        DecodedRequest::ChangeKeyboardMapping(ref change) => {
+            8 + change.keysyms.len()
+        }
+        DecodedRequest::SetModifierMapping(ref set) => 4 + set.keycodes.len(),
+        DecodedRequest::SetPointerMapping(ref set) => 4 + set.map.len(),
+        DecodedRequest::GrabServer
+        | DecodedRequest::UngrabServer
+        | DecodedRequest::ForceScreenSaver(_) => 4,
+        DecodedRequest::GetAtomName(_)
+        | DecodedRequest::DestroyWindow(_)
+        | DecodedRequest::FreePixmap(_)
+        | DecodedRequest::FreeGC(_)
+        | DecodedRequest::QueryTree(_)
+        | DecodedRequest::QueryPointer(_)
+        | DecodedRequest::ListProperties(_)
+        | DecodedRequest::GetSelectionOwner(_)
+        | DecodedRequest::GetGeometry(_)
+        | DecodedRequest::UngrabKeyboard(_)
+        | DecodedRequest::AllowEvents(_)
+        | DecodedRequest::ShmDetach { .. } => 8,
+        DecodedRequest::UngrabKey(_)
+        | DecodedRequest::DeleteProperty(_)
+        | DecodedRequest::SetInputFocus(_)
+        | DecodedRequest::SetScreenSaver(_) => 12,
+        DecodedRequest::ShmAttach { minor_opcode, .. } => {
+            if minor_opcode == SHM_ATTACH_FD {
+                12
+            } else {
+                16
+            }
+        }
+        DecodedRequest::SetSelectionOwner(_)
+        | DecodedRequest::ReparentWindow(_)
+        | DecodedRequest::GetMotionEvents(_)
+        | DecodedRequest::TranslateCoordinates(_)
+        | DecodedRequest::CreatePixmap(_)
+        | DecodedRequest::GrabKeyboard(_)
+        | DecodedRequest::GrabKey(_)
+        | DecodedRequest::SelectSelectionInput(_) => 16,
+        DecodedRequest::GetImage(_) => 20,
+        DecodedRequest::CopyArea(_) => 28,
+        DecodedRequest::CopyPlane(_) => 32,
+        DecodedRequest::GetProperty(_)
+        | DecodedRequest::ConvertSelection(_)
+        | DecodedRequest::GrabPointer(_)
+        | DecodedRequest::GrabButton(_) => 24,
+        DecodedRequest::ShmGetImage(_) => 32,
+        DecodedRequest::ShapeChange(ref shape) => {
+            if shape.minor_opcode == SHAPE_RECTANGLES {
+                16 + shape.rectangles.len()
+            } else {
+                20
+            }
+        }
+        DecodedRequest::XTestFakeInput(_) => 36,
+        DecodedRequest::SendEvent(_) => 44,
+        DecodedRequest::XISelectEvents(_)
+        | DecodedRequest::XFixesCursor { .. }
+        | DecodedRequest::RandrConfigure { .. }
+        | DecodedRequest::XkbChange { .. }
+        | DecodedRequest::Dri3Import { .. }
+        | DecodedRequest::SyncResource { .. }
+        | DecodedRequest::XidQuery { .. }
+        | DecodedRequest::RecordContext { .. }
+        | DecodedRequest::ScreenSaverControl { .. }
+        | DecodedRequest::Other(_)
+        | DecodedRequest::Extension { .. }
+        | DecodedRequest::Unknown(_) => return None,
+    };
+    Some(length)
+}
+
+/// Whether a request of `length` bytes is longer than its padded fields,
+/// with room for data a filter that trusts the length wouldn't look at.
+fn oversized(decoded: &DecodeResult, length: usize) -> bool {
+    match decoded.as_ref().ok().and_then(decoded_length) {
+        Some(needed) => length > (needed + 3) & !3,
+        None => false,
+    }
+}
+
+/// The error code and bad value to answer a property request with instead
+/// of passing it on: BadValue for a GetProperty starting past the policy's
+/// ceiling, BadLength for a ChangeProperty with more or less data than it
+/// says.
+fn property_error(
+    policy: &Policy,
+    decoded: &DecodeResult,
+    length: usize,
+) -> Option<(u8, u32)> {
+    match *decoded {
+        Ok(DecodedRequest::GetProperty(ref get)) => {
+            match policy.max_property_offset() {
+                Some(limit) if 4 * u64::from(get.offset) > limit => {
+                    Some((BAD_VALUE, get.offset))
+                }
+                _ => None,
+            }
+        }
+        Ok(DecodedRequest::ChangeProperty(ref change))
+            if (24 + change.data.len() + 3) & !3 != length =>
+        {
+            Some((BAD_LENGTH, 0))
+        }
+        _ => None,
+    }
+}
+
+/// BadValue for an InternAtom of an atom the policy denies with an error.
+fn atom_error(policy: &Policy, decoded: &DecodeResult) -> Option<(u8, u32)> {
+    match *decoded {
+        Ok(DecodedRequest::InternAtom(ref intern))
+            if policy.atom_denial(&intern.name) == Some(AtomDenial::Error) =>
+        {
+            Some((BAD_VALUE, 0))
+        }
+        _ => None,
+    }
+}
+
+/// Put in front of the names of atoms the policy denies by spoofing them.
+const SPOOFED_ATOM_PREFIX: &str = "_RUSTYWIN_SPOOFED_";
+
+/// The name to intern in place of `name`, if the policy denies it by
+/// spoofing it.
+fn spoofed_atom(policy: &Policy, name: &str) -> Option<Vec<u8>> {
+    if policy.atom_denial(name) != Some(AtomDenial::Spoof) {
+        return None;
+    }
+    info!("Spoofing atom {}", name);
+    metrics::DENIED_ATOMS.add(1);
+    Some(format!("{}{}", SPOOFED_ATOM_PREFIX, name).into_bytes())
+}
+
+/// Atom names of the conventions clients and window managers talk to each
+/// other by, which stay shared when the client's own atoms are partitioned.
+/// Names not starting with an underscore, such as the predefined atoms,
+/// those of the ICCCM and selection targets, are always shared.
+const SHARED_ATOM_PREFIXES: &[&str] = &[
+    "_NET_",
+    "_MOTIF_",
+    "_XEMBED",
+    "_XSETTINGS_",
+    "_XKB_",
+    "_GTK_",
+    "_KDE_",
+    "_ICC_PROFILE",
+    "_XROOTPMAP_ID",
+];
+
+/// The name to intern in place of `name`, if the policy partitions atoms
+/// and `name` is the client's own.
+fn partitioned_atom(policy: &Policy, name: &[u8]) -> Option<Vec<u8>> {
+    let prefix = policy.atom_prefix()?;
+    if !name.starts_with(b"_")
+        || SHARED_ATOM_PREFIXES
+            .iter()
+            .any(|shared| name.starts_with(shared.as_bytes()))
+    {
+        return None;
+    }
+    let mut partitioned = prefix.as_bytes().to_vec();
+    partitioned.extend(name);
+    Some(partitioned)
+}
+
+/// Write a changed `request` to `out` in its place, if the policy asks
+/// for one, and return whether it did: a GetProperty asking for more than the ceiling cut down to it,
+/// as the reply says how much is left and clients read on from there,
+/// or an InternAtom of a spoofed atom or of one of the client's own atoms
+/// in its partition.
+fn rewrite_request(
+    policy: &Policy,
+    decoded: &DecodeResult,
+    request: &[u8],
+    e: Endianness,
+    out: &mut Vec<u8>,
+) -> bool {
+    let start = out.len();
+    match *decoded {
+        Ok(DecodedRequest::GetProperty(ref get)) => {
+            let limit = match policy.max_property_length() {
+                Some(limit) if 4 * u64::from(get.length) > limit => limit,
+                _ => return false,
+            };
+            metrics::PROPERTY_LENGTHS_CLAMPED.add(1);
+            out.extend(request);
+            write_u32(&mut out[start + 20..start + 24], (limit / 4) as u32, e);
+            true
+        }
+        Ok(DecodedRequest::InternAtom(ref intern)) => {
+            let name = match request.get(8..8 + intern.name_length as usize) {
+                Some(name) => name,
+                None => return false,
+            };
+            let name = match spoofed_atom(policy, &intern.name)
+                .or_else(|| partitioned_atom(policy, name))
+            {
+                Some(name) => name,
+                None => return false,
+            };
+            out.extend(&request[0..8]);
+            write_u16(&mut out[start + 4..start + 6], name.len() as u16, e);
+            out.extend(name);
+            out.resize((out.len() + 3) & !3, 0);
+            let length = (out.len() - start) as u16 / 4;
+            write_u16(&mut out[start + 2..start + 4], length, e);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Whether `header` is a GLX, DRI3 or Present request to pass on without
+/// decoding it, as the policy's GPU mode says.
+fn gpu_passthrough(
+    state: &ConnectionState,
+    policy: &Policy,
+    header: &Request,
+) -> bool {
+    match extension_request(state, header) {
+        Some((extension, request)) if GPU_EXTENSIONS.contains(&extension) => {
+            // The DRI3 imports are the only ones decoded.
+            policy.gpu_mode() == GpuMode::Passthrough || request.is_none()
+        }
+        _ => false,
+    }
+}
+
+/// Update the connection state for a request that goes to the server.
+/// Returns its sequence number.
+fn track_request(
+    state: &mut ConnectionState,
+    header: &Request,
+    decoded: &DecodeResult,
+) -> u16 {
+    let sequence = state.next_sequence();
+    state.sent_request(sequence, sent_request(header, decoded));
+    if state.extension(header.opcode) == Some(BIG_REQUESTS_NAME)
+        && header.datab == BIG_REQ_ENABLE
+    {
+        state.enable_big_requests();
+    }
+    match *decoded {
+        Ok(DecodedRequest::QueryExtension(ref query)) => {
+            state.query_extension(sequence, &query.name);
+        }
+        Ok(DecodedRequest::InternAtom(ref intern)) => {
+            state.intern_atom(sequence, &intern.name);
+        }
+        Ok(DecodedRequest::GetAtomName(ref get)) => {
+            state.atom_name_query(sequence, get.atom)
+        }
+        Ok(DecodedRequest::QueryPointer(ref query))
+            if !state.owns(query.window) =>
+        {
+            state.foreign_pointer_query(sequence);
+        }
+        Ok(DecodedRequest::Extension {
+            major_opcode,
+            minor_opcode,
+        }) if state.extension(major_opcode) == Some(RANDR_NAME)
+            && RANDR_GEOMETRY_QUERIES.contains(&minor_opcode) =>
+        {
+            state.geometry_query(sequence, minor_opcode);
+        }
+        Ok(DecodedRequest::SetSelectionOwner(ref set)) => {
+            let owned = set.owner != 0 && state.owns(set.owner);
+            state.set_selection_owned(set.selection, owned);
+        }
+        // The owner of its selection is the input method's window.
+        Ok(DecodedRequest::GetSelectionOwner(ref get))
+            if state
+                .atom_name(get.selection)
+                .is_some_and(xim::is_server_selection) =>
+        {
+            state.input_method_query(sequence);
+        }
+        // The input method answers on the window the client names.
+        Ok(DecodedRequest::SendEvent(ref send))
+            if input_method_message(state, send)
+                == Some(xim::CONNECT_MESSAGE) =>
+        {
+            let e = state.byte_order();
+            state.input_method_connect(read_u32(&send.event[12..16], e));
+        }
+        Ok(DecodedRequest::ChangeProperty(ref change)) => {
+            let incr = state.atom_name(change.prop_type) == Some("INCR");
+            let size = change.data.len() as u64;
+            state.transfer_sent(change.window, change.property, size, incr);
+            if change.property == WM_HINTS && change.format == 32 {
+                let e = state.byte_order();
+                let hints =
+                    change.data.chunks(4).map(|field| read_u32(field, e));
+                state.set_wm_hints(change.window, hints.collect());
+            }
+        }
+        Ok(DecodedRequest::CreateWindow(ref create)) => {
+            state.add_resource(create.wid, Resource::Window);
+            if let Some(enabled) = create.attributes.get(CW_OVERRIDE_REDIRECT) {
+                state.set_override_redirect(create.wid, enabled != 0);
+            }
+            state.set_window_size(create.wid, create.width, create.height);
+            state.window_created(create.wid, create.parent);
+        }
+        Ok(DecodedRequest::ChangeWindowAttributes(ref change)) => {
+            if let Some(enabled) = change.attributes.get(CW_OVERRIDE_REDIRECT) {
+                state.set_override_redirect(change.window, enabled != 0);
+            }
+        }
+        Ok(DecodedRequest::ConfigureWindow(ref configure)) => {
+            if let Some((width, height)) = state.window_size(configure.window) {
+                let values = &configure.values;
+                state.set_window_size(
+                    configure.window,
+                    values.get(CONFIG_WINDOW_WIDTH).map_or(width, |w| w as u16),
+                    values
+                        .get(CONFIG_WINDOW_HEIGHT)
+                        .map_or(height, |h| h as u16),
+                );
+            }
+        }
+        Ok(DecodedRequest::CreatePixmap(ref create)) => {
+            state.add_resource(create.pid, Resource::Pixmap);
+        }
+        Ok(DecodedRequest::ShmAttach { shmseg, .. }) => {
+            state.add_resource(shmseg, Resource::ShmSegment);
+        }
+        Ok(DecodedRequest::ShmDetach { shmseg }) => {
+            state.remove_resource(shmseg);
+        }
+        Ok(DecodedRequest::CreateGC(ref create)) => {
+            state.add_resource(create.cid, Resource::GContext);
+        }
+        Ok(DecodedRequest::DestroyWindow(ref free)) => {
+            state.remove_resource(free.id);
+            state.forget_window_size(free.id);
+            state.window_destroyed(free.id);
+        }
+        Ok(DecodedRequest::SyncResource { minor_opcode, id }) => {
+            match minor_opcode {
+                SYNC_CREATE_COUNTER => {
+                    state.add_resource(id, Resource::Counter)
+                }
+                SYNC_CREATE_ALARM => state.add_resource(id, Resource::Alarm),
+                SYNC_CREATE_FENCE => state.add_resource(id, Resource::Fence),
+                _ => state.remove_resource(id),
+            }
+        }
+        Ok(DecodedRequest::XidQuery { minor_opcode }) => {
+            state.xid_query(sequence, minor_opcode);
+        }
+        Ok(DecodedRequest::FreePixmap(ref free))
+        | Ok(DecodedRequest::FreeGC(ref free)) => {
+            state.remove_resource(free.id);
+        }
+        _ => (),
+    }
+    sequence
+}
+
+fn print_decoded(decoded: &DecodeResult) {
+    match *decoded {
+        Ok(DecodedRequest::Unknown(_))
+        | Ok(DecodedRequest::Extension { .. }) => (),
+        Ok(DecodedRequest::Other(opcode)) => println!("{:?}", opcode),
+        Ok(ref decoded) => println!("{:?}", decoded),
+        Err(ref err) => println!("{:?}", err),
+    }
+}
+
+/// The name of the request with `header`, with its extension's.
+fn request_name(state: &ConnectionState, header: &Request) -> String {
+    match extension_request(state, header) {
+        Some((extension, Some(request))) => {
+            format!("{} {}", extension, request.name)
+        }
+        Some((extension, None)) => format!("{} {}", extension, header.datab),
+        None => opcode_name(header.opcode),
+    }
+}
+
+/// Name of the core request with `opcode`, or the bare number.
+pub fn opcode_name(opcode: u8) -> String {
+    match Opcode::from_u8(opcode) {
+        Some(opcode) => format!("{:?}", opcode),
+        None => format!("Opcode {}", opcode),
+    }
+}
+
+/// What a request the server is answering was, for the log. It's only
+/// named, like `GetProperty #1234 on _NET_WM_NAME`, when it's shown.
+pub struct RequestLabel<'s> {
+    state: &'s ConnectionState,
+    sequence: u16,
+    sent: SentRequest,
+}
+
+impl<'s> fmt::Display for RequestLabel<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = Request {
+            opcode: self.sent.opcode,
+            datab: self.sent.minor_opcode,
+            length: 0,
+            data: &[],
+        };
+        let name = request_name(self.state, &header);
+        write!(f, "{} #{}", name, self.sequence)?;
+        match self.sent.property {
+            Some(property) => match self.state.atom_name(property) {
+                Some(atom) => write!(f, " on {}", atom),
+                None => write!(f, " on atom {}", property),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// The request with `sequence`, if the server is answering it.
+pub fn request_label(
+    state: &ConnectionState,
+    sequence: u16,
+) -> Option<RequestLabel<'_>> {
+    let sent = state.request(sequence)?;
+    Some(RequestLabel {
+        state,
+        sequence,
+        sent,
+    })
+}
+
+/// What to remember of a request passed on, to name it in the log once
+/// the server answers it.
+fn sent_request(header: &Request, decoded: &DecodeResult) -> SentRequest {
+    let property = match *decoded {
+        Ok(DecodedRequest::GetProperty(ref get)) => Some(get.property),
+        Ok(DecodedRequest::ChangeProperty(ref change)) => Some(change.property),
+        _ => None,
+    };
+    SentRequest {
+        opcode: header.opcode,
+        minor_opcode: header.datab,
+        property,
+    }
+}
+
+/// Annotated hexdump of a request, see `hexdump::format_request`.
+fn format_request(
+    state: &ConnectionState,
+    offset: usize,
+    header: &Request,
+    request: &[u8],
+    decoded: &DecodeResult,
+) -> String {
+    let name = request_name(state, header);
+    let decoded = match *decoded {
+        Ok(ref decoded) => format!("{:?}", decoded),
+        Err(ref err) => format!("Decoding failed: {:?}", err),
+    };
+    hexdump::format_request(offset, &name, request, header.length, &decoded)
+}
+
+pub fn check(
+    state: &ConnectionState,
+    policy: &Policy,
+    capability: Capability,
+) -> Outcome {
+    let client_base = state.setup_info().resource_id_base;
+    if policy.allows(capability) {
+        Outcome::Granted(capability)
+    } else if state.has_exception(capability) {
+        info!("Granting {} under a timed exception", capability);
+        Outcome::Granted(capability)
+    } else if policy
+        .grants()
+        .is_some_and(|grants| grants.allows(client_base, capability))
+    {
+        info!(
+            "Granting {} for now, on the window manager's say",
+            capability
+        );
+        Outcome::Granted(capability)
+    } else if policy.spoofs(capability) {
+        info!("Spoofing a reply to request needing {}", capability);
+        Outcome::Spoofed(capability)
+    } else {
+        warn!("Denying request needing {}", capability);
+        Outcome::Denied(capability)
+    }
+}
+
+/// Core requests that fail closed when their fields don't decode, with
+/// the capability they would need if they said the worst. Requests of
+/// other opcodes fail open.
+const DECODE_FAILURE_CAPABILITIES: &[(Opcode, Capability)] = &[
+    (Opcode::ChangeWindowAttributes, Capability::SnoopKeys),
+    (Opcode::ReparentWindow, Capability::RearrangeWindows),
+    (Opcode::ConfigureWindow, Capability::RearrangeWindows),
+    (Opcode::QueryTree, Capability::ListWindows),
+    (Opcode::ChangeProperty, Capability::ClipboardCopy),
+    (Opcode::DeleteProperty, Capability::ForeignProperties),
+    (Opcode::RotateProperties, Capability::ForeignProperties),
+    (Opcode::SetSelectionOwner, Capability::ClipboardCopy),
+    (Opcode::ConvertSelection, Capability::ClipboardPaste),
+    (Opcode::SendEvent, Capability::SendEvent),
+    (Opcode::GrabPointer, Capability::GrabPointer),
+    (Opcode::GrabButton, Capability::GrabPointer),
+    (Opcode::GrabKeyboard, Capability::GrabKeyboard),
+    (Opcode::GrabKey, Capability::GrabKeyboard),
+    (Opcode::AllowEvents, Capability::ReplayEvents),
+    (Opcode::GrabServer, Capability::GrabServer),
+    (Opcode::QueryPointer, Capability::TrackPointer),
+    (Opcode::GetMotionEvents, Capability::TrackPointer),
+    (Opcode::GetGeometry, Capability::ReadGeometry),
+    (Opcode::TranslateCoordinates, Capability::ReadGeometry),
+    (Opcode::SetInputFocus, Capability::StealFocus),
+    (Opcode::GetImage, Capability::Screenshot),
+    (Opcode::CopyArea, Capability::Screenshot),
+    (Opcode::CopyPlane, Capability::Screenshot),
+    (Opcode::ChangeKeyboardMapping, Capability::RemapInput),
+    (Opcode::SetScreenSaver, Capability::ScreenSaver),
+    (Opcode::ForceScreenSaver, Capability::ScreenSaver),
+    (Opcode::SetPointerMapping, Capability::RemapInput),
+    (Opcode::SetModifierMapping, Capability::RemapInput),
+];
+
+/// Decide on a request whose header is fine but whose fields didn't
+/// decode, by its opcode alone.
+fn decode_failure(
+    state: &ConnectionState,
+    policy: &Policy,
+    header: &Request,
+) -> Outcome {
+    let capability = match extension_request(state, header) {
+        Some((_, request)) => request.and_then(|request| request.capability),
+        None => Opcode::from_u8(header.opcode).and_then(|opcode| {
+            DECODE_FAILURE_CAPABILITIES
+                .iter()
+                .find(|&&(failed, _)| failed == opcode)
+                .map(|&(_, capability)| capability)
+        }),
+    };
+    match capability {
+        Some(capability) => {
+            metrics::DECODE_FAILURES_CLOSED.add(1);
+            check(state, policy, capability)
+        }
+        None => {
+            metrics::DECODE_FAILURES_OPEN.add(1);
+            Outcome::Allowed
+        }
+    }
+}
+
+/// Whether `shape` takes the pointer away from most of a window that
+/// covers a whole screen. What masks and the shapes of other windows
+/// hold isn't known, so taking the input region from those counts.
+fn click_through(state: &ConnectionState, shape: &ShapeChange) -> bool {
+    let (width, height) = match state.window_size(shape.window) {
+        Some(size) => size,
+        None => return false,
+    };
+    if shape.kind != SHAPE_INPUT || !state.covers_screen(width, height) {
+        return false;
+    }
+    let half = u64::from(width) * u64::from(height) / 2;
+    let area: u64 = shape
+        .rectangle_sizes()
+        .map(|(width, height)| u64::from(width) * u64::from(height))
+        .sum();
+    match (shape.minor_opcode, shape.operation) {
+        // Only ever adds to the region.
+        (_, SHAPE_UNION) => false,
+        (SHAPE_RECTANGLES, SHAPE_SUBTRACT) => area > half,
+        (SHAPE_RECTANGLES, _) => area < half,
+        // Without a pixmap the window gets its default shape back.
+        (SHAPE_MASK, _) => shape.source != 0,
+        _ => true,
+    }
+}
+
+/// Whether `window` will be an override-redirect one as big as the screen
+/// at `width` and `height`, where given.
+fn covers_screen(
+    state: &ConnectionState,
+    window: u32,
+    width: Option<u32>,
+    height: Option<u32>,
+) -> bool {
+    if !state.is_override_redirect(window) {
+        return false;
+    }
+    let (old_width, old_height) = state.window_size(window).unwrap_or((0, 0));
+    state.covers_screen(
+        width.map_or(old_width, |width| width as u16),
+        height.map_or(old_height, |height| height as u16),
+    )
+}
+
+/// Properties holding the title of a window, in full or shortened.
+const TITLE_PROPERTIES: &[&str] = &[
+    "WM_NAME",
+    "WM_ICON_NAME",
+    "_NET_WM_NAME",
+    "_NET_WM_VISIBLE_NAME",
+    "_NET_WM_ICON_NAME",
+    "_NET_WM_VISIBLE_ICON_NAME",
+];
+
+/// The selections the XSETTINGS manager of each screen owns, as a prefix
+/// of the screen number.
+const XSETTINGS_SELECTION_PREFIX: &str = "_XSETTINGS_S";
+
+/// The property on the manager's window the settings are read from.
+const XSETTINGS_PROPERTY: &str = "_XSETTINGS_SETTINGS";
+
+/// The selection name `--selection` rules for the cut buffers go under.
+const CUT_BUFFERS_SELECTION: &str = "CUT_BUFFERS";
+
+/// Whether a pointer grab stays on the client's windows. Confining the
+/// pointer to someone else's window would hold it there just the same.
+fn owns_grab(
+    state: &ConnectionState,
+    grab_window: u32,
+    confine_to: u32,
+) -> bool {
+    state.owns(grab_window) && (confine_to == 0 || state.owns(confine_to))
+}
+
+/// Whether `property` on `window` is one of CUT_BUFFER0 to CUT_BUFFER7 on
+/// the root window, the clipboard of older toolkits.
+fn is_cut_buffer(state: &ConnectionState, window: u32, property: u32) -> bool {
+    state.is_root(window)
+        && state
+            .atom_name(property)
+            .is_some_and(|name| name.starts_with("CUT_BUFFER"))
+}
+
+fn analyze_request_opcode(
+    state: &ConnectionState,
+    policy: &Policy,
+    header: &Request,
+    decoded: &DecodeResult,
+) -> ParseResult {
+    let outcome = match *decoded {
+        Err(_) => decode_failure(state, policy, header),
+        Ok(DecodedRequest::XTestFakeInput(_)) => {
+            check(state, policy, Capability::FakeInput)
+        }
+        Ok(DecodedRequest::SendEvent(ref send))
+            if input_method_message(state, send).is_some() =>
+        {
+            input_method(state, policy)
+        }
+        Ok(DecodedRequest::SendEvent(ref send)) => {
+            if policy.allows_event(send.event_code) {
+                Outcome::Allowed
+            } else {
+                check(state, policy, Capability::SendEvent)
+            }
+        }
+        // Selecting key events on someone else's window is how xev -id
+        // and friends log keystrokes.
+        Ok(DecodedRequest::ChangeWindowAttributes(ref change))
+            if !state.owns(change.window) =>
+        {
+            let key_events = KEY_PRESS_MASK | KEY_RELEASE_MASK;
+            match change.attributes.get(CW_EVENT_MASK) {
+                Some(mask) if mask & key_events != 0 => {
+                    check(state, policy, Capability::SnoopKeys)
+                }
+                _ => Outcome::Allowed,
+            }
+        }
+        // Another client's window is the window manager's to arrange, and
+        // the client's own may only go on the root window or its own.
+        Ok(DecodedRequest::ConfigureWindow(ref configure))
+            if !state.owns(configure.window) =>
+        {
+            check(state, policy, Capability::RearrangeWindows)
+        }
+        Ok(DecodedRequest::ReparentWindow(ref reparent))
+            if !state.owns(reparent.window)
+                || !(state.owns(reparent.parent)
+                    || state.is_root(reparent.parent)) =>
+        {
+            check(state, policy, Capability::RearrangeWindows)
+        }
+        Ok(DecodedRequest::ConfigureWindow(ref configure))
+            if policy.deny_cover_screen()
+                && covers_screen(
+                    state,
+                    configure.window,
+                    configure.values.get(CONFIG_WINDOW_WIDTH),
+                    configure.values.get(CONFIG_WINDOW_HEIGHT),
+                ) =>
+        {
+            check(state, policy, Capability::CoverScreen)
+        }
+        Ok(DecodedRequest::CreateWindow(ref create))
+            if policy.deny_cover_screen()
+                && create.attributes.get(CW_OVERRIDE_REDIRECT).unwrap_or(0)
+                    != 0
+                && state.covers_screen(create.width, create.height) =>
+        {
+            check(state, policy, Capability::CoverScreen)
+        }
+        // Turning it on for a window already that big.
+        Ok(DecodedRequest::ChangeWindowAttributes(ref change))
+            if policy.deny_cover_screen()
+                && state.owns(change.window)
+                && change.attributes.get(CW_OVERRIDE_REDIRECT).unwrap_or(0)
+                    != 0
+                && state.window_size(change.window).is_some_and(
+                    |(width, height)| state.covers_screen(width, height),
+                ) =>
+        {
+            check(state, policy, Capability::CoverScreen)
+        }
+        Ok(DecodedRequest::GetImage(ref get)) if !state.owns(get.drawable) => {
+            check(state, policy, Capability::Screenshot)
+        }
+        Ok(DecodedRequest::CopyArea(ref copy))
+        | Ok(DecodedRequest::CopyPlane(ref copy))
+            if !state.owns(copy.src_drawable) =>
+        {
+            check(state, policy, Capability::Screenshot)
+        }
+        Ok(DecodedRequest::ShmGetImage(ref get))
+            if !state.owns(get.drawable) =>
+        {
+            check(state, policy, Capability::Screenshot)
+        }
+        Ok(DecodedRequest::QueryExtension(ref query))
+            if query.name == SHM_NAME =>
+        {
+            check(state, policy, Capability::SharedMemory)
+        }
+        Ok(DecodedRequest::ShmAttach { .. }) => {
+            check(state, policy, Capability::SharedMemory)
+        }
+        // With isolated windows the reply only lists the client's own.
+        Ok(DecodedRequest::QueryTree(ref query))
+            if state.is_root(query.window) && !policy.isolate_windows() =>
+        {
+            check(state, policy, Capability::ListWindows)
+        }
+        // Without it, the client finds no input method and goes without.
+        Ok(DecodedRequest::GetProperty(ref get))
+            if policy.deny_input_methods()
+                && state.is_root(get.window)
+                && state.atom_name(get.property)
+                    == Some(xim::SERVERS_PROPERTY) =>
+        {
+            check(state, policy, Capability::InputMethod)
+        }
+        Ok(DecodedRequest::GetProperty(ref get))
+            if is_cut_buffer(state, get.window, get.property)
+                && !policy
+                    .selection_access(Some(CUT_BUFFERS_SELECTION))
+                    .can_paste() =>
+        {
+            check(state, policy, Capability::ClipboardPaste)
+        }
+        Ok(DecodedRequest::GetProperty(ref get))
+            if !state.owns(get.window)
+                && state
+                    .atom_name(get.property)
+                    .is_some_and(|name| TITLE_PROPERTIES.contains(&name)) =>
+        {
+            check(state, policy, Capability::ReadWindowTitles)
+        }
+        Ok(DecodedRequest::QueryPointer(ref query))
+            if !state.owns(query.window) =>
+        {
+            check(state, policy, Capability::TrackPointer)
+        }
+        Ok(DecodedRequest::GetMotionEvents(ref get))
+            if !state.owns(get.window) =>
+        {
+            check(state, policy, Capability::TrackPointer)
+        }
+        // How big the screen is, everyone knows.
+        Ok(DecodedRequest::GetGeometry(ref get))
+            if !state.owns(get.drawable) && !state.is_root(get.drawable) =>
+        {
+            check(state, policy, Capability::ReadGeometry)
+        }
+        Ok(DecodedRequest::TranslateCoordinates(ref translate))
+            if [translate.src_window, translate.dst_window].iter().any(
+                |&window| !state.owns(window) && !state.is_root(window),
+            ) =>
+        {
+            check(state, policy, Capability::ReadGeometry)
+        }
+        Ok(DecodedRequest::GrabPointer(ref grab))
+            if !owns_grab(state, grab.grab_window, grab.confine_to) =>
+        {
+            check(state, policy, Capability::GrabPointer)
+        }
+        Ok(DecodedRequest::GrabButton(ref grab))
+            if !owns_grab(state, grab.grab_window, grab.confine_to) =>
+        {
+            check(state, policy, Capability::GrabPointer)
+        }
+        Ok(DecodedRequest::GrabKeyboard(_)) => {
+            check(state, policy, Capability::GrabKeyboard)
+        }
+        Ok(DecodedRequest::GrabKey(ref grab))
+            if !state.owns(grab.grab_window) =>
+        {
+            check(state, policy, Capability::GrabKeyboard)
+        }
+        Ok(DecodedRequest::SetInputFocus(ref focus)) => {
+            // None and PointerRoot are never owned.
+            if state.owns(focus.focus) && policy.own_focus() {
+                Outcome::Allowed
+            } else {
+                check(state, policy, Capability::StealFocus)
+            }
+        }
+        // Key events on someone else's window, as with the core masks,
+        // or raw ones from anywhere.
+        Ok(DecodedRequest::XISelectEvents(ref select)) => {
+            let key_events = if state.owns(select.window) {
+                XI_RAW_KEY_EVENTS
+            } else {
+                XI_RAW_KEY_EVENTS | XI_KEY_EVENTS
+            };
+            if select.events & key_events != 0 {
+                check(state, policy, Capability::SnoopKeys)
+            } else if select.events & XI_RAW_POINTER_EVENTS != 0 {
+                check(state, policy, Capability::TrackPointer)
+            } else {
+                Outcome::Allowed
+            }
+        }
+        Ok(DecodedRequest::RecordContext { .. }) => {
+            check(state, policy, Capability::RecordInput)
+        }
+        // Only gets here in the strict mode, see `gpu_passthrough`.
+        Ok(DecodedRequest::Dri3Import { .. }) => {
+            check(state, policy, Capability::ImportBuffers)
+        }
+        Ok(DecodedRequest::RandrConfigure { .. }) => {
+            check(state, policy, Capability::ConfigureDisplay)
+        }
+        // Like the core mappings, the keymap and controls are shared by
+        // every client.
+        Ok(DecodedRequest::XkbChange { minor_opcode })
+            if minor_opcode == XKB_SET_MAP
+                || minor_opcode == XKB_SET_CONTROLS =>
+        {
+            check(state, policy, Capability::RemapInput)
+        }
+        Ok(DecodedRequest::XkbChange { .. }) => {
+            check(state, policy, Capability::KeyboardFeedback)
+        }
+        Ok(DecodedRequest::ShapeChange(ref shape))
+            if click_through(state, shape) =>
+        {
+            check(state, policy, Capability::ClickThrough)
+        }
+        // A mask of zero stops watching.
+        Ok(DecodedRequest::SelectSelectionInput(ref select))
+            if select.event_mask != 0 =>
+        {
+            check(state, policy, Capability::WatchSelections)
+        }
+        // Naming its own cursors is how a client themes them. The others
+        // reach past the client's windows: the cursor stays hidden on the
+        // whole screen, and changed cursors change for everyone.
+        Ok(DecodedRequest::XFixesCursor { minor_opcode, id })
+            if minor_opcode != XFIXES_SET_CURSOR_NAME || !state.owns(id) =>
+        {
+            check(state, policy, Capability::ChangeCursor)
+        }
+        // Blanking the screen, or keeping the lock screen from coming up.
+        Ok(DecodedRequest::ScreenSaverControl { .. })
+        | Ok(DecodedRequest::SetScreenSaver(_))
+        | Ok(DecodedRequest::ForceScreenSaver(_)) => {
+            check(state, policy, Capability::ScreenSaver)
+        }
+        Ok(DecodedRequest::AllowEvents(ref allow))
+            if allow.mode == ALLOW_REPLAY_POINTER
+                || allow.mode == ALLOW_REPLAY_KEYBOARD =>
+        {
+            check(state, policy, Capability::ReplayEvents)
+        }
+        // No one else gets anything done while the server is grabbed.
+        Ok(DecodedRequest::GrabServer) | Ok(DecodedRequest::UngrabServer) => {
+            check(state, policy, Capability::GrabServer)
+        }
+        Ok(DecodedRequest::ChangeKeyboardMapping(_))
+        | Ok(DecodedRequest::SetModifierMapping(_))
+        | Ok(DecodedRequest::SetPointerMapping(_)) => {
+            check(state, policy, Capability::RemapInput)
+        }
+        // Reading the settings is fine, handing them out isn't.
+        Ok(DecodedRequest::SetSelectionOwner(ref set))
+            if set.owner != 0
+                && state.atom_name(set.selection).is_some_and(|name| {
+                    name.starts_with(XSETTINGS_SELECTION_PREFIX)
+                }) =>
+        {
+            check(state, policy, Capability::SettingsManager)
+        }
+        Ok(DecodedRequest::SetSelectionOwner(ref set)) if set.owner != 0 => {
+            let name = state.atom_name(set.selection);
+            if policy.selection_access(name).can_copy() {
+                Outcome::Allowed
+            } else {
+                check(state, policy, Capability::ClipboardCopy)
+            }
+        }
+        // Answering a paste of one of our selections, in one piece or as
+        // the chunks of an INCR transfer. The final empty chunk always
+        // goes through, so that a cut-off transfer still ends.
+        Ok(DecodedRequest::ChangeProperty(ref change)) => {
+            let size = change.data.len();
+            let window = change.window;
+            match property_change(state, policy, window, change.property, size)
+            {
+                Outcome::Allowed if size > 0 => {
+                    let transfer = state.transfer(window, change.property);
+                    let limit = policy.max_selection_size();
+                    match (transfer, limit) {
+                        (Some(transfer), Some(limit))
+                            if transfer.sent + size as u64 > limit =>
+                        {
+                            check(state, policy, Capability::ClipboardCopy)
+                        }
+                        _ => Outcome::Allowed,
+                    }
+                }
+                outcome => outcome,
+            }
+        }
+        Ok(DecodedRequest::DeleteProperty(ref delete)) => {
+            property_change(state, policy, delete.window, delete.property, 0)
+        }
+        Ok(DecodedRequest::RotateProperties(ref rotate)) => {
+            rotate_properties(state, policy, rotate)
+        }
+        Ok(DecodedRequest::ConvertSelection(ref convert))
+            if state
+                .atom_name(convert.selection)
+                .is_some_and(xim::is_server_selection) =>
+        {
+            input_method(state, policy)
+        }
+        Ok(DecodedRequest::ConvertSelection(ref convert))
+            if !state.owns_selection(convert.selection) =>
+        {
+            let name = state.atom_name(convert.selection);
+            if policy.selection_access(name).can_paste() {
+                Outcome::Allowed
+            } else {
+                check(state, policy, Capability::ClipboardPaste)
+            }
+        }
+        _ => Outcome::Allowed,
+    };
+
+    Ok(outcome)
+}
+
+/// Whether the client may set `property` on `window` to `size` bytes, or
+/// delete or rotate it. A paste is answered with one of these on someone
+/// else's window, how much is handed out is up to the caller.
+fn property_change(
+    state: &ConnectionState,
+    policy: &Policy,
+    window: u32,
+    property: u32,
+    size: usize,
+) -> Outcome {
+    let name = state.atom_name(property);
+    let foreign = !state.owns(window);
+    if name.is_some_and(|name| policy.protects_property(name)) {
+        check(state, policy, Capability::ChangeProtectedProperty)
+    } else if foreign && name == Some(XSETTINGS_PROPERTY) {
+        check(state, policy, Capability::SettingsManager)
+    } else if is_cut_buffer(state, window, property) {
+        // XStoreBytes and the like.
+        let access = policy.selection_access(Some(CUT_BUFFERS_SELECTION));
+        let too_big = policy
+            .max_selection_size()
+            .is_some_and(|limit| size as u64 > limit);
+        if access.can_copy() && !too_big {
+            Outcome::Allowed
+        } else {
+            check(state, policy, Capability::ClipboardCopy)
+        }
+    } else if foreign
+        && state.is_input_method(window)
+        && name.is_some_and(xim::is_transport_property)
+    {
+        // Long XIM messages, on the input method's window.
+        input_method(state, policy)
+    } else if foreign
+        && state.transfer(window, property).is_none()
+        && !name.is_some_and(|name| policy.shares_property(name))
+    {
+        // Say _NET_WM_STATE or WM_COMMAND on someone else's window.
+        check(state, policy, Capability::ForeignProperties)
+    } else {
+        Outcome::Allowed
+    }
+}
+
+/// A RotateProperties is as good as changing every one of them.
+fn rotate_properties(
+    state: &ConnectionState,
+    policy: &Policy,
+    rotate: &RotateProperties,
+) -> Outcome {
+    let mut outcome = Outcome::Allowed;
+    for property in rotate.properties() {
+        match property_change(state, policy, rotate.window, property, 0) {
+            Outcome::Allowed => (),
+            Outcome::Granted(capability) => {
+                outcome = Outcome::Granted(capability)
+            }
+            denied => return denied,
+        }
+    }
+    outcome
+}
+
+/// The type of the ClientMessage `send` passes on, if it's one of the
+/// XIM transport and goes to an input method's window.
+fn input_method_message<'s>(
+    state: &'s ConnectionState,
+    send: &SendEvent,
+) -> Option<&'s str> {
+    if send.event_code != CLIENT_MESSAGE
+        || !state.is_input_method(send.destination)
+    {
+        return None;
+    }
+    let e = state.byte_order();
+    state
+        .atom_name(read_u32(&send.event[8..12], e))
+        .filter(|&name| xim::is_transport_message(name))
+}
+
+/// XIM goes through unless the policy denies input methods.
+fn input_method(state: &ConnectionState, policy: &Policy) -> Outcome {
+    if policy.deny_input_methods() {
+        check(state, policy, Capability::InputMethod)
+    } else {
+        Outcome::Allowed
+    }
+}
+
+/// A SelectionNotify telling the requestor that the selection couldn't
+/// be converted.
+fn selection_notify_none(convert: &ConvertSelection, e: Endianness) -> Vec<u8> {
+    let mut event = vec![0; 32];
+    event[0] = SELECTION_NOTIFY;
+    write_u32(&mut event[4..8], convert.time, e);
+    write_u32(&mut event[8..12], convert.requestor, e);
+    write_u32(&mut event[12..16], convert.selection, e);
+    write_u32(&mut event[16..20], convert.target, e);
+    event
+}
+
+/// The reply to a request like SetModifierMapping, which only needs the
+/// status to say no.
+fn mapping_status(status: u8) -> Vec<u8> {
+    let mut reply = vec![0; 32];
+    reply[0] = 1;
+    reply[1] = status;
+    reply
+}
+
+/// A QueryTree reply for a root window without children.
+fn empty_tree(query: &QueryTree, e: Endianness) -> Vec<u8> {
+    let mut reply = vec![0; 32];
+    reply[0] = 1;
+    write_u32(&mut reply[8..12], query.window, e);
+    reply
+}
+
+/// A QueryPointer reply with the pointer in the corner of the first
+/// screen, over no window and without buttons or modifiers held.
+fn nowhere_pointer(state: &ConnectionState, e: Endianness) -> Vec<u8> {
+    let mut reply = vec![0; 32];
+    reply[0] = 1;
+    // same-screen
+    reply[1] = 1;
+    if let Some(screen) = state.setup_info().screens.first() {
+        write_u32(&mut reply[8..12], screen.root, e);
+    }
+    reply
+}
+
+/// A GetGeometry reply for a window of a pixel in the corner of the first
+/// screen, without a border.
+fn pixel_geometry(state: &ConnectionState, e: Endianness) -> Vec<u8> {
+    let mut reply = vec![0; 32];
+    reply[0] = 1;
+    if let Some(screen) = state.setup_info().screens.first() {
+        reply[1] = screen.root_depth;
+        write_u32(&mut reply[8..12], screen.root, e);
+    }
+    write_u16(&mut reply[16..18], 1, e);
+    write_u16(&mut reply[18..20], 1, e);
+    reply
+}
+
+/// A TranslateCoordinates reply as if both windows were in the same
+/// place, with the point over none of their children.
+fn untranslated_coordinates(
+    translate: &TranslateCoordinates,
+    e: Endianness,
+) -> Vec<u8> {
+    let mut reply = vec![0; 32];
+    reply[0] = 1;
+    // same-screen
+    reply[1] = 1;
+    write_u16(&mut reply[12..14], translate.src_x as u16, e);
+    write_u16(&mut reply[14..16], translate.src_y as u16, e);
+    reply
+}
+
+/// A GetImage reply in which every pixel is zero. Foreign drawables are
+/// assumed to have the depth and visual of the first screen. Returns None
+/// for images we'd rather not allocate.
+fn blank_image(
+    state: &ConnectionState,
+    get: &GetImage,
+    e: Endianness,
+) -> Option<Vec<u8>> {
+    let setup = state.setup_info();
+    let (depth, visual) = match setup.screens.first() {
+        Some(screen) => (screen.root_depth, screen.root_visual),
+        None => (24, 0),
+    };
+    let format = setup.formats.iter().find(|format| format.depth == depth);
+    let (bits_per_pixel, pad) = match format {
+        Some(format) => (
+            u64::from(format.bits_per_pixel),
+            u64::from(format.scanline_pad.max(8)),
+        ),
+        None => (32, 32),
+    };
+    let width = u64::from(get.width);
+    let height = u64::from(get.height);
+    let image_length = if get.format == XY_PIXMAP {
+        let depth_mask = ((1u64 << depth) - 1) as u32;
+        let planes = u64::from((get.plane_mask & depth_mask).count_ones());
+        width.div_ceil(pad) * pad / 8 * height * planes
+    } else {
+        (width * bits_per_pixel).div_ceil(pad) * pad / 8 * height
+    };
+    if image_length > MAX_BLANK_IMAGE {
+        return None;
+    }
+
+    let mut reply = vec![0; 32 + image_length as usize];
+    reply[0] = 1;
+    reply[1] = depth;
+    write_u32(&mut reply[4..8], (image_length / 4) as u32, e);
+    write_u32(&mut reply[8..12], visual, e);
+    Some(reply)
+}
+
+/// Handles the connection setup if the client didn't send it yet.
+/// Returns the number of bytes it took up at the start of the buffer.
+pub fn track_setup(state: &mut ConnectionState, buffer: &[u8]) -> usize {
+    if state.setup_done() || buffer.is_empty() {
+        return 0;
+    }
+    match setup_request(buffer) {
+        // Everything after the byte order byte is already in that order,
+        // so a sane protocol version tells us this really is a setup.
+        Ok((rest, setup)) if setup.major_version == 11 => {
+            info!(
+                "Connection setup: X{}.{}, byte order {:?}, auth {:?}",
+                setup.major_version,
+                setup.minor_version,
+                setup.byte_order,
+                String::from_utf8_lossy(setup.auth_name),
+            );
+            debug!("Auth data is {} bytes", setup.auth_data.len());
+            state.set_byte_order(setup.byte_order);
+            buffer.len() - rest.len()
+        }
+        _ => {
+            warn!("No connection setup seen, assuming LSB first client.");
+            state.set_byte_order(Endianness::Little);
+            0
+        }
+    }
+}
+
+/// An error for the request `header`, as the server would send it.
+fn request_error(
+    code: u8,
+    bad_value: u32,
+    header: &Request,
+    e: Endianness,
+) -> Vec<u8> {
+    let mut error = vec![0; 32];
+    error[1] = code;
+    write_u32(&mut error[4..8], bad_value, e);
+    // Extension requests have their minor opcode in the data byte.
+    if header.opcode >= 128 {
+        write_u16(&mut error[8..10], header.datab as u16, e);
+    }
+    error[10] = header.opcode;
+    error
+}
+
+/// Pass on a stand-in for the request `header` and answer it with error
+/// `code` instead, so that the client still gets an answer and the
+/// sequence numbers add up.
+fn stand_in_error(
+    state: &mut ConnectionState,
+    accepted: &mut Vec<u8>,
+    code: u8,
+    bad_value: u32,
+    header: &Request,
+) {
+    let e = state.byte_order();
+    accepted.extend(&get_input_focus(e));
+    let sequence = state.next_sequence();
+    let mut error = request_error(code, bad_value, header, e);
+    write_u16(&mut error[2..4], sequence, e);
+    state.fake_reply(sequence, error);
+}
+
+/// A GetInputFocus request. It is harmless and gets a reply, which
+/// makes it a good stand-in for a denied request.
+fn get_input_focus(e: Endianness) -> [u8; 4] {
+    let mut request = [0x2B, 0, 0, 0];
+    write_u16(&mut request[2..4], 1, e);
+    request
+}
+
+/// A reply to give the client in place of a denied request, if the
+/// policy asks for one.
+fn substitute_reply(
+    state: &ConnectionState,
+    policy: &Policy,
+    decoded: &DecodeResult,
+    spoof: bool,
+    e: Endianness,
+) -> Option<Vec<u8>> {
+    match *decoded {
+        Ok(DecodedRequest::GetImage(ref get))
+            if spoof || policy.blank_images() =>
+        {
+            blank_image(state, get, e)
+        }
+        Ok(DecodedRequest::QueryTree(ref query))
+            if spoof || policy.empty_trees() =>
+        {
+            Some(empty_tree(query, e))
+        }
+        Ok(DecodedRequest::QueryPointer(_))
+            if spoof || policy.spoof_pointer() =>
+        {
+            Some(nowhere_pointer(state, e))
+        }
+        // No motion history, which servers are free to not keep anyway.
+        Ok(DecodedRequest::GetMotionEvents(_))
+            if spoof || policy.spoof_pointer() =>
+        {
+            let mut reply = vec![0; 32];
+            reply[0] = 1;
+            Some(reply)
+        }
+        Ok(DecodedRequest::GetGeometry(_))
+            if spoof || policy.spoof_geometry() =>
+        {
+            Some(pixel_geometry(state, e))
+        }
+        Ok(DecodedRequest::TranslateCoordinates(ref translate))
+            if spoof || policy.spoof_geometry() =>
+        {
+            Some(untranslated_coordinates(translate, e))
+        }
+        // The property isn't set: no type, no format, no value.
+        Ok(DecodedRequest::GetProperty(ref get))
+            if spoof
+                || state.atom_name(get.property)
+                    == Some(xim::SERVERS_PROPERTY) =>
+        {
+            let mut reply = vec![0; 32];
+            reply[0] = 1;
+            Some(reply)
+        }
+        // The extension isn't there, as far as the client can tell.
+        Ok(DecodedRequest::QueryExtension(_)) => {
+            let mut reply = vec![0; 32];
+            reply[0] = 1;
+            Some(reply)
+        }
+        // The others have no reply.
+        Ok(DecodedRequest::RandrConfigure { minor_opcode })
+            if minor_opcode == RANDR_SET_SCREEN_CONFIG
+                || minor_opcode == RANDR_SET_CRTC_CONFIG
+                || minor_opcode == RANDR_SET_PANNING =>
+        {
+            Some(mapping_status(RANDR_FAILED))
+        }
+        // Recording ends before it started, instead of never.
+        Ok(DecodedRequest::RecordContext {
+            minor_opcode: RECORD_ENABLE_CONTEXT,
+        }) => {
+            let mut reply = vec![0; 32];
+            reply[0] = 1;
+            reply[1] = RECORD_END_OF_DATA;
+            Some(reply)
+        }
+        // Tell the client the paste failed, or it may wait forever.
+        Ok(DecodedRequest::ConvertSelection(ref convert)) => {
+            Some(selection_notify_none(convert, e))
+        }
+        // MappingFailed and MappingBusy, the only way to say no.
+        Ok(DecodedRequest::SetModifierMapping(_)) => Some(mapping_status(2)),
+        Ok(DecodedRequest::SetPointerMapping(_)) => Some(mapping_status(1)),
+        _ => None,
+    }
+}
+
+/// A harmless request to send in place of a denied one that doesn't have
+/// a reply. Returns None for requests that are just dropped.
+fn substitute_request(
+    decoded: &DecodeResult,
+    request: &[u8],
+) -> Option<Vec<u8>> {
+    match *decoded {
+        // Still unfreeze the device, just without passing the event on.
+        Ok(DecodedRequest::AllowEvents(ref allow)) => {
+            let mut request = request.to_vec();
+            request[1] = match allow.mode {
+                ALLOW_REPLAY_POINTER => ALLOW_ASYNC_POINTER,
+                _ => ALLOW_ASYNC_KEYBOARD,
+            };
+            Some(request)
+        }
+        _ => None,
+    }
+}
+
+/// The decoy atom a request touches, if any. The atoms a request refers
+/// to are matched by name as far as the client interned them, and by
+/// value for the decoys we interned ourselves.
+fn honeypot_hit(
+    state: &ConnectionState,
+    policy: &Policy,
+    decoded: &DecodeResult,
+) -> Option<String> {
+    let atoms = match *decoded {
+        Ok(DecodedRequest::InternAtom(ref intern)) => {
+            return if policy.is_honeypot(&intern.name) {
+                Some(intern.name.to_string())
+            } else {
+                None
+            };
+        }
+        // Places left over are None, which is no atom.
+        Ok(DecodedRequest::GetAtomName(ref get)) => [get.atom, 0, 0],
+        Ok(DecodedRequest::GetProperty(ref get)) => [get.property, 0, 0],
+        Ok(DecodedRequest::ChangeProperty(ref change)) => {
+            [change.property, 0, 0]
+        }
+        Ok(DecodedRequest::DeleteProperty(ref delete)) => {
+            [delete.property, 0, 0]
+        }
+        Ok(DecodedRequest::SetSelectionOwner(ref set)) => [set.selection, 0, 0],
+        Ok(DecodedRequest::GetSelectionOwner(ref get)) => [get.selection, 0, 0],
+        Ok(DecodedRequest::SelectSelectionInput(ref select)) => {
+            [select.selection, 0, 0]
+        }
+        Ok(DecodedRequest::ConvertSelection(ref convert)) => {
+            [convert.selection, convert.target, convert.property]
+        }
+        _ => return None,
+    };
+    atoms.iter().filter(|&&atom| atom != 0).find_map(|&atom| {
+        policy
+            .honeypot_atom(atom)
+            .or_else(|| state.atom_name(atom).filter(|n| policy.is_honeypot(n)))
+            .map(str::to_string)
+    })
+}
+
+/// Result of filtering a buffer of client requests.
+pub struct Filtered {
+    pub accepted: Vec<u8>,
+    /// Capabilities that the rejected requests needed.
+    pub denials: Vec<Capability>,
+    /// Decoy atoms the requests touched. They are let through all the
+    /// same, so the client doesn't notice.
+    pub honeypots: Vec<String>,
+    /// Capabilities that let the other requests through.
+    pub granted: Vec<Capability>,
+    /// Requests looked at, and how many of them we couldn't parse.
+    pub requests: usize,
+    pub parse_failures: usize,
+    /// File descriptors the accepted requests pass, in order, and those
+    /// of the rejected ones, which go nowhere.
+    pub fds: Vec<RawFd>,
+    pub dropped_fds: Vec<RawFd>,
+}
+
+/// Filters the buffer with X commands, forwarding only the requests
+/// that `policy` allows.
+pub fn filter_buffer(
+    state: &mut ConnectionState,
+    policy: &Policy,
+    buffer: &[u8],
+) -> Filtered {
+    let mut rejected = 0;
+    let mut out_accept_buff = state.arena.take(buffer.len());
+    let mut denials = Vec::new();
+    let mut honeypots = Vec::new();
+    let mut granted = Vec::new();
+    let mut requests = 0;
+    let mut parse_failures = 0;
+    let mut fds = Vec::new();
+    let mut dropped_fds = Vec::new();
+    let setup_length = track_setup(state, buffer);
+    out_accept_buff.extend(&buffer[0..setup_length]);
+    let mut work_buffer = &buffer[setup_length..buffer.len()];
+    let e = state.byte_order();
+
+    while buffer.len() > 0 {
+        let size = work_buffer.len();
+        trace!("Buffer size={}", size);
+
+        // Parse request headers
+        let req = request(work_buffer, e, state.big_requests());
+        if req.is_err() {
+            rejected += size;
+            break;
+        }
+
+        let (_, req_header) = req.unwrap();
+        trace!("{:?}", req_header);
+        let _allocations = allocations::Span::request(req_header.opcode);
+
+        if req_header.length > size {
+            warn!(
+                "Packet size ({}) is smaller than header size ({})",
+                size, req_header.length
+            );
+            rejected += size;
+            break;
+        }
+
+        let length = req_header.length;
+        let passed = passed_fds(state, &req_header, &work_buffer[0..length]);
+        if gpu_passthrough(state, policy, &req_header) {
+            state.take_fds(passed, &mut fds);
+            requests += 1;
+            let sequence = state.next_sequence();
+            let sent = SentRequest {
+                opcode: req_header.opcode,
+                minor_opcode: req_header.datab,
+                property: None,
+            };
+            state.sent_request(sequence, sent);
+            out_accept_buff.extend(&work_buffer[0..length]);
+            work_buffer = &work_buffer[length..];
+            continue;
+        }
+        // They only go along if the request does.
+        let passed_from = dropped_fds.len();
+        state.take_fds(passed, &mut dropped_fds);
+        let decoded = decode_request(state, &req_header, work_buffer, e);
+        trace!("{:?}", decoded);
+        requests += 1;
+        if decoded.is_err() {
+            parse_failures += 1;
+        }
+        if log_enabled!(Level::Debug) {
+            let offset = buffer.len() - work_buffer.len();
+            let request = &work_buffer[0..length];
+            debug!(
+                "\n{}",
+                format_request(state, offset, &req_header, request, &decoded)
+            );
+        }
+
+        if policy.paranoid() && oversized(&decoded, length) {
+            warn!(
+                "Rejecting {} byte request {:?} with trailing data",
+                length, decoded
+            );
+            metrics::OVERSIZED_REQUESTS.add(1);
+            rejected += length;
+            let value = length as u32;
+            stand_in_error(
+                state,
+                &mut out_accept_buff,
+                BAD_LENGTH,
+                value,
+                &req_header,
+            );
+            work_buffer = &work_buffer[length..];
+            continue;
+        }
+        if let Some((code, value)) = property_error(policy, &decoded, length) {
+            warn!("Answering {:?} with error {}", decoded, code);
+            metrics::PROPERTY_ERRORS.add(1);
+            stand_in_error(
+                state,
+                &mut out_accept_buff,
+                code,
+                value,
+                &req_header,
+            );
+            work_buffer = &work_buffer[length..];
+            continue;
+        }
+        if let Some(decoy) = honeypot_hit(state, policy, &decoded) {
+            honeypots.push(decoy);
+        }
+        if let Some((code, value)) = atom_error(policy, &decoded) {
+            warn!("Answering {:?} with error {}", decoded, code);
+            metrics::DENIED_ATOMS.add(1);
+            stand_in_error(
+                state,
+                &mut out_accept_buff,
+                code,
+                value,
+                &req_header,
+            );
+            work_buffer = &work_buffer[length..];
+            continue;
+        }
+        let decision =
+            analyze_request_opcode(state, policy, &req_header, &decoded);
+        trace!("{:?}", decision);
+        match decision {
+            Ok(Outcome::Denied(capability))
+            | Ok(Outcome::Spoofed(capability)) => {
+                let spoof = matches!(decision, Ok(Outcome::Spoofed(_)));
+                match substitute_reply(state, policy, &decoded, spoof, e) {
+                    Some(mut reply) => {
+                        // Something with a reply takes its place, so the
+                        // sequence numbers still add up. Hidden extensions
+                        // are still looked up, to recognize their requests
+                        // should the client go ahead anyway.
+                        let sequence = match decoded {
+                            Ok(DecodedRequest::QueryExtension(_)) => {
+                                let request = &work_buffer[0..length];
+                                out_accept_buff.extend(request);
+                                track_request(state, &req_header, &decoded)
+                            }
+                            _ => {
+                                out_accept_buff.extend(&get_input_focus(e));
+                                state.next_sequence()
+                            }
+                        };
+                        write_u16(&mut reply[2..4], sequence, e);
+                        state.fake_reply(sequence, reply);
+                    }
+                    None => {
+                        let request = &work_buffer[0..length];
+                        match substitute_request(&decoded, request) {
+                            Some(replacement) => {
+                                state.next_sequence();
+                                out_accept_buff.extend(replacement);
+                            }
+                            None => {
+                                rejected += length;
+                                stand_in_error(
+                                    state,
+                                    &mut out_accept_buff,
+                                    BAD_ACCESS,
+                                    0,
+                                    &req_header,
+                                );
+                            }
+                        }
+                    }
+                }
+                denials.push(capability);
+            }
+            Ok(Outcome::Granted(capability)) => {
+                track_request(state, &req_header, &decoded);
+                out_accept_buff.extend(&work_buffer[0..length]);
+                fds.extend(dropped_fds.drain(passed_from..));
+                granted.push(capability);
+            }
+            Ok(Outcome::Allowed) | Err(_) => {
+                track_request(state, &req_header, &decoded);
+                fds.extend(dropped_fds.drain(passed_from..));
+                let request = &work_buffer[0..length];
+                if !rewrite_request(
+                    policy,
+                    &decoded,
+                    request,
+                    e,
+                    &mut out_accept_buff,
+                ) {
+                    out_accept_buff.extend(request);
+                }
+            }
+        }
+        if decision.is_ok() {
+            trace!("Skipping {} bytes...", req_header.length);
+            work_buffer = &work_buffer[length..];
+        }
+    }
+
+    trace!(
+        "Accepted {} bytes, rejected {} bytes",
+        out_accept_buff.len(),
+        rejected,
+    );
+    Filtered {
+        accepted: out_accept_buff,
+        denials,
+        honeypots,
+        granted,
+        requests,
+        parse_failures,
+        fds,
+        dropped_fds,
+    }
+}
+
+/// Follows the requests of a client that isn't filtered, so that the
+/// connection state stays accurate, and moves the file descriptors they
+/// pass to `fds`.
+pub fn track_buffer(
+    state: &mut ConnectionState,
+    buffer: &[u8],
+    fds: &mut Vec<RawFd>,
+) {
+    let setup_length = track_setup(state, buffer);
+    let mut buffer = &buffer[setup_length..];
+    let e = state.byte_order();
+    while let Ok((_, req_header)) = request(buffer, e, state.big_requests()) {
+        let length = req_header.length;
+        if length == 0 || length > buffer.len() {
+            break;
+        }
+        let passed = passed_fds(state, &req_header, &buffer[0..length]);
+        state.take_fds(passed, fds);
+        let decoded = decode_request(state, &req_header, buffer, e);
+        track_request(state, &req_header, &decoded);
+        buffer = &buffer[length..];
+    }
+}
+
+/// Returns the names of all atoms interned by the requests in the buffer.
+/// Without the connection state, requests of either form are taken.
+pub fn interned_atoms(mut buffer: &[u8], e: Endianness) -> Vec<String> {
+    let mut atoms = Vec::new();
+    while let Ok((_, req_header)) = request(buffer, e, true) {
+        let length = req_header.length;
+        if length == 0 || length > buffer.len() {
+            break;
+        }
+        if Opcode::from_u8(req_header.opcode) == Some(Opcode::InternAtom) {
+            if let Ok((_, intern)) = intern_atom(buffer, e) {
+                atoms.push(intern.name.into_owned());
+            }
+        }
+        buffer = &buffer[length..];
+    }
+    atoms
+}
+
+/// Analyze a buffer of requests. `offset` is the position of the buffer
+/// in the stream, used for the annotated hexdump output.
+fn analyze_buffer(
+    state: &mut ConnectionState,
+    policy: &Policy,
+    buffer: &[u8],
+    hexdump: bool,
+    mut offset: usize,
+) -> ParseResult {
+    let setup_length = track_setup(state, buffer);
+    offset += setup_length;
+    let mut buffer = &buffer[setup_length..];
+    let e = state.byte_order();
+    while buffer.len() > 0 {
+        let size = buffer.len();
+        if !hexdump {
+            println!("Buffer size={}", size);
+        }
+
+        // Parse request headers
+        let req = request(buffer, e, state.big_requests());
+
+        if req.is_ok() {
+            let (_, req_header) = req.unwrap();
+            if !hexdump {
+                println!("{:?}", req_header);
+            }
+
+            if req_header.length > size {
+                warn!(
+                    "Packet size ({}) is smaller than header size ({})",
+                    size, req_header.length
+                );
+                return Err(ParseError::InconsistentLength);
+            }
+
+            let length = req_header.length;
+            let decoded = decode_request(state, &req_header, buffer, e);
+            if hexdump {
+                let request = &buffer[0..length];
+                println!(
+                    "{}",
+                    format_request(
+                        state,
+                        offset,
+                        &req_header,
+                        request,
+                        &decoded
+                    )
+                );
+            } else {
+                print_decoded(&decoded);
+            }
+
+            let decision =
+                analyze_request_opcode(state, policy, &req_header, &decoded);
+            println!("{:?}", decision);
+            match decision {
+                Ok(Outcome::Allowed) | Ok(Outcome::Granted(_)) => {
+                    track_request(state, &req_header, &decoded);
+                }
+                _ => (),
+            }
+            if decision.is_ok() {
+                if !hexdump {
+                    println!("Skipping {} bytes...", req_header.length);
+                }
+                buffer = &buffer[length..];
+                offset += length;
+            }
+        } else {
+            break;
+        }
+    }
+
+    Ok(Outcome::Allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use policy::{Preset, SelectionAccess};
+    use state::{Screen, SetupInfo};
+    use std::time::{Duration, Instant};
+    const D_INTERNATOM: &'static [u8] = include_bytes!("../dumps/blocked.dmp");
+    // A ChangeProperty in each format, each followed by a GetInputFocus.
+    const D_CHANGE_PROPERTY_8: &[u8] =
+        include_bytes!("../dumps/change_property_8.dmp");
+    const D_CHANGE_PROPERTY_16: &[u8] =
+        include_bytes!("../dumps/change_property_16.dmp");
+    const D_CHANGE_PROPERTY_32: &[u8] =
+        include_bytes!("../dumps/change_property_32.dmp");
+    // What's passed on in place of a request that's answered with an error.
+    const STAND_IN: &[u8] = &[0x2B, 0, 1, 0];
+
+    #[test]
+    fn test_request() {
+        let req = request(D_INTERNATOM, Endianness::Little, false);
+        let req_header = req.unwrap().1;
+        assert_eq!(
+            req_header,
+            Request {
+                opcode: 16,
+                datab: 0,
+                length: 32,
+                data: &[
+                    21, 0, 64, 7, 95, 71, 84, 75, 95, 69, 68, 71, 69, 95, 67,
+                    79, 78, 83, 84, 82, 65, 73, 78, 84, 83, 47, 109, 111
+                ]
+            }
+        );
+        let ia = intern_atom(D_INTERNATOM, Endianness::Little);
+        let ia = ia.unwrap().1;
+        assert_eq!(
+            ia,
+            InternAtom {
+                only_if_exists: false,
+                name_length: 21,
+                name: std::borrow::Cow::from("_GTK_EDGE_CONSTRAINTS"),
+            },
+        );
+    }
+
+    #[test]
+    fn test_interned_atoms() {
+        let atoms = interned_atoms(D_INTERNATOM, Endianness::Little);
+        assert_eq!(atoms[0], "_GTK_EDGE_CONSTRAINTS");
+    }
+
+    #[test]
+    fn test_setup_byte_order() {
+        let mut state = ConnectionState::new();
+        let setup_msb = b"B\x00\x00\x0b\x00\x00\x00\x04\x00\x00\x00\x00ABCD";
+        assert_eq!(track_setup(&mut state, setup_msb), 16);
+        assert_eq!(state.byte_order(), Endianness::Big);
+        // Only the first message is a setup.
+        assert_eq!(track_setup(&mut state, setup_msb), 0);
+
+        let mut state = ConnectionState::new();
+        let setup_lsb = b"l\x00\x0b\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(track_setup(&mut state, setup_lsb), 12);
+        assert_eq!(state.byte_order(), Endianness::Little);
+    }
+
+    #[test]
+    fn test_client_framing() {
+        let mut stream =
+            b"B\x00\x00\x0b\x00\x00\x00\x04\x00\x00\x00\x00ABCD".to_vec();
+        stream.extend(b"\x10\x01\x00\x04\x00\x07\x00\x00WM_NAME\x00");
+        // BigReqEnable, then a BIG-REQUESTS encoded NoOperation
+        stream.extend(&[133, 0, 0, 1]);
+        stream.extend(b"\x7f\x00\x00\x00\x00\x00\x00\x03\x00\x00\x00\x00");
+
+        let mut state = ConnectionState::new();
+        state.query_extension(1, BIG_REQUESTS_NAME);
+        state.extension_reply(1, 133);
+        let mut complete = Vec::new();
+        let mut policy = Policy::default();
+        for chunk in stream.chunks(5) {
+            let mut framer = ClientFramer::new(&state, &policy);
+            let whole =
+                state.client_stream.complete(chunk, |m| framer.frame(m));
+            analyze_buffer(&mut state, &policy, &whole, false, 0).unwrap();
+            assert!(framer.check().is_ok());
+            complete.extend(whole);
+        }
+        assert_eq!(complete, stream);
+        assert_eq!(state.byte_order(), Endianness::Big);
+        assert!(state.big_requests());
+
+        // BIG-REQUESTS lengths too short for the header, and one longer
+        // than allowed.
+        policy.set_max_request_size(1024);
+        for &length in &[0u8, 1] {
+            let big = [0x7f, 0, 0, 0, 0, 0, 0, length];
+            let mut framer = ClientFramer::new(&state, &policy);
+            assert_eq!(framer.frame(&big), None);
+            assert!(framer.check().is_err());
+        }
+        let mut framer = ClientFramer::new(&state, &policy);
+        assert_eq!(framer.frame(&[0x7f, 0, 0, 0, 0, 0, 0, 2]), Some(8));
+        assert_eq!(framer.frame(&[0x7f, 0, 1, 0]), Some(1024));
+        assert!(framer.check().is_ok());
+        assert_eq!(framer.frame(&[0x7f, 0, 1, 1]), None);
+        assert!(framer.check().is_err());
+        let e = state.byte_order();
+        assert!(request(&[0x7f, 0, 0, 0, 0, 0, 0, 1], e, true).is_err());
+        assert!(request(&[0x7f, 0, 0, 0, 0, 0, 0, 0], e, true).is_err());
+    }
+
+    #[test]
+    fn test_big_requests_enable() {
+        // A NoOperation in the BIG-REQUESTS form, which is only one after
+        // BigReqEnable.
+        let big = [0x7f, 0, 0, 0, 0, 0, 0, 2];
+        let e = Endianness::Big;
+        assert!(request(&big, e, false).is_err());
+        assert_eq!(request(&big, e, true).unwrap().1.length, 8);
+
+        let mut state = ConnectionState::new();
+        state.set_byte_order(e);
+        let policy = Policy::default();
+        let mut framer = ClientFramer::new(&state, &policy);
+        assert_eq!(framer.frame(&big), None);
+        assert!(framer.check().is_err());
+
+        // Sent before the server said which opcode BIG-REQUESTS has, it's
+        // just an unknown request.
+        let enable = [133, 0, 0, 1];
+        filter_buffer(&mut state, &policy, &enable);
+        assert!(!state.big_requests());
+        state.query_extension(2, BIG_REQUESTS_NAME);
+        state.extension_reply(2, 133);
+        let filtered = filter_buffer(&mut state, &policy, &enable);
+        assert_eq!(filtered.accepted, enable);
+        assert!(state.big_requests());
+        let filtered = filter_buffer(&mut state, &policy, &big);
+        assert_eq!(filtered.accepted, big);
+    }
+
+    #[test]
+    fn test_split_requests() {
+        let mut stream =
+            b"l\x00\x0b\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        stream.extend(b"\x10\x00\x04\x00\x07\x00\x00\x00WM_NAME\x00");
+        // GrabServer, BigReqEnable and a BIG-REQUESTS NoOperation of 64K.
+        stream.extend(&[0x24, 0, 1, 0]);
+        stream.extend(&[133, 0, 1, 0]);
+        stream.extend(&[0x7f, 0, 0, 0, 0, 0x40, 0, 0]);
+        stream.resize(stream.len() + 0x10000 - 8, 0);
+
+        let policy = Policy::default();
+        let mut state = ConnectionState::new();
+        state.query_extension(1, BIG_REQUESTS_NAME);
+        state.extension_reply(1, 133);
+        let mut accepted = Vec::new();
+        let mut denials = Vec::new();
+        for chunk in stream.chunks(4093) {
+            let mut framer = ClientFramer::new(&state, &policy);
+            let whole =
+                state.client_stream.complete(chunk, |m| framer.frame(m));
+            let filtered = filter_buffer(&mut state, &policy, &whole);
+            assert_eq!(filtered.parse_failures, 0);
+            accepted.extend(filtered.accepted);
+            denials.extend(filtered.denials);
+        }
+        assert_eq!(state.client_stream.pending(), 0);
+        assert_eq!(denials, vec![Capability::GrabServer]);
+        assert_eq!(accepted.len(), stream.len());
+    }
+
+    #[test]
+    fn test_xtest_fake_input() {
+        let mut state = ConnectionState::new();
+        let mut query =
+            b"l\x00\x0b\x00\x00\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        query.extend(b"\x62\x00\x04\x00\x05\x00\x00\x00XTEST\x00\x00\x00");
+        let policy = Policy::default();
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.accepted, query);
+        state.extension_reply(1, 140);
+
+        // FakeInput of a key press.
+        let mut fake_input = vec![140, 2, 9, 0, 2, 38];
+        fake_input.resize(36, 0);
+        let filtered = filter_buffer(&mut state, &policy, &fake_input);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::FakeInput]);
+
+        let policy = Policy::new(vec![Capability::FakeInput]);
+        let filtered = filter_buffer(&mut state, &policy, &fake_input);
+        assert_eq!(filtered.accepted, fake_input);
+    }
+
+    #[test]
+    fn test_extension_requests() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.query_extension(1, "XTEST");
+        state.extension_reply(1, 140);
+        let e = Endianness::Little;
+
+        let mut fake_input = vec![140, 2, 9, 0, 2, 38];
+        fake_input.resize(36, 0);
+        let header = request(&fake_input, e, false).unwrap().1;
+        let decoded = decode_request(&state, &header, &fake_input, e);
+        assert!(matches!(decoded, Ok(DecodedRequest::XTestFakeInput(_))));
+        let dump = format_request(&state, 0, &header, &fake_input, &decoded);
+        assert!(dump.contains("XTEST FakeInput"));
+
+        // GetVersion, which isn't decoded.
+        let get_version = [140, 0, 2, 0, 2, 0, 2, 0];
+        let header = request(&get_version, e, false).unwrap().1;
+        let decoded = decode_request(&state, &header, &get_version, e);
+        assert_eq!(
+            decoded,
+            Ok(DecodedRequest::Extension {
+                major_opcode: 140,
+                minor_opcode: 0
+            })
+        );
+        let dump = format_request(&state, 0, &header, &get_version, &decoded);
+        assert!(dump.contains("XTEST 0"));
+
+        // RANDR replies with sizes in them are looked out for.
+        state.query_extension(2, RANDR_NAME);
+        state.extension_reply(2, 141);
+        let get_crtc_info =
+            [141, RANDR_GET_CRTC_INFO, 3, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        let filtered =
+            filter_buffer(&mut state, &Policy::default(), &get_crtc_info);
+        assert_eq!(filtered.accepted, &get_crtc_info[..]);
+        assert_eq!(state.take_geometry_query(1), Some(RANDR_GET_CRTC_INFO));
+    }
+
+    #[test]
+    fn test_send_event() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        // SendEvent of a KeyPress to window 0x400001, then a ClientMessage.
+        let mut key_press = vec![0x19, 0, 11, 0, 1, 0, 0x40, 0, 1, 0, 0, 0];
+        key_press.resize(44, 0);
+        key_press[12] = 2 | 0x80;
+        let mut client_message = key_press.clone();
+        client_message[12] = 33;
+
+        let filtered = filter_buffer(&mut state, &policy, &key_press);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::SendEvent]);
+        let filtered = filter_buffer(&mut state, &policy, &client_message);
+        assert_eq!(filtered.accepted, client_message);
+    }
+
+    #[test]
+    fn test_keyboard_grabs() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        let policy = Policy::default();
+
+        let grab_keyboard =
+            [0x1F, 0, 4, 0, 1, 0, 0x40, 0, 0, 0, 0, 0, 1, 1, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &grab_keyboard);
+        assert_eq!(filtered.denials, vec![Capability::GrabKeyboard]);
+
+        // Passive grabs are fine on the client's own windows only.
+        let grab_key =
+            [0x21, 0, 4, 0, 1, 0, 0x40, 0, 0, 0x80, 38, 1, 1, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &grab_key);
+        assert_eq!(filtered.accepted, &grab_key[..]);
+        let mut grab_root = grab_key;
+        grab_root[4..8].copy_from_slice(&[0x23, 0x01, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &grab_root);
+        assert_eq!(filtered.denials, vec![Capability::GrabKeyboard]);
+        let mut grab_foreign = grab_key;
+        grab_foreign[4..8].copy_from_slice(&[1, 0, 0x80, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &grab_foreign);
+        assert_eq!(filtered.denials, vec![Capability::GrabKeyboard]);
+    }
+
+    #[test]
+    fn test_pointer_grabs() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let policy = Policy::default();
+
+        let mut create = vec![1, 0, 8, 0, 1, 0, 0x40, 0, 0x23, 0x01, 0, 0];
+        create.resize(32, 0);
+        let mut grab_pointer = vec![0x1A, 0, 6, 0, 1, 0, 0x40, 0];
+        grab_pointer.resize(24, 0);
+        let mut grab_button = grab_pointer.clone();
+        grab_button[0] = 0x1C;
+
+        // Nobody may grab on someone else's window.
+        let mut foreign = grab_pointer.clone();
+        foreign[4..8].copy_from_slice(&[0x23, 0x01, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.denials, vec![Capability::GrabPointer]);
+        foreign[0] = 0x1C;
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.denials, vec![Capability::GrabPointer]);
+
+        filter_buffer(&mut state, &policy, &create);
+        let filtered = filter_buffer(&mut state, &policy, &grab_pointer);
+        assert_eq!(filtered.accepted, grab_pointer);
+        let filtered = filter_buffer(&mut state, &policy, &grab_button);
+        assert_eq!(filtered.accepted, grab_button);
+
+        // Nor confine the pointer to someone else's window.
+        grab_pointer[12..16].copy_from_slice(&[0x23, 0x01, 0, 0]);
+        grab_button[12..16].copy_from_slice(&[0x23, 0x01, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &grab_pointer);
+        assert_eq!(filtered.denials, vec![Capability::GrabPointer]);
+        let filtered = filter_buffer(&mut state, &policy, &grab_button);
+        assert_eq!(filtered.denials, vec![Capability::GrabPointer]);
+        grab_pointer[12..16].copy_from_slice(&[1, 0, 0x40, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &grab_pointer);
+        assert_eq!(filtered.accepted, grab_pointer);
+    }
+
+    #[test]
+    fn test_event_mask_snooping() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.add_resource(0x400001, Resource::Window);
+        let policy = Policy::default();
+
+        // Background pixel and an event mask with KeyPress.
+        let mut change = vec![2, 0, 5, 0, 1, 0, 0x80, 0, 0x02, 0x08, 0, 0];
+        change.extend(&[0xff, 0, 0, 0, 0x01, 0x80, 0, 0]);
+        let (_, decoded) =
+            changewindowattributes(&change, Endianness::Little).unwrap();
+        assert_eq!(decoded.attributes.get(CW_EVENT_MASK), Some(0x8001));
+        assert_eq!(decoded.attributes.get(0x2), Some(0xff));
+        assert_eq!(decoded.attributes.get(0x1), None);
+
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.denials, vec![Capability::SnoopKeys]);
+        change[6] = 0x40;
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+        // Other events on foreign windows are fine.
+        change[6] = 0x80;
+        change[16] = 0;
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+    }
+
+    #[test]
+    fn test_resource_tracking() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let policy = Policy::default();
+
+        // A 100x50 InputOutput window with a background pixel.
+        let mut create = vec![1, 0, 9, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        create.extend(&[0, 0, 0, 0, 100, 0, 50, 0, 0, 0, 1, 0]);
+        create.extend(&[0, 0, 0, 0, 0x02, 0, 0, 0, 0xff, 0, 0, 0]);
+        let (_, decoded) = createwindow(&create, Endianness::Little).unwrap();
+        assert_eq!((decoded.width, decoded.height), (100, 50));
+        assert_eq!(decoded.attributes.get(0x2), Some(0xff));
+
+        let mut get = vec![0x49, 2, 5, 0, 1, 0, 0x40, 0, 0, 0, 0, 0];
+        get.extend(&[3, 0, 2, 0, 0xff, 0xff, 0xff, 0xff]);
+        filter_buffer(&mut state, &policy, &create);
+        assert!(state.owns(0x400001));
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, get);
+
+        // The id stays the client's, only the size is gone.
+        let destroy = [4, 0, 2, 0, 1, 0, 0x40, 0];
+        filter_buffer(&mut state, &policy, &destroy);
+        assert!(state.owns(0x400001));
+        assert_eq!(state.window_size(0x400001), None);
+
+        let pixmap = [0x35, 24, 4, 0, 2, 0, 0x40, 0, 0x23, 1, 0, 0, 8, 0, 8, 0];
+        let gc = [0x37, 0, 4, 0, 3, 0, 0x40, 0, 2, 0, 0x40, 0, 0, 0, 0, 0];
+        filter_buffer(&mut state, &policy, &pixmap);
+        filter_buffer(&mut state, &policy, &gc);
+        assert!(state.owns(0x400002) && state.owns(0x400003));
+    }
+
+    #[test]
+    fn test_ownership_before_setup() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+
+        // Without the client's range, creating a window proves nothing.
+        let mut create = vec![1, 0, 8, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        create.extend(&[0, 0, 0, 0, 100, 0, 50, 0, 0, 0, 1, 0]);
+        create.extend(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        filter_buffer(&mut state, &policy, &create);
+        assert!(!state.owns(0x400001));
+        let mut get = vec![0x49, 2, 5, 0, 1, 0, 0x40, 0, 0, 0, 0, 0];
+        get.extend(&[3, 0, 2, 0, 0xff, 0xff, 0xff, 0xff]);
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+    }
+
+    #[test]
+    fn test_foreign_resource_ids() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let policy = Policy::default();
+
+        // GetImage of the root window, before and after a CreatePixmap
+        // naming the root window as its id, which the server refuses.
+        let mut get = vec![0x49, 2, 5, 0, 0x23, 1, 0, 0, 0, 0, 0, 0];
+        get.extend(&[3, 0, 2, 0, 0xff, 0xff, 0xff, 0xff]);
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+        let pixmap = [0x35, 24, 4, 0, 0x23, 1, 0, 0, 1, 0, 0x40, 0, 8, 0, 8, 0];
+        filter_buffer(&mut state, &policy, &pixmap);
+        assert!(!state.owns(0x123));
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+    }
+
+    #[test]
+    fn test_copy_area() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.add_resource(0x400001, Resource::Window);
+        state.add_resource(0x400002, Resource::Pixmap);
+        let policy = Policy::default();
+
+        // From its pixmap to its window, then from the root window.
+        let mut copy = vec![0x3E, 0, 7, 0, 2, 0, 0x40, 0, 1, 0, 0x40, 0];
+        copy.extend(&[3, 0, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 0, 8, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &copy);
+        assert_eq!(filtered.accepted, copy);
+        copy[4..8].copy_from_slice(&[0x23, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &copy);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+
+        // A plane of the root window's background pixmap.
+        let mut plane = copy.clone();
+        plane[0] = 0x3F;
+        plane[2] = 8;
+        plane[4..8].copy_from_slice(&[1, 0, 0x60, 0]);
+        plane.extend(&[1, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &plane);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+        let policy = Policy::new(vec![Capability::Screenshot]);
+        let filtered = filter_buffer(&mut state, &policy, &plane);
+        assert_eq!(filtered.accepted, plane);
+    }
+
+    #[test]
+    fn test_selection_access() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.add_resource(0x400001, Resource::Window);
+        state.intern_atom(7, "CLIPBOARD");
+        state.atom_reply(7, 0x150);
+        let mut policy = Policy::default();
+        policy.set_selection_access(
+            "CLIPBOARD".to_string(),
+            SelectionAccess::CopyOut,
+        );
+        policy
+            .set_selection_access("PRIMARY".to_string(), SelectionAccess::None);
+
+        // Paste from CLIPBOARD, answered with a failed SelectionNotify.
+        let mut convert = vec![0x18, 0, 6, 0, 1, 0, 0x40, 0, 0x50, 1, 0, 0];
+        convert.extend(&[31, 0, 0, 0, 0x51, 1, 0, 0, 0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &convert);
+        assert_eq!(filtered.accepted, get_input_focus(Endianness::Little));
+        assert_eq!(filtered.denials, vec![Capability::ClipboardPaste]);
+        let notify = state.take_fake_reply(1).unwrap();
+        assert_eq!(&notify[0..4], &[SELECTION_NOTIFY, 0, 1, 0]);
+        assert_eq!(&notify[8..20], &convert[4..16]);
+        assert_eq!(&notify[20..24], &[0, 0, 0, 0]);
+
+        // Copying is fine, and reading back our own data too.
+        let set = [0x16, 0, 4, 0, 1, 0, 0x40, 0, 0x50, 1, 0, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &set);
+        assert_eq!(filtered.accepted, &set[..]);
+        let filtered = filter_buffer(&mut state, &policy, &convert);
+        assert_eq!(filtered.accepted, convert);
+
+        let mut set_primary = set;
+        set_primary[8..10].copy_from_slice(&[1, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &set_primary);
+        assert_eq!(filtered.denials, vec![Capability::ClipboardCopy]);
+
+        // The rules also hold for atoms known from the start.
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.seed_atoms(&[("CLIPBOARD".to_string(), 0x150)]);
+        let filtered = filter_buffer(&mut state, &policy, &convert);
+        assert_eq!(filtered.denials, vec![Capability::ClipboardPaste]);
+    }
+
+    #[test]
+    fn test_incr_transfer() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.intern_atom(1, "INCR");
+        state.atom_reply(1, 0x160);
+        state.selection_request(0x200001, 0x170, 0x150);
+        let mut policy = Policy::default();
+        policy.set_max_selection_size(8);
+
+        let change = |prop_type: u8, format: u8, data: &[u8]| {
+            let units = data.len() / usize::from(format / 8);
+            let mut change = vec![0x12, 0, 0, 0, 1, 0, 0x20, 0];
+            change.extend(&[0x70, 1, 0, 0, prop_type, 1, 0, 0]);
+            change.extend(&[format, 0, 0, 0, units as u8, 0, 0, 0]);
+            change.extend(data);
+            change.resize((change.len() + 3) & !3, 0);
+            change[2] = (change.len() / 4) as u8;
+            change
+        };
+        let incr = change(0x60, 32, &[0, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &incr);
+        assert_eq!(filtered.accepted, incr);
+        assert!(state.transfer(0x200001, 0x170).unwrap().incremental);
+
+        let chunk = change(0x31, 8, b"abcdef");
+        let filtered = filter_buffer(&mut state, &policy, &chunk);
+        assert_eq!(filtered.accepted, chunk);
+        let filtered = filter_buffer(&mut state, &policy, &chunk);
+        assert_eq!(filtered.denials, vec![Capability::ClipboardCopy]);
+        let end = change(0x31, 8, b"");
+        let filtered = filter_buffer(&mut state, &policy, &end);
+        assert_eq!(filtered.accepted, end);
+        assert_eq!(state.transfer(0x200001, 0x170), None);
+    }
+
+    #[test]
+    fn test_get_image() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let mut policy = Policy::default();
+        // ZPixmap of 3x2 pixels of the root window.
+        let get = [0x49, 2, 5, 0, 0x23, 1, 0, 0, 0, 0, 0, 0, 3, 0, 2, 0];
+        let mut get = get.to_vec();
+        get.extend(&[0xff, 0xff, 0xff, 0xff]);
+
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+        let error = state.take_fake_reply(1).unwrap();
+        assert_eq!(&error[0..4], &[0, BAD_ACCESS, 1, 0]);
+        assert_eq!(error[10], 0x49);
+
+        policy.set_blank_images(true);
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let reply = state.take_fake_reply(2).unwrap();
+        assert_eq!(&reply[0..8], &[1, 24, 2, 0, 6, 0, 0, 0]);
+        assert_eq!(reply.len(), 32 + 3 * 4 * 2);
+    }
+
+    #[test]
+    fn test_query_tree() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        let query = [0x0F, 0, 2, 0, 0x23, 1, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.denials, vec![Capability::ListWindows]);
+
+        policy.set_empty_trees(true);
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let reply = state.take_fake_reply(2).unwrap();
+        assert_eq!(&reply[0..12], &[1, 0, 2, 0, 0, 0, 0, 0, 0x23, 1, 0, 0]);
+        assert_eq!(reply.len(), 32);
+
+        let own = [0x0F, 0, 2, 0, 1, 0, 0x40, 0];
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.accepted, &own[..]);
+
+        // The server's reply is cut down to the client's own windows.
+        policy.set_isolate_windows(true);
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.accepted, &query[..]);
+    }
+
+    #[test]
+    fn test_spoof() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        policy.set_spoofed(vec![Capability::ListWindows]);
+        let query = [0x0F, 0, 2, 0, 0x23, 1, 0, 0];
+        assert_eq!(
+            check(&state, &policy, Capability::ListWindows),
+            Outcome::Spoofed(Capability::ListWindows)
+        );
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.denials, vec![Capability::ListWindows]);
+        assert_eq!(filtered.accepted, STAND_IN);
+        let reply = state.take_fake_reply(1).unwrap();
+        assert_eq!(&reply[0..12], &[1, 0, 1, 0, 0, 0, 0, 0, 0x23, 1, 0, 0]);
+
+        // Without a reply to make up, it's an error like any denial.
+        policy
+            .set_spoofed(vec![Capability::ListWindows, Capability::GrabServer]);
+        let grab = [0x24, 0, 1, 0];
+        let filtered = filter_buffer(&mut state, &policy, &grab);
+        assert_eq!(filtered.denials, vec![Capability::GrabServer]);
+        assert_eq!(filtered.accepted, STAND_IN);
+    }
+
+    #[test]
+    fn test_protected_property() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        policy.set_protected_properties(vec!["_NET_ACTIVE_WINDOW".to_string()]);
+        // The client learns the atom from GetAtomName, and so do we.
+        let get = [0x11, 0, 2, 0, 0x50, 1, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, &get[..]);
+        state.atom_name_reply(1, b"_NET_ACTIVE_WINDOW");
+        assert_eq!(state.atom_name(0x150), Some("_NET_ACTIVE_WINDOW"));
+
+        let mut change = vec![0x12, 0, 7, 0, 1, 0, 0x40, 0, 0x50, 1, 0, 0];
+        change.extend(&[33, 0, 0, 0, 32, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0x40, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.denials, vec![Capability::ChangeProtectedProperty]);
+        change[8] = 0x51;
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+    }
+
+    #[test]
+    fn test_foreign_properties() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        // WM_COMMAND on window 0x123.
+        let mut change = vec![0x12, 0, 7, 0, 0x23, 1, 0, 0, 0x22, 0, 0, 0];
+        change.extend(&[31, 0, 0, 0, 8, 0, 0, 0, 4, 0, 0, 0]);
+        change.extend(b"evil");
+        let filtered = filter_buffer(&mut state, &Policy::default(), &change);
+        assert_eq!(filtered.denials, vec![Capability::ForeignProperties]);
+        assert_eq!(filtered.accepted, STAND_IN);
+
+        let policy = Policy::new(vec![Capability::ForeignProperties]);
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+
+        // Storing a selection there is what pasting takes.
+        state.selection_request(0x123, 0x22, 1);
+        let filtered = filter_buffer(&mut state, &Policy::default(), &change);
+        assert_eq!(filtered.denials, vec![]);
+    }
+
+    #[test]
+    fn test_window_titles() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.seed_atoms(&[("_NET_WM_NAME".to_string(), 0x150)]);
+        let mut policy = Policy::default();
+        // GetProperty WM_NAME on someone else's window 0x200001.
+        let mut get = vec![0x14, 0, 6, 0, 1, 0, 0x20, 0, 39, 0, 0, 0];
+        get.extend(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.denials, vec![Capability::ReadWindowTitles]);
+        get[8] = 0x50;
+        get[9] = 1;
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.denials, vec![Capability::ReadWindowTitles]);
+
+        policy.set_spoofed(vec![Capability::ReadWindowTitles]);
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let reply = state.take_fake_reply(3).unwrap();
+        assert_eq!(&reply[0..8], &[1, 0, 3, 0, 0, 0, 0, 0]);
+
+        // Its own window, and other properties.
+        get[6] = 0x40;
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, get);
+        get[6] = 0x20;
+        get[8] = 0x17;
+        get[9] = 0;
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, get);
+    }
+
+    #[test]
+    fn test_query_pointer() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        let query = [0x26, 0, 2, 0, 0x23, 1, 0, 0];
+        let mut motion = vec![0x27, 0, 4, 0, 0x23, 1, 0, 0];
+        motion.extend(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.denials, vec![Capability::TrackPointer]);
+        assert_eq!(filtered.accepted, STAND_IN);
+        let filtered = filter_buffer(&mut state, &policy, &motion);
+        assert_eq!(filtered.denials, vec![Capability::TrackPointer]);
+
+        policy.set_spoof_pointer(true);
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let reply = state.take_fake_reply(3).unwrap();
+        assert_eq!(&reply[0..12], &[1, 1, 3, 0, 0, 0, 0, 0, 0x23, 1, 0, 0]);
+        assert!(reply[12..].iter().all(|&b| b == 0));
+        filter_buffer(&mut state, &policy, &motion);
+        let reply = state.take_fake_reply(4).unwrap();
+        assert_eq!(&reply[0..4], &[1, 0, 4, 0]);
+
+        let own = [0x26, 0, 2, 0, 1, 0, 0x40, 0];
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.accepted, &own[..]);
+    }
+
+    #[test]
+    fn test_geometry() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        let mut policy = Policy::default();
+        let root = [0xe, 0, 2, 0, 0x23, 1, 0, 0];
+        let own = [0xe, 0, 2, 0, 1, 0, 0x40, 0];
+        let foreign = [0xe, 0, 2, 0, 1, 0, 0x80, 0];
+        let filtered =
+            filter_buffer(&mut state, &policy, &[root, own].concat());
+        assert_eq!(filtered.accepted, [root, own].concat());
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::ReadGeometry]);
+
+        policy.set_spoof_geometry(true);
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.accepted, STAND_IN);
+        let reply = state.take_fake_reply(4).unwrap();
+        assert_eq!(&reply[0..12], &[1, 24, 4, 0, 0, 0, 0, 0, 0x23, 1, 0, 0]);
+        assert_eq!(&reply[12..20], &[0, 0, 0, 0, 1, 0, 1, 0]);
+
+        // Its own window on the screen, but not relative to theirs.
+        let mut translate = vec![0x28, 0, 4, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        translate.extend(&[10, 0, 20, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &translate);
+        assert_eq!(filtered.accepted, translate);
+        translate[8..12].copy_from_slice(&[1, 0, 0x80, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &translate);
+        assert_eq!(filtered.denials, vec![Capability::ReadGeometry]);
+        let reply = state.take_fake_reply(6).unwrap();
+        assert_eq!(
+            &reply[0..16],
+            &[1, 1, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 20, 0]
+        );
+    }
+
+    #[test]
+    fn test_shm() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        let policy = Policy::default();
+
+        // The query still goes out, but the client hears MIT-SHM is absent.
+        let mut query = vec![0x62, 0, 4, 0, 7, 0, 0, 0];
+        query.extend(b"MIT-SHM\0");
+        let filtered = filter_buffer(&mut state, &policy, &query);
+        assert_eq!(filtered.accepted, query);
+        assert_eq!(filtered.denials, vec![Capability::SharedMemory]);
+        let reply = state.take_fake_reply(1).unwrap();
+        assert_eq!(&reply[0..4], &[1, 0, 1, 0]);
+        assert_eq!(reply[8], 0);
+        state.extension_reply(1, 130);
+
+        let attach = [130, 1, 4, 0, 1, 0, 0x40, 0, 7, 0, 0, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &attach);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::SharedMemory]);
+
+        let policy = Policy::new(vec![Capability::SharedMemory]);
+        let filtered = filter_buffer(&mut state, &policy, &attach);
+        assert_eq!(filtered.accepted, &attach[..]);
+        let mut get_image = vec![130, 4, 8, 0, 0x23, 1, 0, 0];
+        get_image.extend(&[0, 0, 0, 0, 10, 0, 10, 0, 255, 255, 255, 255]);
+        get_image.extend(&[2, 0, 0, 0, 1, 0, 0x40, 0, 0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &get_image);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::Screenshot]);
+    }
+
+    #[test]
+    fn test_set_input_focus() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.add_resource(0x400001, Resource::Window);
+        let mut policy = Policy::default();
+        let own = [0x2A, 1, 3, 0, 1, 0, 0x40, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.accepted, &own[..]);
+
+        let foreign = [0x2A, 1, 3, 0, 0x23, 1, 0, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.denials, vec![Capability::StealFocus]);
+        let pointer_root = [0x2A, 1, 3, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &pointer_root);
+        assert_eq!(filtered.denials, vec![Capability::StealFocus]);
+
+        policy.set_own_focus(false);
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.denials, vec![Capability::StealFocus]);
+    }
+
+    #[test]
+    fn test_allow_events() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        let sync_both = [0x23, 7, 2, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &sync_both);
+        assert_eq!(filtered.accepted, &sync_both[..]);
+
+        let replay_pointer = [0x23, 2, 2, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &replay_pointer);
+        assert_eq!(filtered.denials, vec![Capability::ReplayEvents]);
+        assert_eq!(filtered.accepted, vec![0x23, 0, 2, 0, 0, 0, 0, 0]);
+        let replay_keyboard = [0x23, 5, 2, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &replay_keyboard);
+        assert_eq!(filtered.accepted, vec![0x23, 3, 2, 0, 0, 0, 0, 0]);
+
+        let policy = Policy::new(vec![Capability::ReplayEvents]);
+        let filtered = filter_buffer(&mut state, &policy, &replay_pointer);
+        assert_eq!(filtered.accepted, &replay_pointer[..]);
+    }
+
+    #[test]
+    fn test_screen_saver() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        let force = [0x73, 1, 1, 0];
+        let set = [0x6B, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for request in &[&force[..], &set[..]] {
+            let filtered = filter_buffer(&mut state, &policy, request);
+            assert_eq!(filtered.denials, vec![Capability::ScreenSaver]);
+        }
+
+        // The denied requests had stand-ins sent, this is the third.
+        let query = b"\x62\x00\x06\x00\x10\x00\x00\x00MIT-SCREEN-SAVER";
+        filter_buffer(&mut state, &policy, query);
+        state.extension_reply(3, 150);
+        let suspend = [150, 5, 2, 0, 1, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &suspend);
+        assert_eq!(filtered.denials, vec![Capability::ScreenSaver]);
+        let query_info = [150, 1, 2, 0, 1, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &query_info);
+        assert_eq!(filtered.accepted, &query_info[..]);
+
+        let policy = Policy::new(vec![Capability::ScreenSaver]);
+        let filtered = filter_buffer(&mut state, &policy, &suspend);
+        assert_eq!(filtered.accepted, &suspend[..]);
+    }
+
+    #[test]
+    fn test_xi_select_events() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.query_extension(1, XI_NAME);
+        state.extension_reply(1, 131);
+        let policy = Policy::default();
+        let select = |window: [u8; 4], mask: u32| {
+            let mut request = vec![131, XI_SELECT_EVENTS, 5, 0];
+            request.extend(&window);
+            request.extend(&[1, 0, 0, 0, 1, 0, 1, 0]);
+            request.extend(&[0, 0, 0, 0]);
+            write_u32(&mut request[16..20], mask, Endianness::Little);
+            request
+        };
+        let root = [0x23, 1, 0, 0];
+        let own = [1, 0, 0x40, 0];
+
+        let raw_keys = select(root, 1 << 13);
+        let filtered = filter_buffer(&mut state, &policy, &raw_keys);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::SnoopKeys]);
+        let raw_motion = select(root, 1 << 17);
+        let filtered = filter_buffer(&mut state, &policy, &raw_motion);
+        assert_eq!(filtered.denials, vec![Capability::TrackPointer]);
+        let keys = select(root, 1 << 2);
+        let filtered = filter_buffer(&mut state, &policy, &keys);
+        assert_eq!(filtered.denials, vec![Capability::SnoopKeys]);
+
+        let own_keys = select(own, 1 << 2 | 1 << 3);
+        let filtered = filter_buffer(&mut state, &policy, &own_keys);
+        assert_eq!(filtered.accepted, own_keys);
+        let hierarchy = select(root, 1 << 11);
+        let filtered = filter_buffer(&mut state, &policy, &hierarchy);
+        assert_eq!(filtered.accepted, hierarchy);
+    }
+
+    #[test]
+    fn test_randr_configure() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.query_extension(1, RANDR_NAME);
+        state.extension_reply(1, 140);
+        state.next_sequence();
+        let policy = Policy::default();
+
+        let mut set_crtc = vec![140, RANDR_SET_CRTC_CONFIG, 7, 0];
+        set_crtc.resize(28, 0);
+        let filtered = filter_buffer(&mut state, &policy, &set_crtc);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        assert_eq!(filtered.denials, vec![Capability::ConfigureDisplay]);
+        let reply = state.take_fake_reply(2).unwrap();
+        assert_eq!(&reply[0..4], &[1, RANDR_FAILED, 2, 0]);
+
+        let primary = [
+            140,
+            RANDR_SET_OUTPUT_PRIMARY,
+            3,
+            0,
+            0x23,
+            1,
+            0,
+            0,
+            0x41,
+            0,
+            0,
+            0,
+        ];
+        let filtered = filter_buffer(&mut state, &policy, &primary);
+        assert_eq!(filtered.accepted, STAND_IN);
+
+        // Gamma, transforms, panning and monitors are as much everyone's.
+        let mut panning = vec![140, RANDR_SET_PANNING, 9, 0];
+        panning.resize(36, 0);
+        let filtered = filter_buffer(&mut state, &policy, &panning);
+        assert_eq!(filtered.denials, vec![Capability::ConfigureDisplay]);
+        let reply = state.take_fake_reply(4).unwrap();
+        assert_eq!(&reply[0..4], &[1, RANDR_FAILED, 4, 0]);
+        for &minor_opcode in &[
+            RANDR_SET_CRTC_GAMMA,
+            RANDR_SET_CRTC_TRANSFORM,
+            RANDR_SET_MONITOR,
+            RANDR_DELETE_MONITOR,
+        ] {
+            let request = [140, minor_opcode, 3, 0, 0x23, 1, 0, 0, 0, 0, 0, 0];
+            let filtered = filter_buffer(&mut state, &policy, &request);
+            assert_eq!(filtered.accepted, STAND_IN);
+            assert_eq!(filtered.denials, vec![Capability::ConfigureDisplay]);
+        }
+
+        // Looking is fine.
+        let get_monitors =
+            [140, RANDR_GET_MONITORS, 3, 0, 0x23, 1, 0, 0, 1, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &get_monitors);
+        assert_eq!(filtered.accepted, &get_monitors[..]);
+
+        let policy = Policy::new(vec![Capability::ConfigureDisplay]);
+        let filtered = filter_buffer(&mut state, &policy, &primary);
+        assert_eq!(filtered.accepted, &primary[..]);
+    }
+
+    #[test]
+    fn test_xkb() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.query_extension(1, XKB_NAME);
+        state.extension_reply(1, 135);
+        state.next_sequence();
+        let policy = Policy::default();
+
+        let mut set_map = vec![135, XKB_SET_MAP, 7, 0, 0, 1];
+        set_map.resize(28, 0);
+        let filtered = filter_buffer(&mut state, &policy, &set_map);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::RemapInput]);
+
+        let mut bell = vec![135, XKB_BELL, 7, 0, 0, 1];
+        bell.resize(28, 0);
+        let filtered = filter_buffer(&mut state, &policy, &bell);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::KeyboardFeedback]);
+
+        // GetState is fine.
+        let get_state = [135, 4, 2, 0, 0, 1, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &get_state);
+        assert_eq!(filtered.accepted, &get_state[..]);
+
+        let policy = Policy::new(vec![Capability::KeyboardFeedback]);
+        let filtered = filter_buffer(&mut state, &policy, &bell);
+        assert_eq!(filtered.accepted, bell);
+    }
+
+    #[test]
+    fn test_rearrange_windows() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        let mut policy = Policy::default();
+
+        // An override-redirect window of 100 by 100, and another client's.
+        let mut create = vec![1, 24, 9, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        create.extend(&[0, 0, 0, 0, 100, 0, 100, 0, 0, 0, 1, 0]);
+        create.extend(&[0, 0, 0, 0, 0, 2, 0, 0, 1, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &create);
+        assert_eq!(filtered.accepted, create);
+        let foreign = [12, 0, 3, 0, 1, 0, 0x80, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::RearrangeWindows]);
+
+        // Its own on the root window, but not theirs into its own or the
+        // other way around.
+        let mut reparent = vec![7, 0, 4, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        reparent.extend(&[0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &reparent);
+        assert_eq!(filtered.accepted, reparent);
+        reparent[8..12].copy_from_slice(&[1, 0, 0x80, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &reparent);
+        assert_eq!(filtered.denials, vec![Capability::RearrangeWindows]);
+        reparent[4..12].copy_from_slice(&[1, 0, 0x80, 0, 1, 0, 0x40, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &reparent);
+        assert_eq!(filtered.denials, vec![Capability::RearrangeWindows]);
+
+        // Growing it to cover the screen, only denied if asked for.
+        let cover = [
+            12, 0, 5, 0, 1, 0, 0x40, 0, 0xc, 0, 0, 0, 0x80, 0x07, 0, 0, 0x38,
+            0x04, 0, 0,
+        ];
+        policy.set_deny_cover_screen(true);
+        let filtered = filter_buffer(&mut state, &policy, &cover);
+        assert_eq!(filtered.denials, vec![Capability::CoverScreen]);
+        create[4] = 2;
+        create[16..20].copy_from_slice(&[0x80, 0x07, 0x38, 0x04]);
+        let filtered = filter_buffer(&mut state, &policy, &create);
+        assert_eq!(filtered.denials, vec![Capability::CoverScreen]);
+        policy.set_deny_cover_screen(false);
+        let filtered = filter_buffer(&mut state, &policy, &cover);
+        assert_eq!(filtered.accepted, cover);
+        assert_eq!(state.window_size(0x400001), Some((1920, 1080)));
+
+        // Once it's that big, turning on override-redirect is the same.
+        policy.set_deny_cover_screen(true);
+        let mut change = vec![2, 0, 4, 0, 1, 0, 0x40, 0, 0, 2, 0, 0];
+        change.extend(&[0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+        assert!(!state.is_override_redirect(0x400001));
+        change[12] = 1;
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.denials, vec![Capability::CoverScreen]);
+    }
+
+    #[test]
+    fn test_legacy_toolkits() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        state.seed_atoms(&[
+            ("_XSETTINGS_S0".to_string(), 0x150),
+            ("_XSETTINGS_SETTINGS".to_string(), 0x151),
+            ("_MOTIF_DRAG_WINDOW".to_string(), 0x152),
+            ("_NET_WM_NAME".to_string(), 0x153),
+        ]);
+        let mut policy = Policy::default();
+
+        // XStoreBytes from xterm, "abc" into CUT_BUFFER0 as STRING.
+        let mut store = vec![18, 0, 7, 0, 0x23, 1, 0, 0, 9, 0, 0, 0];
+        store.extend(&[
+            31, 0, 0, 0, 8, 0, 0, 0, 3, 0, 0, 0, b'a', b'b', b'c', 0,
+        ]);
+        let filtered = filter_buffer(&mut state, &policy, &store);
+        assert_eq!(filtered.accepted, store);
+        let rule = CUT_BUFFERS_SELECTION.to_string();
+        policy.set_selection_access(rule.clone(), SelectionAccess::PasteIn);
+        let filtered = filter_buffer(&mut state, &policy, &store);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::ClipboardCopy]);
+
+        // XFetchBytes of CUT_BUFFER7.
+        let mut fetch = vec![20, 0, 6, 0, 0x23, 1, 0, 0, 16, 0, 0, 0];
+        fetch.extend(&[31, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &fetch);
+        assert_eq!(filtered.accepted, fetch);
+        policy.set_selection_access(rule, SelectionAccess::CopyOut);
+        let filtered = filter_buffer(&mut state, &policy, &fetch);
+        assert_eq!(filtered.denials, vec![Capability::ClipboardPaste]);
+
+        // GTK and Java read the XSETTINGS of the settings manager's window.
+        let mut settings = vec![20, 0, 6, 0, 1, 0, 0x80, 0, 0x51, 1, 0, 0];
+        settings.extend(&[0x51, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &settings);
+        assert_eq!(filtered.accepted, settings);
+
+        // Motif drag and drop keeps its drag window on the root window.
+        let mut drag = vec![18, 0, 7, 0, 0x23, 1, 0, 0, 0x52, 1, 0, 0];
+        drag.extend(&[33, 0, 0, 0, 32, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0x40, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &drag);
+        assert_eq!(filtered.denials, vec![Capability::ForeignProperties]);
+        let shared = Preset::Legacy.shared_properties();
+        policy.set_shared_properties(
+            shared.iter().map(|name| name.to_string()).collect(),
+        );
+        let filtered = filter_buffer(&mut state, &policy, &drag);
+        assert_eq!(filtered.accepted, drag);
+
+        // AWT reads the title of its frame's window manager decoration.
+        let mut title = vec![20, 0, 6, 0, 1, 0, 0x80, 0, 0x53, 1, 0, 0];
+        title.extend(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &title);
+        assert_eq!(filtered.denials, vec![Capability::ReadWindowTitles]);
+        let policy = Policy::new(Preset::Legacy.capabilities().to_vec());
+        let filtered = filter_buffer(&mut state, &policy, &title);
+        assert_eq!(filtered.accepted, title);
+    }
+
+    #[test]
+    fn test_xsettings() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.seed_atoms(&[
+            ("_XSETTINGS_S0".to_string(), 0x150),
+            ("_XSETTINGS_SETTINGS".to_string(), 0x151),
+        ]);
+        let policy = Policy::default();
+
+        // Reading the theme from the manager's window.
+        let mut get = vec![20, 0, 6, 0, 1, 0, 0x80, 0, 0x51, 1, 0, 0];
+        get.extend(&[0x51, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &get);
+        assert_eq!(filtered.accepted, get);
+
+        // Taking over from it, on a window of its own.
+        let mut own = vec![0x16, 0, 4, 0, 1, 0, 0x40, 0, 0x50, 1, 0, 0];
+        own.extend(&[0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.denials, vec![Capability::SettingsManager]);
+        own[4..8].copy_from_slice(&[0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.accepted, own);
+
+        // Rewriting the settings in place.
+        let mut change = vec![18, 0, 7, 0, 1, 0, 0x80, 0, 0x51, 1, 0, 0];
+        change.extend(&[0x51, 1, 0, 0, 8, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.denials, vec![Capability::SettingsManager]);
+        let policy = Policy::new(vec![Capability::SettingsManager]);
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+    }
+
+    #[test]
+    fn test_input_methods() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        state.seed_atoms(&[
+            ("@server=ibus".to_string(), 0x150),
+            ("_XIM_XCONNECT".to_string(), 0x151),
+            ("_client0".to_string(), 0x152),
+            ("XIM_SERVERS".to_string(), 0x153),
+            ("LOCALES".to_string(), 0x154),
+        ]);
+        let mut policy = Policy::default();
+        policy.set_allowed_events(Vec::new());
+
+        // What Xlib does when XOpenIM finds ibus: look it up and find its
+        // window, ask for its locales, connect, and once ibus answers
+        // with another window hand over a long message there.
+        let mut servers = vec![20, 0, 6, 0, 0x23, 1, 0, 0, 0x53, 1, 0, 0];
+        servers.extend(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        let owner = [23, 0, 2, 0, 0x50, 1, 0, 0];
+        let mut locales = vec![24, 0, 6, 0, 1, 0, 0x40, 0, 0x50, 1, 0, 0];
+        locales.extend(&[0x54, 1, 0, 0, 0x54, 1, 0, 0, 0, 0, 0, 0]);
+        let mut connect = vec![25, 0, 11, 0, 1, 0, 0x80, 0, 0, 0, 0, 0];
+        connect.extend(&[33, 32, 0, 0, 1, 0, 0x80, 0, 0x51, 1, 0, 0]);
+        connect.extend(&[2, 0, 0x40, 0]);
+        connect.resize(44, 0);
+        let mut long = vec![18, 2, 7, 0, 2, 0, 0x80, 0, 0x52, 1, 0, 0];
+        long.extend(&[31, 0, 0, 0, 8, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0]);
+        let trace = [&servers[..], &owner, &locales].concat();
+        let filtered = filter_buffer(&mut state, &policy, &trace);
+        assert_eq!(filtered.accepted, trace);
+        assert!(state.take_input_method_query(2));
+        state.add_input_method(0x800001);
+        let filtered = filter_buffer(&mut state, &policy, &connect);
+        assert_eq!(filtered.accepted, connect);
+        // The answer goes to the window the client named.
+        state.input_method_connected(0x400001, 0x800002);
+        assert!(!state.is_input_method(0x800002));
+        state.input_method_connected(0x400002, 0x800002);
+        let filtered = filter_buffer(&mut state, &policy, &long);
+        assert_eq!(filtered.accepted, long);
+
+        // Neither passes for someone else's window.
+        let mut foreign = connect.clone();
+        foreign[4..8].copy_from_slice(&[1, 0, 0x90, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.denials, vec![Capability::SendEvent]);
+        let mut foreign = long.clone();
+        foreign[4..8].copy_from_slice(&[1, 0, 0x90, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &foreign);
+        assert_eq!(filtered.denials, vec![Capability::ForeignProperties]);
+
+        // Denied, the client is told there is no input method.
+        policy.set_deny_input_methods(true);
+        let filtered = filter_buffer(&mut state, &policy, &servers);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::InputMethod]);
+        let reply = state.take_fake_reply(8).unwrap();
+        assert_eq!(&reply[0..4], &[1, 0, 8, 0]);
+        assert!(reply[4..].iter().all(|&b| b == 0));
+        for request in &[&locales, &connect, &long] {
+            let filtered = filter_buffer(&mut state, &policy, request);
+            assert_eq!(filtered.denials, vec![Capability::InputMethod]);
+        }
+        let mut policy = Policy::new(vec![Capability::InputMethod]);
+        policy.set_deny_input_methods(true);
+        let filtered = filter_buffer(&mut state, &policy, &connect);
+        assert_eq!(filtered.accepted, connect);
+    }
+
+    #[test]
+    fn test_property_requests() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        state.seed_atoms(&[("_NET_WM_STATE".to_string(), 0x150)]);
+        let mut policy = Policy::default();
+
+        // Listing is fine, deleting goes by the ChangeProperty rules.
+        let list = [0x15, 0, 2, 0, 1, 0, 0x80, 0];
+        let filtered = filter_buffer(&mut state, &policy, &list);
+        assert_eq!(filtered.accepted, list);
+        let mut delete = vec![0x13, 0, 3, 0, 1, 0, 0x80, 0, 0x50, 1, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &delete);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::ForeignProperties]);
+        delete[4..8].copy_from_slice(&[1, 0, 0x40, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &delete);
+        assert_eq!(filtered.accepted, delete);
+        policy.set_protected_properties(vec!["_NET_WM_STATE".to_string()]);
+        let filtered = filter_buffer(&mut state, &policy, &delete);
+        assert_eq!(filtered.denials, vec![Capability::ChangeProtectedProperty]);
+
+        // XRotateBuffers, which only passes with the cut buffer rules.
+        let mut rotate = vec![0x72, 0, 11, 0, 0x23, 1, 0, 0, 8, 0, 1, 0];
+        for atom in 9..17 {
+            rotate.extend(&[atom, 0, 0, 0]);
+        }
+        let filtered = filter_buffer(&mut state, &policy, &rotate);
+        assert_eq!(filtered.accepted, rotate);
+        policy.set_selection_access(
+            CUT_BUFFERS_SELECTION.to_string(),
+            SelectionAccess::PasteIn,
+        );
+        let filtered = filter_buffer(&mut state, &policy, &rotate);
+        assert_eq!(filtered.denials, vec![Capability::ClipboardCopy]);
+
+        // One of someone else's is enough to deny it.
+        let mut rotate = vec![0x72, 0, 5, 0, 1, 0, 0x80, 0, 2, 0, 1, 0];
+        rotate.extend(&[39, 0, 0, 0, 0x50, 1, 0, 0]);
+        let mut policy = Policy::new(vec![Capability::ForeignProperties]);
+        let filtered = filter_buffer(&mut state, &policy, &rotate);
+        assert_eq!(filtered.accepted, rotate);
+        assert_eq!(filtered.granted, vec![Capability::ForeignProperties]);
+        policy.set_protected_properties(vec!["_NET_WM_STATE".to_string()]);
+        let filtered = filter_buffer(&mut state, &policy, &rotate);
+        assert_eq!(filtered.denials, vec![Capability::ChangeProtectedProperty]);
+    }
+
+    fn shape_rectangles_request(
+        window: u32,
+        operation: u8,
+        rectangles: &[(u16, u16)],
+    ) -> Vec<u8> {
+        let length = 4 + 2 * rectangles.len() as u8;
+        let mut request =
+            vec![130, SHAPE_RECTANGLES, length, 0, operation, SHAPE_INPUT];
+        request.resize(16, 0);
+        write_u32(&mut request[8..12], window, Endianness::Little);
+        for &(width, height) in rectangles {
+            request.extend(&[0, 0, 0, 0]);
+            request.extend(&[width as u8, (width >> 8) as u8]);
+            request.extend(&[height as u8, (height >> 8) as u8]);
+        }
+        request
+    }
+
+    #[test]
+    fn test_main_window() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+            ..SetupInfo::default()
+        });
+        let policy = Policy::default();
+        // A top-level window and a child of it.
+        let mut create = vec![1, 24, 8, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        create.resize(32, 0);
+        let mut child = vec![1, 24, 8, 0, 2, 0, 0x40, 0, 1, 0, 0x40, 0];
+        child.resize(32, 0);
+        filter_buffer(&mut state, &policy, &[create, child].concat());
+        assert_eq!(state.main_window(), Some(0x400001));
+
+        // WM_HINTS with the input hint.
+        let mut hints = vec![0x12, 0, 15, 0, 1, 0, 0x40, 0, 35, 0, 0, 0];
+        hints.extend(&[35, 0, 0, 0, 32, 0, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0]);
+        hints.extend(&[1, 0, 0, 0]);
+        hints.resize(60, 0);
+        filter_buffer(&mut state, &policy, &hints);
+        assert_eq!(state.wm_hints(), Some(&[1, 1, 0, 0, 0, 0, 0, 0, 0][..]));
+
+        let destroy = [4, 0, 2, 0, 1, 0, 0x40, 0];
+        filter_buffer(&mut state, &policy, &destroy);
+        assert_eq!(state.main_window(), None);
+    }
+
+    #[test]
+    fn test_shape() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            formats: Vec::new(),
+            screens: vec![Screen {
+                root: 0x123,
+                width: 1920,
+                height: 1080,
+                root_visual: 0x21,
+                root_depth: 24,
+            }],
+        });
+        state.query_extension(1, SHAPE_NAME);
+        state.extension_reply(1, 130);
+        state.next_sequence();
+        let policy = Policy::default();
+
+        // A full-screen window, 1920 by 1080.
+        let mut create = vec![1, 24, 8, 0, 1, 0, 0x40, 0, 0x23, 1, 0, 0];
+        create.extend(&[0, 0, 0, 0, 0x80, 0x07, 0x38, 0x04]);
+        create.resize(32, 0);
+        let filtered = filter_buffer(&mut state, &policy, &create);
+        assert_eq!(filtered.accepted, create);
+
+        let empty = shape_rectangles_request(0x400001, 0, &[]);
+        let filtered = filter_buffer(&mut state, &policy, &empty);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::ClickThrough]);
+        let corner = shape_rectangles_request(0x400001, 0, &[(10, 10)]);
+        let filtered = filter_buffer(&mut state, &policy, &corner);
+        assert_eq!(filtered.denials, vec![Capability::ClickThrough]);
+        let most = shape_rectangles_request(0x400001, 0, &[(1920, 1000)]);
+        let filtered = filter_buffer(&mut state, &policy, &most);
+        assert_eq!(filtered.accepted, most);
+        let union = shape_rectangles_request(0x400001, SHAPE_UNION, &[]);
+        let filtered = filter_buffer(&mut state, &policy, &union);
+        assert_eq!(filtered.accepted, union);
+
+        // Masks whose contents we can't see, unless they reset the shape.
+        let mut mask = vec![130, SHAPE_MASK, 5, 0, 0, SHAPE_INPUT, 0, 0];
+        mask.extend(&[1, 0, 0x40, 0, 0, 0, 0, 0, 2, 0, 0x40, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &mask);
+        assert_eq!(filtered.denials, vec![Capability::ClickThrough]);
+        mask[16..20].copy_from_slice(&[0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &mask);
+        assert_eq!(filtered.accepted, mask);
+
+        // Small windows may let clicks through, until they grow.
+        state.set_window_size(0x400002, 100, 100);
+        let small = shape_rectangles_request(0x400002, 0, &[]);
+        let filtered = filter_buffer(&mut state, &policy, &small);
+        assert_eq!(filtered.accepted, small);
+        let configure = [
+            12, 0, 5, 0, 2, 0, 0x40, 0, 0xc, 0, 0, 0, 0x80, 0x07, 0, 0, 0x38,
+            0x04, 0, 0,
+        ];
+        filter_buffer(&mut state, &policy, &configure);
+        assert_eq!(state.window_size(0x400002), Some((1920, 1080)));
+        let filtered = filter_buffer(&mut state, &policy, &small);
+        assert_eq!(filtered.denials, vec![Capability::ClickThrough]);
+
+        let policy = Policy::new(vec![Capability::ClickThrough]);
+        let filtered = filter_buffer(&mut state, &policy, &empty);
+        assert_eq!(filtered.accepted, empty);
+    }
+
+    #[test]
+    fn test_sync_resources() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.query_extension(1, SYNC_NAME);
+        state.extension_reply(1, 134);
+        state.query_extension(2, XC_MISC_NAME);
+        state.extension_reply(2, 136);
+        state.next_sequence();
+        state.next_sequence();
+        let policy = Policy::default();
+
+        let mut counter = vec![134, SYNC_CREATE_COUNTER, 4, 0, 1, 0, 0x40, 0];
+        counter.resize(16, 0);
+        let mut fence = vec![134, SYNC_CREATE_FENCE, 4, 0, 0x23, 1, 0, 0];
+        fence.extend(&[2, 0, 0x40, 0, 0, 0, 0, 0]);
+        counter.extend(&fence);
+        let filtered = filter_buffer(&mut state, &policy, &counter);
+        assert_eq!(filtered.accepted, counter);
+        assert!(state.owns(0x400001));
+        assert!(state.owns(0x400002));
+        assert!(!state.owns(0x123));
+
+        let range = [136, XC_MISC_GET_XID_RANGE, 1, 0];
+        let filtered = filter_buffer(&mut state, &policy, &range);
+        assert_eq!(filtered.accepted, range);
+        assert_eq!(state.take_xid_query(5), Some(XC_MISC_GET_XID_RANGE));
+    }
+
+    #[test]
+    fn test_timed_exceptions() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        let grab = [36, 0, 1, 0];
+        let later = Instant::now() + Duration::from_secs(60);
+        state.set_exceptions(vec![(Capability::GrabServer, later)]);
+        let filtered = filter_buffer(&mut state, &policy, &grab);
+        assert_eq!(filtered.accepted, grab);
+        assert_eq!(filtered.granted, vec![Capability::GrabServer]);
+
+        state.set_exceptions(vec![(Capability::GrabServer, Instant::now())]);
+        let filtered = filter_buffer(&mut state, &policy, &grab);
+        assert_eq!(filtered.denials, vec![Capability::GrabServer]);
+    }
+
+    #[test]
+    fn test_passed_fds() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.query_extension(1, DRI3_NAME);
+        state.extension_reply(1, 149);
+        state.query_extension(2, SHM_NAME);
+        state.extension_reply(2, 130);
+        state.next_sequence();
+        state.next_sequence();
+        let mut policy = Policy::new(vec![Capability::SharedMemory]);
+        policy.set_gpu_mode(GpuMode::Strict);
+
+        // A denied import's buffer goes nowhere, the segment after it
+        // still goes along with its AttachFd.
+        let mut import = vec![149, DRI3_PIXMAP_FROM_BUFFER, 6, 0];
+        import.resize(24, 0);
+        let attach = [130, SHM_ATTACH_FD, 3, 0, 1, 0, 0x40, 0, 0, 0, 0, 0];
+        state.receive_fds(&mut vec![10, 11]);
+        let filtered = filter_buffer(
+            &mut state,
+            &policy,
+            &[&import[..], &attach].concat(),
+        );
+        assert_eq!(filtered.accepted, [STAND_IN, &attach[..]].concat());
+        assert_eq!(filtered.fds, vec![11]);
+        assert_eq!(filtered.dropped_fds, vec![10]);
+
+        // Those of a request that isn't whole yet wait for it.
+        let mut buffers = vec![149, DRI3_PIXMAP_FROM_BUFFERS, 16, 0];
+        buffers.resize(64, 0);
+        buffers[12] = 2;
+        state.receive_fds(&mut vec![12, 13, 14]);
+        let filtered = filter_buffer(&mut state, &policy, &[]);
+        assert!(filtered.fds.is_empty());
+        policy.set_gpu_mode(GpuMode::Passthrough);
+        let filtered = filter_buffer(&mut state, &policy, &buffers);
+        assert_eq!(filtered.fds, vec![12, 13]);
+        let mut rest = Vec::new();
+        state.take_fds(usize::MAX, &mut rest);
+        assert_eq!(rest, vec![14]);
+    }
+
+    #[test]
+    fn test_gpu_mode() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.query_extension(1, DRI3_NAME);
+        state.extension_reply(1, 149);
+        state.query_extension(2, "GLX");
+        state.extension_reply(2, 152);
+        state.next_sequence();
+        state.next_sequence();
+        let mut policy = Policy::default();
+
+        let mut import = vec![149, DRI3_PIXMAP_FROM_BUFFER, 6, 0];
+        import.resize(24, 0);
+        let glx = [152, 1, 2, 0, 0, 0, 0, 0];
+        let both = [&import[..], &glx[..]].concat();
+        let filtered = filter_buffer(&mut state, &policy, &both);
+        assert_eq!(filtered.accepted, both);
+        assert_eq!(filtered.requests, 2);
+
+        policy.set_gpu_mode(GpuMode::Strict);
+        let filtered = filter_buffer(&mut state, &policy, &both);
+        assert_eq!(filtered.accepted, [STAND_IN, &glx[..]].concat());
+        assert_eq!(filtered.denials, vec![Capability::ImportBuffers]);
+        // DRI3 Open has the client given a file descriptor, not the
+        // server.
+        let open = [149, 1, 3, 0, 0x23, 1, 0, 0, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &open);
+        assert_eq!(filtered.accepted, &open[..]);
+
+        let mut policy = Policy::new(vec![Capability::ImportBuffers]);
+        policy.set_gpu_mode(GpuMode::Strict);
+        let filtered = filter_buffer(&mut state, &policy, &import);
+        assert_eq!(filtered.accepted, import);
+
+        // As does the gpu preset.
+        let mut policy = Policy::new(Preset::Gpu.capabilities().to_vec());
+        policy.set_gpu_mode(GpuMode::Strict);
+        let filtered = filter_buffer(&mut state, &policy, &both);
+        assert_eq!(filtered.accepted, both);
+        assert!(policy.allows(Capability::SharedMemory));
+    }
+
+    #[test]
+    fn test_decode_failures() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        // A SendEvent without the event, and an InternAtom whose name
+        // runs past the end.
+        let send_event = [0x19, 0, 3, 0, 1, 0, 0x40, 0, 0, 0, 0, 0];
+        let intern = [0x10, 0, 3, 0, 100, 0, 0, 0, b'f', b'o', b'o', 0];
+        let closed = metrics::DECODE_FAILURES_CLOSED.get();
+        let filtered = filter_buffer(&mut state, &policy, &send_event);
+        assert_eq!(filtered.parse_failures, 1);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::SendEvent]);
+        assert!(metrics::DECODE_FAILURES_CLOSED.get() > closed);
+        let filtered = filter_buffer(&mut state, &policy, &intern);
+        assert_eq!(filtered.parse_failures, 1);
+        assert_eq!(filtered.accepted, &intern[..]);
+        // A ConfigureWindow missing the values its mask promises.
+        let configure = [12, 0, 3, 0, 1, 0, 0x40, 0, 0x0f, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &configure);
+        assert_eq!(filtered.parse_failures, 1);
+        assert_eq!(filtered.denials, vec![Capability::RearrangeWindows]);
+
+        let policy = Policy::new(vec![Capability::SendEvent]);
+        let filtered = filter_buffer(&mut state, &policy, &send_event);
+        assert_eq!(filtered.accepted, &send_event[..]);
+    }
+
+    #[test]
+    fn test_xfixes() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.query_extension(1, XFIXES_NAME);
+        state.extension_reply(1, 138);
+        let policy = Policy::default();
+
+        let mut select = vec![138, XFIXES_SELECT_SELECTION_INPUT, 4, 0];
+        select.extend(&[1, 0, 0x40, 0, 0x45, 0, 0, 0, 7, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &select);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::WatchSelections]);
+        select[12] = 0;
+        let filtered = filter_buffer(&mut state, &policy, &select);
+        assert_eq!(filtered.accepted, select);
+
+        let hide = [138, XFIXES_HIDE_CURSOR, 2, 0, 1, 0, 0x40, 0];
+        let filtered = filter_buffer(&mut state, &policy, &hide);
+        assert_eq!(filtered.denials, vec![Capability::ChangeCursor]);
+        let mut own_name = vec![138, XFIXES_SET_CURSOR_NAME, 4, 0];
+        own_name.extend(&[2, 0, 0x40, 0, 4, 0, 0, 0]);
+        own_name.extend(b"left");
+        let filtered = filter_buffer(&mut state, &policy, &own_name);
+        assert_eq!(filtered.accepted, own_name);
+        let mut foreign_name = own_name.clone();
+        foreign_name[4..8].copy_from_slice(&[2, 0, 0x20, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &foreign_name);
+        assert_eq!(filtered.denials, vec![Capability::ChangeCursor]);
+    }
+
+    #[test]
+    fn test_paranoid() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let mut policy = Policy::default();
+        // A GetProperty with 8 bytes too many, and an InternAtom with
+        // just its padding.
+        let mut get_property = vec![0x14, 0, 8, 0, 1, 0, 0x40, 0];
+        get_property.resize(32, 0);
+        get_property[24..].copy_from_slice(b"smuggled");
+        let intern = [0x10, 0, 3, 0, 3, 0, 0, 0, b'f', b'o', b'o', 0];
+        let filtered = filter_buffer(&mut state, &policy, &get_property);
+        assert_eq!(filtered.accepted, get_property);
+
+        policy.set_paranoid(true);
+        let mut both = get_property.clone();
+        both.extend(&intern);
+        let filtered = filter_buffer(&mut state, &policy, &both);
+        assert_eq!(filtered.accepted, [STAND_IN, &intern[..]].concat());
+        let error = state.take_fake_reply(2).unwrap();
+        assert_eq!(&error[0..8], &[0, BAD_LENGTH, 2, 0, 32, 0, 0, 0]);
+    }
+
+    #[cfg(feature = "alloc-audit")]
+    #[test]
+    fn test_filter_allocations() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        policy.set_max_property_length(4096);
+        // Setting WM_NAME on our window, moving it, reading a property
+        // with the length cut down, filling a rectangle and asking for
+        // the focus.
+        let mut change = vec![0x12, 0, 8, 0, 1, 0, 0x40, 0, 0x27, 0, 0, 0];
+        change.extend(&[0x1F, 0, 0, 0, 8, 0, 0, 0, 8, 0, 0, 0]);
+        change.extend(b"8 bytes!");
+        let configure = [
+            0x0C, 0, 5, 0, 1, 0, 0x40, 0, 3, 0, 0, 0, 10, 0, 0, 0, 20, 0, 0, 0,
+        ];
+        let mut get = vec![0x14, 0, 6, 0, 1, 0, 0x40, 0, 0x17, 0, 0, 0];
+        get.extend(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let fill = [
+            0x46, 0, 5, 0, 1, 0, 0x40, 0, 2, 0, 0x40, 0, 0, 0, 0, 0, 10, 0, 10,
+            0,
+        ];
+        let stream = [&change[..], &configure, &get, &fill, STAND_IN].concat();
+        let mut clamped = stream.clone();
+        let at = change.len() + configure.len() + 20;
+        clamped[at..at + 4].copy_from_slice(&[0, 4, 0, 0]);
+
+        let mut sequence = 0;
+        for pass in 0..3 {
+            let before = allocations::count();
+            let filtered = filter_buffer(&mut state, &policy, &stream);
+            let after = allocations::count();
+            assert_eq!(filtered.accepted, clamped);
+            assert!(filtered.denials.is_empty());
+            // The first time around is what warms the connection up.
+            if pass > 0 {
+                assert_eq!(after, before);
+            }
+            state.arena.reset(filtered.accepted);
+            // And the server answers them all.
+            sequence += 5;
+            state.answered(sequence + 1);
+        }
+    }
+
+    #[test]
+    fn test_property_limits() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        policy.set_max_property_offset(1024);
+        policy.set_max_property_length(4096);
+        // GetProperty of 0x10000 units from 256 units in, i.e. at 1024.
+        let mut get_property = vec![0x14, 0, 6, 0, 1, 0, 0x40, 0];
+        get_property.extend(&[0x17, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        get_property.extend(&[0, 0, 1, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &get_property);
+        let mut clamped = get_property.clone();
+        clamped[20..24].copy_from_slice(&[0, 4, 0, 0]);
+        assert_eq!(filtered.accepted, clamped);
+
+        get_property[16] = 1;
+        let filtered = filter_buffer(&mut state, &policy, &get_property);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let error = state.take_fake_reply(2).unwrap();
+        assert_eq!(&error[0..8], &[0, BAD_VALUE, 2, 0, 1, 1, 0, 0]);
+        assert_eq!(error[10], 0x14);
+
+        // A ChangeProperty of 8 bytes saying it has 4, and one saying it
+        // has 12, which would take in the request after it.
+        let mut change = vec![0x12, 0, 8, 0, 1, 0, 0x40, 0, 0x17, 0, 0, 0];
+        change.extend(&[0x1F, 0, 0, 0, 8, 0, 0, 0, 4, 0, 0, 0]);
+        change.extend(b"8 bytes!");
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let error = state.take_fake_reply(3).unwrap();
+        assert_eq!(error[1], BAD_LENGTH);
+        change[20] = 12;
+        change.extend(&[0x2B, 0, 1, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0, 0x2B, 0, 1, 0]);
+        change[20] = 8;
+        let filtered = filter_buffer(&mut state, &policy, &change);
+        assert_eq!(filtered.accepted, change);
+    }
+
+    #[test]
+    fn test_change_property_formats() {
+        let policy = Policy::default();
+        for &(dump, format, data_length, bytes) in &[
+            (D_CHANGE_PROPERTY_8, 8, 5, 5),
+            (D_CHANGE_PROPERTY_16, 16, 3, 6),
+            (D_CHANGE_PROPERTY_32, 32, 2, 8),
+        ] {
+            let change = changeproperty(dump, Endianness::Little).unwrap().1;
+            assert_eq!(change.format, format);
+            assert_eq!(change.data_length, data_length);
+            assert_eq!(change.data.len(), bytes);
+
+            let mut state = ConnectionState::new();
+            state.set_byte_order(Endianness::Little);
+            state.set_setup_info(SetupInfo {
+                resource_id_base: 0x4000000,
+                resource_id_mask: 0x1fffff,
+                ..SetupInfo::default()
+            });
+            let filtered = filter_buffer(&mut state, &policy, dump);
+            assert_eq!(filtered.accepted, dump);
+            assert_eq!(filtered.requests, 2);
+            assert_eq!(filtered.parse_failures, 0);
+            assert_eq!(state.take_fake_reply(1), None);
+            assert!(analyze_file(
+                &format!("dumps/change_property_{}.dmp", format),
+                &policy,
+                false
+            )
+            .is_ok());
+        }
+
+        // No such format, so no telling how long the data is.
+        let mut odd = D_CHANGE_PROPERTY_16.to_vec();
+        odd[16] = 12;
+        assert!(changeproperty(&odd, Endianness::Little).is_err());
+    }
+
+    #[test]
+    fn test_partition_atoms() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let mut policy = Policy::default();
+        let own = [0x10, 0, 4, 0, 7, 0, 0, 0, b'_', b'A', b'P', b'P'];
+        let own = [&own[..], b"_ID\0"].concat();
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        assert_eq!(filtered.accepted, own);
+
+        policy.set_atom_prefix("_P_");
+        let filtered = filter_buffer(&mut state, &policy, &own);
+        let mut partitioned = vec![0x10, 0, 5, 0, 10, 0, 0, 0];
+        partitioned.extend(b"_P__APP_ID\0\0");
+        assert_eq!(filtered.accepted, partitioned);
+        state.atom_reply(2, 0x200);
+        assert_eq!(state.atom_name(0x200), Some("_APP_ID"));
+
+        // Conventions, and names without an underscore, are shared.
+        for name in &["_NET_WM_NAME", "CLIPBOARD"] {
+            let mut intern = vec![0x10, 0, 0, 0, name.len() as u8, 0, 0, 0];
+            intern.extend(name.as_bytes());
+            intern.resize((intern.len() + 3) & !3, 0);
+            intern[2] = intern.len() as u8 / 4;
+            let filtered = filter_buffer(&mut state, &policy, &intern);
+            assert_eq!(filtered.accepted, intern);
+        }
+
+        let get_name = [0x11, 0, 2, 0, 0, 2, 0, 0];
+        filter_buffer(&mut state, &policy, &get_name);
+        assert!(state.take_atom_name_query(5));
+    }
+
+    #[test]
+    fn test_denied_atoms() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let mut policy = Policy::default();
+        policy.add_denied_atom("XdndAware".to_string(), AtomDenial::Spoof);
+        policy.add_denied_atom("_NET_WM_PID".to_string(), AtomDenial::Error);
+        let intern = [0x10, 0, 5, 0, 9, 0, 0, 0];
+        let intern = [&intern[..], b"XdndAware\0\0\0"].concat();
+        let filtered = filter_buffer(&mut state, &policy, &intern);
+        let mut spoofed = vec![0x10, 0, 9, 0, 27, 0, 0, 0];
+        spoofed.extend(b"_RUSTYWIN_SPOOFED_XdndAware\0");
+        assert_eq!(filtered.accepted, spoofed);
+        // The client still knows it by its own name.
+        state.atom_reply(1, 0x200);
+        assert_eq!(state.atom_name(0x200), Some("XdndAware"));
+
+        let intern = [0x10, 0, 5, 0, 11, 0, 0, 0];
+        let intern = [&intern[..], b"_NET_WM_PID\0"].concat();
+        let filtered = filter_buffer(&mut state, &policy, &intern);
+        assert_eq!(filtered.accepted, STAND_IN);
+        let error = state.take_fake_reply(2).unwrap();
+        assert_eq!(&error[0..4], &[0, BAD_VALUE, 2, 0]);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        state.query_extension(1, "RECORD");
+        state.extension_reply(1, 146);
+        state.next_sequence();
+
+        let mut create = vec![146, 1, 7, 0, 1, 0, 0x40, 0, 0, 0, 0, 0];
+        create.extend(&[1, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &create);
+        assert_eq!(filtered.accepted, STAND_IN);
+        assert_eq!(filtered.denials, vec![Capability::RecordInput]);
+
+        let enable = [146, 5, 2, 0, 1, 0, 0x40, 0];
+        let filtered = filter_buffer(&mut state, &policy, &enable);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        assert_eq!(filtered.denials, vec![Capability::RecordInput]);
+        let reply = state.take_fake_reply(3).unwrap();
+        assert_eq!(&reply[0..4], &[1, RECORD_END_OF_DATA, 3, 0]);
+
+        let policy = Policy::new(vec![Capability::RecordInput]);
+        let filtered = filter_buffer(&mut state, &policy, &enable);
+        assert_eq!(filtered.accepted, &enable[..]);
+    }
+
+    #[test]
+    fn test_grab_server() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let grab = [0x24, 0, 1, 0, 0x25, 0, 1, 0];
+        let filtered = filter_buffer(&mut state, &Policy::default(), &grab);
+        assert_eq!(
+            filtered.denials,
+            vec![Capability::GrabServer, Capability::GrabServer]
+        );
+        assert_eq!(filtered.accepted, [STAND_IN, STAND_IN].concat());
+
+        let policy = Policy::new(vec![Capability::GrabServer]);
+        let filtered = filter_buffer(&mut state, &policy, &grab);
+        assert_eq!(filtered.accepted, &grab[..]);
+    }
+
+    #[test]
+    fn test_mappings() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let policy = Policy::default();
+        // Keycode 38 to one keysym, "a".
+        let keyboard = [0x64, 1, 3, 0, 38, 1, 0, 0, 0x61, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &keyboard);
+        assert_eq!(filtered.denials, vec![Capability::RemapInput]);
+        assert_eq!(filtered.accepted, STAND_IN);
+
+        let modifiers = [0x76, 1, 3, 0, 50, 0, 66, 37, 0, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &modifiers);
+        assert_eq!(filtered.denials, vec![Capability::RemapInput]);
+        assert_eq!(filtered.accepted, vec![0x2B, 0, 1, 0]);
+        let reply = state.take_fake_reply(2).unwrap();
+        assert_eq!(&reply[0..4], &[1, 2, 2, 0]);
+
+        let pointer = [0x74, 3, 2, 0, 3, 2, 1, 0];
+        let filtered = filter_buffer(&mut state, &policy, &pointer);
+        assert_eq!(filtered.denials, vec![Capability::RemapInput]);
+        let reply = state.take_fake_reply(3).unwrap();
+        assert_eq!(&reply[0..4], &[1, 1, 3, 0]);
+
+        let policy = Policy::new(vec![Capability::RemapInput]);
+        let filtered = filter_buffer(&mut state, &policy, &pointer);
+        assert_eq!(filtered.accepted, &pointer[..]);
+    }
+
+    #[test]
+    fn test_honeypot() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let mut policy = Policy::default();
+        policy.set_honeypots(vec![
+            ("_KEEPASS_DB".to_string(), Some(0x200)),
+            ("_PASS".to_string(), None),
+        ]);
+        let intern = b"\x10\x00\x04\x00\x05\x00\x00\x00_PASS\x00\x00\x00";
+        let filtered = filter_buffer(&mut state, &policy, intern);
+        assert_eq!(filtered.honeypots, vec!["_PASS"]);
+        // Nothing is denied, the client shouldn't notice.
+        assert_eq!(filtered.accepted, &intern[..]);
+        state.atom_reply(1, 0x300);
+
+        // GetAtomName of the decoy we interned, and GetProperty of the
+        // one the client interned.
+        let mut requests = vec![0x11, 0, 2, 0, 0, 2, 0, 0];
+        requests.extend(&[0x14, 0, 6, 0, 1, 0, 0, 0, 0, 3, 0, 0]);
+        requests.extend(&[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        let filtered = filter_buffer(&mut state, &policy, &requests);
+        assert_eq!(filtered.honeypots, vec!["_KEEPASS_DB", "_PASS"]);
+
+        let primary = [0x11, 0, 2, 0, 1, 0, 0, 0];
+        let filtered = filter_buffer(&mut state, &policy, &primary);
+        assert!(filtered.honeypots.is_empty());
+    }
+
+    #[test]
+    fn test_analyze_file() {
+        let policy = Policy::default();
+        assert!(analyze_file("dumps/blocked.dmp", &policy, false).is_ok());
+        assert!(analyze_file("dumps/blocked.dmp", &policy, true).is_ok());
+        let missing = "dumps/does-not-exist.dmp";
+        assert!(analyze_file(missing, &policy, false).is_err());
+    }
+
+    #[test]
+    fn test_request_msb() {
+        // InternAtom "WM_NAME", MSB first.
+        let intern = b"\x10\x01\x00\x04\x00\x07\x00\x00WM_NAME\x00";
+        let req_header = request(intern, Endianness::Big, false).unwrap().1;
+        assert_eq!(req_header.opcode, 16);
+        assert_eq!(req_header.length, 16);
+        let ia = intern_atom(intern, Endianness::Big).unwrap().1;
+        assert!(ia.only_if_exists);
+        assert_eq!(ia.name, "WM_NAME");
+        assert_eq!(interned_atoms(intern, Endianness::Big), vec!["WM_NAME"]);
     }
 }
@@ -0,0 +1,54 @@
+use std::mem;
+
+/// Where the requests of a batch are written out to, front to back, and
+/// let go of all at once when the batch has been passed on.
+///
+/// It keeps the room the largest batch so far took, so that once a
+/// connection has warmed up, filtering doesn't go to the allocator for
+/// the requests that are let through.
+pub struct Arena {
+    bytes: Vec<u8>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena { bytes: Vec::new() }
+    }
+
+    /// An empty buffer for a batch, with room for at least `size` bytes.
+    pub fn take(&mut self, size: usize) -> Vec<u8> {
+        let mut bytes = mem::take(&mut self.bytes);
+        bytes.clear();
+        bytes.reserve(size);
+        bytes
+    }
+
+    /// Have the buffer of a batch back, its contents done with.
+    pub fn reset(&mut self, bytes: Vec<u8>) {
+        if bytes.capacity() > self.bytes.capacity() {
+            self.bytes = bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arena() {
+        let mut arena = Arena::new();
+        let mut bytes = arena.take(16);
+        bytes.extend(&[1, 2, 3]);
+        let capacity = bytes.capacity();
+        assert!(capacity >= 16);
+        arena.reset(bytes);
+        let bytes = arena.take(8);
+        assert!(bytes.is_empty());
+        assert_eq!(bytes.capacity(), capacity);
+        // Whoever asks meanwhile gets a buffer of their own.
+        assert_eq!(arena.take(0).capacity(), 0);
+        arena.reset(bytes);
+        assert_eq!(arena.take(0).capacity(), capacity);
+    }
+}
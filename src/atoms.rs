@@ -0,0 +1,228 @@
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use xauth;
+use xauth::{pad, protocol_error};
+
+const INTERN_ATOM: u8 = 16;
+
+/// The atoms every server has from the start, the n-th being atom n + 1.
+const PREDEFINED_ATOMS: [&str; 68] = [
+    "PRIMARY",
+    "SECONDARY",
+    "ARC",
+    "ATOM",
+    "BITMAP",
+    "CARDINAL",
+    "COLORMAP",
+    "CURSOR",
+    "CUT_BUFFER0",
+    "CUT_BUFFER1",
+    "CUT_BUFFER2",
+    "CUT_BUFFER3",
+    "CUT_BUFFER4",
+    "CUT_BUFFER5",
+    "CUT_BUFFER6",
+    "CUT_BUFFER7",
+    "DRAWABLE",
+    "FONT",
+    "INTEGER",
+    "PIXMAP",
+    "POINT",
+    "RECTANGLE",
+    "RESOURCE_MANAGER",
+    "RGB_COLOR_MAP",
+    "RGB_BEST_MAP",
+    "RGB_BLUE_MAP",
+    "RGB_DEFAULT_MAP",
+    "RGB_GRAY_MAP",
+    "RGB_GREEN_MAP",
+    "RGB_RED_MAP",
+    "STRING",
+    "VISUALID",
+    "WINDOW",
+    "WM_COMMAND",
+    "WM_HINTS",
+    "WM_CLIENT_MACHINE",
+    "WM_ICON_NAME",
+    "WM_ICON_SIZE",
+    "WM_NAME",
+    "WM_NORMAL_HINTS",
+    "WM_SIZE_HINTS",
+    "WM_ZOOM_HINTS",
+    "MIN_SPACE",
+    "NORM_SPACE",
+    "MAX_SPACE",
+    "END_SPACE",
+    "SUPERSCRIPT_X",
+    "SUPERSCRIPT_Y",
+    "SUBSCRIPT_X",
+    "SUBSCRIPT_Y",
+    "UNDERLINE_POSITION",
+    "UNDERLINE_THICKNESS",
+    "STRIKEOUT_ASCENT",
+    "STRIKEOUT_DESCENT",
+    "ITALIC_ANGLE",
+    "X_HEIGHT",
+    "QUAD_WIDTH",
+    "WEIGHT",
+    "POINT_SIZE",
+    "RESOLUTION",
+    "COPYRIGHT",
+    "NOTICE",
+    "FONT_NAME",
+    "FAMILY_NAME",
+    "FULL_NAME",
+    "CAP_HEIGHT",
+    "WM_CLASS",
+    "WM_TRANSIENT_FOR",
+];
+
+/// Atoms of the ICCCM, EWMH and drag and drop conventions, and common
+/// selection targets. Their numbers depend on the server, so they're
+/// interned up front, to be known by name before a client does so.
+pub const COMMON_ATOMS: &[&str] = &[
+    "CLIPBOARD",
+    "CLIPBOARD_MANAGER",
+    "TARGETS",
+    "MULTIPLE",
+    "TIMESTAMP",
+    "INCR",
+    "ATOM_PAIR",
+    "SAVE_TARGETS",
+    "MANAGER",
+    "TEXT",
+    "UTF8_STRING",
+    "COMPOUND_TEXT",
+    "text/plain",
+    "text/plain;charset=utf-8",
+    "text/uri-list",
+    "text/html",
+    "image/png",
+    "WM_PROTOCOLS",
+    "WM_DELETE_WINDOW",
+    "WM_TAKE_FOCUS",
+    "WM_STATE",
+    "WM_CHANGE_STATE",
+    "WM_CLIENT_LEADER",
+    "WM_WINDOW_ROLE",
+    "WM_LOCALE_NAME",
+    "_NET_SUPPORTED",
+    "_NET_SUPPORTING_WM_CHECK",
+    "_NET_CLIENT_LIST",
+    "_NET_CLIENT_LIST_STACKING",
+    "_NET_ACTIVE_WINDOW",
+    "_NET_NUMBER_OF_DESKTOPS",
+    "_NET_CURRENT_DESKTOP",
+    "_NET_DESKTOP_NAMES",
+    "_NET_WORKAREA",
+    "_NET_CLOSE_WINDOW",
+    "_NET_MOVERESIZE_WINDOW",
+    "_NET_STARTUP_ID",
+    "_NET_WM_NAME",
+    "_NET_WM_VISIBLE_NAME",
+    "_NET_WM_ICON_NAME",
+    "_NET_WM_ICON",
+    "_NET_WM_PID",
+    "_NET_WM_DESKTOP",
+    "_NET_WM_STATE",
+    "_NET_WM_WINDOW_TYPE",
+    "_NET_WM_ALLOWED_ACTIONS",
+    "_NET_WM_STRUT",
+    "_NET_WM_STRUT_PARTIAL",
+    "_NET_WM_USER_TIME",
+    "_NET_WM_PING",
+    "_NET_WM_SYNC_REQUEST",
+    "_NET_WM_MOVERESIZE",
+    "_NET_WM_WINDOW_OPACITY",
+    "_NET_WM_BYPASS_COMPOSITOR",
+    "_NET_FRAME_EXTENTS",
+    "_MOTIF_WM_HINTS",
+    "_XEMBED",
+    "_XEMBED_INFO",
+    "XdndAware",
+    "XdndSelection",
+    "XdndTypeList",
+    "XdndActionCopy",
+    "XdndEnter",
+    "XdndPosition",
+    "XdndStatus",
+    "XdndLeave",
+    "XdndDrop",
+    "XdndFinished",
+];
+
+/// The name of predefined atom `atom`.
+pub fn predefined_name(atom: u32) -> Option<&'static str> {
+    let index = (atom as usize).checked_sub(1)?;
+    PREDEFINED_ATOMS.get(index).cloned()
+}
+
+/// Intern the atoms `names` on the server behind `server`, local display
+/// `display`, and return them in order.
+///
+/// Atoms live until the server resets, which only happens once its last
+/// client is gone, so the connection isn't kept.
+pub fn intern_atoms(
+    mut server: UnixStream,
+    display: usize,
+    names: &[String],
+) -> Result<Vec<u32>, io::Error> {
+    xauth::connect(&mut server, display)?;
+    intern(&mut server, names)
+}
+
+/// Intern the atoms `names` on the connection `server` and return them in
+/// order.
+pub fn intern(
+    server: &mut UnixStream,
+    names: &[String],
+) -> Result<Vec<u32>, io::Error> {
+    let mut requests = Vec::new();
+    for name in names {
+        let mut request = vec![INTERN_ATOM, 0, 0, 0, 0, 0, 0, 0];
+        LittleEndian::write_u16(&mut request[4..6], name.len() as u16);
+        request.extend(name.as_bytes());
+        pad(&mut request);
+        let length = request.len() as u16 / 4;
+        LittleEndian::write_u16(&mut request[2..4], length);
+        requests.extend(request);
+    }
+    server.write_all(&requests)?;
+
+    let mut atoms = Vec::new();
+    while atoms.len() < names.len() {
+        let mut message = [0; 32];
+        server.read_exact(&mut message)?;
+        match message[0] {
+            0 => {
+                return Err(protocol_error(format!(
+                    "error {} interning {}",
+                    message[1],
+                    names[atoms.len()]
+                )))
+            }
+            1 => atoms.push(LittleEndian::read_u32(&message[8..12])),
+            // Events, of which we selected none.
+            _ => (),
+        }
+    }
+    Ok(atoms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_predefined_name() {
+        assert_eq!(predefined_name(1), Some("PRIMARY"));
+        assert_eq!(predefined_name(39), Some("WM_NAME"));
+        assert_eq!(predefined_name(68), Some("WM_TRANSIENT_FOR"));
+        assert_eq!(predefined_name(0), None);
+        assert_eq!(predefined_name(69), None);
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha256::{to_hex, Sha256};
+
+/// Executables bigger than this are only hashed up to here.
+const MAX_FINGERPRINT_READ: u64 = 256 << 20;
+
+/// Append-only record of security relevant events, one line each:
+/// the time in seconds since the epoch, the event name and its fields
+/// as key=value pairs. Values that aren't plain words are quoted.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn new(file: File) -> AuditLog {
+        AuditLog {
+            file: Mutex::new(file),
+        }
+    }
+
+    pub fn record(&self, event: &str, fields: &[(&str, String)]) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or(0);
+        let mut line = format!("{} {}", time, event);
+        for &(key, ref value) in fields {
+            line.push(' ');
+            line.push_str(key);
+            line.push('=');
+            line.push_str(&quote(value));
+        }
+        line.push('\n');
+        // One write per line, so concurrent connections don't interleave.
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Couldn't write audit log: {}", e);
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Which binary a process is running.
+pub struct Fingerprint {
+    pub exe: Option<PathBuf>,
+    /// The hash of the binary, if it could be read.
+    pub sha256: Option<String>,
+    /// Set if the binary was too big to hash completely.
+    pub truncated: bool,
+}
+
+impl Fingerprint {
+    pub fn of_pid(pid: i32) -> Fingerprint {
+        let link = format!("/proc/{}/exe", pid);
+        let exe = ::std::fs::read_link(&link).ok();
+        // Hash through the link, the path may have been replaced since.
+        let hash = File::open(&link).and_then(hash_file);
+        match hash {
+            Ok((sha256, truncated)) => Fingerprint {
+                exe,
+                sha256: Some(sha256),
+                truncated,
+            },
+            Err(e) => {
+                warn!("Couldn't fingerprint executable of PID {}: {}", pid, e);
+                Fingerprint {
+                    exe,
+                    sha256: None,
+                    truncated: false,
+                }
+            }
+        }
+    }
+
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let exe = match self.exe {
+            Some(ref exe) => exe.to_string_lossy().into_owned(),
+            None => "unknown".to_string(),
+        };
+        let mut fields = vec![("exe", exe)];
+        match self.sha256 {
+            Some(ref sha256) => fields.push(("sha256", sha256.clone())),
+            None => fields.push(("sha256", "unavailable".to_string())),
+        }
+        if self.truncated {
+            fields.push(("truncated", MAX_FINGERPRINT_READ.to_string()));
+        }
+        fields
+    }
+}
+
+fn hash_file(file: File) -> Result<(String, bool), io::Error> {
+    let mut reader = file.take(MAX_FINGERPRINT_READ);
+    let mut sha = Sha256::new();
+    let mut buffer = [0; 1 << 16];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        total += n as u64;
+        sha.update(&buffer[..n]);
+    }
+    // Anything left past the limit?
+    let truncated = total == MAX_FINGERPRINT_READ
+        && reader.into_inner().read(&mut [0])? > 0;
+    Ok((to_hex(&sha.finish()), truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("/usr/bin/xterm"), "/usr/bin/xterm");
+        assert_eq!(quote("/opt/My App/app"), "\"/opt/My App/app\"");
+        assert_eq!(quote(""), "\"\"");
+    }
+
+    #[test]
+    fn test_fingerprint_self() {
+        let fingerprint = Fingerprint::of_pid(::std::process::id() as i32);
+        assert!(fingerprint.exe.is_some());
+        assert_eq!(fingerprint.sha256.map(|h| h.len()), Some(64));
+    }
+}
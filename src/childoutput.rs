@@ -0,0 +1,247 @@
+use std::env;
+use std::fs;
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dirs;
+use log::Level;
+
+/// Size at which a client's log file is rotated.
+const MAX_LOG_SIZE: u64 = 1 << 20;
+
+/// How many rotated log files are kept, as NAME.log.1 and on.
+const KEPT_LOGS: usize = 3;
+
+/// Read what `child`, started from `program` with its stdout and stderr
+/// piped, writes there and log it line by line under its name and PID.
+/// Nothing else reads the pipes, so this keeps the child from blocking on
+/// full ones. With `passthrough` the output goes to our own stdout and
+/// stderr as well, with `log_file` to the program's log file.
+pub fn forward(
+    child: &mut Child,
+    program: &str,
+    passthrough: bool,
+    log_file: bool,
+) {
+    let name = program_name(program);
+    let prefix = format!("{}[{}]", name, child.id());
+    let log_file = if log_file {
+        match LogFile::open(&name) {
+            Ok(mut file) => {
+                let started = format!(
+                    "--- {} started at {}\n",
+                    prefix,
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|time| time.as_secs())
+                        .unwrap_or(0)
+                );
+                if let Err(e) = file.write_line(started.as_bytes()) {
+                    warn!("Couldn't write to {:?}: {}", file.path, e);
+                }
+                info!("Logging the output of {} to {:?}", prefix, file.path);
+                Some(Arc::new(Mutex::new(file)))
+            }
+            Err(e) => {
+                warn!("Couldn't open a log file for {}: {}", prefix, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(stdout) = child.stdout.take() {
+        let prefix = prefix.clone();
+        let log_file = log_file.clone();
+        thread::spawn(move || {
+            let terminal = if passthrough {
+                Some(io::stdout())
+            } else {
+                None
+            };
+            if let Err(e) =
+                forward_lines(stdout, &prefix, Level::Info, terminal, log_file)
+            {
+                warn!("Stopped reading the stdout of {}: {}", prefix, e);
+            }
+        });
+    }
+    if let Some(stderr) = child.stderr.take() {
+        thread::spawn(move || {
+            let terminal = if passthrough {
+                Some(io::stderr())
+            } else {
+                None
+            };
+            if let Err(e) =
+                forward_lines(stderr, &prefix, Level::Warn, terminal, log_file)
+            {
+                warn!("Stopped reading the stderr of {}: {}", prefix, e);
+            }
+        });
+    }
+}
+
+/// The file name of `program`, which its log file is named after.
+fn program_name(program: &str) -> String {
+    Path::new(program)
+        .file_name()
+        .map_or_else(|| program.into(), |name| name.to_string_lossy())
+        .into_owned()
+}
+
+/// Log the lines of `input` at `level`, copying them to `terminal` and
+/// `log_file` as they are, until it's closed. A copy that fails is given
+/// up on, the input is still read.
+fn forward_lines<R: Read, W: Write>(
+    input: R,
+    prefix: &str,
+    level: Level,
+    mut terminal: Option<W>,
+    mut log_file: Option<Arc<Mutex<LogFile>>>,
+) -> Result<(), io::Error> {
+    let mut input = BufReader::new(input);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let copied = terminal.as_mut().map(|terminal| {
+            terminal.write_all(&line)?;
+            terminal.flush()
+        });
+        if let Some(Err(e)) = copied {
+            warn!("Stopped copying the output of {}: {}", prefix, e);
+            terminal = None;
+        }
+        let logged = log_file
+            .as_ref()
+            .map(|file| file.lock().unwrap().write_line(&line));
+        if let Some(Err(e)) = logged {
+            warn!("Stopped writing the log file of {}: {}", prefix, e);
+            log_file = None;
+        }
+        let text = String::from_utf8_lossy(&line);
+        log!(
+            level,
+            "{}: {}",
+            prefix,
+            text.trim_end_matches(&['\r', '\n'][..])
+        );
+    }
+}
+
+/// Where the log files of clients go: rustywin under $XDG_STATE_HOME, or
+/// ~/.local/state.
+pub fn log_dir() -> Result<PathBuf, io::Error> {
+    let state_home = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local/state")))
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no home directory")
+        })?;
+    Ok(state_home.join("rustywin"))
+}
+
+/// The output of a client, appended to NAME.log and rotated when it grows
+/// past `MAX_LOG_SIZE`.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    /// Open the log file of the program called `name`.
+    fn open(name: &str) -> Result<LogFile, io::Error> {
+        let dir = log_dir()?;
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        LogFile::open_at(dir.join(format!("{}.log", name)))
+    }
+
+    fn open_at(path: PathBuf) -> Result<LogFile, io::Error> {
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path, file, size })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<(), io::Error> {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Start a new file, moving the older ones one up.
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        for n in (1..KEPT_LOGS).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        *self = LogFile::open_at(self.path.clone())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_lines() {
+        let output = b"first\nunterminated";
+        let mut terminal = Vec::new();
+        forward_lines(
+            &output[..],
+            "app[1]",
+            Level::Info,
+            Some(&mut terminal),
+            None,
+        )
+        .unwrap();
+        assert_eq!(terminal, &output[..]);
+        assert_eq!(program_name("/usr/bin/app"), "app");
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let dir = env::temp_dir()
+            .join(format!("rustywin-test-logs-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let mut file = LogFile::open_at(path.clone()).unwrap();
+        let line = vec![b'x'; MAX_LOG_SIZE as usize / 2];
+        for _ in 0..4 * KEPT_LOGS {
+            file.write_line(&line).unwrap();
+        }
+        file.write_line(b"last\n").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"last\n");
+        let kept = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(kept, 1 + KEPT_LOGS);
+        assert_eq!(
+            fs::metadata(dir.join("app.log.1")).unwrap().len(),
+            MAX_LOG_SIZE
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
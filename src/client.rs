@@ -1,10 +1,17 @@
 use std::process::Stdio;
 use std::process::{Child, Command};
 
+use namespace;
+use security::UntrustedAuthority;
+use urlopen::Mediator;
+
 pub fn launch_client(
     client_exe: &str,
     args: &Option<Vec<String>>,
     display: &str,
+    private_socket: Option<&str>,
+    mediator: Option<&Mediator>,
+    authority: Option<&UntrustedAuthority>,
 ) -> Child {
 
     let args_v = if args.is_some() {
@@ -13,14 +20,31 @@ pub fn launch_client(
         Vec::new()
     };
 
+    let mut command = Command::new(client_exe);
+    let display = match private_socket {
+        Some(socket) => {
+            namespace::isolate_socket_dir(&mut command, socket)
+                .expect("Bad socket path.");
+            namespace::PRIVATE_DISPLAY
+        }
+        None => display,
+    };
+    if let Some(mediator) = mediator {
+        mediator.configure(&mut command);
+    }
+    if let Some(authority) = authority {
+        authority.configure(&mut command);
+    }
+
     info!(
         "Launching client process \"{}\" args {:?} with DISPLAY=\"{}\"",
         client_exe, args_v, display
     );
 
-    Command::new(client_exe)
+    command
         .args(args_v)
         .env("DISPLAY", display)
+        .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .expect("Failed to spawn subprocess.")
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::thread;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use atoms;
+use policy::Capability;
+use xauth;
+use xauth::pad;
+
+/// The property `Marking::Property` sets on a window: the names of the
+/// capabilities its client was denied, space separated.
+pub const DENIED_ATOM: &str = "_RUSTYWIN_DENIED";
+
+/// Number of fields of WM_HINTS.
+const WM_HINTS_LENGTH: usize = 9;
+
+const CHANGE_PROPERTY: u8 = 18;
+const STRING: u32 = 31;
+const WM_HINTS: u32 = 35;
+const URGENCY_HINT: u32 = 1 << 8;
+
+/// How a window is marked for the window manager to draw attention to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Marking {
+    /// The urgency hint in WM_HINTS, which most window managers and
+    /// panels highlight.
+    Urgency,
+    /// `DENIED_ATOM`, for the user's own setup to pick up.
+    Property,
+}
+
+impl Marking {
+    pub fn from_name(name: &str) -> Option<Marking> {
+        match name {
+            "urgency" => Some(Marking::Urgency),
+            "property" => Some(Marking::Property),
+            _ => None,
+        }
+    }
+}
+
+/// A connection of our own to the server, on which the windows of
+/// clients that were denied something dangerous are marked.
+pub struct Companion {
+    server: Mutex<UnixStream>,
+    marking: Marking,
+    atom: u32,
+    /// Capabilities each window was marked for so far.
+    marked: Mutex<HashMap<u32, Vec<Capability>>>,
+}
+
+/// Connect to local display `display` through `server` to mark windows
+/// with `marking`.
+pub fn connect(
+    mut server: UnixStream,
+    display: usize,
+    marking: Marking,
+) -> Result<Companion, io::Error> {
+    xauth::connect(&mut server, display)?;
+    let atom = atoms::intern(&mut server, &[DENIED_ATOM.to_string()])?[0];
+    // No events are selected, so errors for windows gone in the meantime
+    // are all that comes back.
+    let mut errors = server.try_clone()?;
+    thread::spawn(move || {
+        let mut message = [0; 32];
+        while errors.read_exact(&mut message).is_ok() {
+            if message[0] == 0 {
+                debug!("Marking a window failed with error {}", message[1]);
+            }
+        }
+    });
+    Ok(Companion {
+        server: Mutex::new(server),
+        marking,
+        atom,
+        marked: Mutex::new(HashMap::new()),
+    })
+}
+
+/// A ChangeProperty request replacing `property` of `window`.
+fn change_property(
+    window: u32,
+    property: u32,
+    prop_type: u32,
+    format: u8,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut request = vec![CHANGE_PROPERTY, 0, 0, 0];
+    request.extend(&[0; 20]);
+    LittleEndian::write_u32(&mut request[4..8], window);
+    LittleEndian::write_u32(&mut request[8..12], property);
+    LittleEndian::write_u32(&mut request[12..16], prop_type);
+    request[16] = format;
+    let units = data.len() / (format as usize / 8);
+    LittleEndian::write_u32(&mut request[20..24], units as u32);
+    request.extend(data);
+    pad(&mut request);
+    let length = request.len() as u16 / 4;
+    LittleEndian::write_u16(&mut request[2..4], length);
+    request
+}
+
+/// WM_HINTS with the urgency hint set on top of the client's own `hints`.
+fn urgent_hints(hints: Option<&[u32]>) -> Vec<u8> {
+    let mut fields = [0; WM_HINTS_LENGTH];
+    if let Some(hints) = hints {
+        let known = hints.len().min(WM_HINTS_LENGTH);
+        fields[..known].copy_from_slice(&hints[..known]);
+    }
+    fields[0] |= URGENCY_HINT;
+    let mut data = vec![0; 4 * WM_HINTS_LENGTH];
+    LittleEndian::write_u32_into(&fields, &mut data);
+    data
+}
+
+impl Companion {
+    /// Mark `window` for its client having been denied `capability`, once.
+    /// `hints` are the WM_HINTS the client set on it, if known.
+    pub fn mark(
+        &self,
+        window: u32,
+        capability: Capability,
+        hints: Option<&[u32]>,
+    ) {
+        let mut marked = self.marked.lock().unwrap();
+        let capabilities = marked.entry(window).or_default();
+        if capabilities.contains(&capability) {
+            return;
+        }
+        capabilities.push(capability);
+        let request = match self.marking {
+            Marking::Urgency => change_property(
+                window,
+                WM_HINTS,
+                WM_HINTS,
+                32,
+                &urgent_hints(hints),
+            ),
+            Marking::Property => {
+                let names: Vec<&str> =
+                    capabilities.iter().map(|cap| cap.name()).collect();
+                let value = names.join(" ");
+                change_property(window, self.atom, STRING, 8, value.as_bytes())
+            }
+        };
+        info!(
+            "Marking window {:#x} for being denied {}",
+            window, capability
+        );
+        if let Err(e) = self.server.lock().unwrap().write_all(&request) {
+            warn!("Couldn't mark window {:#x}: {}", window, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_property() {
+        let request = change_property(0x400001, 0x123, STRING, 8, b"abcde");
+        assert_eq!(request.len(), 32);
+        assert_eq!(&request[0..4], &[CHANGE_PROPERTY, 0, 8, 0]);
+        assert_eq!(&request[16..24], &[8, 0, 0, 0, 5, 0, 0, 0]);
+        assert_eq!(&request[24..29], b"abcde");
+
+        let request = change_property(1, WM_HINTS, WM_HINTS, 32, &[0; 36]);
+        assert_eq!(request[2], 15);
+        assert_eq!(&request[20..24], &[9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_urgent_hints() {
+        // Input and initial state hints, kept.
+        let hints = [3, 1, 1, 0, 0, 0, 0, 0, 0];
+        let data = urgent_hints(Some(&hints));
+        assert_eq!(&data[0..12], &[3, 1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        let data = urgent_hints(None);
+        assert_eq!(&data[0..4], &[0, 1, 0, 0]);
+        assert!(data[4..].iter().all(|&b| b == 0));
+    }
+}
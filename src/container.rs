@@ -0,0 +1,87 @@
+/// The display containers see the filtered socket as.
+const CONTAINER_DISPLAY: usize = 0;
+
+/// What runs the container, they take the same options for this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Podman,
+    Docker,
+}
+
+impl Engine {
+    pub fn from_name(name: &str) -> Option<Engine> {
+        match name {
+            "podman" => Some(Engine::Podman),
+            "docker" => Some(Engine::Docker),
+            _ => None,
+        }
+    }
+
+    /// The command that runs it.
+    pub fn command(self) -> &'static str {
+        match self {
+            Engine::Podman => "podman",
+            Engine::Docker => "docker",
+        }
+    }
+}
+
+/// The options that mount only the socket at `listen_path` into a
+/// container, as its only X display.
+pub fn mount_args(listen_path: &str) -> Vec<String> {
+    vec![
+        "--volume".to_string(),
+        format!("{}:/tmp/.X11-unix/X{}", listen_path, CONTAINER_DISPLAY),
+        "--env".to_string(),
+        format!("DISPLAY=:{}", CONTAINER_DISPLAY),
+    ]
+}
+
+/// The arguments to `Engine::command` for running `image` with `mount`, see
+/// `mount_args`.
+pub fn run_args(mount: &[String], image: &str, args: &[String]) -> Vec<String> {
+    let mut run = vec!["run".to_string(), "--rm".to_string()];
+    run.extend(mount.iter().cloned());
+    run.push(image.to_string());
+    run.extend(args.iter().cloned());
+    run
+}
+
+/// `args` as one line for a shell, quoted where needed.
+pub fn shell_line(args: &[String]) -> String {
+    let quoted: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || "-_./:=,@".contains(c)
+                });
+            if plain {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    quoted.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_args() {
+        let mount = mount_args("/tmp/.X11-unix/X3");
+        assert_eq!(
+            shell_line(&mount),
+            "--volume /tmp/.X11-unix/X3:/tmp/.X11-unix/X0 --env DISPLAY=:0"
+        );
+        let run = run_args(&mount, "fedora", &["xterm".to_string()]);
+        assert_eq!(&run[..2], &["run", "--rm"]);
+        assert_eq!(&run[6..], &["fedora", "xterm"]);
+        let args = ["it's".to_string(), "a b".to_string()];
+        assert_eq!(shell_line(&args), "'it'\\''s' 'a b'");
+        assert_eq!(Engine::from_name("docker"), Some(Engine::Docker));
+    }
+}
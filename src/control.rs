@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use lockdown::Lockdown;
+use policy::Capability;
+use session::Sessions;
+
+/// Create the control socket at `path`. Commands are only handled once
+/// `spawn_control_socket` is called.
+pub fn bind_control_socket(path: &str) -> Result<UnixListener, io::Error> {
+    let listener = UnixListener::bind(path)?;
+    // Only our own user gets to talk to us.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("Control socket listening at {}", path);
+    Ok(listener)
+}
+
+/// Start handling commands sent to the control socket.
+///
+/// Each connection carries a single command line, the reply is written
+/// back and the connection closed. Supported commands:
+///
+/// `dump-recent <conn-id>`: the flight recorder contents of a connection,
+/// in the same format as --dump.
+///
+/// `grant <conn-id> <capability> <seconds>`: let a connection do what
+/// needs the capability for a while, on top of the policy.
+///
+/// `lockdown`: engage lockdown, see `Lockdown`. Commands that change
+/// anything are refused from then on.
+///
+pub fn spawn_control_socket(
+    listener: UnixListener,
+    sessions: Arc<Sessions>,
+    lockdown: Arc<Lockdown>,
+) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => handle_command(stream, &sessions, &lockdown),
+                Err(e) => {
+                    error!("Error accepting on control socket: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn handle_command(
+    mut stream: UnixStream,
+    sessions: &Sessions,
+    lockdown: &Lockdown,
+) {
+    let mut line = String::new();
+    {
+        let mut reader = BufReader::new(&mut stream);
+        if let Err(e) = reader.read_line(&mut line) {
+            warn!("Couldn't read control command: {}", e);
+            return;
+        }
+    }
+    info!("Control command: {}", line.trim());
+
+    let reply = run_command(line.trim(), sessions, lockdown);
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => format!("error: {}\n", e).into_bytes(),
+    };
+    if let Err(e) = stream.write_all(&reply) {
+        warn!("Couldn't write control reply: {}", e);
+    }
+}
+
+fn run_command(
+    line: &str,
+    sessions: &Sessions,
+    lockdown: &Lockdown,
+) -> Result<Vec<u8>, String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("dump-recent") => {
+            let id = words
+                .next()
+                .and_then(|id| id.parse::<usize>().ok())
+                .ok_or_else(|| "usage: dump-recent <conn-id>".to_string())?;
+            let session = sessions
+                .get(id)
+                .ok_or_else(|| format!("no connection {}", id))?;
+            let contents = session.recorder.lock().unwrap().contents();
+            Ok(contents)
+        }
+        Some("grant") => {
+            lockdown.check("grant")?;
+            let usage = || "usage: grant <conn-id> <capability> <seconds>";
+            let id = words
+                .next()
+                .and_then(|id| id.parse::<usize>().ok())
+                .ok_or_else(usage)?;
+            let capability = words
+                .next()
+                .and_then(Capability::from_name)
+                .ok_or_else(usage)?;
+            let seconds = words
+                .next()
+                .and_then(|seconds| seconds.parse::<u64>().ok())
+                .ok_or_else(usage)?;
+            let session = sessions
+                .get(id)
+                .ok_or_else(|| format!("no connection {}", id))?;
+            session.grant(capability, Duration::from_secs(seconds));
+            Ok(b"ok\n".to_vec())
+        }
+        Some("lockdown") => {
+            lockdown.engage();
+            Ok(b"ok\n".to_vec())
+        }
+        Some(command) => Err(format!("unknown command {}", command)),
+        None => Err("empty command".to_string()),
+    }
+}
@@ -0,0 +1,178 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use dirs;
+
+/// First line of the entries we write, to recognize them.
+const MARKER: &str = "# Wrapped by rustywin";
+
+/// Where desktop entries are found when XDG_DATA_DIRS isn't set.
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+
+/// Make a copy of desktop entry `entry`, a path or the name of an
+/// installed one, whose programs are run through `rustywin` with
+/// `options`. It goes to the user's own applications directory under the
+/// same name, so launchers pick it over the original. Returns its path.
+pub fn install_wrapper(
+    entry: &str,
+    rustywin: &Path,
+    options: &[String],
+) -> Result<PathBuf, io::Error> {
+    let source = find_entry(entry)?;
+    let contents = fs::read_to_string(&source)?;
+    let mut wrapper = vec![rustywin.to_string_lossy().into_owned()];
+    wrapper.extend(options.iter().cloned());
+    let wrapped = wrap_entry(&contents, &wrapper)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let dir = dirs::data_dir()
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no data directory")
+        })?
+        .join("applications");
+    fs::create_dir_all(&dir)?;
+    let target = dir.join(source.file_name().unwrap());
+    // The user's own copy is theirs to remove, unless it's the one we
+    // were asked to wrap.
+    if target.exists() && fs::canonicalize(&target)? != source {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} exists already", target),
+        ));
+    }
+    fs::write(&target, wrapped)?;
+    Ok(target)
+}
+
+/// The desktop entry file `entry` names.
+fn find_entry(entry: &str) -> Result<PathBuf, io::Error> {
+    if entry.contains('/') {
+        return fs::canonicalize(entry);
+    }
+    let name = if entry.ends_with(".desktop") {
+        entry.to_string()
+    } else {
+        format!("{}.desktop", entry)
+    };
+    let data_dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| DEFAULT_DATA_DIRS.to_string());
+    dirs::data_dir()
+        .into_iter()
+        .chain(data_dirs.split(':').map(PathBuf::from))
+        .map(|dir| dir.join("applications").join(&name))
+        .find(|path| path.is_file())
+        .map_or_else(
+            || {
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no desktop entry {}", name),
+                ))
+            },
+            fs::canonicalize,
+        )
+}
+
+/// Quote `arg` for an Exec key, as the desktop entry specification has
+/// it: in double quotes if it has any reserved characters, with `"`, `` `
+/// ``, `$` and `\` escaped inside. Backslashes are then escaped once more,
+/// as in any string value. A `%` is doubled to not be a field code.
+fn quote_exec_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./,:=+@%".contains(c));
+    if plain {
+        return arg;
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        if "\"`$\\".contains(c) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted.replace('\\', "\\\\")
+}
+
+/// The desktop entry `contents` with the programs of its application and
+/// its actions run by `wrapper`. D-Bus activation is turned off, as it
+/// would start the application without looking at Exec.
+fn wrap_entry(contents: &str, wrapper: &[String]) -> Result<String, String> {
+    if contents.starts_with(MARKER) {
+        return Err("the entry is wrapped already".to_string());
+    }
+    let prefix: Vec<String> =
+        wrapper.iter().map(|arg| quote_exec_arg(arg)).collect();
+    let prefix = prefix.join(" ");
+    let mut wrapped = format!("{}\n", MARKER);
+    let mut execs = 0;
+    for line in contents.lines() {
+        let (key, value) = line.split_once('=').unwrap_or((line, ""));
+        let key = key.trim();
+        if key == "Exec" {
+            wrapped.push_str(&format!("Exec={} {}\n", prefix, value.trim()));
+            execs += 1;
+        } else if key == "DBusActivatable" {
+            wrapped.push_str("DBusActivatable=false\n");
+        } else {
+            wrapped.push_str(line);
+            wrapped.push('\n');
+        }
+    }
+    if execs == 0 {
+        return Err("the entry has no Exec key".to_string());
+    }
+    Ok(wrapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_exec_arg() {
+        assert_eq!(quote_exec_arg("--allow"), "--allow");
+        assert_eq!(quote_exec_arg("/usr/bin/rustywin"), "/usr/bin/rustywin");
+        assert_eq!(quote_exec_arg("two words"), "\"two words\"");
+        assert_eq!(quote_exec_arg("$HOME"), "\"\\\\$HOME\"");
+        assert_eq!(quote_exec_arg(""), "\"\"");
+        assert_eq!(quote_exec_arg("50%"), "50%%");
+    }
+
+    #[test]
+    fn test_wrap_entry() {
+        let entry = "[Desktop Entry]\n\
+                     Name=Editor\n\
+                     Exec=editor %F\n\
+                     DBusActivatable=true\n\
+                     \n\
+                     [Desktop Action new-window]\n\
+                     Exec = editor --new-window\n";
+        let wrapper = vec![
+            "/usr/bin/rustywin".to_string(),
+            "--allow".to_string(),
+            "clipboard-paste".to_string(),
+        ];
+        let wrapped = wrap_entry(entry, &wrapper).unwrap();
+        assert_eq!(
+            wrapped,
+            "# Wrapped by rustywin\n\
+             [Desktop Entry]\n\
+             Name=Editor\n\
+             Exec=/usr/bin/rustywin --allow clipboard-paste editor %F\n\
+             DBusActivatable=false\n\
+             \n\
+             [Desktop Action new-window]\n\
+             Exec=/usr/bin/rustywin --allow clipboard-paste editor \
+             --new-window\n"
+        );
+        assert!(wrap_entry(&wrapped, &wrapper).is_err());
+        assert!(wrap_entry("[Desktop Entry]\nName=Editor\n", &wrapper).is_err());
+    }
+}
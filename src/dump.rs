@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use libc;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::unistd::ftruncate;
+
+use metrics;
+
+/// Somewhere the connection threads can dump client traffic to.
+pub trait DumpSink: Send + Sync {
+    fn write(&self, buffer: &[u8]) -> Result<(), io::Error>;
+
+    /// Called at shutdown. Nothing is written after this.
+    fn finish(&self) -> Result<(), io::Error>;
+}
+
+/// Capture of client to server traffic, optionally capped in size.
+///
+/// Once the cap is reached capture stops for the rest of the session,
+/// so a long running session can't fill up the disk. Writes are never
+/// split, which keeps the dump parseable up to the point where it stops.
+pub struct Dump {
+    writer: Box<dyn Write + Send>,
+    written: u64,
+    limit: Option<u64>,
+    stopped: bool,
+}
+
+impl Dump {
+    pub fn new(writer: Box<dyn Write + Send>, limit: Option<u64>) -> Dump {
+        Dump {
+            writer,
+            written: 0,
+            limit,
+            stopped: false,
+        }
+    }
+
+    pub fn write(&mut self, buffer: &[u8]) -> Result<(), io::Error> {
+        if !self.stopped {
+            if let Some(limit) = self.limit {
+                if self.written + buffer.len() as u64 > limit {
+                    warn!("Dump limit of {} bytes reached, stopping.", limit);
+                    self.stopped = true;
+                }
+            }
+        }
+        if self.stopped {
+            metrics::DUMP_BYTES_DROPPED.add(buffer.len());
+            return Ok(());
+        }
+        self.writer.write_all(buffer)?;
+        self.written += buffer.len() as u64;
+        Ok(())
+    }
+}
+
+impl DumpSink for Mutex<Dump> {
+    fn write(&self, buffer: &[u8]) -> Result<(), io::Error> {
+        self.lock().unwrap().write(buffer)
+    }
+
+    fn finish(&self) -> Result<(), io::Error> {
+        let mut dump = self.lock().unwrap();
+        dump.stopped = true;
+        dump.writer.flush()
+    }
+}
+
+fn nix_to_io(err: ::nix::Error) -> io::Error {
+    match err {
+        ::nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        err => io::Error::other(err.to_string()),
+    }
+}
+
+/// Capture into a preallocated, memory mapped file of a fixed size. The
+/// file must be opened for reading and writing.
+///
+/// Writers reserve their part of the file with an atomic add and copy
+/// into the mapping without taking a lock, so a busy connection doesn't
+/// hold up the others. Like `Dump`, capture stops once a chunk doesn't
+/// fit anymore. The file is cut to the size actually used by `finish`;
+/// if the proxy dies before that the rest of it stays zero filled.
+pub struct MappedDump {
+    file: File,
+    map: *mut u8,
+    capacity: usize,
+    used: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+// The mapping is only written through reserved, disjoint ranges.
+unsafe impl Send for MappedDump {}
+unsafe impl Sync for MappedDump {}
+
+impl MappedDump {
+    pub fn new(file: File, capacity: u64) -> Result<MappedDump, io::Error> {
+        let fd = file.as_raw_fd();
+        // Allocate the blocks now rather than on first touch, so running
+        // out of disk space shows up here instead of as a SIGBUS later.
+        let err = unsafe { libc::posix_fallocate(fd, 0, capacity as i64) };
+        if err != 0 {
+            warn!(
+                "Couldn't preallocate dump: {}",
+                io::Error::from_raw_os_error(err)
+            );
+            ftruncate(fd, capacity as i64).map_err(nix_to_io)?;
+        }
+        let map = unsafe {
+            mmap(
+                ptr::null_mut(),
+                capacity as usize,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )
+        }
+        .map_err(nix_to_io)?;
+        Ok(MappedDump {
+            file,
+            map: map as *mut u8,
+            capacity: capacity as usize,
+            used: AtomicUsize::new(0),
+            stopped: AtomicBool::new(false),
+        })
+    }
+}
+
+impl DumpSink for MappedDump {
+    fn write(&self, buffer: &[u8]) -> Result<(), io::Error> {
+        if self.stopped.load(Ordering::Relaxed) {
+            metrics::DUMP_BYTES_DROPPED.add(buffer.len());
+            return Ok(());
+        }
+        let mut start = self.used.load(Ordering::Acquire);
+        loop {
+            let end = match start.checked_add(buffer.len()) {
+                Some(end) if end <= self.capacity => end,
+                _ => {
+                    if !self.stopped.swap(true, Ordering::Relaxed) {
+                        warn!(
+                            "Dump limit of {} bytes reached, stopping.",
+                            self.capacity
+                        );
+                    }
+                    metrics::DUMP_BYTES_DROPPED.add(buffer.len());
+                    return Ok(());
+                }
+            };
+            match self.used.compare_exchange_weak(
+                start,
+                end,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => start = current,
+            }
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(
+                buffer.as_ptr(),
+                self.map.add(start),
+                buffer.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), io::Error> {
+        // Makes every later reservation fail.
+        let used = self.used.swap(usize::MAX, Ordering::AcqRel);
+        if used == usize::MAX {
+            return Ok(());
+        }
+        self.stopped.store(true, Ordering::Relaxed);
+        ftruncate(self.file.as_raw_fd(), used as i64).map_err(nix_to_io)
+    }
+}
+
+impl Drop for MappedDump {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.map as *mut libc::c_void, self.capacity).ok();
+        }
+    }
+}
+
+/// Parse a size given in bytes, with an optional K, M or G suffix
+/// (powers of 1024).
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (digits, multiplier) = match size.chars().last() {
+        Some('k') | Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("2m"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("12x"), None);
+    }
+
+    #[test]
+    fn test_dump_limit() {
+        let mut dump = Dump::new(Box::new(io::sink()), Some(10));
+        dump.write(&[0; 8]).unwrap();
+        assert!(!dump.stopped);
+        dump.write(&[0; 4]).unwrap();
+        assert!(dump.stopped);
+        // Once stopped, capture doesn't resume even for small writes.
+        dump.write(&[0; 1]).unwrap();
+        assert_eq!(dump.written, 8);
+    }
+
+    #[test]
+    fn test_mapped_dump() {
+        let path = ::std::env::temp_dir()
+            .join(format!("rustywin-test-{}.dmp", ::std::process::id()));
+        let file = ::std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let dump = MappedDump::new(file, 10).unwrap();
+        dump.write(b"abcd").unwrap();
+        dump.write(b"efgh").unwrap();
+        dump.write(b"ijk").unwrap();
+        dump.write(b"l").unwrap();
+        dump.finish().unwrap();
+        dump.write(b"m").unwrap();
+        assert_eq!(::std::fs::read(&path).unwrap(), b"abcdefgh");
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
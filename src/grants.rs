@@ -0,0 +1,431 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use atoms;
+use metrics;
+use policy::Capability;
+use sha256::{hmac, to_hex};
+use xauth;
+use xauth::protocol_error;
+
+/// The property a window manager, or a helper of the user's, sets on a
+/// window to grant its client a capability for a while. Its value is
+/// `CAPABILITY:DURATION:TIME:SIGNATURE`, say `screenshot:30s:1760000000:..`,
+/// where TIME is the current Unix time and SIGNATURE the hex HMAC-SHA256,
+/// under the grant key, of `CAPABILITY:DURATION:TIME:WINDOW` with the
+/// window in decimal.
+pub const GRANT_ATOM: &str = "_RUSTYWIN_GRANT";
+
+/// How far the time in a grant may be off from ours, in seconds. Grants
+/// are only good for one use within that window.
+const MAX_SKEW: u64 = 60;
+
+/// Digits in the longest u64, which durations and times are parsed as.
+const MAX_NUMBER_DIGITS: usize = 20;
+
+/// Hex digits of an HMAC-SHA256.
+const SIGNATURE_LENGTH: usize = 64;
+
+const CHANGE_WINDOW_ATTRIBUTES: u8 = 2;
+const GET_PROPERTY: u8 = 20;
+const CREATE_NOTIFY: u8 = 16;
+const PROPERTY_NOTIFY: u8 = 28;
+const CW_EVENT_MASK: u32 = 1 << 11;
+const SUBSTRUCTURE_NOTIFY_MASK: u32 = 1 << 19;
+const PROPERTY_CHANGE_MASK: u32 = 1 << 22;
+
+struct Grant {
+    client_base: u32,
+    capability: Capability,
+    until: Instant,
+}
+
+/// Capabilities granted for a while to single clients, by way of
+/// `GRANT_ATOM`.
+pub struct Grants {
+    id_mask: u32,
+    active: Mutex<Vec<Grant>>,
+}
+
+impl Grants {
+    /// Whether the client whose resource ids start at `client_base` holds
+    /// a grant for `capability` that hasn't run out yet.
+    pub fn allows(&self, client_base: u32, capability: Capability) -> bool {
+        let now = Instant::now();
+        let mut active = self.active.lock().unwrap();
+        active.retain(|grant| grant.until > now);
+        active.iter().any(|grant| {
+            grant.client_base == client_base && grant.capability == capability
+        })
+    }
+
+    fn add(&self, window: u32, capability: Capability, duration: Duration) {
+        self.active.lock().unwrap().push(Grant {
+            client_base: window & !self.id_mask,
+            capability,
+            until: Instant::now() + duration,
+        });
+    }
+}
+
+/// A connection of our own to the server, on which top-level windows are
+/// watched for grants.
+pub struct Watcher {
+    server: UnixStream,
+    key: Vec<u8>,
+    atom: u32,
+    grants: Arc<Grants>,
+    /// Windows whose grant was asked for, in the order of the requests.
+    pending: VecDeque<u32>,
+    /// Signatures seen within the last `MAX_SKEW` seconds, with their time.
+    used: HashMap<String, u64>,
+}
+
+/// Connect to local display `display` through `server` to watch for
+/// grants signed with `key`. They're only picked up once `Watcher::spawn`
+/// is called.
+pub fn connect(
+    mut server: UnixStream,
+    display: usize,
+    key: Vec<u8>,
+) -> Result<(Arc<Grants>, Watcher), io::Error> {
+    let setup = xauth::connect(&mut server, display)?;
+    if setup.len() < 32 {
+        return Err(protocol_error("setup reply cut short".to_string()));
+    }
+    let id_mask = LittleEndian::read_u32(&setup[8..12]);
+    let vendor_length = LittleEndian::read_u16(&setup[16..18]) as usize;
+    let formats = setup[21] as usize;
+    let screen = 32 + ((vendor_length + 3) & !3) + 8 * formats;
+    if setup.len() < screen + 4 {
+        return Err(protocol_error("setup reply has no screen".to_string()));
+    }
+    let root = LittleEndian::read_u32(&setup[screen..screen + 4]);
+
+    let atom = atoms::intern(&mut server, &[GRANT_ATOM.to_string()])?[0];
+    // Top-level windows are announced, to select their property changes.
+    server.write_all(&change_event_mask(root, SUBSTRUCTURE_NOTIFY_MASK))?;
+
+    let grants = Arc::new(Grants {
+        id_mask,
+        active: Mutex::new(Vec::new()),
+    });
+    let watcher = Watcher {
+        server,
+        key,
+        atom,
+        grants: grants.clone(),
+        pending: VecDeque::new(),
+        used: HashMap::new(),
+    };
+    Ok((grants, watcher))
+}
+
+fn change_event_mask(window: u32, mask: u32) -> Vec<u8> {
+    let mut request = vec![CHANGE_WINDOW_ATTRIBUTES, 0, 4, 0];
+    request.extend(&[0; 12]);
+    LittleEndian::write_u32(&mut request[4..8], window);
+    LittleEndian::write_u32(&mut request[8..12], CW_EVENT_MASK);
+    LittleEndian::write_u32(&mut request[12..16], mask);
+    request
+}
+
+/// Longest value of the property read: the longest capability name, a
+/// duration with its unit, a time, the signature and the three colons
+/// between them.
+fn max_grant_length() -> usize {
+    let capability = Capability::names()
+        .iter()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or(0);
+    capability
+        + MAX_NUMBER_DIGITS
+        + 1
+        + MAX_NUMBER_DIGITS
+        + SIGNATURE_LENGTH
+        + 3
+}
+
+/// Taking the grant off the window, so it's only read once. The server
+/// only deletes it if it's read to the end.
+fn get_and_delete(window: u32, atom: u32) -> Vec<u8> {
+    let mut request = vec![GET_PROPERTY, 1, 6, 0];
+    request.extend(&[0; 20]);
+    LittleEndian::write_u32(&mut request[4..8], window);
+    LittleEndian::write_u32(&mut request[8..12], atom);
+    let units = max_grant_length().div_ceil(4);
+    LittleEndian::write_u32(&mut request[20..24], units as u32);
+    request
+}
+
+/// A duration like `30s`, `5m` or `1h`.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    if duration.len() < 2 {
+        return None;
+    }
+    let (number, unit) = duration.split_at(duration.len() - 1);
+    let number: u64 = number.parse().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+/// Check the grant `value` set on `window` at Unix time `now`, and
+/// return what it grants for how long, and its signature.
+fn parse_grant(
+    value: &[u8],
+    window: u32,
+    key: &[u8],
+    now: u64,
+) -> Result<(Capability, Duration, String), String> {
+    let value = String::from_utf8_lossy(value);
+    let fields: Vec<&str> = value.split(':').collect();
+    if fields.len() != 4 {
+        return Err(format!("malformed grant {:?}", value));
+    }
+    let capability = Capability::from_name(fields[0])
+        .ok_or_else(|| format!("unknown capability {}", fields[0]))?;
+    let duration = parse_duration(fields[1])
+        .ok_or_else(|| format!("bad duration {}", fields[1]))?;
+    let time: u64 = fields[2]
+        .parse()
+        .map_err(|_| format!("bad time {}", fields[2]))?;
+    if time.max(now) - time.min(now) > MAX_SKEW {
+        return Err(format!("grant made at {}, it's now {}", time, now));
+    }
+    let message =
+        format!("{}:{}:{}:{}", fields[0], fields[1], fields[2], window);
+    let signature = to_hex(&hmac(key, message.as_bytes()));
+    if !same_bytes(signature.as_bytes(), fields[3].as_bytes()) {
+        return Err("bad signature".to_string());
+    }
+    Ok((capability, duration, fields[3].to_string()))
+}
+
+/// Whether `a` and `b` are equal, in the same time wherever they differ,
+/// so that how long a check takes doesn't give away the signature.
+fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+impl Watcher {
+    pub fn spawn(mut self) {
+        thread::spawn(move || {
+            if let Err(e) = self.run() {
+                error!("Stopped watching for grants: {}", e);
+            }
+        });
+    }
+
+    fn run(&mut self) -> Result<(), io::Error> {
+        loop {
+            let mut message = vec![0; 32];
+            self.server.read_exact(&mut message)?;
+            match message[0] & 0x7f {
+                // Windows may well be gone by the time we get to them.
+                0 => {
+                    if message[10] == GET_PROPERTY {
+                        self.pending.pop_front();
+                    }
+                }
+                1 => {
+                    let length = LittleEndian::read_u32(&message[4..8]);
+                    let mut rest = vec![0; 4 * length as usize];
+                    self.server.read_exact(&mut rest)?;
+                    message.extend(rest);
+                    if let Some(window) = self.pending.pop_front() {
+                        self.received(window, &message);
+                    }
+                }
+                CREATE_NOTIFY => {
+                    let window = LittleEndian::read_u32(&message[8..12]);
+                    let request =
+                        change_event_mask(window, PROPERTY_CHANGE_MASK);
+                    self.server.write_all(&request)?;
+                }
+                PROPERTY_NOTIFY => {
+                    let window = LittleEndian::read_u32(&message[4..8]);
+                    let atom = LittleEndian::read_u32(&message[8..12]);
+                    // Zero is NewValue, as opposed to Deleted.
+                    if atom == self.atom && message[16] == 0 {
+                        self.server.write_all(&get_and_delete(window, atom))?;
+                        self.pending.push_back(window);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Handle the GetProperty `reply` for the grant on `window`.
+    fn received(&mut self, window: u32, reply: &[u8]) {
+        let length = LittleEndian::read_u32(&reply[16..20]) as usize;
+        if reply[1] != 8 || reply.len() < 32 + length {
+            return;
+        }
+        // Longer than any grant, and left on the window.
+        if LittleEndian::read_u32(&reply[12..16]) != 0 {
+            warn!("Refusing grant on window {:#x}: too long", window);
+            metrics::HINT_GRANTS_REFUSED.add(1);
+            return;
+        }
+        let value = &reply[32..32 + length];
+        let now = unix_time();
+        self.used.retain(|_, &mut time| time + 2 * MAX_SKEW > now);
+        match parse_grant(value, window, &self.key, now) {
+            Ok((_, _, ref signature)) if self.used.contains_key(signature) => {
+                warn!("Refusing grant on window {:#x}: used before", window);
+                metrics::HINT_GRANTS_REFUSED.add(1);
+            }
+            Ok((capability, duration, signature)) => {
+                info!(
+                    "Granting {} to the client of window {:#x} for {:?}",
+                    capability, window, duration
+                );
+                metrics::HINT_GRANTS.add(1);
+                self.used.insert(signature, now);
+                self.grants.add(window, capability, duration);
+            }
+            Err(e) => {
+                warn!("Refusing grant on window {:#x}: {}", window, e);
+                metrics::HINT_GRANTS_REFUSED.add(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(grant: &str, window: u32, key: &[u8]) -> String {
+        let message = format!("{}:{}", grant, window);
+        format!("{}:{}", grant, to_hex(&hmac(key, message.as_bytes())))
+    }
+
+    #[test]
+    fn test_parse_grant() {
+        let key = b"secret";
+        let now = 1_760_000_000;
+        let grant = sign("screenshot:30s:1760000010", 0x400001, key);
+        let (capability, duration, _) =
+            parse_grant(grant.as_bytes(), 0x400001, key, now).unwrap();
+        assert_eq!(capability, Capability::Screenshot);
+        assert_eq!(duration, Duration::from_secs(30));
+        // Signed for another window, or with another key.
+        assert!(parse_grant(grant.as_bytes(), 0x400002, key, now).is_err());
+        assert!(parse_grant(grant.as_bytes(), 0x400001, b"k", now).is_err());
+        // Too old.
+        assert!(
+            parse_grant(grant.as_bytes(), 0x400001, key, now + 100).is_err()
+        );
+        let grant = sign("everything:30s:1760000000", 0x400001, key);
+        assert!(parse_grant(grant.as_bytes(), 0x400001, key, now).is_err());
+        assert!(parse_grant(b"screenshot:30s", 0x400001, key, now).is_err());
+
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("30"), None);
+    }
+
+    /// The reply to `get_and_delete` when the property holds `value`, of
+    /// which the server hands out at most `max` bytes.
+    fn get_property_reply(value: &[u8], max: usize) -> Vec<u8> {
+        let sent = value.len().min(max);
+        let mut reply = vec![1, 8, 1, 0];
+        reply.resize(32, 0);
+        LittleEndian::write_u32(&mut reply[4..8], sent.div_ceil(4) as u32);
+        LittleEndian::write_u32(&mut reply[8..12], 31);
+        let after = (value.len() - sent) as u32;
+        LittleEndian::write_u32(&mut reply[12..16], after);
+        LittleEndian::write_u32(&mut reply[16..20], sent as u32);
+        reply.extend(&value[..sent]);
+        reply.resize(32 + 4 * sent.div_ceil(4), 0);
+        reply
+    }
+
+    #[test]
+    fn test_received() {
+        let (server, _theirs) = UnixStream::pair().unwrap();
+        let grants = Arc::new(Grants {
+            id_mask: 0x1fffff,
+            active: Mutex::new(Vec::new()),
+        });
+        let mut watcher = Watcher {
+            server,
+            key: b"secret".to_vec(),
+            atom: 0x150,
+            grants: grants.clone(),
+            pending: VecDeque::new(),
+            used: HashMap::new(),
+        };
+        let request = get_and_delete(0x400001, 0x150);
+        let max = 4 * LittleEndian::read_u32(&request[20..24]) as usize;
+
+        // The longest capability and a duration and time of 20 digits.
+        let names = Capability::names();
+        let longest = names.iter().max_by_key(|name| name.len()).unwrap();
+        let time = unix_time();
+        let duration = format!("{:020}s", 1);
+        let grant = format!("{}:{}:{:020}", longest, duration, time);
+        let grant = sign(&grant, 0x400001, &watcher.key);
+        assert_eq!(grant.len(), max_grant_length());
+        assert!(grant.len() <= max);
+        watcher.received(0x400001, &get_property_reply(grant.as_bytes(), max));
+        let capability = Capability::from_name(longest).unwrap();
+        assert!(grants.allows(0x400000, capability));
+
+        let grant =
+            sign(&format!("screenshot:30s:{}", time), 0x400001, b"secret");
+        watcher.received(0x400001, &get_property_reply(grant.as_bytes(), max));
+        assert!(grants.allows(0x400000, Capability::Screenshot));
+        // Only once.
+        grants.active.lock().unwrap().clear();
+        watcher.received(0x400001, &get_property_reply(grant.as_bytes(), max));
+        assert!(!grants.allows(0x400000, Capability::Screenshot));
+
+        // Cut short, whatever it says.
+        let padded = format!("{}{}", grant, " ".repeat(max));
+        let reply = get_property_reply(padded.as_bytes(), max);
+        watcher.used.clear();
+        watcher.received(0x400001, &reply);
+        assert!(!grants.allows(0x400000, Capability::Screenshot));
+        assert!(same_bytes(b"abc", b"abc"));
+        assert!(!same_bytes(b"abc", b"abd") && !same_bytes(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn test_grants() {
+        let grants = Grants {
+            id_mask: 0x1fffff,
+            active: Mutex::new(Vec::new()),
+        };
+        grants.add(0x400005, Capability::Screenshot, Duration::from_secs(30));
+        grants.add(0x400005, Capability::FakeInput, Duration::from_secs(0));
+        assert!(grants.allows(0x400000, Capability::Screenshot));
+        assert!(!grants.allows(0x600000, Capability::Screenshot));
+        assert!(!grants.allows(0x400000, Capability::FakeInput));
+        assert_eq!(grants.active.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,61 @@
+use std::ffi::CString;
+use std::io;
+
+use libc;
+
+/// Make sure neither the proxy nor anything it might execute can gain
+/// privileges, through setuid binaries or file capabilities.
+#[cfg(target_os = "linux")]
+pub fn set_no_new_privs() -> Result<(), io::Error> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Drop any ambient capabilities we were started with. Kernels older
+/// than 4.3 don't have them, which is as good as having none.
+#[cfg(target_os = "linux")]
+pub fn clear_ambient_capabilities() -> Result<(), io::Error> {
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINVAL) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_no_new_privs() -> Result<(), io::Error> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn clear_ambient_capabilities() -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Confine the filesystem view of the proxy to `dir`. Needs
+/// CAP_SYS_CHROOT. Anything the proxy needs from outside, like the dump
+/// file, has to be opened before this.
+pub fn chroot(dir: &str) -> Result<(), io::Error> {
+    let dir = CString::new(dir)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let root = CString::new("/").unwrap();
+    unsafe {
+        if libc::chroot(dir.as_ptr()) != 0 || libc::chdir(root.as_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
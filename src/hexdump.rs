@@ -0,0 +1,99 @@
+use std::fmt::Write;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Formats one request as an annotated hexdump.
+///
+/// The header fields are split out and labelled, the rest of the request
+/// follows as hex and ASCII, and `decoded` goes underneath, e.g.
+///
+/// ```text
+/// 00000000  InternAtom, 32 bytes
+///           [opcode 10] [data 00] [length 08 00 = 32]
+/// 00000004  15 00 40 07 5f 47 54 4b  5f 45 44 47 45 5f 43 4f  ..@._GTK_EDGE_CO
+/// 00000014  4e 53 54 52 41 49 4e 54  53 2f 6d 6f              NSTRAINTS/mo
+///           InternAtom { only_if_exists: false, ... }
+/// ```
+///
+/// `offset` is the position of the request in the stream and `length` its
+/// decoded length, which for BIG-REQUESTS comes from the extended field.
+pub fn format_request(
+    offset: usize,
+    name: &str,
+    request: &[u8],
+    length: usize,
+    decoded: &str,
+) -> String {
+    let mut out = String::new();
+    writeln!(out, "{:08x}  {}, {} bytes", offset, name, length).unwrap();
+
+    let big_request = request.len() >= 8 && request[2] == 0 && request[3] == 0;
+    let header_len = if big_request { 8 } else { 4 };
+    if request.len() < header_len {
+        format_lines(&mut out, offset, request);
+    } else {
+        write!(
+            out,
+            "          [opcode {:02x}] [data {:02x}] [length {:02x} {:02x}",
+            request[0], request[1], request[2], request[3]
+        )
+        .unwrap();
+        if big_request {
+            write!(
+                out,
+                "] [big length {:02x} {:02x} {:02x} {:02x}",
+                request[4], request[5], request[6], request[7]
+            )
+            .unwrap();
+        }
+        writeln!(out, " = {}]", length).unwrap();
+        format_lines(&mut out, offset + header_len, &request[header_len..]);
+    }
+
+    write!(out, "          {}", decoded).unwrap();
+    out
+}
+
+fn format_lines(out: &mut String, offset: usize, data: &[u8]) {
+    for (i, line) in data.chunks(BYTES_PER_LINE).enumerate() {
+        write!(out, "{:08x} ", offset + i * BYTES_PER_LINE).unwrap();
+        for j in 0..BYTES_PER_LINE {
+            if j == BYTES_PER_LINE / 2 {
+                out.push(' ');
+            }
+            match line.get(j) {
+                Some(byte) => write!(out, " {:02x}", byte).unwrap(),
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  ");
+        for &byte in line {
+            out.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_request() {
+        let request = b"\x10\x00\x04\x00\x05\x00\x00\x00ABCDE\x00\x00\x00";
+        let formatted = format_request(0x40, "InternAtom", request, 16, "X");
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert_eq!(lines[0], "00000040  InternAtom, 16 bytes");
+        assert_eq!(
+            lines[1],
+            "          [opcode 10] [data 00] [length 04 00 = 16]"
+        );
+        assert!(lines[2].starts_with("00000044  05 00 00 00 41 42 43 44  45"));
+        assert!(lines[2].ends_with("  ....ABCDE..."));
+        assert_eq!(lines[3], "          X");
+    }
+}
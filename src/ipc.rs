@@ -9,6 +9,9 @@ use nix::fcntl::OFlag;
 use nix::sys::socket::{recv, send, MsgFlags};
 use nix::Error;
 
+use lockdown::Lockdown;
+use trusted::TrustedPids;
+
 pub fn send_display(fd: RawFd, display: &str) {
     let mut flags = MsgFlags::empty();
     flags.insert(MsgFlags::MSG_DONTWAIT);
@@ -20,25 +23,48 @@ pub fn send_display(fd: RawFd, display: &str) {
     info!("Display string sent: {:?}", display);
 }
 
-fn process_pid_message(cmd: u8, pid: i32, pids: &mut Vec<i32>) {
+/// Tell the parent about a connection that closed, as a single message
+/// in the format of the audit log.
+pub fn send_summary(fd: RawFd, summary: &str) {
+    let mut flags = MsgFlags::empty();
+    flags.insert(MsgFlags::MSG_DONTWAIT);
+
+    if let Err(e) = send(fd, summary.as_bytes(), flags) {
+        warn!("Couldn't send session summary: {}", e);
+    }
+}
+
+fn process_pid_message(
+    cmd: u8,
+    pid: i32,
+    pids: &mut TrustedPids,
+    lockdown: &Lockdown,
+) {
     if cmd == 0 {
-        if !pids.contains(&pid) {
-            pids.push(pid);
-            info!("Added PID {}, PIDS={:?}", pid, pids);
-        } else {
-            info!("Already contains PID {}, PIDS={:?}", pid, pids);
+        if lockdown.check(&format!("trusting PID {}", pid)).is_err() {
+            return;
+        }
+        match pids.add(pid) {
+            Ok(true) => info!("Added PID {}, PIDS={:?}", pid, pids),
+            Ok(false) => {
+                info!("Already contains PID {}, PIDS={:?}", pid, pids)
+            }
+            Err(e) => error!("Couldn't trust PID {}: {}", pid, e),
         }
     } else if cmd == 1 {
-        if !pids.contains(&pid) {
+        if !pids.remove(pid) {
             error!("Trying to harden a PID that wasn't started: {}", pid);
             return;
         }
-        pids.retain(|&x| x != pid);
         info!("Removed PID {}, PIDS={:?}", pid, pids);
     }
 }
 
-pub fn try_receive_pids(fd: Option<RawFd>, pids: &mut Vec<i32>) {
+pub fn try_receive_pids(
+    fd: Option<RawFd>,
+    pids: &mut TrustedPids,
+    lockdown: &Lockdown,
+) {
     if fd.is_none() {
         return;
     }
@@ -61,7 +87,7 @@ pub fn try_receive_pids(fd: Option<RawFd>, pids: &mut Vec<i32>) {
             }
             let cmd = buffer[0];
             let pid = NativeEndian::read_i32(&buffer[1..5]);
-            process_pid_message(cmd, pid, pids);
+            process_pid_message(cmd, pid, pids, lockdown);
         }
         Err(e) => {
             if let Error::Sys(err) = e {
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use libc;
+
+// From linux/landlock.h. The syscall numbers are the same on every
+// architecture.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_EXECUTE: u64 = 1 << 0;
+pub const ACCESS_WRITE_FILE: u64 = 1 << 1;
+pub const ACCESS_READ_FILE: u64 = 1 << 2;
+pub const ACCESS_READ_DIR: u64 = 1 << 3;
+pub const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+pub const ACCESS_MAKE_REG: u64 = 1 << 8;
+pub const ACCESS_MAKE_SOCK: u64 = 1 << 9;
+
+/// Everything the first version of the ABI knows about. Later versions
+/// add more rights, which we then simply don't restrict.
+const ACCESS_ALL: u64 = (1 << 13) - 1;
+
+/// The rights that make sense on something that isn't a directory.
+const ACCESS_FILE: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Outcome of `Ruleset::restrict_self`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status {
+    /// In force, with this ABI version.
+    Enforced(i64),
+    /// The kernel is too old or has Landlock disabled.
+    Unsupported,
+}
+
+/// The paths the process keeps access to once it restricts itself.
+pub struct Ruleset {
+    rules: Vec<(File, u64)>,
+}
+
+impl Ruleset {
+    pub fn new() -> Ruleset {
+        Ruleset { rules: Vec::new() }
+    }
+
+    /// Allow `access` to `path` and, for a directory, everything beneath
+    /// it. Paths that don't exist are skipped.
+    pub fn allow<P: AsRef<Path>>(&mut self, path: P, access: u64) {
+        let path = path.as_ref();
+        match File::open(path) {
+            Ok(file) => {
+                let is_dir =
+                    file.metadata().map(|m| m.is_dir()).unwrap_or(false);
+                let access = if is_dir { access } else { access & ACCESS_FILE };
+                self.rules.push((file, access));
+            }
+            Err(e) => {
+                debug!("Not adding {:?} to the Landlock rules: {}", path, e)
+            }
+        }
+    }
+
+    /// Take away access to every path not allowed. Needs no_new_privs to
+    /// be set.
+    pub fn restrict_self(&self) -> Result<Status, io::Error> {
+        let abi = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                ::std::ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+                    Ok(Status::Unsupported)
+                }
+                _ => Err(e),
+            };
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_ALL,
+        };
+        let ruleset = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                ::std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ruleset = ruleset as RawFd;
+        let result = self.add_rules(ruleset).and_then(|()| {
+            if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) }
+                != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+        unsafe { libc::close(ruleset) };
+        result.map(|()| Status::Enforced(abi))
+    }
+
+    fn add_rules(&self, ruleset: RawFd) -> Result<(), io::Error> {
+        for &(ref file, access) in &self.rules {
+            let attr = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: file.as_raw_fd(),
+            };
+            let result = unsafe {
+                libc::syscall(
+                    SYS_LANDLOCK_ADD_RULE,
+                    ruleset,
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &attr as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
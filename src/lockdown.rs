@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// One-way switch for high-assurance sessions.
+///
+/// Once engaged, nothing that could widen what clients are allowed to do
+/// can be changed for the rest of the process lifetime: control socket
+/// commands that modify state are refused and the parent can no longer
+/// add PIDs to the trusted list. Removing trust stays possible. There is
+/// deliberately no way to disengage it.
+pub struct Lockdown {
+    engaged: AtomicBool,
+}
+
+impl Lockdown {
+    pub fn new(engaged: bool) -> Lockdown {
+        if engaged {
+            info!("Lockdown engaged at startup.");
+        }
+        Lockdown {
+            engaged: AtomicBool::new(engaged),
+        }
+    }
+
+    pub fn engage(&self) {
+        if !self.engaged.swap(true, Ordering::SeqCst) {
+            warn!("Lockdown engaged, configuration is now read-only.");
+        }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::SeqCst)
+    }
+
+    /// Fail with an explanation if `what` has to be refused.
+    pub fn check(&self, what: &str) -> Result<(), String> {
+        if self.is_engaged() {
+            warn!("Refusing {} in lockdown.", what);
+            Err(format!("{} is not allowed in lockdown", what))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockdown_is_one_way() {
+        let lockdown = Lockdown::new(false);
+        assert!(lockdown.check("reload").is_ok());
+        lockdown.engage();
+        lockdown.engage();
+        assert!(lockdown.is_engaged());
+        assert!(lockdown.check("reload").is_err());
+    }
+}
@@ -18,22 +18,70 @@ extern crate itertools;
 extern crate libc;
 extern crate nix;
 
+mod allocations;
 mod analyze;
+mod arena;
+mod atoms;
+mod audit;
+mod childoutput;
 mod client;
+mod companion;
+mod container;
+mod control;
+mod desktop;
 mod display;
+mod dump;
+mod grants;
+mod hardening;
+mod hexdump;
 mod ipc;
+mod landlock;
+mod lockdown;
+mod metrics;
+mod namespace;
+mod policy;
+mod reaper;
+mod reassembly;
+mod recorder;
+mod registry;
+mod replay;
+mod sched;
+mod security;
+mod server;
+mod session;
+mod sha256;
 mod socket;
 mod socketloop;
+mod state;
+mod transport;
+mod trigger;
+mod trusted;
+mod turns;
+mod urlopen;
+mod watchdog;
+mod xauth;
+mod xim;
 
-use clap::{App, AppSettings, Arg};
+use audit::AuditLog;
+use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use dump::{Dump, DumpSink, MappedDump};
 use env_logger::{Builder, Env};
-use socketloop::ChildInfo;
+use lockdown::Lockdown;
+use policy::{Capability, GpuMode, Policy, Preset, UnknownPeer};
+use replay::ServerCapture;
+use session::Sessions;
+use socketloop::{ChildInfo, Proxy};
 use std::env;
+use std::ffi::OsString;
 use std::fs::OpenOptions;
+use std::io;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread;
+use trigger::Triggers;
+use watchdog::{BypassAction, Watchdog};
 
-pub type DumpFile = Arc<Mutex<std::io::Write + Send>>;
+pub type DumpFile = Arc<dyn DumpSink>;
 
 /// Set up `env_logger` to log from Info and up.
 fn setup_logging() {
@@ -66,74 +114,850 @@ fn get_exe_name() -> Option<String> {
     }
 }
 
-fn main() {
-    setup_logging();
+/// Subcommands, which the first argument is taken for if it names one.
+/// Anything else is short for `run`.
+const SUBCOMMANDS: &[&str] = &[
+    "run",
+    "container",
+    "analyze",
+    "registry",
+    "list",
+    "inspect",
+    "install-wrapper",
+    "completions",
+    "help",
+];
 
-    let my_name =
-        get_exe_name().expect("Couldn't parse current executable name");
+/// Options that make up the policy, for running clients under it as well
+/// as for analyzing dumps.
+fn policy_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("allow")
+            .long("allow")
+            .help("Let filtered clients use this capability.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&Capability::names()),
+        Arg::with_name("allow_event")
+            .long("allow-event")
+            .help(
+                "Let filtered clients send this event even without \
+                 send-event. Replaces the default of selection-notify \
+                 and client-message.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&policy::event_names()),
+        Arg::with_name("selection")
+            .long("selection")
+            .value_name("SELECTION=ACCESS")
+            .help(
+                "Restrict how filtered clients use a selection, e.g. \
+                 CLIPBOARD=copy-out. ACCESS is one of both, copy-out, \
+                 paste-in and none. CUT_BUFFERS stands for the eight \
+                 cut buffers on the root window.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("max_selection_size")
+            .long("max-selection-size")
+            .help(
+                "Most data a filtered client may hand out per paste, \
+                 also across an incremental transfer.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("max_property_offset")
+            .long("max-property-offset")
+            .help(
+                "Furthest into a property a filtered client may start \
+                 reading. Reading past it gets a BadValue error.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("max_property_length")
+            .long("max-property-length")
+            .help(
+                "Most of a property a filtered client may read at \
+                 once. Longer reads are cut down to it, and the client \
+                 sees that there's more.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("max_request_size")
+            .long("max-request-size")
+            .help(
+                "Longest request a client may send (default 16M). A \
+                 client that sends a longer one is disconnected.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("coarsen_timestamps")
+            .long("coarsen-timestamps")
+            .help(
+                "Round the timestamps of input events sent to filtered \
+                 clients down to this many milliseconds, to make timing \
+                 keystrokes harder.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("coarsen_pointer")
+            .long("coarsen-pointer")
+            .help(
+                "Round pointer positions that filtered clients see \
+                 outside their own windows down to this many pixels, \
+                 to make following the mouse across other windows \
+                 harder.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("fake_screen")
+            .long("fake-screen")
+            .help(
+                "Show filtered clients a screen of this size, like \
+                 1920x1080 or 1920x1080@96 with the DPI, instead of \
+                 the real monitor setup.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("fake_vendor")
+            .long("fake-vendor")
+            .help(
+                "Vendor string to tell filtered clients the server has, \
+                 instead of the real one.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("fake_release")
+            .long("fake-release")
+            .help(
+                "Release number to tell filtered clients the server has, \
+                 instead of the real one.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("partition_atoms")
+            .long("partition-atoms")
+            .value_name("PREFIX")
+            .help(
+                "Experimental: intern the atoms of filtered clients that \
+                 aren't part of a common convention under their name \
+                 with PREFIX in front, so they can't meet other \
+                 clients' atoms. The clients never see the prefix.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("unknown_peer")
+            .long("unknown-peer")
+            .help(
+                "How to treat clients whose PID can't be determined \
+                 (default untrusted).",
+            )
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["untrusted", "trusted", "reject"]),
+        Arg::with_name("preset")
+            .long("preset")
+            .help(
+                "Start from a built-in policy that the other options add \
+                 to: gpu lets OpenGL and Vulkan clients render through \
+                 DRI3, Present and MIT-SHM, legacy makes Java AWT and \
+                 Motif apps work.",
+            )
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["gpu", "legacy"]),
+        Arg::with_name("gpu")
+            .long("gpu")
+            .help(
+                "How to handle the GLX, DRI3 and Present requests of \
+                 filtered clients: passthrough passes them on without \
+                 a closer look (default), strict also denies importing \
+                 DRI3 buffers and fences unless import-buffers is \
+                 allowed.",
+            )
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["passthrough", "strict"]),
+        Arg::with_name("blank_images")
+            .long("blank-images")
+            .help("Answer denied screenshots with a blank image."),
+        Arg::with_name("honeypot_atom")
+            .long("honeypot-atom")
+            .help(
+                "Intern a decoy atom, and raise the alarm when a \
+                 filtered client touches it. Can be given more than \
+                 once.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("deny_atom")
+            .long("deny-atom")
+            .value_name("PATTERN")
+            .help(
+                "Keep filtered clients from interning atoms of this name, \
+                 or starting with what comes before a final *, e.g. \
+                 XdndAware to turn off drag and drop. They get an error, \
+                 or with PATTERN=spoof an atom nobody else knows. Can be \
+                 given more than once.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("protect_property")
+            .long("protect-property")
+            .value_name("ATOM")
+            .help(
+                "Deny filtered clients changing properties of this name, \
+                 unless change-protected-property is allowed. Can be \
+                 given more than once.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("deny_own_focus")
+            .long("deny-own-focus")
+            .help(
+            "Also deny filtered clients moving the focus between their own \
+             windows, unless steal-focus is allowed.",
+        ),
+        Arg::with_name("deny_input_methods")
+            .long("deny-input-methods")
+            .help(
+                "Keep filtered clients from talking to input methods such as \
+                 ibus and fcitx over XIM, unless input-method is allowed. \
+                 Otherwise XIM goes through whatever the event and \
+                 selection rules say.",
+            ),
+        Arg::with_name("deny_cover_screen")
+            .long("deny-cover-screen")
+            .help(
+            "Deny filtered clients override-redirect windows as big as the \
+             screen, which no window manager keeps in check, unless \
+             cover-screen is allowed.",
+        ),
+        Arg::with_name("spoof_pointer").long("spoof-pointer").help(
+            "Answer denied pointer queries with a pointer that is in the \
+             corner of the screen, over no window.",
+        ),
+        Arg::with_name("spoof_geometry").long("spoof-geometry").help(
+            "Answer denied geometry queries about other clients' windows \
+             as if they were a pixel in the corner of the screen.",
+        ),
+        Arg::with_name("spoof")
+            .long("spoof")
+            .help(
+                "Answer denied requests needing this capability with a \
+                 harmless made up reply where they have one: no windows, \
+                 blank images, empty properties, windows a pixel big and \
+                 a pointer that is nowhere. For clients that give up on errors.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&Capability::names()),
+        Arg::with_name("paranoid").long("paranoid").help(
+            "Reject requests of filtered clients that are longer than \
+             their fields need, rather than pass the extra bytes on.",
+        ),
+        Arg::with_name("safe_visuals").long("safe-visuals").help(
+            "Only tell filtered clients about TrueColor visuals and the \
+             usual depths, hiding the rest of what the server supports.",
+        ),
+        Arg::with_name("empty_trees").long("empty-trees").help(
+            "Answer denied window listings with an empty list of \
+             windows.",
+        ),
+        Arg::with_name("isolate_windows")
+            .long("isolate-windows")
+            .help(
+                "Let filtered clients list windows, but only ever show \
+                 them their own: other clients' windows are left out of \
+                 QueryTree replies and the window lists of the root window.",
+            ),
+    ]
+}
 
-    let matches = App::new("Rusty Windows")
+/// Options for running clients, on top of `policy_args`.
+fn run_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("dumpfile")
+            .long("dump")
+            .help("Dump client to server traffic to file.")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("capture_server")
+            .long("capture-server")
+            .help(
+                "Record server to client traffic with its timing, for \
+                 --replay-server.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("replay_server")
+            .long("replay-server")
+            .help(
+                "Instead of proxying, play a --capture-server recording \
+                 back to the target, one recorded connection per \
+                 connecting client.",
+            )
+            .takes_value(true)
+            .number_of_values(1)
+            .conflicts_with("capture_server")
+            .requires("target"),
+        Arg::with_name("replay_speed")
+            .long("replay-speed")
+            .help(
+                "How many times faster than recorded to replay, 0 for \
+                 no delays (default 1).",
+            )
+            .takes_value(true)
+            .number_of_values(1)
+            .requires("replay_server"),
+        Arg::with_name("audit_log")
+            .long("audit-log")
+            .help(
+                "Append security relevant events, like new connections \
+                 and the binary behind them, to this file. Disables \
+                 Landlock.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("dump_limit")
+            .long("dump-limit")
+            .help("Stop dumping after this many bytes (K/M/G suffix).")
+            .takes_value(true)
+            .number_of_values(1)
+            .requires("dumpfile"),
+        Arg::with_name("dump_mmap")
+            .long("dump-mmap")
+            .help("Preallocate the dump and write it through a mapping.")
+            .requires("dump_limit"),
+        Arg::with_name("trigger_atom")
+            .long("trigger-atom")
+            .help("Start dumping once an atom by this name is interned.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("dumpfile"),
+        Arg::with_name("trigger_on_denial")
+            .long("trigger-on-denial")
+            .help(
+                "Only start dumping once a request needing this \
+                 capability is denied.",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&Capability::names())
+            .requires("dumpfile"),
+        Arg::with_name("watchdog")
+            .long("watchdog")
+            .help(
+                "Look for the launched client connecting to other X or \
+                 Wayland sockets, and log it or kill the client. \
+                 Disables Landlock.",
+            )
+            .takes_value(true)
+            .possible_values(&["alert", "kill"])
+            .requires("target"),
+        Arg::with_name("private_socket_dir")
+            .long("private-socket-dir")
+            .help(
+                "Start the client in its own user and mount namespace, \
+                 where the proxy socket is the only one in \
+                 /tmp/.X11-unix, as X0.",
+            )
+            .requires("target"),
+        Arg::with_name("mediate_urls")
+            .long("mediate-urls")
+            .help(
+                "Route the client's attempts to open URLs and files \
+                 through a prompt on this terminal. Disables Landlock.",
+            )
+            .requires("target"),
+        Arg::with_name("untrusted_cookie")
+            .long("untrusted-cookie")
+            .value_name("TIMEOUT")
+            .help(
+                "Have the server generate an untrusted cookie with the \
+                 SECURITY extension and give the client only that, so \
+                 the server restricts it too. The cookie expires once \
+                 no client has used it for TIMEOUT seconds.",
+            )
+            .requires("target"),
+        Arg::with_name("grant_key")
+            .long("grant-key")
+            .value_name("FILE")
+            .help(
+                "Let whoever holds the key in FILE grant a filtered \
+                 client a capability for a while, by setting the \
+                 _RUSTYWIN_GRANT property of one of its windows to \
+                 CAPABILITY:DURATION:TIME:HMAC, like screenshot:30s:... \
+                 TIME is the Unix time and HMAC the hex HMAC-SHA256, \
+                 under the key, of CAPABILITY:DURATION:TIME:WINDOW with \
+                 the window id in decimal.",
+            ),
+        Arg::with_name("mark_denials")
+            .long("mark-denials")
+            .value_name("HOW")
+            .help(
+                "Mark the window of a filtered client denied something \
+                 dangerous, for the window manager to draw attention to it: \
+                 urgency sets its urgency hint, property lists what it was \
+                 denied in its _RUSTYWIN_DENIED property.",
+            )
+            .takes_value(true)
+            .number_of_values(1)
+            .possible_values(&["urgency", "property"]),
+        Arg::with_name("fail_on_denial")
+            .long("fail-on-denial")
+            .help(
+                "Kill the client and exit with status 3 the first time \
+                 a filtered client is denied something dangerous, like \
+                 grabbing the keyboard or taking screenshots.",
+            ),
+        Arg::with_name("recorder_size")
+            .long("recorder-size")
+            .help("Recent traffic to keep per connection (default 64K).")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("control_socket")
+            .long("control-socket")
+            .help("Listen for control commands on this Unix socket.")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("lockdown").long("lockdown").help(
+            "Make the configuration read-only for the lifetime of \
+             the proxy: no control socket changes, no new trusted \
+             PIDs.",
+        ),
+        Arg::with_name("chroot")
+            .long("chroot")
+            .help(
+                "Confine the proxy to this directory once everything is \
+                 set up. It has to contain the X server socket.",
+            )
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("no_landlock").long("no-landlock").help(
+            "Don't use Landlock to restrict the proxy to the files \
+             it needs.",
+        ),
+        Arg::with_name("cpus")
+            .long("cpus")
+            .help("Run the proxy on these CPUs only, e.g. 0,2-3.")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name("nice")
+            .long("nice")
+            .help("Scheduling priority of the proxy, -20 to 19.")
+            .takes_value(true)
+            .number_of_values(1)
+            .allow_hyphen_values(true),
+        Arg::with_name("inject_faults")
+            .long("inject-faults")
+            .help("Make socket calls fail with this probability.")
+            .takes_value(true)
+            .number_of_values(1)
+            .hidden(true),
+        Arg::with_name("fd")
+            .short("f")
+            .long("fd")
+            .help("Starts as server communicating on fd#.")
+            .takes_value(true)
+            .number_of_values(1)
+            .required(true)
+            .display_order(1)
+            .conflicts_with("target"),
+        Arg::with_name("child_passthrough")
+            .long("child-passthrough")
+            .help(
+                "Copy the output of the target program to the terminal, \
+                 besides logging it.",
+            ),
+        Arg::with_name("child_log").long("child-log").help(
+            "Also write the output of the target program to a log file \
+             of its own, under $XDG_STATE_HOME/rustywin.",
+        ),
+        Arg::with_name("target")
+            .help("Launches the target program.")
+            .index(1)
+            .required(true)
+            .conflicts_with("fd"),
+        Arg::with_name("target_args")
+            .help("Arguments for the target program.")
+            .index(2)
+            .multiple(true)
+            .requires("target"),
+    ]
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Rusty Windows")
         .version(crate_version!())
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .author(crate_authors!())
-        .setting(AppSettings::TrailingVarArg)
-        .arg(
-            Arg::with_name("dumpfile")
-                .long("dump")
-                .help("Dump client to server traffic to file.")
-                .takes_value(true)
-                .number_of_values(1),
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .after_help(
+            "Without a subcommand, `rustywin [options] <target>` is short \
+             for `rustywin run [options] <target>`.",
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a program, or serves a connection, filtered.")
+                .setting(AppSettings::TrailingVarArg)
+                .args(&policy_args())
+                .args(&run_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("container")
+                .about(
+                    "Serves a filtered socket for containers, printing the \
+                     options that mount it, or runs an image with them.",
+                )
+                .setting(AppSettings::TrailingVarArg)
+                .args(&policy_args())
+                .arg(
+                    Arg::with_name("engine")
+                        .long("engine")
+                        .help("What runs the container.")
+                        .takes_value(true)
+                        .default_value("podman")
+                        .possible_values(&["podman", "docker"]),
+                )
+                .arg(
+                    Arg::with_name("image")
+                        .help("Image to run, with the socket mounted.")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("image_args")
+                        .help("Command and arguments to run in the image.")
+                        .index(2)
+                        .multiple(true)
+                        .requires("image"),
+                ),
         )
-        .arg(
-            Arg::with_name("analyze_file")
-                .long("analyze")
-                .help("File of dumped traffic to analyze.")
-                .takes_value(true)
-                .number_of_values(1)
-                .required(true)
-                .conflicts_with("dumpfile")
-                .conflicts_with("fd")
-                .conflicts_with("target"),
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Analyzes dumped traffic under the policy.")
+                .args(&policy_args())
+                .arg(
+                    Arg::with_name("analyze_file")
+                        .help("File of dumped traffic to analyze, - for stdin.")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("hexdump")
+                        .long("hexdump")
+                        .help("Print analyzed requests as annotated hexdumps."),
+                ),
         )
-        .arg(
-            Arg::with_name("fd")
-                .short("f")
-                .long("fd")
-                .help("Starts as server communicating on fd#.")
-                .takes_value(true)
-                .number_of_values(1)
-                .required(true)
-                .display_order(1)
-                .conflicts_with("target")
-                .conflicts_with("analyze_file"),
+        .subcommand(
+            SubCommand::with_name("registry")
+                .about("Keeps track of all instances in this session."),
         )
-        .arg(
-            Arg::with_name("target")
-                .help("Launches the target program.")
-                .index(1)
-                .required(true)
-                .conflicts_with("fd")
-                .conflicts_with("analyze_file"),
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Shows the instances the registry knows of."),
         )
-        .arg(
-            Arg::with_name("target_args")
-                .help("Arguments for the target program.")
-                .index(2)
-                .multiple(true)
-                .requires("target"),
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Follows the instance on a display.")
+                .arg(Arg::with_name("display").index(1).required(true)),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("install-wrapper")
+                .about(
+                    "Makes an application's launcher entry run it through \
+                     rustywin with the options given.",
+                )
+                .setting(AppSettings::TrailingVarArg)
+                .arg(
+                    Arg::with_name("entry")
+                        .help("Desktop entry file, or the name of one.")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("options")
+                        .help("Options to run the application with.")
+                        .index(2)
+                        .multiple(true)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("completions")
+                .about("Prints a completion script for a shell.")
+                .arg(
+                    Arg::with_name("shell")
+                        .index(1)
+                        .required(true)
+                        .possible_values(&Shell::variants()),
+                ),
+        )
+}
 
-    info!("Rusty Windows - Starting up");
+/// The command line with `run` put in front of the arguments, unless they
+/// start with a subcommand or ask for help or the version.
+fn with_default_subcommand(mut args: Vec<OsString>) -> Vec<OsString> {
+    let explicit = match args.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) => {
+            SUBCOMMANDS.contains(&arg)
+                || ["-h", "--help", "-V", "--version"].contains(&arg)
+        }
+        None => true,
+    };
+    if !explicit {
+        args.insert(1, OsString::from("run"));
+    }
+    args
+}
+
+/// The policy the options in `matches` make up, see `policy_args`.
+fn parse_policy(matches: &ArgMatches) -> Policy {
+    let preset = matches
+        .value_of("preset")
+        .map(|name| Preset::from_name(name).unwrap());
+    let mut allowed: Vec<Capability> = matches
+        .values_of("allow")
+        .map(|names| names.filter_map(Capability::from_name).collect())
+        .unwrap_or_default();
+    if let Some(preset) = preset {
+        allowed.extend(preset.capabilities());
+    }
+    let mut policy = Policy::new(allowed);
+    policy.set_blank_images(matches.is_present("blank_images"));
+    policy.set_empty_trees(matches.is_present("empty_trees"));
+    policy.set_isolate_windows(matches.is_present("isolate_windows"));
+    policy.set_spoof_pointer(
+        matches.is_present("spoof_pointer")
+            || preset.is_some_and(Preset::spoof_pointer),
+    );
+    policy.set_spoof_geometry(matches.is_present("spoof_geometry"));
+    if let Some(names) = matches.values_of("spoof") {
+        policy.set_spoofed(names.filter_map(Capability::from_name).collect());
+    }
+    policy.set_safe_visuals(matches.is_present("safe_visuals"));
+    policy.set_paranoid(matches.is_present("paranoid"));
+    policy.set_own_focus(!matches.is_present("deny_own_focus"));
+    policy.set_deny_cover_screen(matches.is_present("deny_cover_screen"));
+    policy.set_deny_input_methods(matches.is_present("deny_input_methods"));
+    policy.set_protected_properties(
+        matches
+            .values_of_lossy("protect_property")
+            .unwrap_or_default(),
+    );
+    let decoys = matches.values_of_lossy("honeypot_atom").unwrap_or_default();
+    policy.set_honeypots(
+        decoys.iter().map(|name| (name.clone(), None)).collect(),
+    );
+    if let Some(events) = matches.values_of("allow_event") {
+        policy.set_allowed_events(
+            events.filter_map(policy::event_code).collect(),
+        );
+    }
+    if let Some(name) = matches.value_of("unknown_peer") {
+        policy.set_unknown_peer(UnknownPeer::from_name(name).unwrap());
+    }
+    if let Some(name) = matches.value_of("gpu") {
+        policy.set_gpu_mode(GpuMode::from_name(name).unwrap());
+    } else if let Some(gpu_mode) = preset.and_then(Preset::gpu_mode) {
+        policy.set_gpu_mode(gpu_mode);
+    }
+    if let Some(preset) = preset {
+        let shared = preset.shared_properties().iter();
+        policy.set_shared_properties(shared.map(|s| s.to_string()).collect());
+    }
+    if let Some(size) = matches.value_of("max_selection_size") {
+        match dump::parse_size(size) {
+            Some(size) => policy.set_max_selection_size(size),
+            None => {
+                error!("Couldn't parse selection size: {}", size);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(size) = matches.value_of("max_property_offset") {
+        match dump::parse_size(size) {
+            Some(size) => policy.set_max_property_offset(size),
+            None => {
+                error!("Couldn't parse property offset: {}", size);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(size) = matches.value_of("max_property_length") {
+        match dump::parse_size(size) {
+            Some(size) => policy.set_max_property_length(size),
+            None => {
+                error!("Couldn't parse property length: {}", size);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(size) = matches.value_of("max_request_size") {
+        match dump::parse_size(size) {
+            Some(size) => policy.set_max_request_size(size),
+            None => {
+                error!("Couldn't parse request size: {}", size);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(millis) = matches.value_of("coarsen_timestamps") {
+        match millis.parse() {
+            Ok(millis) => policy.set_timestamp_granularity(millis),
+            Err(_) => {
+                error!("Couldn't parse timestamp granularity: {}", millis);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(pixels) = matches.value_of("coarsen_pointer") {
+        match pixels.parse() {
+            Ok(pixels) => policy.set_pointer_granularity(pixels),
+            Err(_) => {
+                error!("Couldn't parse pointer granularity: {}", pixels);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(spec) = matches.value_of("fake_screen") {
+        match policy::parse_fake_screen(spec) {
+            Some(screen) => policy.set_fake_screen(screen),
+            None => {
+                error!("Couldn't parse screen size: {}", spec);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(vendor) = matches.value_of("fake_vendor") {
+        policy.set_fake_vendor(vendor.to_string());
+    }
+    if let Some(release) = matches.value_of("fake_release") {
+        match release.parse() {
+            Ok(release) => policy.set_fake_release(release),
+            Err(_) => {
+                error!("Couldn't parse release number: {}", release);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(prefix) = matches.value_of("partition_atoms") {
+        if prefix.is_empty() {
+            error!("The atom prefix can't be empty.");
+            std::process::exit(1);
+        }
+        policy.set_atom_prefix(prefix);
+    }
+    for rule in matches.values_of("deny_atom").into_iter().flatten() {
+        match policy::parse_atom_rule(rule) {
+            Some((pattern, denial)) => policy.add_denied_atom(pattern, denial),
+            None => {
+                error!("Couldn't parse atom rule: {}", rule);
+                std::process::exit(1);
+            }
+        }
+    }
+    for rule in matches.values_of("selection").into_iter().flatten() {
+        match policy::parse_selection_rule(rule) {
+            Some((selection, access)) => {
+                policy.set_selection_access(selection, access)
+            }
+            None => {
+                error!("Couldn't parse selection rule: {}", rule);
+                std::process::exit(1);
+            }
+        }
+    }
+    policy
+}
+
+fn main() {
+    setup_logging();
+
+    let my_name =
+        get_exe_name().expect("Couldn't parse current executable name");
 
-    // If we're just analzying an existing dump,
-    // ignore all other options.
-    if matches.is_present("analyze_file") {
-        let filename = matches.value_of("analyze_file").unwrap();
-        info!("Analzying dumpfile {}", filename);
-        let res = analyze::analyze_file(filename);
-        std::process::exit(if res.is_err() { 1 } else { 0 });
+    // The client opening a URL through `urlopen::Mediator`.
+    if urlopen::invoked_as_helper() {
+        std::process::exit(urlopen::run_helper(env::args().nth(1)));
     }
+    let matches = app()
+        .get_matches_from(with_default_subcommand(env::args_os().collect()));
+    let matches = match matches.subcommand() {
+        ("run", Some(matches)) | ("container", Some(matches)) => {
+            matches.clone()
+        }
+        ("analyze", Some(matches)) => {
+            let policy = parse_policy(matches);
+            let filename = matches.value_of("analyze_file").unwrap();
+            info!("Analzying dumpfile {}", filename);
+            let hexdump = matches.is_present("hexdump");
+            let res = analyze::analyze_file(filename, &policy, hexdump);
+            if let Err(ref e) = res {
+                error!("Analysis failed: {}", e);
+            }
+            std::process::exit(if res.is_err() { 1 } else { 0 });
+        }
+        ("registry", _) => {
+            if let Err(e) = registry::run_registry() {
+                error!("Registry failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        ("list", _) | ("inspect", _) => {
+            let mut command = matches.subcommand_name().unwrap().to_string();
+            if let Some(display) = matches
+                .subcommand_matches("inspect")
+                .and_then(|matches| matches.value_of("display"))
+            {
+                command = format!("{} {}", command, display);
+            }
+            if let Err(e) = registry::query(&command) {
+                error!("Couldn't reach the registry: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        ("install-wrapper", Some(matches)) => {
+            let entry = matches.value_of("entry").unwrap();
+            let options =
+                matches.values_of_lossy("options").unwrap_or_default();
+            let installed = env::current_exe().and_then(|exe| {
+                desktop::install_wrapper(entry, &exe, &options)
+            });
+            match installed {
+                Ok(path) => info!("Installed {:?}", path),
+                Err(e) => {
+                    error!("Couldn't install the wrapper: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        ("completions", Some(matches)) => {
+            let shell = matches.value_of("shell").unwrap().parse().unwrap();
+            app().gen_completions_to("rustywin", shell, &mut io::stdout());
+            return;
+        }
+        _ => unreachable!(),
+    };
+
+    info!("Rusty Windows - Starting up");
+
+    let mut policy = parse_policy(&matches);
+    let decoys = matches.values_of_lossy("honeypot_atom").unwrap_or_default();
 
     if matches.is_present("target") {
         info!(
@@ -149,25 +973,143 @@ fn main() {
 
     let target = matches.value_of("target");
     let args = matches.values_of_lossy("target_args");
+    // Only given for `container`.
+    let engine = matches
+        .value_of("engine")
+        .map(|name| container::Engine::from_name(name).unwrap());
     let fd = match matches.value_of("fd") {
         Some(fd) => fd.parse::<i32>().ok(),
         None => None,
     };
 
+    let dump_limit = match matches.value_of("dump_limit") {
+        Some(limit) => match dump::parse_size(limit) {
+            Some(limit) => Some(limit),
+            None => {
+                error!("Couldn't parse dump limit: {}", limit);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     // Open the dumpfile if we got one
     let dumpfile = match matches.value_of("dumpfile") {
         Some(filename) => {
             info!("Dumping to {}", filename);
             let dumpfile = OpenOptions::new()
+                .read(true)
                 .write(true)
                 .create_new(true)
                 .open(filename)
                 .expect("Error opening dumpfile");
-            Some(Arc::new(Mutex::new(dumpfile)) as DumpFile)
+            if matches.is_present("dump_mmap") {
+                let size = dump_limit.unwrap();
+                match MappedDump::new(dumpfile, size) {
+                    Ok(dump) => Some(Arc::new(dump) as DumpFile),
+                    Err(e) => {
+                        error!("Couldn't map dumpfile: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                let dump = Dump::new(Box::new(dumpfile), dump_limit);
+                Some(Arc::new(Mutex::new(dump)) as DumpFile)
+            }
         }
         None => None,
     };
 
+    let server_capture = matches.value_of("capture_server").map(|filename| {
+        info!("Capturing server traffic to {}", filename);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(filename)
+            .expect("Error opening server capture");
+        ServerCapture::new(Box::new(file))
+    });
+
+    let audit = matches.value_of("audit_log").map(|filename| {
+        info!("Audit log at {}", filename);
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(filename)
+            .expect("Error opening audit log");
+        AuditLog::new(file)
+    });
+
+    let triggers = Triggers::new(
+        matches.values_of_lossy("trigger_atom").unwrap_or_default(),
+        matches
+            .values_of("trigger_on_denial")
+            .map(|names| names.filter_map(Capability::from_name).collect())
+            .unwrap_or_default(),
+    );
+
+    // The flight recorders are only needed if something can read them.
+    let control_socket = matches.value_of("control_socket");
+    let recorder_size = match matches.value_of("recorder_size") {
+        Some(size) => match dump::parse_size(size) {
+            Some(size) => size as usize,
+            None => {
+                error!("Couldn't parse recorder size: {}", size);
+                std::process::exit(1);
+            }
+        },
+        None if !triggers.is_empty() || control_socket.is_some() => 64 * 1024,
+        None => 0,
+    };
+    let sessions = Arc::new(Sessions::new(recorder_size));
+    let lockdown = Arc::new(Lockdown::new(matches.is_present("lockdown")));
+
+    let cpus = match matches.value_of("cpus") {
+        Some(list) => match sched::parse_cpu_list(list) {
+            Some(cpus) => Some(cpus),
+            None => {
+                error!("Couldn't parse CPU list: {}", list);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let nice = match matches.value_of("nice") {
+        Some(nice) => match nice.parse::<i32>() {
+            Ok(nice) => Some(nice),
+            Err(_) => {
+                error!("Couldn't parse nice value: {}", nice);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let fault_rate = match matches.value_of("inject_faults") {
+        Some(rate) => match rate.parse::<f64>() {
+            Ok(rate) if (0.0..=1.0).contains(&rate) => {
+                warn!("Injecting socket faults with probability {}", rate);
+                rate
+            }
+            _ => {
+                error!("Fault probability must be in [0, 1]: {}", rate);
+                std::process::exit(1);
+            }
+        },
+        None => 0.0,
+    };
+
+    let control_listener = match control_socket {
+        Some(path) => match control::bind_control_socket(path) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                error!("Couldn't set up control socket {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     // Get the X11 display connection
     let key = "DISPLAY";
     let x11_display = match env::var(key) {
@@ -183,43 +1125,484 @@ fn main() {
 
     let connection = display::parse_x11_display(x11_display.as_str());
 
+    if let Some(path) = matches.value_of("replay_server") {
+        let capture = match replay::load_capture(path) {
+            Ok(capture) => capture,
+            Err(e) => {
+                error!("Couldn't read {}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        let speed = match matches.value_of("replay_speed") {
+            Some(speed) => match speed.parse::<f64>() {
+                Ok(speed) if speed >= 0.0 => speed,
+                _ => {
+                    error!("Replay speed must be 0 or more: {}", speed);
+                    std::process::exit(1);
+                }
+            },
+            None => 1.0,
+        };
+        let sockets = match socket::setup_unix_socket(&connection) {
+            Ok(sockets) => sockets,
+            Err(e) => {
+                error!("Couldn't claim a display: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let listen_socket = match socketloop::setup_listen_socket(&sockets) {
+            Some(socket) => socket,
+            None => std::process::exit(1),
+        };
+        thread::spawn(move || {
+            if let Err(e) = replay::serve(&listen_socket, &capture, speed) {
+                error!("Replay failed: {}", e);
+            }
+        });
+        let mut child = client::launch_client(
+            target.unwrap(),
+            &args,
+            sockets.get_display(),
+            None,
+            None,
+            None,
+        );
+        childoutput::forward(
+            &mut child,
+            target.unwrap(),
+            matches.is_present("child_passthrough"),
+            matches.is_present("child_log"),
+        );
+        let status = child.wait().expect("Client exited abnormally");
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    // The client's, once it's done.
+    let mut status = 0;
     if connection.is_unix_socket() {
-        let sockets = socket::setup_unix_socket(&connection);
+        let mut sockets = match socket::setup_unix_socket(&connection) {
+            Ok(sockets) => sockets,
+            Err(e) => {
+                error!("Couldn't claim a display: {}", e);
+                std::process::exit(1);
+            }
+        };
         // The listen socket needs to be up before we launch the client.
         let listen_socket = match socketloop::setup_listen_socket(&sockets) {
             Some(socket) => socket,
             None => std::process::exit(1),
         };
 
+        // Before the client is around to see them appear.
+        if !decoys.is_empty() {
+            let interned = sockets.send_stream().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "no server")
+            });
+            let interned = interned.and_then(|stream| {
+                atoms::intern_atoms(stream, connection.server_num(), &decoys)
+            });
+            match interned {
+                Ok(atoms) => policy.set_honeypots(
+                    decoys
+                        .iter()
+                        .cloned()
+                        .zip(atoms.into_iter().map(Some))
+                        .collect(),
+                ),
+                Err(e) => warn!(
+                    "Couldn't intern the decoy atoms, only catching clients \
+                     that look them up by name: {}",
+                    e
+                ),
+            }
+        }
+        // Protected properties are known by name before anyone uses them.
+        let common: Vec<String> = atoms::COMMON_ATOMS
+            .iter()
+            .map(|name| name.to_string())
+            .chain(policy.protected_properties().iter().cloned())
+            .collect();
+        let interned = sockets
+            .send_stream()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "no server")
+            })
+            .and_then(|stream| {
+                atoms::intern_atoms(stream, connection.server_num(), &common)
+            });
+        match interned {
+            Ok(interned) => policy
+                .set_known_atoms(common.into_iter().zip(interned).collect()),
+            Err(e) => warn!(
+                "Couldn't intern the common atoms, only knowing the names \
+                 of those clients intern: {}",
+                e
+            ),
+        }
+
+        let grant_watcher = match matches.value_of("grant_key") {
+            Some(path) => {
+                let watcher = std::fs::read(path).and_then(|mut key| {
+                    while key.last().is_some_and(u8::is_ascii_whitespace) {
+                        key.pop();
+                    }
+                    let stream = sockets.send_stream().ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotConnected, "no server")
+                    })?;
+                    grants::connect(stream, connection.server_num(), key)
+                });
+                match watcher {
+                    Ok((grants, watcher)) => {
+                        policy.set_grants(grants);
+                        Some(watcher)
+                    }
+                    Err(e) => {
+                        error!("Couldn't watch for grants: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let companion = matches.value_of("mark_denials").map(|how| {
+            let marking = companion::Marking::from_name(how).unwrap();
+            let companion = sockets
+                .send_stream()
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotConnected, "no server")
+                })
+                .and_then(|stream| {
+                    companion::connect(stream, connection.server_num(), marking)
+                });
+            match companion {
+                Ok(companion) => companion,
+                Err(e) => {
+                    error!("Couldn't connect to mark windows: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        });
+
+        let authority = match matches.value_of("untrusted_cookie") {
+            Some(timeout) => {
+                let timeout = match timeout.parse::<u32>() {
+                    Ok(timeout) => timeout,
+                    Err(e) => {
+                        error!("Bad cookie timeout {}: {}", timeout, e);
+                        std::process::exit(1);
+                    }
+                };
+                let generated = sockets
+                    .send_stream()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotConnected, "no server")
+                    })
+                    .and_then(|stream| {
+                        security::generate_untrusted(
+                            stream,
+                            connection.server_num(),
+                            timeout,
+                        )
+                    })
+                    .and_then(|cookie| {
+                        security::UntrustedAuthority::new(&cookie)
+                    });
+                match generated {
+                    Ok(authority) => Some(authority),
+                    Err(e) => {
+                        error!("Couldn't set up an untrusted cookie: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            None => None,
+        };
+        let authority_path = authority.as_ref().map(|a| a.path().to_path_buf());
+
         // to_string() is needed here to break the lifetime link between
         // sockets and (eventually) client_handle.
         let display_for_client = sockets.get_display().to_string();
 
-        // Now either get a handle to the child (from which we will extract
-        // standards fds) or the fd to listen to.
-        let client_handle = if target.is_some() {
-            ChildInfo::Child(client::launch_client(
-                &target.unwrap().to_string(),
+        let mediator = if matches.is_present("mediate_urls") {
+            match urlopen::Mediator::new() {
+                Ok(mediator) => Some(mediator),
+                Err(e) => {
+                    error!("Couldn't set up URL mediation: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            None
+        };
+        let mediator_dir = mediator.as_ref().map(|m| m.dir().to_path_buf());
+        let registration = registry::Registration::new(
+            sockets.get_display(),
+            &policy.describe(),
+        );
+
+        // Now either get a handle to the child (whose output we pass on)
+        // or the fd to listen to.
+        let client_handle = if let Some(target) = target {
+            let mut child = client::launch_client(
+                target,
                 &args,
                 display_for_client.as_str(),
-            ))
+                if matches.is_present("private_socket_dir") {
+                    Some(sockets.listen_path())
+                } else {
+                    None
+                },
+                mediator.as_ref(),
+                authority.as_ref(),
+            );
+            childoutput::forward(
+                &mut child,
+                target,
+                matches.is_present("child_passthrough"),
+                matches.is_present("child_log"),
+            );
+            ChildInfo::Child(child)
+        } else if let Some(engine) = engine {
+            let mount = container::mount_args(sockets.listen_path());
+            match matches.value_of("image") {
+                Some(image) => {
+                    let image_args = matches
+                        .values_of_lossy("image_args")
+                        .unwrap_or_default();
+                    let run = container::run_args(&mount, image, &image_args);
+                    let mut child = client::launch_client(
+                        engine.command(),
+                        &Some(run),
+                        display_for_client.as_str(),
+                        None,
+                        None,
+                        None,
+                    );
+                    childoutput::forward(
+                        &mut child,
+                        engine.command(),
+                        false,
+                        false,
+                    );
+                    ChildInfo::Child(child)
+                }
+                None => {
+                    info!("Serving containers until interrupted.");
+                    println!("{}", container::shell_line(&mount));
+                    ChildInfo::Standalone
+                }
+            }
         } else {
             assert!(fd.is_some());
             info!("Socket FD: {:?}", fd.unwrap());
             ChildInfo::RawFd(fd.unwrap())
         };
 
+        // Only now, so the client doesn't inherit them. The threads
+        // handling connections are started later and do.
+        if let Some(ref cpus) = cpus {
+            if let Err(e) = sched::set_affinity(cpus) {
+                warn!("Couldn't set CPU affinity: {}", e);
+            }
+        }
+        if let Some(nice) = nice {
+            if let Err(e) = sched::set_nice(nice) {
+                warn!("Couldn't set nice value {}: {}", nice, e);
+            }
+        }
+
+        // Everything we need by path has been opened or bound by now.
+        // All of this only applies to the calling thread and the ones it
+        // starts afterwards, so no other thread may be running yet.
+        if let Err(e) = hardening::set_no_new_privs() {
+            warn!("Couldn't set no_new_privs: {}", e);
+        }
+        if let Err(e) = hardening::clear_ambient_capabilities() {
+            warn!("Couldn't clear ambient capabilities: {}", e);
+        }
+        let watchdog = matches
+            .value_of("watchdog")
+            .map(|action| BypassAction::from_name(action).unwrap());
+        if audit.is_some() && !matches.is_present("no_landlock") {
+            // A Landlock domain can't look at processes outside of it.
+            info!("Not using Landlock, clients couldn't be fingerprinted.");
+        } else if watchdog.is_some() && !matches.is_present("no_landlock") {
+            info!("Not using Landlock, the watchdog couldn't see the client.");
+        } else if mediator.is_some() && !matches.is_present("no_landlock") {
+            info!("Not using Landlock, URLs couldn't be opened.");
+        } else if !matches.is_present("no_landlock") {
+            let mut rules = landlock::Ruleset::new();
+            rules.allow(
+                socket::X11_SOCKET_DIR,
+                landlock::ACCESS_READ_DIR
+                    | landlock::ACCESS_MAKE_SOCK
+                    | landlock::ACCESS_REMOVE_FILE,
+            );
+            rules.allow(
+                socket::socket_list_path(),
+                landlock::ACCESS_READ_FILE | landlock::ACCESS_WRITE_FILE,
+            );
+            if registration.is_some() {
+                // For the names of clients.
+                rules.allow("/proc", landlock::ACCESS_READ_FILE);
+            }
+            if let Some(path) = matches.value_of("dumpfile") {
+                rules.allow(path, landlock::ACCESS_WRITE_FILE);
+            }
+            // To rotate the log files.
+            if let (true, Ok(dir)) =
+                (matches.is_present("child_log"), childoutput::log_dir())
+            {
+                rules.allow(
+                    dir,
+                    landlock::ACCESS_WRITE_FILE
+                        | landlock::ACCESS_REMOVE_FILE
+                        | landlock::ACCESS_MAKE_REG,
+                );
+            }
+            if let Some(path) = control_socket {
+                let dir = std::path::Path::new(path)
+                    .parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .unwrap_or_else(|| std::path::Path::new("."));
+                rules.allow(dir, landlock::ACCESS_REMOVE_FILE);
+            }
+            if let Some(dir) = authority_path.as_ref().and_then(|p| p.parent())
+            {
+                rules.allow(dir, landlock::ACCESS_REMOVE_FILE);
+            }
+            match rules.restrict_self() {
+                Ok(landlock::Status::Enforced(abi)) => {
+                    info!("Landlock restrictions in force, ABI {}", abi)
+                }
+                Ok(landlock::Status::Unsupported) => {
+                    info!("Landlock isn't supported by this kernel.")
+                }
+                Err(e) => warn!("Couldn't set up Landlock: {}", e),
+            }
+        }
+        if let Some(dir) = matches.value_of("chroot") {
+            if watchdog.is_some() {
+                warn!("The watchdog needs /proc, which the chroot may lack.");
+            }
+            if mediator.is_some() {
+                warn!("URLs can only be opened if the chroot can do that.");
+            }
+            if !sockets.rebase(dir) {
+                error!("The X server socket isn't inside {}", dir);
+                std::process::exit(1);
+            }
+            if let Err(e) = hardening::chroot(dir) {
+                error!("Couldn't chroot to {}: {}", dir, e);
+                std::process::exit(1);
+            }
+            info!("Chrooted to {}", dir);
+        }
+
+        if let Some(mediator) = mediator {
+            mediator.spawn();
+        }
+        if let Some(watcher) = grant_watcher {
+            watcher.spawn();
+        }
+        if let Some(listener) = control_listener {
+            control::spawn_control_socket(
+                listener,
+                sessions.clone(),
+                lockdown.clone(),
+            );
+        }
+
         // We've been given an fd corresponding to a socketpair to
         // communicate over. Send our X DISPLAY var.
         if fd.is_some() {
             ipc::send_display(fd.unwrap(), sockets.get_display())
         }
 
-        socketloop::run_unix_socket_loop(
+        let proxy = Arc::new(Proxy {
+            dumpfile: dumpfile.clone(),
+            server_capture,
+            triggers: Arc::new(triggers),
+            sessions,
+            policy,
+            fault_rate,
+            lockdown,
+            audit,
+            parent_fd: fd,
+            registry: registration,
+            fail_on_denial: matches.is_present("fail_on_denial"),
+            companion,
+            child_pid: match client_handle {
+                ChildInfo::Child(ref child) => Some(child.id() as i32),
+                ChildInfo::RawFd(_) | ChildInfo::Standalone => None,
+            },
+            shutdown: socketloop::Shutdown::new()
+                .expect("Couldn't set up shutting down"),
+            turns: turns::Turns::new(),
+        });
+        if let (Some(action), ChildInfo::Child(ref child)) =
+            (watchdog, &client_handle)
+        {
+            let spoofed = sockets.listen_path();
+            Watchdog::new(child.id() as i32, action, spoofed, proxy.clone())
+                .spawn();
+        }
+
+        status = socketloop::run_unix_socket_loop(
             sockets,
             listen_socket,
             client_handle,
-            dumpfile,
+            proxy,
         );
+        if let Some(dump) = dumpfile {
+            if let Err(e) = dump.finish() {
+                error!("Couldn't finish dumpfile: {}", e);
+            }
+        }
+        metrics::report();
+        allocations::report();
+        if let Some(dir) = mediator_dir {
+            urlopen::cleanup(&dir);
+        }
+        if let Some(path) = authority_path {
+            security::cleanup(&path);
+        }
+    }
+
+    if let Some(path) = control_socket {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Couldn't remove control socket {}: {}", path, e);
+        }
+    }
+    if status != 0 {
+        std::process::exit(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_default_subcommand() {
+        let bare = with_default_subcommand(args(&["rustywin", "xterm"]));
+        assert_eq!(bare, args(&["rustywin", "run", "xterm"]));
+        let matches = app().get_matches_from_safe(bare).unwrap();
+        let run = matches.subcommand_matches("run").unwrap();
+        assert_eq!(run.value_of("target"), Some("xterm"));
+
+        let fd = with_default_subcommand(args(&["rustywin", "--fd", "3"]));
+        assert_eq!(fd, args(&["rustywin", "run", "--fd", "3"]));
+        let explicit = args(&["rustywin", "analyze", "dump"]);
+        assert_eq!(with_default_subcommand(explicit.clone()), explicit);
+        let help = args(&["rustywin", "-h"]);
+        assert_eq!(with_default_subcommand(help.clone()), help);
+        let nothing = args(&["rustywin"]);
+        assert_eq!(with_default_subcommand(nothing.clone()), nothing);
     }
 }
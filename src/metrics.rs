@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A named, process wide event counter.
+pub struct Counter {
+    name: &'static str,
+    value: AtomicUsize,
+}
+
+impl Counter {
+    const fn new(name: &'static str) -> Counter {
+        Counter {
+            name,
+            value: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes that were not written to the dumpfile because the cap was hit.
+pub static DUMP_BYTES_DROPPED: Counter = Counter::new("dump_bytes_dropped");
+
+/// Requests that didn't decode and were treated as needing a capability,
+/// and those that were let through.
+pub static DECODE_FAILURES_CLOSED: Counter =
+    Counter::new("decode_failures_closed");
+pub static DECODE_FAILURES_OPEN: Counter = Counter::new("decode_failures_open");
+
+/// Requests rejected for being longer than their contents.
+pub static OVERSIZED_REQUESTS: Counter = Counter::new("oversized_requests");
+
+/// Property requests answered with an error instead of passed on, and
+/// GetProperty lengths cut down to the ceiling.
+pub static PROPERTY_ERRORS: Counter = Counter::new("property_errors");
+pub static PROPERTY_LENGTHS_CLAMPED: Counter =
+    Counter::new("property_lengths_clamped");
+
+/// InternAtom requests of atoms the policy denies, answered with an error
+/// or a spoofed atom.
+pub static DENIED_ATOMS: Counter = Counter::new("denied_atoms");
+
+/// Signed grants set on windows that were taken up, and those that were
+/// turned down.
+pub static HINT_GRANTS: Counter = Counter::new("hint_grants");
+pub static HINT_GRANTS_REFUSED: Counter = Counter::new("hint_grants_refused");
+
+static ALL_COUNTERS: &[&Counter] = &[
+    &DUMP_BYTES_DROPPED,
+    &DECODE_FAILURES_CLOSED,
+    &DECODE_FAILURES_OPEN,
+    &OVERSIZED_REQUESTS,
+    &PROPERTY_ERRORS,
+    &PROPERTY_LENGTHS_CLAMPED,
+    &DENIED_ATOMS,
+    &HINT_GRANTS,
+    &HINT_GRANTS_REFUSED,
+];
+
+/// Log every counter that saw any events.
+pub fn report() {
+    for counter in ALL_COUNTERS {
+        let value = counter.get();
+        if value > 0 {
+            info!("{}: {}", counter.name, value);
+        }
+    }
+}
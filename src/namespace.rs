@@ -0,0 +1,155 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use libc;
+
+use socket::X11_SOCKET_DIR;
+
+/// The display the client sees in its private socket directory.
+pub const PRIVATE_DISPLAY: &str = ":0";
+
+fn cstring<P: AsRef<Path>>(path: P) -> Result<CString, io::Error> {
+    CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn check(result: libc::c_int) -> Result<(), io::Error> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write all of `data` to the file at `path`, without allocating.
+fn write_file(path: &CString, data: &[u8]) -> Result<(), io::Error> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written =
+            libc::write(fd, data.as_ptr() as *const libc::c_void, data.len());
+        libc::close(fd);
+        if written != data.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// "/proc/self/fd/<fd>" as a C string, in `buffer`.
+fn fd_path(fd: libc::c_int, buffer: &mut [u8; 32]) -> *const libc::c_char {
+    let prefix = b"/proc/self/fd/";
+    buffer[..prefix.len()].copy_from_slice(prefix);
+    let mut digits = [0u8; 10];
+    let mut n = fd as u32;
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (n % 10) as u8;
+        count += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for i in 0..count {
+        buffer[prefix.len() + i] = digits[count - 1 - i];
+    }
+    buffer[prefix.len() + count] = 0;
+    buffer.as_ptr() as *const libc::c_char
+}
+
+/// Start `command` in new user and mount namespaces where the X11 socket
+/// directory is an empty tmpfs, apart from `socket` appearing as X0. The
+/// client has to be told to use `PRIVATE_DISPLAY`.
+///
+/// This needs unprivileged user namespaces. Abstract sockets belong to
+/// the network namespace, so an X server listening on one can still be
+/// reached.
+pub fn isolate_socket_dir(
+    command: &mut Command,
+    socket: &str,
+) -> Result<(), io::Error> {
+    // Everything the child needs is prepared here, after the fork it
+    // must not allocate.
+    let socket = cstring(socket)?;
+    let dir = cstring(X11_SOCKET_DIR)?;
+    let private = cstring(Path::new(X11_SOCKET_DIR).join("X0"))?;
+    let root = cstring("/")?;
+    let tmpfs = cstring("tmpfs")?;
+    let options = cstring("mode=1777")?;
+    let setgroups = cstring("/proc/self/setgroups")?;
+    let uid_map = cstring("/proc/self/uid_map")?;
+    let gid_map = cstring("/proc/self/gid_map")?;
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let uid_line = format!("{} {} 1\n", uid, uid).into_bytes();
+    let gid_line = format!("{} {} 1\n", gid, gid).into_bytes();
+
+    let setup = move || -> Result<(), io::Error> {
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS))?;
+            write_file(&setgroups, b"deny")?;
+            write_file(&uid_map, &uid_line)?;
+            write_file(&gid_map, &gid_line)?;
+
+            // Don't let the mounts below leak back out.
+            check(libc::mount(
+                ::std::ptr::null(),
+                root.as_ptr(),
+                ::std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                ::std::ptr::null(),
+            ))?;
+            // Keep the socket reachable once the tmpfs hides it. This
+            // has to be a path from the new namespace to bind from.
+            let fd =
+                libc::open(socket.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+            check(fd)?;
+            let mut source = [0; 32];
+            check(libc::mount(
+                tmpfs.as_ptr(),
+                dir.as_ptr(),
+                tmpfs.as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                options.as_ptr() as *const libc::c_void,
+            ))?;
+            let target = libc::open(
+                private.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                0o600,
+            );
+            check(target)?;
+            libc::close(target);
+            check(libc::mount(
+                fd_path(fd, &mut source),
+                private.as_ptr(),
+                ::std::ptr::null(),
+                libc::MS_BIND,
+                ::std::ptr::null(),
+            ))?;
+            libc::close(fd);
+        }
+        Ok(())
+    };
+    unsafe {
+        command.pre_exec(setup);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn test_fd_path() {
+        let mut buffer = [0; 32];
+        let path = unsafe { CStr::from_ptr(fd_path(0, &mut buffer)) };
+        assert_eq!(path.to_bytes(), b"/proc/self/fd/0");
+        let path = unsafe { CStr::from_ptr(fd_path(1023, &mut buffer)) };
+        assert_eq!(path.to_bytes(), b"/proc/self/fd/1023");
+    }
+}
@@ -0,0 +1,952 @@
+use std::fmt;
+use std::sync::Arc;
+
+use grants::Grants;
+
+/// Things a filtered client can be allowed or denied to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Injecting input through XTEST.
+    FakeInput,
+    /// Sending synthetic events to other clients' windows.
+    SendEvent,
+    /// Grabbing the keyboard, or keys on the root window.
+    GrabKeyboard,
+    /// Grabbing the pointer or buttons on windows of other clients.
+    GrabPointer,
+    /// Listening to key events on windows of other clients.
+    SnoopKeys,
+    /// Recording all input and requests through the RECORD extension.
+    RecordInput,
+    /// Reading the contents of other clients' windows.
+    Screenshot,
+    /// Listing the top-level windows with QueryTree on the root window.
+    ListWindows,
+    /// Asking where the pointer is over windows of other clients, or
+    /// where it has been.
+    TrackPointer,
+    /// Asking where windows of other clients are and how big, with
+    /// GetGeometry or TranslateCoordinates.
+    ReadGeometry,
+    /// Replaying the input that triggered a passive grab to other clients,
+    /// after looking at it.
+    ReplayEvents,
+    /// Starting the screen saver, or keeping it and the lock screen from
+    /// coming up.
+    ScreenSaver,
+    /// Grabbing the server, which stops it from serving anyone else.
+    GrabServer,
+    /// Changing the screen size, the monitor modes and layout, or which
+    /// output is the primary one. These outlast the client.
+    ConfigureDisplay,
+    /// Exchanging images with the server through shared memory. Without
+    /// it the client is told there is no MIT-SHM.
+    SharedMemory,
+    /// Handing the server GPU buffers or fences as file descriptors through
+    /// DRI3. Only checked in the strict GPU mode.
+    ImportBuffers,
+    /// Changing the keyboard, modifier or pointer button mappings, which
+    /// apply to every client.
+    RemapInput,
+    /// Ringing the bell or setting the keyboard LEDs through XKB.
+    KeyboardFeedback,
+    /// Moving the keyboard focus to windows of other clients, or taking
+    /// it away from them.
+    StealFocus,
+    /// Owning a selection the policy only lets the client paste from, or
+    /// handing out more of one than the size limit.
+    ClipboardCopy,
+    /// Reading a selection the policy only lets the client copy to.
+    ClipboardPaste,
+    /// Being told whenever a selection changes hands, through XFIXES.
+    WatchSelections,
+    /// Hiding the cursor, or changing cursors other clients use.
+    ChangeCursor,
+    /// Shaping a window that covers the screen so that most clicks go
+    /// through it, to whatever it hides.
+    ClickThrough,
+    /// Changing the properties the policy protects, by atom name.
+    ChangeProtectedProperty,
+    /// Setting properties on windows of other clients, or the root
+    /// window, other than in answer to a paste.
+    ForeignProperties,
+    /// Reading the titles of windows of other clients, which tell of the
+    /// pages, files and chats open in them.
+    ReadWindowTitles,
+    /// Moving, resizing or restacking windows of other clients, or taking
+    /// one into a window of its own, or putting its own into theirs.
+    RearrangeWindows,
+    /// Making an override-redirect window, which the window manager
+    /// doesn't decorate or stack, as big as the screen. Only checked with
+    /// `deny_cover_screen`.
+    CoverScreen,
+    /// Becoming the XSETTINGS manager, or rewriting the settings it hands
+    /// to every toolkit, some of which load modules into other clients.
+    SettingsManager,
+    /// Talking to an input method such as ibus or fcitx over XIM, which
+    /// it is handed the keystrokes through. Only checked with
+    /// `deny_input_methods`.
+    InputMethod,
+}
+
+const ALL_CAPABILITIES: &[Capability] = &[
+    Capability::FakeInput,
+    Capability::SendEvent,
+    Capability::GrabKeyboard,
+    Capability::GrabPointer,
+    Capability::SnoopKeys,
+    Capability::RecordInput,
+    Capability::Screenshot,
+    Capability::ListWindows,
+    Capability::TrackPointer,
+    Capability::ReadGeometry,
+    Capability::ReplayEvents,
+    Capability::ScreenSaver,
+    Capability::GrabServer,
+    Capability::ConfigureDisplay,
+    Capability::SharedMemory,
+    Capability::ImportBuffers,
+    Capability::RemapInput,
+    Capability::KeyboardFeedback,
+    Capability::StealFocus,
+    Capability::ClipboardCopy,
+    Capability::ClipboardPaste,
+    Capability::WatchSelections,
+    Capability::ChangeCursor,
+    Capability::ClickThrough,
+    Capability::ChangeProtectedProperty,
+    Capability::ForeignProperties,
+    Capability::ReadWindowTitles,
+    Capability::RearrangeWindows,
+    Capability::CoverScreen,
+    Capability::SettingsManager,
+    Capability::InputMethod,
+];
+
+/// Core event codes that can be named in an event exception list.
+const EVENT_NAMES: &[(&str, u8)] = &[
+    ("key-press", 2),
+    ("key-release", 3),
+    ("button-press", 4),
+    ("button-release", 5),
+    ("motion-notify", 6),
+    ("expose", 12),
+    ("property-notify", 28),
+    ("selection-clear", 29),
+    ("selection-request", 30),
+    ("selection-notify", 31),
+    ("client-message", 33),
+];
+
+/// Events that are sent as part of ICCCM conventions and are allowed by
+/// default. The window manager is talked to through ClientMessage, and
+/// selection owners answer a paste with a SelectionNotify.
+const DEFAULT_ALLOWED_EVENTS: &[u8] = &[31, 33];
+
+/// The most the X.Org server takes in one request with BIG-REQUESTS.
+const DEFAULT_MAX_REQUEST_SIZE: u64 = 16 << 20;
+
+/// Look up the code of an event by name.
+pub fn event_code(name: &str) -> Option<u8> {
+    EVENT_NAMES
+        .iter()
+        .find(|&&(event, _)| event == name)
+        .map(|&(_, code)| code)
+}
+
+/// Names of all events that can be looked up with `event_code`.
+pub fn event_names() -> Vec<&'static str> {
+    EVENT_NAMES.iter().map(|&(name, _)| name).collect()
+}
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::FakeInput => "fake-input",
+            Capability::SendEvent => "send-event",
+            Capability::GrabKeyboard => "grab-keyboard",
+            Capability::GrabPointer => "grab-pointer",
+            Capability::SnoopKeys => "snoop-keys",
+            Capability::RecordInput => "record-input",
+            Capability::Screenshot => "screenshot",
+            Capability::ListWindows => "list-windows",
+            Capability::TrackPointer => "track-pointer",
+            Capability::ReadGeometry => "read-geometry",
+            Capability::ReplayEvents => "replay-events",
+            Capability::ScreenSaver => "screensaver",
+            Capability::GrabServer => "grab-server",
+            Capability::ConfigureDisplay => "configure-display",
+            Capability::SharedMemory => "shared-memory",
+            Capability::ImportBuffers => "import-buffers",
+            Capability::RemapInput => "remap-input",
+            Capability::KeyboardFeedback => "keyboard-feedback",
+            Capability::StealFocus => "steal-focus",
+            Capability::ClipboardCopy => "clipboard-copy",
+            Capability::ClipboardPaste => "clipboard-paste",
+            Capability::WatchSelections => "watch-selections",
+            Capability::ChangeCursor => "change-cursor",
+            Capability::ClickThrough => "click-through",
+            Capability::ChangeProtectedProperty => "change-protected-property",
+            Capability::ForeignProperties => "foreign-properties",
+            Capability::ReadWindowTitles => "read-window-titles",
+            Capability::RearrangeWindows => "rearrange-windows",
+            Capability::CoverScreen => "cover-screen",
+            Capability::SettingsManager => "settings-manager",
+            Capability::InputMethod => "input-method",
+        }
+    }
+
+    /// Whether wanting this is a sign of an app attacking others, rather
+    /// than one being nosy or just clumsy.
+    pub fn is_high_severity(self) -> bool {
+        match self {
+            Capability::FakeInput
+            | Capability::SendEvent
+            | Capability::GrabKeyboard
+            | Capability::GrabPointer
+            | Capability::SnoopKeys
+            | Capability::RecordInput
+            | Capability::Screenshot
+            | Capability::GrabServer
+            | Capability::RemapInput
+            | Capability::ReplayEvents
+            | Capability::RearrangeWindows
+            | Capability::CoverScreen
+            | Capability::SettingsManager => true,
+            Capability::ListWindows
+            | Capability::TrackPointer
+            | Capability::ReadGeometry
+            | Capability::ScreenSaver
+            | Capability::ConfigureDisplay
+            | Capability::SharedMemory
+            | Capability::ImportBuffers
+            | Capability::KeyboardFeedback
+            | Capability::StealFocus
+            | Capability::ClipboardCopy
+            | Capability::ClipboardPaste
+            | Capability::WatchSelections
+            | Capability::ChangeCursor
+            | Capability::ClickThrough
+            | Capability::ChangeProtectedProperty
+            | Capability::ForeignProperties
+            | Capability::ReadWindowTitles
+            | Capability::InputMethod => false,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        ALL_CAPABILITIES
+            .iter()
+            .find(|cap| cap.name() == name)
+            .cloned()
+    }
+
+    /// Names of all capabilities, for help and error messages.
+    pub fn names() -> Vec<&'static str> {
+        ALL_CAPABILITIES.iter().map(|cap| cap.name()).collect()
+    }
+}
+
+/// How to treat clients whose PID can't be determined, so they can't be
+/// matched against the trusted PIDs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownPeer {
+    Untrusted,
+    Trusted,
+    Reject,
+}
+
+impl UnknownPeer {
+    pub fn from_name(name: &str) -> Option<UnknownPeer> {
+        match name {
+            "untrusted" => Some(UnknownPeer::Untrusted),
+            "trusted" => Some(UnknownPeer::Trusted),
+            "reject" => Some(UnknownPeer::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// How to handle the GLX, DRI3 and Present requests of 3D clients, which
+/// are many and timing sensitive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GpuMode {
+    /// Passed on without being decoded or logged one by one.
+    Passthrough,
+    /// The same, except that DRI3 requests importing buffers or fences
+    /// from file descriptors need `Capability::ImportBuffers`.
+    Strict,
+}
+
+impl GpuMode {
+    pub fn from_name(name: &str) -> Option<GpuMode> {
+        match name {
+            "passthrough" => Some(GpuMode::Passthrough),
+            "strict" => Some(GpuMode::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// A built-in starting point for the policy, for a kind of client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// OpenGL and Vulkan clients on Mesa. Hardware drivers hand the server
+    /// their buffers and fences through DRI3, software rendering and some
+    /// drivers' copies go through MIT-SHM. All of GLX, DRI3 and Present is
+    /// passed on.
+    Gpu,
+    /// Java AWT, Motif and other older toolkits. AWT tells window managers
+    /// apart by the title of their check window and asks where the
+    /// pointer is for drag and drop, Motif keeps its drag and drop tables
+    /// on a window shared by all its clients.
+    Legacy,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Preset> {
+        match name {
+            "gpu" => Some(Preset::Gpu),
+            "legacy" => Some(Preset::Legacy),
+            _ => None,
+        }
+    }
+
+    /// What the preset allows, on top of what's allowed by default.
+    pub fn capabilities(self) -> &'static [Capability] {
+        match self {
+            Preset::Gpu => {
+                &[Capability::SharedMemory, Capability::ImportBuffers]
+            }
+            Preset::Legacy => &[Capability::ReadWindowTitles],
+        }
+    }
+
+    pub fn gpu_mode(self) -> Option<GpuMode> {
+        match self {
+            Preset::Gpu => Some(GpuMode::Passthrough),
+            Preset::Legacy => None,
+        }
+    }
+
+    /// Properties the preset lets the client set on other clients'
+    /// windows, by name.
+    pub fn shared_properties(self) -> &'static [&'static str] {
+        match self {
+            Preset::Gpu => &[],
+            Preset::Legacy => &[
+                "_MOTIF_DRAG_WINDOW",
+                "_MOTIF_DRAG_TARGETS",
+                "_MOTIF_DRAG_ATOMS",
+                "_MOTIF_DRAG_ATOM_PAIRS",
+            ],
+        }
+    }
+
+    /// Whether denied pointer queries get a made up answer.
+    pub fn spoof_pointer(self) -> bool {
+        self == Preset::Legacy
+    }
+}
+
+/// Which way data may flow through a selection, like CLIPBOARD or
+/// PRIMARY.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionAccess {
+    Both,
+    /// The client may own the selection, but not read it from others.
+    CopyOut,
+    /// The client may read the selection from others, but not own it.
+    PasteIn,
+    None,
+}
+
+impl SelectionAccess {
+    pub fn from_name(name: &str) -> Option<SelectionAccess> {
+        match name {
+            "both" => Some(SelectionAccess::Both),
+            "copy-out" => Some(SelectionAccess::CopyOut),
+            "paste-in" => Some(SelectionAccess::PasteIn),
+            "none" => Some(SelectionAccess::None),
+            _ => None,
+        }
+    }
+
+    pub fn can_copy(self) -> bool {
+        self == SelectionAccess::Both || self == SelectionAccess::CopyOut
+    }
+
+    pub fn can_paste(self) -> bool {
+        self == SelectionAccess::Both || self == SelectionAccess::PasteIn
+    }
+}
+
+/// Parse a selection rule like "CLIPBOARD=copy-out".
+pub fn parse_selection_rule(rule: &str) -> Option<(String, SelectionAccess)> {
+    let mut parts = rule.splitn(2, '=');
+    let selection = parts.next()?;
+    let access = SelectionAccess::from_name(parts.next()?)?;
+    if selection.is_empty() {
+        return None;
+    }
+    Some((selection.to_string(), access))
+}
+
+/// What a filtered client interning a denied atom gets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtomDenial {
+    /// A BadValue error.
+    Error,
+    /// An atom under another name, which nobody else looks for, so that
+    /// whatever the atom is for quietly doesn't happen.
+    Spoof,
+}
+
+impl AtomDenial {
+    pub fn from_name(name: &str) -> Option<AtomDenial> {
+        match name {
+            "error" => Some(AtomDenial::Error),
+            "spoof" => Some(AtomDenial::Spoof),
+            _ => None,
+        }
+    }
+}
+
+/// Parse an atom rule like "XdndAware" or "Xdnd*=spoof". A name ending in
+/// `*` stands for all names starting with what comes before it. Without
+/// a mode the client gets an error.
+pub fn parse_atom_rule(rule: &str) -> Option<(String, AtomDenial)> {
+    let (pattern, denial) = match rule.split_once('=') {
+        Some((pattern, mode)) => (pattern, AtomDenial::from_name(mode)?),
+        None => (rule, AtomDenial::Error),
+    };
+    if pattern.is_empty() {
+        return None;
+    }
+    Some((pattern.to_string(), denial))
+}
+
+/// Whether atom `name` matches `pattern`, which may end in `*`.
+fn matches_atom(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// A screen size to show clients in place of the real one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FakeScreen {
+    pub width: u16,
+    pub height: u16,
+    pub dpi: u16,
+}
+
+impl FakeScreen {
+    /// Physical width and height in millimeters, as the protocol has them.
+    pub fn millimeters(self) -> (u16, u16) {
+        let mm = |pixels: u16| {
+            (u32::from(pixels) * 254 / (10 * u32::from(self.dpi))) as u16
+        };
+        (mm(self.width), mm(self.height))
+    }
+}
+
+/// Parse a screen size like "1920x1080", optionally with the DPI as in
+/// "1920x1080@144". The DPI defaults to 96.
+pub fn parse_fake_screen(spec: &str) -> Option<FakeScreen> {
+    let mut parts = spec.splitn(2, '@');
+    let mut size = parts.next()?.splitn(2, 'x');
+    let screen = FakeScreen {
+        width: size.next()?.parse().ok()?,
+        height: size.next()?.parse().ok()?,
+        dpi: match parts.next() {
+            Some(dpi) => dpi.parse().ok()?,
+            None => 96,
+        },
+    };
+    if screen.width == 0 || screen.height == 0 || screen.dpi == 0 {
+        return None;
+    }
+    Some(screen)
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What filtered clients are allowed to do. Everything that isn't
+/// explicitly allowed is denied.
+pub struct Policy {
+    allowed: Vec<Capability>,
+    /// Events that may be sent even without the SendEvent capability.
+    allowed_events: Vec<u8>,
+    /// Answer denied GetImage requests with an image instead of nothing.
+    blank_images: bool,
+    /// Answer denied QueryTree requests with a tree without windows.
+    empty_trees: bool,
+    /// Let QueryTree and the window lists of the root window through, but
+    /// only with the client's own windows in their replies.
+    isolate_windows: bool,
+    /// Answer denied pointer queries with a pointer that is nowhere.
+    spoof_pointer: bool,
+    /// Answer denied geometry queries with a window the size of a pixel
+    /// in the corner of the screen.
+    spoof_geometry: bool,
+    /// Capabilities whose requests are answered with a harmless made up
+    /// reply when denied, whatever the flags above say.
+    spoofed: Vec<Capability>,
+    /// Only tell the client about common depths and TrueColor visuals.
+    safe_visuals: bool,
+    /// Reject requests that are longer than their fields need.
+    paranoid: bool,
+    /// Let the client move the focus between its own windows.
+    own_focus: bool,
+    /// Check `Capability::CoverScreen`.
+    deny_cover_screen: bool,
+    /// Check `Capability::InputMethod`, rather than let XIM through
+    /// whatever the event and selection rules say.
+    deny_input_methods: bool,
+    /// Selections that don't allow both directions, by atom name.
+    selections: Vec<(String, SelectionAccess)>,
+    /// Most data the client may hand out in answer to a single paste.
+    max_selection_size: Option<u64>,
+    /// Furthest into a property, and most of one, in bytes, that the
+    /// client may ask for with GetProperty.
+    max_property_offset: Option<u64>,
+    max_property_length: Option<u64>,
+    /// Longest request any client may send, in bytes.
+    max_request_size: u64,
+    /// Milliseconds to round input event timestamps down to.
+    timestamp_granularity: Option<u32>,
+    /// Pixels to round pointer positions outside the client's windows to.
+    pointer_granularity: Option<u16>,
+    /// Screen size to report instead of the real one.
+    fake_screen: Option<FakeScreen>,
+    /// Vendor string and release number to report instead of the real
+    /// ones.
+    fake_vendor: Option<String>,
+    fake_release: Option<u32>,
+    /// Put in front of the names of the client's own atoms, to keep them
+    /// apart from other clients'.
+    atom_prefix: Option<String>,
+    unknown_peer: UnknownPeer,
+    gpu_mode: GpuMode,
+    /// Properties only changed with `ChangeProtectedProperty`, by name.
+    protected_properties: Vec<String>,
+    /// Properties set on other clients' windows without
+    /// `ForeignProperties`, by name.
+    shared_properties: Vec<String>,
+    /// Atoms filtered clients may not intern, by name pattern.
+    denied_atoms: Vec<(String, AtomDenial)>,
+    /// Decoy atoms no honest client has a reason to touch, by name, with
+    /// their atoms where they could be interned.
+    honeypots: Vec<(String, Option<u32>)>,
+    /// Common atoms interned at startup, see `atoms::COMMON_ATOMS`.
+    known_atoms: Vec<(String, u32)>,
+    /// Capabilities granted by signed window properties, see `grants`.
+    grants: Option<Arc<Grants>>,
+}
+
+impl Policy {
+    pub fn new(allowed: Vec<Capability>) -> Policy {
+        Policy {
+            allowed,
+            allowed_events: DEFAULT_ALLOWED_EVENTS.to_vec(),
+            blank_images: false,
+            empty_trees: false,
+            isolate_windows: false,
+            spoof_pointer: false,
+            spoof_geometry: false,
+            spoofed: Vec::new(),
+            safe_visuals: false,
+            paranoid: false,
+            own_focus: true,
+            deny_cover_screen: false,
+            deny_input_methods: false,
+            selections: Vec::new(),
+            max_selection_size: None,
+            max_property_offset: None,
+            max_property_length: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            timestamp_granularity: None,
+            pointer_granularity: None,
+            fake_screen: None,
+            fake_vendor: None,
+            fake_release: None,
+            atom_prefix: None,
+            unknown_peer: UnknownPeer::Untrusted,
+            gpu_mode: GpuMode::Passthrough,
+            protected_properties: Vec::new(),
+            shared_properties: Vec::new(),
+            denied_atoms: Vec::new(),
+            honeypots: Vec::new(),
+            known_atoms: Vec::new(),
+            grants: None,
+        }
+    }
+
+    /// The allowed capabilities, for showing to people.
+    pub fn describe(&self) -> String {
+        if self.allowed.is_empty() {
+            return "allow=none".to_string();
+        }
+        let names: Vec<&str> =
+            self.allowed.iter().map(|cap| cap.name()).collect();
+        format!("allow={}", names.join(","))
+    }
+
+    /// Replace the default list of events that may always be sent.
+    pub fn set_allowed_events(&mut self, events: Vec<u8>) {
+        self.allowed_events = events;
+    }
+
+    pub fn set_blank_images(&mut self, blank_images: bool) {
+        self.blank_images = blank_images;
+    }
+
+    pub fn blank_images(&self) -> bool {
+        self.blank_images
+    }
+
+    pub fn set_empty_trees(&mut self, empty_trees: bool) {
+        self.empty_trees = empty_trees;
+    }
+
+    pub fn empty_trees(&self) -> bool {
+        self.empty_trees
+    }
+
+    pub fn set_isolate_windows(&mut self, isolate_windows: bool) {
+        self.isolate_windows = isolate_windows;
+    }
+
+    pub fn isolate_windows(&self) -> bool {
+        self.isolate_windows
+    }
+
+    pub fn set_spoof_pointer(&mut self, spoof_pointer: bool) {
+        self.spoof_pointer = spoof_pointer;
+    }
+
+    pub fn spoof_pointer(&self) -> bool {
+        self.spoof_pointer
+    }
+
+    pub fn set_spoof_geometry(&mut self, spoof_geometry: bool) {
+        self.spoof_geometry = spoof_geometry;
+    }
+
+    pub fn spoof_geometry(&self) -> bool {
+        self.spoof_geometry
+    }
+
+    pub fn set_spoofed(&mut self, spoofed: Vec<Capability>) {
+        self.spoofed = spoofed;
+    }
+
+    /// Whether requests needing `capability` are spoofed rather than
+    /// denied when they aren't allowed.
+    pub fn spoofs(&self, capability: Capability) -> bool {
+        self.spoofed.contains(&capability)
+    }
+
+    pub fn set_safe_visuals(&mut self, safe_visuals: bool) {
+        self.safe_visuals = safe_visuals;
+    }
+
+    pub fn safe_visuals(&self) -> bool {
+        self.safe_visuals
+    }
+
+    pub fn set_paranoid(&mut self, paranoid: bool) {
+        self.paranoid = paranoid;
+    }
+
+    pub fn paranoid(&self) -> bool {
+        self.paranoid
+    }
+
+    pub fn set_own_focus(&mut self, own_focus: bool) {
+        self.own_focus = own_focus;
+    }
+
+    pub fn own_focus(&self) -> bool {
+        self.own_focus
+    }
+
+    pub fn set_deny_cover_screen(&mut self, deny_cover_screen: bool) {
+        self.deny_cover_screen = deny_cover_screen;
+    }
+
+    pub fn deny_cover_screen(&self) -> bool {
+        self.deny_cover_screen
+    }
+
+    pub fn set_deny_input_methods(&mut self, deny_input_methods: bool) {
+        self.deny_input_methods = deny_input_methods;
+    }
+
+    pub fn deny_input_methods(&self) -> bool {
+        self.deny_input_methods
+    }
+
+    pub fn set_selection_access(
+        &mut self,
+        selection: String,
+        access: SelectionAccess,
+    ) {
+        self.selections.retain(|(name, _)| *name != selection);
+        self.selections.push((selection, access));
+    }
+
+    /// How the selection named `selection` may be used. Selections
+    /// without a rule, and those we don't know the name of, allow both.
+    pub fn selection_access(&self, selection: Option<&str>) -> SelectionAccess {
+        self.selections
+            .iter()
+            .find(|(name, _)| Some(name.as_str()) == selection)
+            .map(|&(_, access)| access)
+            .unwrap_or(SelectionAccess::Both)
+    }
+
+    pub fn set_max_selection_size(&mut self, size: u64) {
+        self.max_selection_size = Some(size);
+    }
+
+    pub fn max_selection_size(&self) -> Option<u64> {
+        self.max_selection_size
+    }
+
+    pub fn set_max_property_offset(&mut self, offset: u64) {
+        self.max_property_offset = Some(offset);
+    }
+
+    pub fn max_property_offset(&self) -> Option<u64> {
+        self.max_property_offset
+    }
+
+    pub fn set_max_property_length(&mut self, length: u64) {
+        self.max_property_length = Some(length);
+    }
+
+    pub fn max_property_length(&self) -> Option<u64> {
+        self.max_property_length
+    }
+
+    pub fn set_max_request_size(&mut self, size: u64) {
+        self.max_request_size = size;
+    }
+
+    pub fn max_request_size(&self) -> u64 {
+        self.max_request_size
+    }
+
+    pub fn set_atom_prefix(&mut self, prefix: &str) {
+        self.atom_prefix = Some(prefix.to_string());
+    }
+
+    pub fn atom_prefix(&self) -> Option<&str> {
+        self.atom_prefix.as_deref()
+    }
+
+    pub fn set_timestamp_granularity(&mut self, granularity: u32) {
+        self.timestamp_granularity = Some(granularity).filter(|&g| g > 1);
+    }
+
+    pub fn timestamp_granularity(&self) -> Option<u32> {
+        self.timestamp_granularity
+    }
+
+    pub fn set_pointer_granularity(&mut self, granularity: u16) {
+        self.pointer_granularity = Some(granularity).filter(|&g| g > 1);
+    }
+
+    pub fn pointer_granularity(&self) -> Option<u16> {
+        self.pointer_granularity
+    }
+
+    pub fn set_fake_screen(&mut self, screen: FakeScreen) {
+        self.fake_screen = Some(screen);
+    }
+
+    pub fn fake_screen(&self) -> Option<FakeScreen> {
+        self.fake_screen
+    }
+
+    pub fn set_fake_vendor(&mut self, vendor: String) {
+        self.fake_vendor = Some(vendor);
+    }
+
+    pub fn fake_vendor(&self) -> Option<&str> {
+        self.fake_vendor.as_deref()
+    }
+
+    pub fn set_fake_release(&mut self, release: u32) {
+        self.fake_release = Some(release);
+    }
+
+    pub fn fake_release(&self) -> Option<u32> {
+        self.fake_release
+    }
+
+    pub fn set_unknown_peer(&mut self, unknown_peer: UnknownPeer) {
+        self.unknown_peer = unknown_peer;
+    }
+
+    pub fn unknown_peer(&self) -> UnknownPeer {
+        self.unknown_peer
+    }
+
+    pub fn set_gpu_mode(&mut self, gpu_mode: GpuMode) {
+        self.gpu_mode = gpu_mode;
+    }
+
+    pub fn gpu_mode(&self) -> GpuMode {
+        self.gpu_mode
+    }
+
+    pub fn set_protected_properties(&mut self, properties: Vec<String>) {
+        self.protected_properties = properties;
+    }
+
+    pub fn protected_properties(&self) -> &[String] {
+        &self.protected_properties
+    }
+
+    pub fn protects_property(&self, name: &str) -> bool {
+        self.protected_properties
+            .iter()
+            .any(|property| property == name)
+    }
+
+    pub fn set_shared_properties(&mut self, properties: Vec<String>) {
+        self.shared_properties = properties;
+    }
+
+    pub fn shares_property(&self, name: &str) -> bool {
+        self.shared_properties
+            .iter()
+            .any(|property| property == name)
+    }
+
+    pub fn add_denied_atom(&mut self, pattern: String, denial: AtomDenial) {
+        self.denied_atoms.push((pattern, denial));
+    }
+
+    /// How interning atom `name` is denied, by the first rule matching it.
+    pub fn atom_denial(&self, name: &str) -> Option<AtomDenial> {
+        self.denied_atoms
+            .iter()
+            .find(|(pattern, _)| matches_atom(pattern, name))
+            .map(|&(_, denial)| denial)
+    }
+
+    pub fn set_honeypots(&mut self, honeypots: Vec<(String, Option<u32>)>) {
+        self.honeypots = honeypots;
+    }
+
+    pub fn is_honeypot(&self, name: &str) -> bool {
+        self.honeypots.iter().any(|(decoy, _)| decoy == name)
+    }
+
+    /// The name of the decoy with atom `atom`, if it is one.
+    pub fn honeypot_atom(&self, atom: u32) -> Option<&str> {
+        self.honeypots
+            .iter()
+            .find(|&&(_, decoy)| decoy == Some(atom))
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn set_known_atoms(&mut self, atoms: Vec<(String, u32)>) {
+        self.known_atoms = atoms;
+    }
+
+    pub fn known_atoms(&self) -> &[(String, u32)] {
+        &self.known_atoms
+    }
+
+    pub fn set_grants(&mut self, grants: Arc<Grants>) {
+        self.grants = Some(grants);
+    }
+
+    pub fn grants(&self) -> Option<&Grants> {
+        self.grants.as_deref()
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+
+    pub fn allows_event(&self, code: u8) -> bool {
+        self.allowed_events.contains(&code)
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_names() {
+        for &cap in ALL_CAPABILITIES {
+            assert_eq!(Capability::from_name(cap.name()), Some(cap));
+        }
+        assert_eq!(Capability::from_name("nonsense"), None);
+    }
+
+    #[test]
+    fn test_event_exceptions() {
+        let mut policy = Policy::default();
+        assert!(policy.allows_event(event_code("client-message").unwrap()));
+        assert!(!policy.allows_event(event_code("key-press").unwrap()));
+        policy.set_allowed_events(vec![]);
+        assert!(!policy.allows_event(event_code("client-message").unwrap()));
+    }
+
+    #[test]
+    fn test_selection_rules() {
+        let mut policy = Policy::default();
+        let (name, access) =
+            parse_selection_rule("CLIPBOARD=copy-out").unwrap();
+        policy.set_selection_access(name, access);
+        let clipboard = policy.selection_access(Some("CLIPBOARD"));
+        assert!(clipboard.can_copy() && !clipboard.can_paste());
+        assert_eq!(policy.selection_access(None), SelectionAccess::Both);
+        assert_eq!(parse_selection_rule("CLIPBOARD"), None);
+        assert_eq!(parse_selection_rule("=none"), None);
+    }
+
+    #[test]
+    fn test_atom_rules() {
+        let mut policy = Policy::default();
+        let (pattern, denial) = parse_atom_rule("XdndAware").unwrap();
+        assert_eq!(denial, AtomDenial::Error);
+        policy.add_denied_atom(pattern, denial);
+        let (pattern, denial) = parse_atom_rule("Xdnd*=spoof").unwrap();
+        policy.add_denied_atom(pattern, denial);
+        assert_eq!(policy.atom_denial("XdndAware"), Some(AtomDenial::Error));
+        assert_eq!(policy.atom_denial("XdndDrop"), Some(AtomDenial::Spoof));
+        assert_eq!(policy.atom_denial("Xdn"), None);
+        assert_eq!(parse_atom_rule("XdndAware=drop"), None);
+        assert_eq!(parse_atom_rule("=spoof"), None);
+    }
+
+    #[test]
+    fn test_parse_fake_screen() {
+        let screen = parse_fake_screen("1920x1080").unwrap();
+        assert_eq!((screen.width, screen.height, screen.dpi), (1920, 1080, 96));
+        assert_eq!(screen.millimeters(), (508, 285));
+        assert_eq!(parse_fake_screen("1280x800@144").unwrap().dpi, 144);
+        assert_eq!(parse_fake_screen("1920"), None);
+        assert_eq!(parse_fake_screen("1920x1080@0"), None);
+    }
+}
@@ -0,0 +1,190 @@
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Once;
+
+use libc;
+
+// From linux/unistd.h, the same on every architecture since 5.3.
+const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+/// The write end of the pipe `on_sigchld` writes to, once installed.
+static SIGCHLD_PIPE: AtomicI32 = AtomicI32::new(-1);
+static INSTALL_HANDLER: Once = Once::new();
+
+extern "C" fn on_sigchld(_: libc::c_int) {
+    let fd = SIGCHLD_PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // A full pipe has a wakeup pending already.
+        unsafe { libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+/// Tells when a child process exits, through a file descriptor to select
+/// on along with the sockets: a pidfd, or on kernels before 5.3 a pipe
+/// written to on SIGCHLD.
+pub struct ChildExit {
+    pid: i32,
+    fd: RawFd,
+    pidfd: bool,
+}
+
+/// What `ChildExit::try_reap` found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    Code(i32),
+    Signal(i32),
+}
+
+impl Exit {
+    /// What to exit with in turn, the way a shell would: the code, or
+    /// 128 and the signal.
+    pub fn status(self) -> i32 {
+        match self {
+            Exit::Code(code) => code,
+            Exit::Signal(signal) => 128 + signal,
+        }
+    }
+}
+
+impl ChildExit {
+    /// Watch our child `pid`.
+    pub fn watch(pid: i32) -> Result<ChildExit, io::Error> {
+        let error = match pidfd_open(pid) {
+            Ok(fd) => {
+                return Ok(ChildExit {
+                    pid,
+                    fd,
+                    pidfd: true,
+                })
+            }
+            Err(error) => error,
+        };
+        if error.raw_os_error() != Some(libc::ENOSYS) {
+            return Err(error);
+        }
+        debug!("No pidfds, watching for SIGCHLD.");
+        let fd = sigchld_pipe()?;
+        // It may have exited before the handler was there.
+        on_sigchld(libc::SIGCHLD);
+        Ok(ChildExit {
+            pid,
+            fd,
+            pidfd: false,
+        })
+    }
+
+    /// Reap the child if it has exited, without blocking.
+    pub fn try_reap(&mut self) -> Result<Option<Exit>, io::Error> {
+        if !self.pidfd {
+            // Other children may have woken us, the wait below tells.
+            let mut drained = [0u8; 64];
+            while unsafe {
+                libc::read(
+                    self.fd,
+                    drained.as_mut_ptr() as *mut libc::c_void,
+                    drained.len(),
+                )
+            } > 0
+            {}
+        }
+        let mut status = 0;
+        match unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) } {
+            0 => Ok(None),
+            pid if pid < 0 => Err(io::Error::last_os_error()),
+            _ => unsafe {
+                if libc::WIFSIGNALED(status) {
+                    Ok(Some(Exit::Signal(libc::WTERMSIG(status))))
+                } else {
+                    Ok(Some(Exit::Code(libc::WEXITSTATUS(status))))
+                }
+            },
+        }
+    }
+}
+
+impl AsRawFd for ChildExit {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for ChildExit {
+    fn drop(&mut self) {
+        // The SIGCHLD pipe is shared by all.
+        if self.pidfd {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+/// A file descriptor for process `pid` that becomes readable once it has
+/// exited. Fails with ENOSYS on kernels before 5.3.
+pub fn pidfd_open(pid: i32) -> Result<RawFd, io::Error> {
+    let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd as RawFd)
+}
+
+/// The read end of the pipe SIGCHLD is passed on to, setting it up the
+/// first time.
+fn sigchld_pipe() -> Result<RawFd, io::Error> {
+    static READ_END: AtomicI32 = AtomicI32::new(-1);
+    let mut result = Ok(());
+    INSTALL_HANDLER.call_once(|| {
+        result =
+            install_handler().map(|fd| READ_END.store(fd, Ordering::SeqCst))
+    });
+    result?;
+    match READ_END.load(Ordering::SeqCst) {
+        -1 => Err(io::Error::new(io::ErrorKind::NotFound, "no SIGCHLD pipe")),
+        fd => Ok(fd),
+    }
+}
+
+fn install_handler() -> Result<RawFd, io::Error> {
+    let mut fds = [0; 2];
+    let flags = libc::O_NONBLOCK | libc::O_CLOEXEC;
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    SIGCHLD_PIPE.store(fds[1], Ordering::SeqCst);
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    let handler: extern "C" fn(libc::c_int) = on_sigchld;
+    action.sa_sigaction = handler as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+    let installed = unsafe {
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGCHLD, &action, ::std::ptr::null_mut())
+    };
+    if installed < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fds[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::select::{select, FdSet};
+    use std::process::Command;
+
+    #[test]
+    fn test_child_exit() {
+        let mut child =
+            Command::new("sh").args(["-c", "exit 7"]).spawn().unwrap();
+        let mut exit = ChildExit::watch(child.id() as i32).unwrap();
+        let mut readable = FdSet::new();
+        readable.insert(exit.as_raw_fd());
+        let ready = select(None, Some(&mut readable), None, None, None);
+        assert_eq!(ready.unwrap(), 1);
+        assert_eq!(exit.try_reap().unwrap(), Some(Exit::Code(7)));
+        assert_eq!(Exit::Code(7).status(), 7);
+        assert_eq!(Exit::Signal(libc::SIGKILL).status(), 137);
+        // Reaped already.
+        assert!(child.wait().is_err());
+    }
+}
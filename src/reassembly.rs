@@ -0,0 +1,63 @@
+use std::mem;
+
+/// Turns a byte stream back into whole protocol messages.
+///
+/// Reads from a socket can end anywhere, including halfway through a
+/// header. Incomplete messages are kept back until the rest arrives.
+pub struct Reassembler {
+    pending: Vec<u8>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            pending: Vec::new(),
+        }
+    }
+
+    /// Number of bytes held back waiting for the rest of a message.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Append `data` to the stream and return all complete messages, in
+    /// order. `frame` is called with the start of each message and must
+    /// return its total length, or None if more data is needed to tell.
+    pub fn complete<F>(&mut self, data: &[u8], mut frame: F) -> Vec<u8>
+    where
+        F: FnMut(&[u8]) -> Option<usize>,
+    {
+        self.pending.extend_from_slice(data);
+
+        let mut complete = 0;
+        while let Some(length) = frame(&self.pending[complete..]) {
+            if length == 0 || complete + length > self.pending.len() {
+                break;
+            }
+            complete += length;
+        }
+
+        let rest = self.pending.split_off(complete);
+        mem::replace(&mut self.pending, rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages of a length given by their first byte.
+    fn frame(buffer: &[u8]) -> Option<usize> {
+        buffer.first().map(|&length| length as usize)
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.complete(&[2, 0, 3], frame), vec![2, 0]);
+        assert_eq!(reassembler.pending(), 1);
+        assert_eq!(reassembler.complete(&[0], frame), Vec::<u8>::new());
+        assert_eq!(reassembler.complete(&[0, 1], frame), vec![3, 0, 0, 1]);
+        assert_eq!(reassembler.pending(), 0);
+    }
+}
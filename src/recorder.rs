@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+
+/// Ring buffer holding the most recent traffic of a connection.
+///
+/// Whole chunks are kept so that the contents stay parseable, the oldest
+/// ones get dropped once the capacity is exceeded. A capacity of zero
+/// disables recording.
+pub struct FlightRecorder {
+    chunks: VecDeque<Vec<u8>>,
+    len: usize,
+    capacity: usize,
+}
+
+impl FlightRecorder {
+    pub fn new(capacity: usize) -> FlightRecorder {
+        FlightRecorder {
+            chunks: VecDeque::new(),
+            len: 0,
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, buffer: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.chunks.push_back(buffer.to_vec());
+        self.len += buffer.len();
+        while self.len > self.capacity && self.chunks.len() > 1 {
+            let dropped = self.chunks.pop_front().unwrap();
+            self.len -= dropped.len();
+        }
+    }
+
+    /// All recorded traffic, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut contents = Vec::with_capacity(self.len);
+        for chunk in &self.chunks {
+            contents.extend(chunk);
+        }
+        contents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flight_recorder() {
+        let mut recorder = FlightRecorder::new(8);
+        recorder.record(&[1, 2, 3]);
+        recorder.record(&[4, 5, 6]);
+        assert_eq!(recorder.contents(), vec![1, 2, 3, 4, 5, 6]);
+        recorder.record(&[7, 8, 9]);
+        assert_eq!(recorder.contents(), vec![4, 5, 6, 7, 8, 9]);
+        // A single chunk larger than the capacity is still kept.
+        recorder.record(&[0; 16]);
+        assert_eq!(recorder.contents().len(), 16);
+
+        let mut disabled = FlightRecorder::new(0);
+        disabled.record(&[1, 2, 3]);
+        assert!(disabled.contents().is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use policy::Capability;
+use socket;
+
+/// Where the registry listens, if there is a runtime directory. Each user
+/// has their own.
+pub fn registry_path() -> Option<PathBuf> {
+    match socket::runtime_dir() {
+        Ok(dir) => Some(dir.join("rustywin-registry")),
+        Err(e) => {
+            debug!("No registry without a runtime directory: {}", e);
+            None
+        }
+    }
+}
+
+/// An instance's connection to the registry.
+///
+/// The protocol is a line per message. An instance opens with
+/// `register <pid> <display> <policy>` and then reports on its clients
+/// with `connect <conn-id> <pid> <name>`, `deny <conn-id> <capability>`
+/// and `close <conn-id> <summary>`. It is forgotten once it hangs up.
+///
+/// A connection that opens with `list` instead gets the registry
+/// contents back. One that opens with `inspect <display>` gets what is
+/// known about that display, followed by everything its instance reports
+/// from then on.
+pub struct Registration {
+    stream: Mutex<UnixStream>,
+}
+
+impl Registration {
+    /// Register with the registry, if one is running.
+    pub fn new(display: &str, policy: &str) -> Option<Registration> {
+        let path = registry_path()?;
+        let stream = match UnixStream::connect(&path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Not registering at {:?}: {}", path, e);
+                return None;
+            }
+        };
+        info!("Registered at {:?}", path);
+        let registration = Registration {
+            stream: Mutex::new(stream),
+        };
+        let pid = ::std::process::id();
+        registration.send(&format!("register {} {} {}", pid, display, policy));
+        Some(registration)
+    }
+
+    pub fn client_connected(&self, id: usize, pid: &str, name: &str) {
+        self.send(&format!("connect {} {} {}", id, pid, name));
+    }
+
+    pub fn client_denied(&self, id: usize, capability: Capability) {
+        self.send(&format!("deny {} {}", id, capability));
+    }
+
+    pub fn client_closed(&self, id: usize, summary: &str) {
+        self.send(&format!("close {} {}", id, summary));
+    }
+
+    fn send(&self, line: &str) {
+        let mut stream = self.stream.lock().unwrap();
+        if let Err(e) = writeln!(stream, "{}", line) {
+            debug!("Couldn't update the registry: {}", e);
+        }
+    }
+}
+
+/// What the registry knows about one rustywin instance.
+struct Instance {
+    pid: String,
+    display: String,
+    policy: String,
+    clients: BTreeMap<usize, Client>,
+    /// Connections inspecting this instance.
+    watchers: Vec<UnixStream>,
+}
+
+struct Client {
+    pid: String,
+    name: String,
+    denied: u64,
+}
+
+impl Instance {
+    fn describe(&self) -> String {
+        let mut description =
+            format!("{} pid {} {}\n", self.display, self.pid, self.policy);
+        for (id, client) in &self.clients {
+            description.push_str(&format!(
+                "  conn {} pid {} {} denied {}\n",
+                id, client.pid, client.name, client.denied
+            ));
+        }
+        description
+    }
+
+    /// Pass a line on to everyone inspecting, and forget those that
+    /// hung up.
+    fn notify(&mut self, line: &str) {
+        let line = format!("{}\n", line);
+        self.watchers
+            .retain(|mut watcher| watcher.write_all(line.as_bytes()).is_ok());
+    }
+}
+
+/// The instances that registered, by registry connection.
+#[derive(Default)]
+struct Registry {
+    instances: BTreeMap<usize, Instance>,
+}
+
+impl Registry {
+    /// Handle one line from registry connection `conn`. Returns an error
+    /// for lines that make no sense.
+    fn handle_line(&mut self, conn: usize, line: &str) -> Result<(), String> {
+        let mut words = line.splitn(4, ' ');
+        match (words.next(), self.instances.get_mut(&conn)) {
+            (Some("register"), None) => {
+                let pid = words.next().ok_or("register without pid")?;
+                let display = words.next().ok_or("register without display")?;
+                let instance = Instance {
+                    pid: pid.to_string(),
+                    display: display.to_string(),
+                    policy: words.next().unwrap_or("").to_string(),
+                    clients: BTreeMap::new(),
+                    watchers: Vec::new(),
+                };
+                self.instances.insert(conn, instance);
+                return Ok(());
+            }
+            (Some("connect"), Some(instance)) => {
+                let id = parse_id(words.next())?;
+                let client = Client {
+                    pid: words.next().unwrap_or("unknown").to_string(),
+                    name: words.next().unwrap_or("unknown").to_string(),
+                    denied: 0,
+                };
+                instance.clients.insert(id, client);
+                instance.notify(line);
+            }
+            (Some("deny"), Some(instance)) => {
+                let id = parse_id(words.next())?;
+                if let Some(client) = instance.clients.get_mut(&id) {
+                    client.denied += 1;
+                }
+                instance.notify(line);
+            }
+            (Some("close"), Some(instance)) => {
+                instance.clients.remove(&parse_id(words.next())?);
+                instance.notify(line);
+            }
+            _ => return Err(format!("unexpected {:?}", line)),
+        }
+        Ok(())
+    }
+
+    /// Send what's known about `display` to `watcher`, and keep it posted.
+    fn inspect(
+        &mut self,
+        display: &str,
+        mut watcher: UnixStream,
+    ) -> Result<(), String> {
+        let instance = self
+            .instances
+            .values_mut()
+            .find(|instance| instance.display == display)
+            .ok_or_else(|| format!("no instance for display {}", display))?;
+        if watcher.write_all(instance.describe().as_bytes()).is_ok() {
+            instance.watchers.push(watcher);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, conn: usize) {
+        self.instances.remove(&conn);
+    }
+
+    /// The registry contents, an instance per line followed by a line per
+    /// client.
+    fn list(&self) -> String {
+        self.instances.values().map(Instance::describe).collect()
+    }
+}
+
+fn parse_id(word: Option<&str>) -> Result<usize, String> {
+    word.and_then(|id| id.parse().ok())
+        .ok_or_else(|| "bad connection id".to_string())
+}
+
+/// Run the registry until killed.
+pub fn run_registry() -> Result<(), io::Error> {
+    let path = registry_path().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "there's no runtime directory")
+    })?;
+    // A registry that's still answering keeps its socket.
+    if UnixStream::connect(&path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another registry is running",
+        ));
+    }
+    fs::remove_file(&path).ok();
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    info!("Registry listening at {:?}", path);
+
+    let registry = Arc::new(Mutex::new(Registry::default()));
+    let connections = AtomicUsize::new(0);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let conn = connections.fetch_add(1, Ordering::SeqCst);
+        let registry = registry.clone();
+        thread::spawn(move || serve(stream, conn, &registry));
+    }
+    Ok(())
+}
+
+fn serve(mut stream: UnixStream, conn: usize, registry: &Mutex<Registry>) {
+    let reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => {
+            warn!("Couldn't read from registry connection: {}", e);
+            return;
+        }
+    };
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line == "list" {
+            let list = registry.lock().unwrap().list();
+            stream.write_all(list.as_bytes()).ok();
+            break;
+        }
+        if let Some(display) = line.strip_prefix("inspect ") {
+            let display = display.trim();
+            let watcher = stream.try_clone();
+            let result = watcher
+                .map_err(|e| e.to_string())
+                .and_then(|w| registry.lock().unwrap().inspect(display, w));
+            if let Err(e) = result {
+                writeln!(stream, "error: {}", e).ok();
+            }
+            break;
+        }
+        if let Err(e) = registry.lock().unwrap().handle_line(conn, &line) {
+            warn!("Registry connection {}: {}", conn, e);
+            break;
+        }
+    }
+    registry.lock().unwrap().remove(conn);
+}
+
+/// Send `command` to the registry and copy whatever comes back to
+/// stdout, until the registry hangs up. For `rustywin list` and
+/// `rustywin inspect <display>`.
+pub fn query(command: &str) -> Result<(), io::Error> {
+    let path = registry_path().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, "there's no runtime directory")
+    })?;
+    let mut stream = UnixStream::connect(&path)?;
+    writeln!(stream, "{}", command)?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut buffer = [0; 4096];
+    loop {
+        match stream.read(&mut buffer)? {
+            0 => return Ok(()),
+            n => {
+                stdout.write_all(&buffer[..n])?;
+                stdout.flush()?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::default();
+        registry
+            .handle_line(0, "register 10 :1 allow=none")
+            .unwrap();
+        registry
+            .handle_line(1, "register 20 :2 allow=fake-input")
+            .unwrap();
+        registry.handle_line(0, "connect 0 11 xterm").unwrap();
+        registry
+            .handle_line(0, "connect 1 unknown unknown")
+            .unwrap();
+        registry.handle_line(0, "deny 1 screenshot").unwrap();
+        registry
+            .handle_line(0, "close 0 bytes_to_server=12")
+            .unwrap();
+        assert!(registry.handle_line(2, "connect 0 12 xeyes").is_err());
+        assert!(registry.handle_line(0, "register 10 :1").is_err());
+        assert_eq!(
+            registry.list(),
+            ":1 pid 10 allow=none\n  conn 1 pid unknown unknown denied 1\n\
+             :2 pid 20 allow=fake-input\n"
+        );
+        registry.remove(0);
+        assert_eq!(registry.list(), ":2 pid 20 allow=fake-input\n");
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut registry = Registry::default();
+        registry
+            .handle_line(0, "register 10 :1 allow=none")
+            .unwrap();
+        let (watcher, mut inspector) = UnixStream::pair().unwrap();
+        assert!(registry
+            .inspect(":2", watcher.try_clone().unwrap())
+            .is_err());
+        registry.inspect(":1", watcher).unwrap();
+        registry.handle_line(0, "connect 1 11 xterm").unwrap();
+        registry.remove(0);
+        let mut seen = String::new();
+        inspector.read_to_string(&mut seen).unwrap();
+        assert_eq!(seen, ":1 pid 10 allow=none\nconnect 1 11 xterm\n");
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Connection id, time since the connection was accepted in microseconds
+/// and length, all little endian, ahead of each chunk.
+const CHUNK_HEADER: usize = 16;
+
+/// Capture of what the server sent to each client, with timing, for
+/// replaying with `--replay-server`.
+///
+/// Unlike the dumpfile this keeps the chunks of all connections apart,
+/// so they can be played back to separate clients.
+pub struct ServerCapture {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ServerCapture {
+    pub fn new(writer: Box<dyn Write + Send>) -> ServerCapture {
+        ServerCapture {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Add a chunk that connection `conn` was sent `at` after it was
+    /// accepted.
+    pub fn record(
+        &self,
+        conn: usize,
+        at: Duration,
+        buffer: &[u8],
+    ) -> Result<(), io::Error> {
+        let chunk = encode_chunk(conn, at, buffer);
+        self.writer.lock().unwrap().write_all(&chunk)
+    }
+}
+
+fn encode_chunk(conn: usize, at: Duration, buffer: &[u8]) -> Vec<u8> {
+    let micros = at.as_secs() * 1_000_000 + u64::from(at.subsec_micros());
+    let mut chunk = vec![0; CHUNK_HEADER];
+    LittleEndian::write_u32(&mut chunk[0..4], conn as u32);
+    LittleEndian::write_u64(&mut chunk[4..12], micros);
+    LittleEndian::write_u32(&mut chunk[12..16], buffer.len() as u32);
+    chunk.extend(buffer);
+    chunk
+}
+
+/// One chunk of a recorded connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Chunk {
+    at: Duration,
+    data: Vec<u8>,
+}
+
+/// The chunks of each connection in a capture, in the order the
+/// connections were accepted. A chunk that was cut short ends it.
+fn parse_capture(mut data: &[u8]) -> Vec<Vec<Chunk>> {
+    let mut connections: BTreeMap<u32, Vec<Chunk>> = BTreeMap::new();
+    while data.len() >= CHUNK_HEADER {
+        let conn = LittleEndian::read_u32(&data[0..4]);
+        let micros = LittleEndian::read_u64(&data[4..12]);
+        let length = LittleEndian::read_u32(&data[12..16]) as usize;
+        if data.len() < CHUNK_HEADER + length {
+            warn!("Capture ends in the middle of a chunk.");
+            break;
+        }
+        let chunk = Chunk {
+            at: Duration::from_micros(micros),
+            data: data[CHUNK_HEADER..CHUNK_HEADER + length].to_vec(),
+        };
+        connections.entry(conn).or_default().push(chunk);
+        data = &data[CHUNK_HEADER + length..];
+    }
+    connections.into_values().collect()
+}
+
+/// Read the capture at `path`.
+pub fn load_capture(path: &str) -> Result<Vec<u8>, io::Error> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Play the server side of a capture back to whoever connects to
+/// `listener`, instead of proxying to a real server. The n-th client gets
+/// what the n-th recorded connection got, at the same pace divided by
+/// `speed`, or all at once for a speed of zero. What the clients send is
+/// ignored, so the replay only holds up as long as they do the same as
+/// the recorded ones.
+pub fn serve(
+    listener: &UnixListener,
+    capture: &[u8],
+    speed: f64,
+) -> Result<(), io::Error> {
+    let connections = parse_capture(capture);
+    info!("Replaying {} connections.", connections.len());
+    let mut connections = connections.into_iter();
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let chunks = match connections.next() {
+            Some(chunks) => chunks,
+            None => {
+                warn!("Nothing left to replay, turning the client away.");
+                continue;
+            }
+        };
+        thread::spawn(move || {
+            if let Err(e) = replay_connection(stream, &chunks, speed) {
+                info!("Replay ended early: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn replay_connection(
+    mut stream: UnixStream,
+    chunks: &[Chunk],
+    speed: f64,
+) -> Result<(), io::Error> {
+    // The client has to be read from, or it may block on writing.
+    let mut requests = stream.try_clone()?;
+    let drain = thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok(n) = requests.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    let start = Instant::now();
+    for chunk in chunks {
+        if speed > 0.0 {
+            let due = chunk.at.div_f64(speed);
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+        stream.write_all(&chunk.data)?;
+    }
+    info!(
+        "Replayed {} chunks, waiting for the client to leave.",
+        chunks.len()
+    );
+    drain.join().ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capture() {
+        let second = Duration::from_secs(1);
+        let mut capture = encode_chunk(2, Duration::from_millis(5), b"one");
+        capture.extend(encode_chunk(1, second, b"two"));
+        capture.extend(encode_chunk(2, second, b"three"));
+        capture.extend(&encode_chunk(1, second, b"cut")[..18]);
+        let connections = parse_capture(&capture);
+        assert_eq!(connections.len(), 2);
+        assert_eq!(
+            connections[0],
+            vec![Chunk {
+                at: second,
+                data: b"two".to_vec()
+            }]
+        );
+        assert_eq!(connections[1].len(), 2);
+        assert_eq!(connections[1][0].at, Duration::from_millis(5));
+        assert_eq!(connections[1][1].data, b"three");
+    }
+}
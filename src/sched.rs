@@ -0,0 +1,77 @@
+use std::io;
+
+use libc;
+
+/// Parse a list of CPUs like "0,2-3".
+pub fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list.split(',') {
+        let mut range = part.trim().splitn(2, '-');
+        let first = range.next()?.parse::<usize>().ok()?;
+        let last = match range.next() {
+            Some(last) => last.parse::<usize>().ok()?,
+            None => first,
+        };
+        if last < first {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// Restrict the calling thread, and any thread it starts afterwards, to
+/// the given CPUs.
+#[cfg(target_os = "linux")]
+pub fn set_affinity(cpus: &[usize]) -> Result<(), io::Error> {
+    unsafe {
+        let mut set: libc::cpu_set_t = ::std::mem::zeroed();
+        for &cpu in cpus {
+            if cpu >= 8 * ::std::mem::size_of::<libc::cpu_set_t>() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("CPU {} is out of range", cpu),
+                ));
+            }
+            libc::CPU_SET(cpu, &mut set);
+        }
+        let size = ::std::mem::size_of::<libc::cpu_set_t>();
+        if libc::sched_setaffinity(0, size, &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_affinity(_cpus: &[usize]) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "CPU affinity is only supported on Linux",
+    ))
+}
+
+/// Change the nice value of the calling thread. On Linux threads started
+/// afterwards inherit it.
+pub fn set_nice(nice: i32) -> Result<(), io::Error> {
+    // The type of the first argument differs between C libraries.
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list("1"), Some(vec![1]));
+        assert_eq!(parse_cpu_list("0,2-4"), Some(vec![0, 2, 3, 4]));
+        assert_eq!(parse_cpu_list("3-1"), None);
+        assert_eq!(parse_cpu_list("a"), None);
+        assert_eq!(parse_cpu_list(""), None);
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use socket;
+use xauth;
+use xauth::{pad, protocol_error, Credentials};
+
+const QUERY_EXTENSION: u8 = 98;
+const SECURITY_GENERATE_AUTHORIZATION: u8 = 1;
+const TIMEOUT_MASK: u32 = 1;
+const TRUST_LEVEL_MASK: u32 = 2;
+const UNTRUSTED: u32 = 1;
+
+/// The next reply on `server`, skipping events.
+fn read_reply(server: &mut UnixStream) -> Result<Vec<u8>, io::Error> {
+    loop {
+        let mut message = vec![0; 32];
+        server.read_exact(&mut message)?;
+        match message[0] {
+            0 => {
+                return Err(protocol_error(format!(
+                    "error {} from request {}.{}",
+                    message[1], message[10], message[8]
+                )))
+            }
+            1 => {
+                let length = LittleEndian::read_u32(&message[4..8]) as usize;
+                let mut rest = vec![0; 4 * length];
+                server.read_exact(&mut rest)?;
+                message.extend(rest);
+                return Ok(message);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Have the server behind `server`, local display `display`, generate an
+/// untrusted MIT-MAGIC-COOKIE-1 that expires once no client has used it
+/// for `timeout` seconds.
+///
+/// The authorization outlives the connection it was made on, so that isn't
+/// kept.
+pub fn generate_untrusted(
+    mut server: UnixStream,
+    display: usize,
+    timeout: u32,
+) -> Result<Credentials, io::Error> {
+    xauth::connect(&mut server, display)?;
+
+    let name = b"SECURITY";
+    let mut request = vec![QUERY_EXTENSION, 0, 0, 0, 0, 0, 0, 0];
+    LittleEndian::write_u16(&mut request[4..6], name.len() as u16);
+    request.extend(name);
+    pad(&mut request);
+    let length = request.len() as u16 / 4;
+    LittleEndian::write_u16(&mut request[2..4], length);
+    server.write_all(&request)?;
+    let reply = read_reply(&mut server)?;
+    if reply[8] == 0 {
+        return Err(protocol_error(
+            "the server has no SECURITY extension".to_string(),
+        ));
+    }
+
+    let auth_name = b"MIT-MAGIC-COOKIE-1";
+    let major = reply[9];
+    let mut request = vec![major, SECURITY_GENERATE_AUTHORIZATION, 0, 0];
+    request.extend(&[0; 8]);
+    // Without data of our own the server picks the cookie.
+    LittleEndian::write_u16(&mut request[4..6], auth_name.len() as u16);
+    LittleEndian::write_u32(
+        &mut request[8..12],
+        TIMEOUT_MASK | TRUST_LEVEL_MASK,
+    );
+    request.extend(&auth_name[..]);
+    pad(&mut request);
+    for value in &[timeout, UNTRUSTED] {
+        let mut encoded = [0; 4];
+        LittleEndian::write_u32(&mut encoded, *value);
+        request.extend(&encoded);
+    }
+    let length = request.len() as u16 / 4;
+    LittleEndian::write_u16(&mut request[2..4], length);
+    server.write_all(&request)?;
+    let reply = read_reply(&mut server)?;
+    let data_length = LittleEndian::read_u16(&reply[12..14]) as usize;
+    if reply.len() < 32 + data_length {
+        return Err(protocol_error("cookie cut short".to_string()));
+    }
+    info!(
+        "Generated untrusted authorization {}",
+        LittleEndian::read_u32(&reply[8..12])
+    );
+    Ok((auth_name.to_vec(), reply[32..32 + data_length].to_vec()))
+}
+
+/// An Xauthority file with only an untrusted cookie in it, for the client.
+pub struct UntrustedAuthority {
+    path: PathBuf,
+}
+
+impl UntrustedAuthority {
+    pub fn new(
+        credentials: &Credentials,
+    ) -> Result<UntrustedAuthority, io::Error> {
+        let path = socket::runtime_dir()?
+            .join(format!("rustywin-auth-{}", ::std::process::id()));
+        xauth::write_authority(&path, credentials)?;
+        Ok(UntrustedAuthority { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Make the client connect with the cookie.
+    pub fn configure(&self, command: &mut Command) {
+        command.env("XAUTHORITY", &self.path);
+    }
+}
+
+/// Remove what `UntrustedAuthority::new` created at `path`.
+pub fn cleanup(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Couldn't remove {:?}: {}", path, e);
+    }
+}
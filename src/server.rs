@@ -0,0 +1,1327 @@
+use nom::{le_u8, Endianness};
+
+use analyze::{
+    check, read_u16, read_u32, request_label, write_u16, write_u32, Outcome,
+};
+use analyze::{
+    RANDR_GET_CRTC_INFO, RANDR_GET_MONITORS, RANDR_GET_OUTPUT_INFO,
+    RANDR_GET_SCREEN_INFO, RANDR_GET_SCREEN_RESOURCES,
+    RANDR_GET_SCREEN_RESOURCES_CURRENT, RANDR_GET_SCREEN_SIZE_RANGE,
+    XC_MISC_GET_XID_LIST, XC_MISC_GET_XID_RANGE, XI_NAME, XI_RAW_KEY_EVENTS,
+    XI_RAW_POINTER_EVENTS,
+};
+use policy::{Capability, FakeScreen, Policy};
+use state::{ConnectionState, PixmapFormat, Screen, SentRequest, SetupInfo};
+use xim;
+
+// Server to client traffic
+// The server answers the connection setup with a reply that is 8 bytes
+// plus a length in 4-byte units found at offset 6. After that everything
+// it sends is an error, a reply or an event. All of those are 32 bytes,
+// except for replies, which have an additional length (again in 4-byte
+// units) at offset 4. The first byte tells them apart: 0 is an error,
+// 1 a reply, anything else an event code. Events that were produced by
+// SendEvent have the top bit of the code set. Extensions send their
+// events as GenericEvents, which have a length at offset 4 like replies,
+// the extension's major opcode at 1 and its event type at 8.
+
+const MESSAGE_SIZE: usize = 32;
+
+// Input events, which all have the time at offset 4.
+const KEY_PRESS: u8 = 2;
+const MOTION_NOTIFY: u8 = 6;
+const LEAVE_NOTIFY: u8 = 8;
+
+const SELECTION_CLEAR: u8 = 29;
+const SELECTION_REQUEST: u8 = 30;
+const CLIENT_MESSAGE: u8 = 33;
+const GENERIC_EVENT: u8 = 35;
+const SENT_GENERIC_EVENT: u8 = GENERIC_EVENT | 0x80;
+
+// XI2 events have the time after the device.
+const XI_TIME_OFFSET: usize = 12;
+
+const QUERY_TREE: u8 = 15;
+const GET_PROPERTY: u8 = 20;
+
+/// Properties of the root window listing the windows of all clients.
+const CLIENT_LISTS: &[&str] =
+    &["_NET_CLIENT_LIST", "_NET_CLIENT_LIST_STACKING"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SetupReply {
+    status: u8,
+    major_version: u16,
+    minor_version: u16,
+    length: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ServerMessage<'a> {
+    Error {
+        code: u8,
+        sequence: u16,
+    },
+    Reply {
+        datab: u8,
+        sequence: u16,
+        length: u32,
+        data: &'a [u8],
+    },
+    Event {
+        code: u8,
+        sent: bool,
+        data: &'a [u8],
+    },
+    GenericEvent {
+        extension: u8,
+        sent: bool,
+        length: u32,
+        evtype: u16,
+    },
+}
+
+named_args!(setup_reply(e: Endianness)<SetupReply>,
+    do_parse!(
+        status: le_u8
+        >> _reason_length: le_u8
+        >> major_version: u16!(e)
+        >> minor_version: u16!(e)
+        >> length: u16!(e)
+        >> (SetupReply {
+               status,
+               major_version,
+               minor_version,
+               length,
+        })
+    )
+);
+
+named_args!(generic_event(e: Endianness, sent: bool)<ServerMessage<'_>>,
+    do_parse!(
+        extension: le_u8
+        >> _sequence: u16!(e)
+        >> length: u32!(e)
+        >> evtype: u16!(e)
+        >> _rest: take!(MESSAGE_SIZE - 10 + 4 * length as usize)
+        >> (ServerMessage::GenericEvent { extension, sent, length, evtype })
+    )
+);
+
+named_args!(server_message<'a>(e: Endianness)<&'a [u8], ServerMessage<'a>>,
+    switch!(le_u8,
+        0 => do_parse!(
+            code: le_u8
+            >> sequence: u16!(e)
+            >> _rest: take!(MESSAGE_SIZE - 4)
+            >> (ServerMessage::Error { code, sequence })
+        )
+        | 1 => do_parse!(
+            datab: le_u8
+            >> sequence: u16!(e)
+            >> length: u32!(e)
+            >> data: take!(MESSAGE_SIZE - 8 + 4 * length as usize)
+            >> (ServerMessage::Reply { datab, sequence, length, data })
+        )
+        | GENERIC_EVENT => apply!(generic_event, e, false)
+        | SENT_GENERIC_EVENT => apply!(generic_event, e, true)
+        | code => do_parse!(
+            data: take!(MESSAGE_SIZE - 1)
+            >> (ServerMessage::Event {
+                   code: code & 0x7F,
+                   sent: code & 0x80 != 0,
+                   data,
+            })
+        )
+    )
+);
+
+/// Pixmap formats and screens listed in a successful setup reply.
+fn setup_info(reply: &[u8], e: Endianness) -> SetupInfo {
+    let mut info = SetupInfo::default();
+    if reply.len() < 40 || reply[0] != 1 {
+        return info;
+    }
+    info.resource_id_base = read_u32(&reply[12..16], e);
+    info.resource_id_mask = read_u32(&reply[16..20], e);
+    let vendor_length = read_u16(&reply[24..26], e) as usize;
+    let formats = reply[29] as usize;
+    let mut offset = 40 + ((vendor_length + 3) & !3);
+    for _ in 0..formats {
+        if reply.len() < offset + 8 {
+            return info;
+        }
+        info.formats.push(PixmapFormat {
+            depth: reply[offset],
+            bits_per_pixel: reply[offset + 1],
+            scanline_pad: reply[offset + 2],
+        });
+        offset += 8;
+    }
+    for offset in screen_offsets(reply, e) {
+        info.screens.push(Screen {
+            root: read_u32(&reply[offset..offset + 4], e),
+            width: read_u16(&reply[offset + 20..offset + 22], e),
+            height: read_u16(&reply[offset + 22..offset + 24], e),
+            root_visual: read_u32(&reply[offset + 32..offset + 36], e),
+            root_depth: reply[offset + 38],
+        });
+    }
+    info
+}
+
+/// Where the screens in a successful setup reply start, as far as they
+/// are there.
+fn screen_offsets(reply: &[u8], e: Endianness) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    if reply.len() < 40 || reply[0] != 1 {
+        return offsets;
+    }
+    let vendor_length = read_u16(&reply[24..26], e) as usize;
+    let screens = reply[28] as usize;
+    let formats = reply[29] as usize;
+    let mut offset = 40 + ((vendor_length + 3) & !3) + 8 * formats;
+    for _ in 0..screens {
+        if reply.len() < offset + 40 {
+            break;
+        }
+        offsets.push(offset);
+        let depths = reply[offset + 39];
+        offset += 40;
+        for _ in 0..depths {
+            if reply.len() < offset + 8 {
+                return offsets;
+            }
+            let visuals = read_u16(&reply[offset + 2..offset + 4], e) as usize;
+            offset += 8 + 24 * visuals;
+        }
+    }
+    offsets
+}
+
+/// A copy of a GetAtomName reply with `prefix` taken off the name, if the
+/// name starts with it.
+fn strip_atom_prefix(
+    reply: &[u8],
+    prefix: &str,
+    e: Endianness,
+) -> Option<Vec<u8>> {
+    let name_length = read_u16(&reply[8..10], e) as usize;
+    let name = reply.get(32..32 + name_length)?;
+    if !name.starts_with(prefix.as_bytes()) {
+        return None;
+    }
+    let mut stripped = reply[0..32].to_vec();
+    stripped.extend(&name[prefix.len()..]);
+    stripped.resize((stripped.len() + 3) & !3, 0);
+    let length = (stripped.len() as u32 - 32) / 4;
+    write_u32(&mut stripped[4..8], length, e);
+    let name_length = (name_length - prefix.len()) as u16;
+    write_u16(&mut stripped[8..10], name_length, e);
+    Some(stripped)
+}
+
+/// A copy of `reply`, the answer to `request`, without the windows of
+/// other clients, if it lists any. The active window is given as none when
+/// it's not the client's.
+fn isolate_reply(
+    state: &ConnectionState,
+    request: SentRequest,
+    reply: &[u8],
+    e: Endianness,
+) -> Option<Vec<u8>> {
+    let values = match request.opcode {
+        QUERY_TREE => read_u16(&reply[16..18], e) as usize,
+        GET_PROPERTY if reply[1] == 32 => {
+            let name = state.atom_name(request.property?)?;
+            if name == "_NET_ACTIVE_WINDOW" {
+                let window = read_u32(reply.get(32..36)?, e);
+                if window == 0 || state.owns(window) {
+                    return None;
+                }
+                let mut isolated = reply.to_vec();
+                write_u32(&mut isolated[32..36], 0, e);
+                return Some(isolated);
+            }
+            if !CLIENT_LISTS.contains(&name) {
+                return None;
+            }
+            read_u32(&reply[16..20], e) as usize
+        }
+        _ => return None,
+    };
+    let windows: Vec<u32> = reply
+        .get(32..)?
+        .chunks(4)
+        .take(values)
+        .map(|window| read_u32(window, e))
+        .collect();
+    if windows.iter().all(|&window| state.owns(window)) {
+        return None;
+    }
+    let mut isolated = reply[0..32].to_vec();
+    for window in windows.into_iter().filter(|&window| state.owns(window)) {
+        let mut value = [0; 4];
+        write_u32(&mut value, window, e);
+        isolated.extend(&value);
+    }
+    let kept = (isolated.len() as u32 - 32) / 4;
+    write_u32(&mut isolated[4..8], kept, e);
+    if request.opcode == QUERY_TREE {
+        write_u16(&mut isolated[16..18], kept as u16, e);
+    } else {
+        write_u32(&mut isolated[16..20], kept, e);
+    }
+    Some(isolated)
+}
+
+/// A copy of a successful setup reply with the vendor string and release
+/// number replaced as far as given.
+fn fake_vendor_setup(
+    reply: &[u8],
+    vendor: Option<&str>,
+    release: Option<u32>,
+    e: Endianness,
+) -> Vec<u8> {
+    if reply.len() < 40 || reply[0] != 1 {
+        return reply.to_vec();
+    }
+    let vendor_length = read_u16(&reply[24..26], e) as usize;
+    let vendor_end = 40 + ((vendor_length + 3) & !3);
+    if reply.len() < vendor_end {
+        return reply.to_vec();
+    }
+    let mut out = reply[..40].to_vec();
+    if let Some(release) = release {
+        write_u32(&mut out[8..12], release, e);
+    }
+    match vendor {
+        Some(vendor) => {
+            let vendor = &vendor.as_bytes()[..vendor.len().min(0xffff)];
+            write_u16(&mut out[24..26], vendor.len() as u16, e);
+            out.extend(vendor);
+            let padded = (out.len() + 3) & !3;
+            out.resize(padded, 0);
+        }
+        None => out.extend(&reply[40..vendor_end]),
+    }
+    out.extend(&reply[vendor_end..]);
+    let length = ((out.len() - 8) / 4) as u16;
+    write_u16(&mut out[6..8], length, e);
+    out
+}
+
+/// Depths that nearly every server and client has, besides that of the
+/// root window.
+const SAFE_DEPTHS: &[u8] = &[1, 24, 32];
+
+const TRUE_COLOR: u8 = 4;
+
+/// A copy of a successful setup reply that lists only the usual depths,
+/// and TrueColor visuals, plus whatever the root windows use. Replies that
+/// don't parse come back as they are.
+fn safe_visuals_setup(reply: &[u8], e: Endianness) -> Vec<u8> {
+    if reply.len() < 40 || reply[0] != 1 {
+        return reply.to_vec();
+    }
+    let vendor_length = read_u16(&reply[24..26], e) as usize;
+    let screens = reply[28] as usize;
+    let formats = reply[29] as usize;
+    let mut offset = 40 + ((vendor_length + 3) & !3);
+    if reply.len() < offset + 8 * formats {
+        return reply.to_vec();
+    }
+    let root_depths: Vec<u8> = screen_offsets(reply, e)
+        .iter()
+        .map(|&screen| reply[screen + 38])
+        .collect();
+    let safe_depth = |depth: u8| {
+        SAFE_DEPTHS.contains(&depth) || root_depths.contains(&depth)
+    };
+
+    let mut out = reply[..offset].to_vec();
+    let mut kept_formats = 0;
+    for format in reply[offset..offset + 8 * formats].chunks(8) {
+        if safe_depth(format[0]) {
+            out.extend(format);
+            kept_formats += 1;
+        }
+    }
+    out[29] = kept_formats;
+    offset += 8 * formats;
+
+    for _ in 0..screens {
+        if reply.len() < offset + 40 {
+            return reply.to_vec();
+        }
+        let root_visual = read_u32(&reply[offset + 32..offset + 36], e);
+        let screen_start = out.len();
+        out.extend(&reply[offset..offset + 40]);
+        let depths = reply[offset + 39];
+        offset += 40;
+        let mut kept_depths = 0;
+        for _ in 0..depths {
+            if reply.len() < offset + 8 {
+                return reply.to_vec();
+            }
+            let depth = reply[offset];
+            let visuals = read_u16(&reply[offset + 2..offset + 4], e) as usize;
+            let end = offset + 8 + 24 * visuals;
+            if reply.len() < end {
+                return reply.to_vec();
+            }
+            if safe_depth(depth) {
+                let depth_start = out.len();
+                out.extend(&reply[offset..offset + 8]);
+                let mut kept_visuals = 0;
+                for visual in reply[offset + 8..end].chunks(24) {
+                    let id = read_u32(&visual[0..4], e);
+                    if visual[4] == TRUE_COLOR || id == root_visual {
+                        out.extend(visual);
+                        kept_visuals += 1;
+                    }
+                }
+                let count = &mut out[depth_start + 2..depth_start + 4];
+                write_u16(count, kept_visuals, e);
+                kept_depths += 1;
+            }
+            offset = end;
+        }
+        out[screen_start + 39] = kept_depths;
+    }
+    out.extend(&reply[offset..]);
+    let length = ((out.len() - 8) / 4) as u16;
+    write_u16(&mut out[6..8], length, e);
+    out
+}
+
+/// Make every screen in a setup reply the size of `screen`.
+fn fake_setup_geometry(reply: &mut [u8], screen: FakeScreen, e: Endianness) {
+    let (width_mm, height_mm) = screen.millimeters();
+    for offset in screen_offsets(reply, e) {
+        write_u16(&mut reply[offset + 20..offset + 22], screen.width, e);
+        write_u16(&mut reply[offset + 22..offset + 24], screen.height, e);
+        write_u16(&mut reply[offset + 24..offset + 26], width_mm, e);
+        write_u16(&mut reply[offset + 26..offset + 28], height_mm, e);
+    }
+}
+
+/// Write `n` at `offset`, unless the message is too short for it.
+fn put_u16(message: &mut [u8], offset: usize, n: u16, e: Endianness) {
+    if let Some(field) = message.get_mut(offset..offset + 2) {
+        write_u16(field, n, e);
+    }
+}
+
+fn put_u32(message: &mut [u8], offset: usize, n: u32, e: Endianness) {
+    if let Some(field) = message.get_mut(offset..offset + 4) {
+        write_u32(field, n, e);
+    }
+}
+
+/// Make a reply to RANDR request `minor_opcode` tell of a single monitor
+/// the size of `screen`. Every monitor, mode and screen size listed gets
+/// that size, so a client reading any of them sees the same.
+fn fake_geometry_reply(
+    minor_opcode: u8,
+    reply: &mut [u8],
+    screen: FakeScreen,
+    e: Endianness,
+) {
+    let (width_mm, height_mm) = screen.millimeters();
+    match minor_opcode {
+        RANDR_GET_SCREEN_INFO => {
+            let sizes = read_u16(&reply[20..22], e) as usize;
+            for offset in (0..sizes).map(|i| 32 + 8 * i) {
+                put_u16(reply, offset, screen.width, e);
+                put_u16(reply, offset + 2, screen.height, e);
+                put_u16(reply, offset + 4, width_mm, e);
+                put_u16(reply, offset + 6, height_mm, e);
+            }
+        }
+        // The smallest and largest size the screen can be set to.
+        RANDR_GET_SCREEN_SIZE_RANGE => {
+            for &offset in &[8, 12] {
+                put_u16(reply, offset, screen.width, e);
+                put_u16(reply, offset + 2, screen.height, e);
+            }
+        }
+        // The modes come after the CRTCs and outputs.
+        RANDR_GET_SCREEN_RESOURCES | RANDR_GET_SCREEN_RESOURCES_CURRENT => {
+            let crtcs = read_u16(&reply[16..18], e) as usize;
+            let outputs = read_u16(&reply[18..20], e) as usize;
+            let modes = read_u16(&reply[20..22], e) as usize;
+            let start = 32 + 4 * (crtcs + outputs);
+            for offset in (0..modes).map(|i| start + 32 * i) {
+                put_u16(reply, offset + 4, screen.width, e);
+                put_u16(reply, offset + 6, screen.height, e);
+            }
+        }
+        // Disconnected outputs and disabled CRTCs have no size.
+        RANDR_GET_OUTPUT_INFO if read_u32(&reply[16..20], e) != 0 => {
+            put_u32(reply, 16, u32::from(width_mm), e);
+            put_u32(reply, 20, u32::from(height_mm), e);
+        }
+        RANDR_GET_CRTC_INFO if read_u16(&reply[16..18], e) != 0 => {
+            put_u16(reply, 12, 0, e);
+            put_u16(reply, 14, 0, e);
+            put_u16(reply, 16, screen.width, e);
+            put_u16(reply, 18, screen.height, e);
+        }
+        RANDR_GET_MONITORS => {
+            let monitors = read_u32(&reply[12..16], e);
+            let mut offset = 32;
+            for _ in 0..monitors {
+                if reply.len() < offset + 24 {
+                    break;
+                }
+                let outputs = read_u16(&reply[offset + 6..offset + 8], e);
+                put_u16(reply, offset + 8, 0, e);
+                put_u16(reply, offset + 10, 0, e);
+                put_u16(reply, offset + 12, screen.width, e);
+                put_u16(reply, offset + 14, screen.height, e);
+                put_u32(reply, offset + 16, u32::from(width_mm), e);
+                put_u32(reply, offset + 20, u32::from(height_mm), e);
+                offset += 24 + 4 * outputs as usize;
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Total length of the server message at the start of `buffer`, if
+/// enough of it is there to tell.
+fn message_length(buffer: &[u8], e: Endianness, setup: bool) -> Option<usize> {
+    if setup {
+        if buffer.len() < 8 {
+            return None;
+        }
+        return Some(8 + 4 * read_u16(&buffer[6..8], e) as usize);
+    }
+    if buffer.len() < 8 {
+        return None;
+    }
+    if buffer[0] == 1 || buffer[0] & 0x7F == GENERIC_EVENT {
+        Some(MESSAGE_SIZE + 4 * read_u32(&buffer[4..8], e) as usize)
+    } else {
+        Some(MESSAGE_SIZE)
+    }
+}
+
+fn analyze_server_message(
+    state: &mut ConnectionState,
+    policy: &Policy,
+    message: &ServerMessage,
+) -> Outcome {
+    match *message {
+        ServerMessage::Error { code, sequence } => {
+            match request_label(state, sequence) {
+                Some(request) => {
+                    debug!("{} failed with error {}", request, code)
+                }
+                None => debug!("Error {} for request #{}", code, sequence),
+            }
+        }
+        ServerMessage::Reply {
+            sequence, length, ..
+        } => {
+            let length = 32 + 4 * length;
+            match request_label(state, sequence) {
+                Some(request) => {
+                    debug!("{} was answered with {} bytes", request, length)
+                }
+                None => debug!("Reply to #{}, {} bytes", sequence, length),
+            }
+        }
+        ServerMessage::Event { code, sent, .. } => {
+            debug!("Event {}{}", code, if sent { " (sent)" } else { "" });
+        }
+        ServerMessage::GenericEvent {
+            extension,
+            sent,
+            length,
+            evtype,
+        } => {
+            let name = state.extension(extension).unwrap_or("unknown");
+            debug!(
+                "{} event {}, {} bytes{}",
+                name,
+                evtype,
+                32 + 4 * length,
+                if sent { " (sent)" } else { "" }
+            );
+            // Raw events are only selected on the root window, which
+            // XISelectEvents is checked for. These are the ones that got
+            // through anyway.
+            if name == XI_NAME && evtype < 64 {
+                let event = 1 << evtype;
+                if event & XI_RAW_KEY_EVENTS != 0 {
+                    return check(state, policy, Capability::SnoopKeys);
+                }
+                if event & XI_RAW_POINTER_EVENTS != 0 {
+                    return check(state, policy, Capability::TrackPointer);
+                }
+            }
+        }
+    }
+    Outcome::Allowed
+}
+
+/// Update the connection state with what the server told the client.
+fn track_server_message(state: &mut ConnectionState, message: &ServerMessage) {
+    match *message {
+        // A QueryExtension reply has the present flag and major opcode
+        // right after the reply length, an InternAtom reply the atom.
+        ServerMessage::Reply { sequence, data, .. } => {
+            state.answered(sequence);
+            let major_opcode = if data[0] != 0 { data[1] } else { 0 };
+            state.extension_reply(sequence, major_opcode);
+            let e = state.byte_order();
+            state.atom_reply(sequence, read_u32(&data[0..4], e));
+            // A GetAtomName reply has the name after the padded header.
+            let name_length = read_u16(&data[0..2], e) as usize;
+            if let Some(name) = data.get(24..24 + name_length) {
+                state.atom_name_reply(sequence, name);
+            }
+            match state.take_xid_query(sequence) {
+                Some(XC_MISC_GET_XID_RANGE) => {
+                    let start = read_u32(&data[0..4], e);
+                    state.add_xid_range(start, read_u32(&data[4..8], e));
+                }
+                // The ids follow the padded reply header.
+                Some(XC_MISC_GET_XID_LIST) => {
+                    let count = read_u32(&data[0..4], e) as usize;
+                    for id in data[24..].chunks(4).take(count) {
+                        state.add_xid_range(read_u32(id, e), 1);
+                    }
+                }
+                _ => (),
+            }
+            if state.take_input_method_query(sequence) {
+                state.add_input_method(read_u32(&data[0..4], e));
+            }
+        }
+        ServerMessage::Error { sequence, .. } => {
+            state.answered(sequence);
+            state.extension_reply(sequence, 0);
+            state.atom_reply(sequence, 0);
+            state.take_pointer_query(sequence);
+            state.take_geometry_query(sequence);
+            state.take_atom_name_query(sequence);
+            state.take_xid_query(sequence);
+            state.take_input_method_query(sequence);
+        }
+        // Someone else took a selection from the client.
+        ServerMessage::Event {
+            code: SELECTION_CLEAR,
+            data,
+            ..
+        } => {
+            let selection = read_u32(&data[11..15], state.byte_order());
+            state.set_selection_owned(selection, false);
+        }
+        // Someone pastes from a selection the client owns. Old clients
+        // leave the property out and mean the target.
+        ServerMessage::Event {
+            code: SELECTION_REQUEST,
+            data,
+            ..
+        } => {
+            let e = state.byte_order();
+            let requestor = read_u32(&data[11..15], e);
+            let selection = read_u32(&data[15..19], e);
+            let property = match read_u32(&data[23..27], e) {
+                0 => read_u32(&data[19..23], e),
+                property => property,
+            };
+            state.selection_request(requestor, property, selection);
+        }
+        // An input method answers the client's connection with the
+        // window to talk to it on.
+        ServerMessage::Event {
+            code: CLIENT_MESSAGE,
+            data,
+            ..
+        } => {
+            let e = state.byte_order();
+            let message_type = read_u32(&data[7..11], e);
+            if state.atom_name(message_type) == Some(xim::CONNECT_MESSAGE) {
+                let window = read_u32(&data[3..7], e);
+                state
+                    .input_method_connected(window, read_u32(&data[11..15], e));
+            }
+        }
+        ServerMessage::Event { .. } | ServerMessage::GenericEvent { .. } => (),
+    }
+}
+
+/// Round the time of an input event down as `policy` asks for.
+fn coarsen_timestamp(policy: &Policy, event: &mut [u8], e: Endianness) {
+    let granularity = match policy.timestamp_granularity() {
+        Some(granularity) => granularity,
+        None => return,
+    };
+    if (KEY_PRESS..=LEAVE_NOTIFY).contains(&(event[0] & 0x7F)) {
+        let time = read_u32(&event[4..8], e);
+        write_u32(&mut event[4..8], time - time % granularity, e);
+    }
+}
+
+/// Round the time in `field` down as `policy` asks for.
+fn coarsen_time(policy: &Policy, field: &mut [u8], e: Endianness) {
+    if let Some(granularity) = policy.timestamp_granularity() {
+        let time = read_u32(field, e);
+        write_u32(field, time - time % granularity, e);
+    }
+}
+
+/// Round the pointer coordinates at `offsets` in `message` down as
+/// `policy` asks for.
+fn coarsen_pointer(
+    policy: &Policy,
+    message: &mut [u8],
+    offsets: &[usize],
+    e: Endianness,
+) {
+    let granularity = match policy.pointer_granularity() {
+        Some(granularity) => i32::from(granularity),
+        None => return,
+    };
+    for &offset in offsets {
+        let field = &mut message[offset..offset + 2];
+        let value = i32::from(read_u16(field, e) as i16);
+        let rounded = value - value.rem_euclid(granularity);
+        write_u16(field, rounded as i16 as u16, e);
+    }
+}
+
+/// Filters server to client traffic. Returns the data to be passed on
+/// to the client. Incomplete messages are held back until the rest
+/// arrives. When `filtered` is false, messages are only tracked.
+pub fn filter_server_buffer(
+    state: &mut ConnectionState,
+    policy: &Policy,
+    buffer: &[u8],
+    filtered: bool,
+) -> Vec<u8> {
+    let e = state.byte_order();
+    let mut setup = !state.server_setup_done();
+    let complete = state.server_stream.complete(buffer, |message| {
+        let length = message_length(message, e, setup);
+        if length.is_some() {
+            setup = false;
+        }
+        length
+    });
+
+    if state.server_stream.pending() > 0 {
+        debug!("Holding back {} bytes", state.server_stream.pending());
+    }
+
+    let mut out_buff = Vec::with_capacity(complete.len());
+    let mut work_buffer = &complete[..];
+
+    if !state.server_setup_done() && !work_buffer.is_empty() {
+        let (_, reply) = setup_reply(work_buffer, e).unwrap();
+        info!(
+            "Connection setup reply: status {}, X{}.{}",
+            reply.status, reply.major_version, reply.minor_version
+        );
+        state.set_server_setup_done();
+        let length = 8 + 4 * reply.length as usize;
+        state.set_setup_info(setup_info(&work_buffer[0..length], e));
+        let mut setup = work_buffer[0..length].to_vec();
+        if filtered {
+            if policy.fake_vendor().is_some() || policy.fake_release().is_some()
+            {
+                setup = fake_vendor_setup(
+                    &setup,
+                    policy.fake_vendor(),
+                    policy.fake_release(),
+                    e,
+                );
+            }
+            if policy.safe_visuals() {
+                setup = safe_visuals_setup(&setup, e);
+            }
+            if let Some(screen) = policy.fake_screen() {
+                fake_setup_geometry(&mut setup, screen, e);
+            }
+        }
+        out_buff.extend(setup);
+        work_buffer = &work_buffer[length..];
+    }
+
+    while !work_buffer.is_empty() {
+        let (rest, message) = match server_message(work_buffer, e) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                // Can't happen with reassembled input.
+                error!("Failed to parse server message: {:?}", err);
+                out_buff.extend(work_buffer);
+                break;
+            }
+        };
+        let length = work_buffer.len() - rest.len();
+        track_server_message(state, &message);
+        let foreign_pointer = match message {
+            ServerMessage::Reply { sequence, .. } => {
+                state.take_pointer_query(sequence)
+            }
+            ServerMessage::Event {
+                code: MOTION_NOTIFY,
+                data,
+                ..
+            } => !state.owns(read_u32(&data[11..15], e)),
+            _ => false,
+        };
+        let geometry_query = match message {
+            ServerMessage::Reply { sequence, .. } => {
+                state.take_geometry_query(sequence)
+            }
+            _ => None,
+        };
+        let atom_name_query = match message {
+            ServerMessage::Reply { sequence, .. } => {
+                state.take_atom_name_query(sequence)
+            }
+            _ => false,
+        };
+        let isolated_request = match message {
+            ServerMessage::Reply { sequence, .. }
+                if policy.isolate_windows() =>
+            {
+                state.request(sequence)
+            }
+            _ => None,
+        };
+        if let ServerMessage::Reply { sequence, .. } = message {
+            if let Some(reply) = state.take_fake_reply(sequence) {
+                debug!("Replacing reply to #{}", sequence);
+                out_buff.extend(reply);
+                work_buffer = rest;
+                continue;
+            }
+        }
+        let decision = if filtered {
+            analyze_server_message(state, policy, &message)
+        } else {
+            Outcome::Allowed
+        };
+        match decision {
+            Outcome::Allowed | Outcome::Granted(_) if filtered => {
+                let start = out_buff.len();
+                let prefix = policy.atom_prefix().filter(|_| atom_name_query);
+                let reply = &work_buffer[0..length];
+                match prefix
+                    .and_then(|prefix| strip_atom_prefix(reply, prefix, e))
+                    .or_else(|| {
+                        isolated_request.and_then(|request| {
+                            isolate_reply(state, request, reply, e)
+                        })
+                    }) {
+                    Some(reply) => out_buff.extend(reply),
+                    None => out_buff.extend(&work_buffer[0..length]),
+                }
+                let sent = &mut out_buff[start..];
+                match message {
+                    ServerMessage::Reply { .. } => {
+                        // Root and window relative positions of both.
+                        if foreign_pointer {
+                            coarsen_pointer(policy, sent, &[16, 18, 20, 22], e);
+                        }
+                        if let (Some(minor_opcode), Some(screen)) =
+                            (geometry_query, policy.fake_screen())
+                        {
+                            fake_geometry_reply(minor_opcode, sent, screen, e);
+                        }
+                    }
+                    ServerMessage::Event { .. } => {
+                        if foreign_pointer {
+                            coarsen_pointer(policy, sent, &[20, 22, 24, 26], e);
+                        }
+                        coarsen_timestamp(policy, sent, e);
+                    }
+                    ServerMessage::GenericEvent { extension, .. }
+                        if state.extension(extension) == Some(XI_NAME) =>
+                    {
+                        let time =
+                            &mut sent[XI_TIME_OFFSET..XI_TIME_OFFSET + 4];
+                        coarsen_time(policy, time, e);
+                    }
+                    _ => (),
+                }
+            }
+            Outcome::Allowed | Outcome::Granted(_) => {
+                out_buff.extend(&work_buffer[0..length])
+            }
+            Outcome::Denied(_) | Outcome::Spoofed(_) => {
+                info!("Dropping {:?}", message);
+            }
+        }
+        work_buffer = rest;
+    }
+
+    out_buff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::SentRequest;
+
+    /// A setup reply with one pixmap format and one 24 bit screen.
+    fn example_setup_reply() -> Vec<u8> {
+        let mut reply = vec![1, 0, 11, 0, 0, 0, 29, 0];
+        reply.resize(40, 0);
+        reply[14] = 0x40;
+        reply[16..20].copy_from_slice(&[0xff, 0xff, 0x1f, 0]);
+        reply[24] = 4;
+        reply[28] = 1;
+        reply[29] = 1;
+        reply.extend(b"Test");
+        reply.extend(&[24, 32, 32, 0, 0, 0, 0, 0]);
+        let mut screen = vec![0x23, 0x01, 0, 0];
+        screen.resize(40, 0);
+        screen[20..24].copy_from_slice(&[0x80, 0x07, 0x38, 0x04]);
+        screen[32] = 0x21;
+        screen[38] = 24;
+        screen[39] = 1;
+        reply.extend(screen);
+        reply.extend(&[24, 0, 1, 0, 0, 0, 0, 0]);
+        reply.extend(&[0; 24]);
+        assert_eq!(reply.len(), 8 + 4 * 29);
+        reply
+    }
+
+    #[test]
+    fn test_setup_info() {
+        let reply = example_setup_reply();
+        let info = setup_info(&reply, Endianness::Little);
+        let format = PixmapFormat {
+            depth: 24,
+            bits_per_pixel: 32,
+            scanline_pad: 32,
+        };
+        let screen = Screen {
+            root: 0x123,
+            width: 1920,
+            height: 1080,
+            root_visual: 0x21,
+            root_depth: 24,
+        };
+        assert_eq!(info.formats, vec![format]);
+        assert_eq!(info.screens, vec![screen]);
+        assert!(info.in_client_range(0x400001));
+        assert!(!info.in_client_range(0x200001));
+        assert!(!info.in_client_range(0x123));
+        let info = setup_info(&reply[0..60], Endianness::Little);
+        assert!(info.screens.is_empty());
+    }
+
+    #[test]
+    fn test_server_framing() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        // Setup reply with 4 bytes of additional data,
+        // a reply with 4 bytes additional data and an event.
+        let mut stream = vec![1, 0, 11, 0, 0, 0, 1, 0, 0, 0, 0, 0];
+        stream.extend(&[1, 0, 1, 0, 1, 0, 0, 0]);
+        stream.extend(&[0; 28]);
+        stream.extend(&[12, 0, 2, 0]);
+        stream.extend(&[0; 28]);
+
+        // Feed it in awkward pieces, everything must come through whole.
+        let policy = Policy::default();
+        let first =
+            filter_server_buffer(&mut state, &policy, &stream[0..10], true);
+        assert!(first.is_empty());
+        let second =
+            filter_server_buffer(&mut state, &policy, &stream[10..40], true);
+        assert_eq!(second, &stream[0..12]);
+        let third =
+            filter_server_buffer(&mut state, &policy, &stream[40..], true);
+        assert_eq!(third, &stream[12..]);
+        assert_eq!(state.server_stream.pending(), 0);
+    }
+
+    #[test]
+    fn test_generic_events() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        state.query_extension(1, XI_NAME);
+        state.extension_reply(1, 131);
+        let mut policy = Policy::default();
+        policy.set_timestamp_granularity(100);
+        // An XI2 KeyPress at 1234ms with 8 more bytes, a RawKeyPress and
+        // a core KeyPress, fed in pieces.
+        let mut stream = vec![35, 131, 1, 0, 2, 0, 0, 0, 2, 0, 2, 0];
+        stream.extend(&[0xD2, 0x04, 0, 0]);
+        stream.resize(40, 0xee);
+        let mut raw = vec![35, 131, 2, 0, 0, 0, 0, 0, 13, 0];
+        raw.resize(32, 0);
+        stream.extend(&raw);
+        let mut key_press = vec![2, 38, 3, 0];
+        key_press.resize(32, 0);
+        stream.extend(&key_press);
+
+        let mut out = Vec::new();
+        for piece in stream.chunks(20) {
+            out.extend(filter_server_buffer(&mut state, &policy, piece, true));
+        }
+        assert_eq!(out.len(), 72);
+        assert_eq!(&out[12..16], &[0xB0, 0x04, 0, 0]);
+        assert_eq!(&out[32..40], &[0xee; 8]);
+        assert_eq!(&out[40..], &key_press[..]);
+
+        let policy = Policy::new(vec![Capability::SnoopKeys]);
+        let out = filter_server_buffer(&mut state, &policy, &raw, true);
+        assert_eq!(out, raw);
+    }
+
+    #[test]
+    fn test_coarsen_timestamps() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        let mut policy = Policy::default();
+        policy.set_timestamp_granularity(100);
+        // A KeyPress at 1234ms, and a PropertyNotify that is left alone.
+        let mut stream = vec![2, 38, 1, 0, 0xD2, 0x04, 0, 0];
+        stream.resize(32, 0);
+        stream.extend(&[28, 0, 1, 0, 0xD2, 0x04, 0, 0]);
+        stream.resize(64, 0);
+
+        let out = filter_server_buffer(&mut state, &policy, &stream, true);
+        assert_eq!(&out[4..8], &[0xB0, 0x04, 0, 0]);
+        assert_eq!(&out[32..], &stream[32..]);
+        let out = filter_server_buffer(&mut state, &policy, &stream, false);
+        assert_eq!(out, stream);
+    }
+
+    #[test]
+    fn test_coarsen_pointer() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        let mut policy = Policy::default();
+        policy.set_pointer_granularity(100);
+        // MotionNotify at (1234, -5) on the root window, then the same on
+        // one of the client's windows.
+        let mut root_motion = vec![6, 0, 1, 0, 0, 0, 0, 0, 0x23, 1, 0, 0];
+        root_motion.extend(&[0x23, 1, 0, 0, 0, 0, 0, 0]);
+        root_motion.extend(&[0xD2, 0x04, 0xFB, 0xFF, 0xD2, 0x04, 0xFB, 0xFF]);
+        root_motion.resize(32, 0);
+        let mut own_motion = root_motion.clone();
+        own_motion[12..16].copy_from_slice(&[1, 0, 0x40, 0]);
+
+        let out = filter_server_buffer(&mut state, &policy, &root_motion, true);
+        assert_eq!(&out[20..28], &[0xB0, 4, 0x9C, 0xFF, 0xB0, 4, 0x9C, 0xFF]);
+        let out = filter_server_buffer(&mut state, &policy, &own_motion, true);
+        assert_eq!(out, own_motion);
+
+        // Only the reply to a foreign QueryPointer is touched.
+        let mut reply = vec![1, 1, 3, 0, 0, 0, 0, 0, 0x23, 1, 0, 0, 0, 0, 0, 0];
+        reply.extend(&[0xD2, 0x04, 0x37, 0, 0xD2, 0x04, 0x37, 0]);
+        reply.resize(32, 0);
+        state.foreign_pointer_query(3);
+        let out = filter_server_buffer(&mut state, &policy, &reply, true);
+        assert_eq!(&out[16..24], &[0xB0, 4, 0, 0, 0xB0, 4, 0, 0]);
+        let out = filter_server_buffer(&mut state, &policy, &reply, true);
+        assert_eq!(out, reply);
+    }
+
+    #[test]
+    fn test_xid_replies() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        let policy = Policy::default();
+        // 16 ids from 0x400100, then a list of two.
+        let mut range =
+            vec![1, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0x40, 0, 16, 0, 0, 0];
+        range.resize(32, 0);
+        let mut list = vec![1, 0, 2, 0, 2, 0, 0, 0, 2, 0, 0, 0];
+        list.resize(32, 0);
+        list.extend(&[7, 0, 0x40, 0, 9, 0, 0x40, 0]);
+        state.xid_query(1, XC_MISC_GET_XID_RANGE);
+        state.xid_query(2, XC_MISC_GET_XID_LIST);
+        range.extend(list);
+        let out = filter_server_buffer(&mut state, &policy, &range, true);
+        assert_eq!(out, range);
+        assert!(state.owns(0x400100));
+        assert!(state.owns(0x40010f));
+        assert!(!state.owns(0x400110));
+        assert!(state.owns(0x400007));
+        assert!(state.owns(0x400009));
+        assert!(!state.owns(0x400008));
+    }
+
+    #[test]
+    fn test_input_method_windows() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        state.seed_atoms(&[("_XIM_XCONNECT".to_string(), 0x151)]);
+        let policy = Policy::default();
+        // ibus owns its selection with 0x800001, then answers the
+        // connection on 0x400002 with 0x800002.
+        let mut owner = vec![1, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0x80, 0];
+        owner.resize(32, 0);
+        let mut connected = vec![0xA1, 32, 0, 0, 2, 0, 0x40, 0, 0x51, 1, 0, 0];
+        connected.extend(&[2, 0, 0x80, 0]);
+        connected.resize(32, 0);
+        state.input_method_query(1);
+        let out = filter_server_buffer(&mut state, &policy, &owner, true);
+        assert_eq!(out, owner);
+        assert!(state.is_input_method(0x800001));
+        filter_server_buffer(&mut state, &policy, &connected, true);
+        assert!(!state.is_input_method(0x800002));
+        state.input_method_connect(0x400002);
+        filter_server_buffer(&mut state, &policy, &connected, true);
+        assert!(state.is_input_method(0x800002));
+    }
+
+    #[test]
+    fn test_request_correlation() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        let policy = Policy::default();
+        let sent = |opcode, property| SentRequest {
+            opcode,
+            minor_opcode: 0,
+            property,
+        };
+        state.sent_request(1, sent(8, None));
+        // WM_NAME
+        state.sent_request(2, sent(20, Some(39)));
+        state.sent_request(3, sent(43, None));
+        let label = |state: &ConnectionState, sequence| {
+            request_label(state, sequence).map(|label| label.to_string())
+        };
+        let mut reply = vec![1, 8, 2, 0, 0, 0, 0, 0];
+        reply.resize(32, 0);
+        filter_server_buffer(&mut state, &policy, &reply, true);
+        assert_eq!(
+            label(&state, 2).as_deref(),
+            Some("GetProperty #2 on WM_NAME")
+        );
+        assert_eq!(label(&state, 1), None);
+        // An error for the last one settles the rest.
+        let mut error = vec![0, 8, 3, 0];
+        error.resize(32, 0);
+        filter_server_buffer(&mut state, &policy, &error, true);
+        assert_eq!(label(&state, 3).as_deref(), Some("Opcode 43 #3"));
+        assert_eq!(label(&state, 2), None);
+
+        // Sequence numbers wrap around.
+        let mut state = ConnectionState::new();
+        state.sent_request(0xffff, sent(127, None));
+        state.sent_request(0, sent(43, None));
+        state.answered(0);
+        assert_eq!(state.request(0), Some(sent(43, None)));
+    }
+
+    #[test]
+    fn test_fake_screen() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        let mut policy = Policy::default();
+        policy.set_fake_screen(FakeScreen {
+            width: 1920,
+            height: 1080,
+            dpi: 96,
+        });
+        let setup = example_setup_reply();
+        let out = filter_server_buffer(&mut state, &policy, &setup, true);
+        // The screen follows the vendor and the pixmap format.
+        assert_eq!(&out[72..80], &[0x80, 7, 0x38, 4, 0xFC, 1, 0x1D, 1]);
+        assert_eq!(&out[..72], &setup[..72]);
+        assert_eq!(&out[80..], &setup[80..]);
+
+        // A GetCrtcInfo reply of a 2560x1440 monitor at (1920, 0).
+        state.geometry_query(2, RANDR_GET_CRTC_INFO);
+        let mut crtc = vec![1, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        crtc.extend(&[0x80, 7, 0, 0, 0, 0x0A, 0xA0, 5]);
+        crtc.resize(32, 0);
+        let out = filter_server_buffer(&mut state, &policy, &crtc, true);
+        assert_eq!(&out[12..20], &[0, 0, 0, 0, 0x80, 7, 0x38, 4]);
+        let out = filter_server_buffer(&mut state, &policy, &crtc, true);
+        assert_eq!(out, crtc);
+    }
+
+    #[test]
+    fn test_safe_visuals() {
+        fn visual(id: u8, class: u8) -> Vec<u8> {
+            let mut visual = vec![id, 0, 0, 0, class, 8, 0, 1];
+            visual.resize(24, 0);
+            visual
+        }
+        let mut reply = vec![1, 0, 11, 0, 0, 0, 0, 0];
+        reply.resize(40, 0);
+        reply[28] = 1;
+        reply[29] = 3;
+        for &depth in &[1, 8, 24] {
+            reply.extend(&[depth, depth, 32, 0, 0, 0, 0, 0]);
+        }
+        let mut screen = vec![0x23, 0x01, 0, 0];
+        screen.resize(40, 0);
+        screen[32] = 0x21;
+        screen[38] = 24;
+        screen[39] = 2;
+        reply.extend(screen);
+        reply.extend(&[8, 0, 1, 0, 0, 0, 0, 0]);
+        reply.extend(visual(0x20, 3));
+        reply.extend(&[24, 0, 2, 0, 0, 0, 0, 0]);
+        reply.extend(visual(0x21, TRUE_COLOR));
+        reply.extend(visual(0x22, 5));
+        let length = (reply.len() as u16 - 8) / 4;
+        write_u16(&mut reply[6..8], length, Endianness::Little);
+
+        let out = safe_visuals_setup(&reply, Endianness::Little);
+        assert_eq!(out.len(), 40 + 16 + 40 + 8 + 24);
+        assert_eq!(read_u16(&out[6..8], Endianness::Little), 30);
+        assert_eq!(out[29], 2);
+        assert_eq!(out[48], 24);
+        assert_eq!(out[56 + 39], 1);
+        assert_eq!(&out[96..100], &[24, 0, 1, 0]);
+        assert_eq!(out[104], 0x21);
+        let info = setup_info(&out, Endianness::Little);
+        assert_eq!(
+            info.screens,
+            setup_info(&reply, Endianness::Little).screens
+        );
+        assert_eq!(info.formats.len(), 2);
+    }
+
+    #[test]
+    fn test_fake_vendor() {
+        let reply = example_setup_reply();
+        let e = Endianness::Little;
+        let out = fake_vendor_setup(&reply, Some("X.Org"), Some(12101004), e);
+        assert_eq!(read_u32(&out[8..12], e), 12101004);
+        assert_eq!(read_u16(&out[24..26], e), 5);
+        assert_eq!(&out[40..48], b"X.Org\0\0\0");
+        assert_eq!(&out[48..], &reply[44..]);
+        assert_eq!(out.len(), 8 + 4 * read_u16(&out[6..8], e) as usize);
+        let info = setup_info(&out, e);
+        assert_eq!(info.screens, setup_info(&reply, e).screens);
+
+        let out = fake_vendor_setup(&reply, None, Some(1), e);
+        assert_eq!(&out[12..], &reply[12..]);
+    }
+
+    #[test]
+    fn test_isolate_windows() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        state.set_setup_info(SetupInfo {
+            resource_id_base: 0x400000,
+            resource_id_mask: 0x1fffff,
+            ..SetupInfo::default()
+        });
+        state.seed_atoms(&[
+            ("_NET_CLIENT_LIST".to_string(), 0x150),
+            ("_NET_ACTIVE_WINDOW".to_string(), 0x151),
+        ]);
+        let mut policy = Policy::default();
+        policy.set_isolate_windows(true);
+        let sent = |opcode, property| SentRequest {
+            opcode,
+            minor_opcode: 0,
+            property,
+        };
+        let window = |id: u32| id.to_le_bytes();
+
+        // A QueryTree on the root window with one foreign child.
+        state.sent_request(1, sent(QUERY_TREE, None));
+        let mut tree = vec![1, 0, 1, 0, 3, 0, 0, 0, 0x23, 1, 0, 0];
+        tree.extend(&[0, 0, 0, 0, 3, 0]);
+        tree.resize(32, 0);
+        for &id in &[0x400001, 0x200005, 0x400002] {
+            tree.extend(&window(id));
+        }
+        let out = filter_server_buffer(&mut state, &policy, &tree, true);
+        let mut isolated = tree[0..32].to_vec();
+        isolated[4] = 2;
+        isolated[16] = 2;
+        isolated.extend(&window(0x400001));
+        isolated.extend(&window(0x400002));
+        assert_eq!(out, isolated);
+
+        // _NET_CLIENT_LIST with only foreign windows.
+        state.sent_request(2, sent(GET_PROPERTY, Some(0x150)));
+        let mut list = vec![1, 32, 2, 0, 2, 0, 0, 0, 33, 0, 0, 0];
+        list.extend(&[0, 0, 0, 0, 2, 0, 0, 0]);
+        list.resize(32, 0);
+        list.extend(&window(0x200005));
+        list.extend(&window(0x600001));
+        let out = filter_server_buffer(&mut state, &policy, &list, true);
+        let mut empty = list[0..32].to_vec();
+        empty[4] = 0;
+        empty[16] = 0;
+        assert_eq!(out, empty);
+
+        state.sent_request(3, sent(GET_PROPERTY, Some(0x151)));
+        let mut active = vec![1, 32, 3, 0, 1, 0, 0, 0, 33, 0, 0, 0];
+        active.extend(&[0, 0, 0, 0, 1, 0, 0, 0]);
+        active.resize(32, 0);
+        active.extend(&window(0x200005));
+        let out = filter_server_buffer(&mut state, &policy, &active, true);
+        assert_eq!(&out[32..36], &[0, 0, 0, 0]);
+        // The client's own, which it may know.
+        state.sent_request(4, sent(GET_PROPERTY, Some(0x151)));
+        active[2] = 4;
+        active[32..36].copy_from_slice(&window(0x400001));
+        let out = filter_server_buffer(&mut state, &policy, &active, true);
+        assert_eq!(out, active);
+
+        // Left alone unless asked for.
+        policy.set_isolate_windows(false);
+        state.sent_request(5, sent(QUERY_TREE, None));
+        tree[2] = 5;
+        let out = filter_server_buffer(&mut state, &policy, &tree, true);
+        assert_eq!(out, tree);
+    }
+
+    #[test]
+    fn test_strip_atom_prefix() {
+        let mut state = ConnectionState::new();
+        state.set_byte_order(Endianness::Little);
+        state.set_server_setup_done();
+        let mut policy = Policy::default();
+        policy.set_atom_prefix("_P_");
+        // GetAtomName replies with "_P__MINE" and "_P_", then one to
+        // something else.
+        let mut reply = vec![1, 0, 4, 0, 2, 0, 0, 0, 8, 0];
+        reply.resize(32, 0);
+        reply.extend(b"_P__MINE");
+        state.atom_name_query(4, 0x150);
+        let out = filter_server_buffer(&mut state, &policy, &reply, true);
+        assert_eq!(state.atom_name(0x150), Some("_P__MINE"));
+        let mut stripped = vec![1, 0, 4, 0, 2, 0, 0, 0, 5, 0];
+        stripped.resize(32, 0);
+        stripped.extend(b"_MINE\0\0\0");
+        assert_eq!(out, stripped);
+
+        let mut bare = vec![1, 0, 5, 0, 1, 0, 0, 0, 3, 0];
+        bare.resize(32, 0);
+        bare.extend(b"_P_\0");
+        state.atom_name_query(5, 0x151);
+        let out = filter_server_buffer(&mut state, &policy, &bare, true);
+        assert_eq!(out.len(), 32);
+        assert_eq!(&out[4..10], &[0, 0, 0, 0, 0, 0]);
+
+        reply[2] = 6;
+        let out = filter_server_buffer(&mut state, &policy, &reply, true);
+        assert_eq!(out, reply);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use analyze::Filtered;
+use policy::Capability;
+use recorder::FlightRecorder;
+
+/// Bookkeeping for a single proxied client connection.
+pub struct Session {
+    id: usize,
+    pub recorder: Mutex<FlightRecorder>,
+    pub summary: Mutex<Summary>,
+    /// Capabilities granted through the control socket, until when.
+    exceptions: Mutex<Vec<(Capability, Instant)>>,
+}
+
+/// What a connection has done so far, reported when it closes.
+pub struct Summary {
+    start: Instant,
+    pub bytes_to_server: u64,
+    pub bytes_to_client: u64,
+    /// Filtered requests, and those we couldn't make sense of.
+    pub requests: u64,
+    pub parse_failures: u64,
+    granted: HashMap<Capability, u64>,
+    denied: HashMap<Capability, u64>,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Summary {
+            start: Instant::now(),
+            bytes_to_server: 0,
+            bytes_to_client: 0,
+            requests: 0,
+            parse_failures: 0,
+            granted: HashMap::new(),
+            denied: HashMap::new(),
+        }
+    }
+
+    pub fn add_filtered(&mut self, filtered: &Filtered) {
+        self.requests += filtered.requests as u64;
+        self.parse_failures += filtered.parse_failures as u64;
+        for &capability in &filtered.granted {
+            *self.granted.entry(capability).or_insert(0) += 1;
+        }
+        for &capability in &filtered.denials {
+            *self.denied.entry(capability).or_insert(0) += 1;
+        }
+    }
+
+    /// The summary as key=value pairs, as used by the audit log.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let elapsed = self.start.elapsed();
+        let millis =
+            elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        vec![
+            ("duration_ms", millis.to_string()),
+            ("bytes_to_server", self.bytes_to_server.to_string()),
+            ("bytes_to_client", self.bytes_to_client.to_string()),
+            ("requests", self.requests.to_string()),
+            ("parse_failures", self.parse_failures.to_string()),
+            ("granted", capability_counts(&self.granted)),
+            ("denied", capability_counts(&self.denied)),
+        ]
+    }
+}
+
+/// Counts like "screenshot:2,send-event:1", in a stable order.
+fn capability_counts(counts: &HashMap<Capability, u64>) -> String {
+    let mut counts: Vec<_> = counts
+        .iter()
+        .map(|(capability, count)| format!("{}:{}", capability, count))
+        .collect();
+    counts.sort();
+    if counts.is_empty() {
+        "none".to_string()
+    } else {
+        counts.join(",")
+    }
+}
+
+impl Session {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Let the connection do what needs `capability` for `duration`,
+    /// whatever the policy says.
+    pub fn grant(&self, capability: Capability, duration: Duration) {
+        info!(
+            "Granting {} to connection {} for {:?}",
+            capability, self.id, duration
+        );
+        self.exceptions
+            .lock()
+            .unwrap()
+            .push((capability, Instant::now() + duration));
+    }
+
+    /// Drop the exceptions that ran out and return the others.
+    pub fn live_exceptions(&self) -> Vec<(Capability, Instant)> {
+        let now = Instant::now();
+        let mut exceptions = self.exceptions.lock().unwrap();
+        exceptions.retain(|&(capability, until)| {
+            if until <= now {
+                info!("Connection {} lost {} again", self.id, capability);
+            }
+            until > now
+        });
+        exceptions.clone()
+    }
+}
+
+/// Table of all live connections, shared with the control socket.
+pub struct Sessions {
+    next_id: AtomicUsize,
+    recorder_size: usize,
+    table: Mutex<BTreeMap<usize, Arc<Session>>>,
+}
+
+impl Sessions {
+    pub fn new(recorder_size: usize) -> Sessions {
+        Sessions {
+            next_id: AtomicUsize::new(1),
+            recorder_size,
+            table: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn register(&self) -> Arc<Session> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let session = Arc::new(Session {
+            id,
+            recorder: Mutex::new(FlightRecorder::new(self.recorder_size)),
+            summary: Mutex::new(Summary::new()),
+            exceptions: Mutex::new(Vec::new()),
+        });
+        self.table.lock().unwrap().insert(id, session.clone());
+        session
+    }
+
+    pub fn remove(&self, id: usize) {
+        self.table.lock().unwrap().remove(&id);
+    }
+
+    pub fn get(&self, id: usize) -> Option<Arc<Session>> {
+        self.table.lock().unwrap().get(&id).cloned()
+    }
+}
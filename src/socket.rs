@@ -1,5 +1,6 @@
 use std;
-use std::path::Path;
+use std::env;
+use std::path::{Path, PathBuf};
 
 use dirs;
 use display::*;
@@ -7,9 +8,11 @@ use itertools::Itertools;
 use libc;
 use nix::fcntl::{flock, FlockArg};
 use nix::unistd::getpid;
-use std::fs::{remove_file, OpenOptions};
+use std::fs::{remove_file, DirBuilder, OpenOptions};
+use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, ErrorKind, Seek, SeekFrom};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 
@@ -43,15 +46,63 @@ impl SocketConnection {
     pub fn get_display(&self) -> &str {
         self.client_display_name.as_str()
     }
+
+    /// The path of the socket clients connect to.
+    pub fn listen_path(&self) -> &str {
+        self.client_socket_name.as_str()
+    }
+
+    /// Make the X server socket path relative to `root`, for running
+    /// chrooted there. Fails if the socket is outside of it.
+    pub fn rebase(&mut self, root: &str) -> bool {
+        let relative =
+            match Path::new(&self.server_socket_name).strip_prefix(root) {
+                Ok(relative) => Path::new("/").join(relative),
+                Err(_) => return false,
+            };
+        self.server_socket_name = relative.to_string_lossy().into_owned();
+        true
+    }
 }
 
 // /tmp/.X11-unix/Xn
 // Unix domain socket for display number n
-const X11_SOCKET_DIR: &str = "/tmp/.X11-unix/";
+pub const X11_SOCKET_DIR: &str = "/tmp/.X11-unix/";
 
 // Store the list of sockets we create, used for cleanup.
 // Format: lines of "pid socket_path"
-const X11_SOCKET_LIST: &str = ".rustywin_sockets";
+const X11_SOCKET_LIST: &str = "rustywin-sockets";
+
+// How many display numbers past the highest in use we try to claim.
+const MAX_DISPLAY_ATTEMPTS: usize = 64;
+
+/// The directory rustywin keeps its sockets and files in while running,
+/// per user: $XDG_RUNTIME_DIR, or else one of our own in the temporary
+/// directory, made private to this user.
+pub fn runtime_dir() -> Result<PathBuf, io::Error> {
+    if let Some(dir) = env::var_os("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let uid = unsafe { libc::getuid() };
+    let dir = env::temp_dir().join(format!("rustywin-{}", uid));
+    if let Err(e) = DirBuilder::new().mode(0o700).create(&dir) {
+        if e.kind() != ErrorKind::AlreadyExists {
+            return Err(e);
+        }
+    }
+    // Someone else may have made it first, for us to use theirs.
+    let metadata = std::fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir()
+        || metadata.uid() != uid
+        || metadata.mode() & 0o077 != 0
+    {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{:?} isn't private to this user", dir),
+        ));
+    }
+    Ok(dir)
+}
 
 pub fn enumerate_unix_x11_sockets() -> Vec<usize> {
     let mut existing_sockets: Vec<usize> = Vec::new();
@@ -68,11 +119,10 @@ pub fn enumerate_unix_x11_sockets() -> Vec<usize> {
                 let dir_entry = dir_entry.unwrap();
                 let path = dir_entry.path();
                 info!("X11 socket found: {}", path.to_string_lossy());
-                let file_name = path.file_name().unwrap().to_str().unwrap();
-                let x_pos = file_name.rfind('X').unwrap();
-                let screen_num = &file_name[x_pos + 1..];
-                let screen_num = screen_num.parse::<usize>().unwrap();
-                existing_sockets.push(screen_num);
+                match display_number(&dir_entry.file_name().to_string_lossy()) {
+                    Some(screen_num) => existing_sockets.push(screen_num),
+                    None => info!("Not a display socket: {:?}", path),
+                }
             } else {
                 warn!("Can't read directory entry in {:?}", socket_path)
             }
@@ -84,9 +134,37 @@ pub fn enumerate_unix_x11_sockets() -> Vec<usize> {
     existing_sockets
 }
 
+/// The display number of socket `file_name` in `X11_SOCKET_DIR`, which
+/// other users and programs may have put anything else in.
+fn display_number(file_name: &str) -> Option<usize> {
+    if !file_name.starts_with('X') {
+        return None;
+    }
+    file_name[1..].parse().ok()
+}
+
+/// Whether process `pid` is alive. It may be another user's, which we
+/// can't signal but mustn't take for dead.
+fn is_alive(pid: libc::pid_t) -> bool {
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Claim display number `n` the way X servers do, with a lock file
+/// holding our pid, and return its path. Fails if it's taken.
+fn claim_display(n: usize) -> Result<String, io::Error> {
+    let path = format!("/tmp/.X{}-lock", n);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o444)
+        .open(&path)?;
+    writeln!(file, "{:10}", getpid())?;
+    Ok(path)
+}
+
 pub fn cleanup_old_sockets() -> Result<(), std::io::Error> {
-    let mut socket_list = dirs::home_dir().unwrap();
-    socket_list.push(X11_SOCKET_LIST);
+    let socket_list = socket_list_path();
     let mut file = OpenOptions::new()
         .read(true)
         .write(true)
@@ -130,8 +208,7 @@ pub fn cleanup_old_sockets() -> Result<(), std::io::Error> {
             // TOCTTOU is prevented by locking the .rusty_sockets file
             // although we can fail to clean up if a non-rustywin process
             // reuses the pid.
-            let res = unsafe { libc::kill(pid, 0) };
-            if res != 0 {
+            if !is_alive(pid) {
                 info!(
                     "Process {} is dead, cleaning socket {}",
                     pid, socket_path
@@ -169,9 +246,22 @@ pub fn cleanup_old_sockets() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Where we keep track of the sockets we created, which is per user so
+/// one user's instances never have to touch another's list.
+pub fn socket_list_path() -> PathBuf {
+    match runtime_dir() {
+        Ok(dir) => dir.join(X11_SOCKET_LIST),
+        Err(e) => {
+            warn!("No runtime directory, using the home directory: {}", e);
+            dirs::home_dir()
+                .unwrap()
+                .join(format!(".{}", X11_SOCKET_LIST))
+        }
+    }
+}
+
 fn register_socket_for_cleanup(filename: &str) -> Result<(), std::io::Error> {
-    let mut socket_list = dirs::home_dir().unwrap();
-    socket_list.push(X11_SOCKET_LIST);
+    let socket_list = socket_list_path();
     let file = OpenOptions::new()
         .append(true)
         .create(true)
@@ -188,9 +278,11 @@ fn register_socket_for_cleanup(filename: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+/// Claim a free display number and name the sockets for it. Fails if no
+/// display could be locked, rather than take one someone else may get.
 pub fn setup_unix_socket(
     x11_conn: &X11ConnectionDescriptor,
-) -> SocketConnection {
+) -> Result<SocketConnection, io::Error> {
     let original_server_num = x11_conn.server_num();
 
     if let Err(e) = cleanup_old_sockets() {
@@ -198,7 +290,7 @@ pub fn setup_unix_socket(
     };
 
     let existing_servers = enumerate_unix_x11_sockets();
-    let free_server_num = match existing_servers.iter().max() {
+    let first_server_num = match existing_servers.iter().max() {
         // Next available from max
         Some(idx) => idx + 1,
         // Anything is OK, but where's the original connection?
@@ -207,6 +299,36 @@ pub fn setup_unix_socket(
             0
         }
     };
+    // Other instances, of this user or others, may be picking one at the
+    // same time, so the number is only ours once its lock file is.
+    let last_server_num = first_server_num + MAX_DISPLAY_ATTEMPTS - 1;
+    let mut claimed = None;
+    for n in first_server_num..=last_server_num {
+        if Path::new(&format!("{}X{}", X11_SOCKET_DIR, n)).exists() {
+            continue;
+        }
+        match claim_display(n) {
+            Ok(lock) => {
+                if let Err(e) = register_socket_for_cleanup(&lock) {
+                    warn!("Failure recording lock file in use: {}", e);
+                }
+                claimed = Some(n);
+                break;
+            }
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                let message = format!("couldn't lock display :{}: {}", n, e);
+                return Err(io::Error::new(e.kind(), message));
+            }
+        }
+    }
+    let free_server_num = claimed.ok_or_else(|| {
+        let message = format!(
+            "displays :{} to :{} are all taken",
+            first_server_num, last_server_num
+        );
+        io::Error::new(ErrorKind::AddrInUse, message)
+    })?;
     info!("Next available X11 server: #{}", free_server_num);
 
     let new_unix_socket_name =
@@ -228,9 +350,30 @@ pub fn setup_unix_socket(
     let target_unix_socket_name =
         format!("{}{}{}", X11_SOCKET_DIR, 'X', original_server_num);
 
-    SocketConnection {
+    Ok(SocketConnection {
         client_display_name,
         client_socket_name: new_unix_socket_name,
         server_socket_name: target_unix_socket_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_number() {
+        assert_eq!(display_number("X0"), Some(0));
+        assert_eq!(display_number("X12"), Some(12));
+        assert_eq!(display_number("X"), None);
+        assert_eq!(display_number("X1-lock"), None);
+        assert_eq!(display_number("ssh-agent"), None);
+    }
+
+    #[test]
+    fn test_is_alive() {
+        assert!(is_alive(::std::process::id() as libc::pid_t));
+        // Init is someone else's, unless we're root.
+        assert!(is_alive(1));
     }
 }
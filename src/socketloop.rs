@@ -1,5 +1,7 @@
 use socket::*;
 
+use std::fmt;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::io::ErrorKind;
@@ -7,10 +9,12 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::process::Child;
 use std::thread;
+use std::time::Instant;
 
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use libc;
 use nix;
 use nix::errno::Errno;
 use nix::libc::c_int;
@@ -18,126 +22,306 @@ use nix::sys::select::{select, FdSet};
 use nix::sys::socket::{getsockopt, sockopt};
 use nix::Error::Sys;
 
+use allocations;
 use analyze;
+use audit::{AuditLog, Fingerprint};
+use companion::Companion;
 use ipc;
+use lockdown::Lockdown;
+use policy::{Policy, UnknownPeer};
+use reaper::{ChildExit, Exit};
+use registry::Registration;
+use replay::ServerCapture;
+use server;
+use session::{Session, Sessions};
+use state::ConnectionState;
+use transport;
+use transport::Transport;
+use trigger::{Capture, Triggers};
+use trusted::TrustedPids;
+use turns::Turns;
 use DumpFile;
 
 const BUFFER_SIZE: usize = 1 << 16;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-enum SelectType {
-    Readers,
-    Writers,
-    ReadersAndWriters,
+/// Output that may be queued up for one direction of a connection before
+/// we stop reading more input for it. This keeps a bulk transfer in one
+/// direction from holding up the other, and from waiting on a peer that
+/// is itself blocked on us.
+const WRITE_BUDGET: usize = 4 * BUFFER_SIZE;
+
+/// Who is on the other end of a client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Peer {
+    Pid(i32),
+    /// The kernel couldn't tell us, or the peer is outside our PID
+    /// namespace.
+    Unknown,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Peer::Pid(pid) => write!(f, "{}", pid),
+            Peer::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+impl Peer {
+    /// The command name of the process, without spaces.
+    fn process_name(self) -> String {
+        let name = match self {
+            Peer::Pid(pid) => {
+                fs::read_to_string(format!("/proc/{}/comm", pid)).ok()
+            }
+            Peer::Unknown => None,
+        };
+        match name {
+            Some(name) if !name.trim().is_empty() => {
+                name.trim().replace(' ', "_")
+            }
+            _ => "unknown".to_string(),
+        }
+    }
+}
+
+// This is only supported on non-ARM Linux in nix
+#[cfg(all(target_os = "linux", not(target_arch = "arm")))]
+fn peer_of(stream: &UnixStream) -> Peer {
+    match getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials) {
+        Ok(creds) if creds.pid() > 0 => Peer::Pid(creds.pid()),
+        Ok(_) => Peer::Unknown,
+        Err(e) => {
+            warn!("Couldn't get peer credentials: {}", e);
+            Peer::Unknown
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", not(target_arch = "arm"))))]
+fn peer_of(_stream: &UnixStream) -> Peer {
+    Peer::Unknown
+}
+
+/// Whether traffic from `peer` goes through unfiltered.
+fn is_trusted(peer: Peer, pids: &PidVector, policy: &Policy) -> bool {
+    match peer {
+        Peer::Pid(pid) => pids.lock().unwrap().contains(pid),
+        Peer::Unknown => policy.unknown_peer() == UnknownPeer::Trusted,
+    }
+}
+
+/// Settings and shared state handed to every client connection.
+pub struct Proxy {
+    pub dumpfile: Option<DumpFile>,
+    /// Where to record what the server sends, for `--replay-server`.
+    pub server_capture: Option<ServerCapture>,
+    pub triggers: Arc<Triggers>,
+    pub sessions: Arc<Sessions>,
+    pub policy: Policy,
+    /// Probability of injecting a socket fault, for testing.
+    pub fault_rate: f64,
+    pub lockdown: Arc<Lockdown>,
+    pub audit: Option<AuditLog>,
+    /// Socket to the process that started us, in --fd mode.
+    pub parent_fd: Option<RawFd>,
+    pub registry: Option<Registration>,
+    /// Give up on the first high severity denial, see `fail_on_denial`.
+    pub fail_on_denial: bool,
+    /// Marks the windows of clients denied something of high severity.
+    pub companion: Option<Companion>,
+    /// The client we launched, if we did.
+    pub child_pid: Option<i32>,
+    /// How `fail_on_denial` has us exit.
+    pub shutdown: Shutdown,
+    /// Which connection's pass it is.
+    pub turns: Turns,
+}
+
+/// What we exit with after `fail_on_denial`.
+const EXIT_DENIED: i32 = 3;
+
+/// Lets a connection thread have the accept loop stop, so that main
+/// cleans up and exits with the status it asks for.
+pub struct Shutdown {
+    status: Mutex<Option<i32>>,
+    // Written to once asked, to wake the accept loop.
+    ours: UnixStream,
+    theirs: UnixStream,
+}
+
+impl Shutdown {
+    pub fn new() -> Result<Shutdown, io::Error> {
+        let (ours, theirs) = UnixStream::pair()?;
+        Ok(Shutdown {
+            status: Mutex::new(None),
+            ours,
+            theirs,
+        })
+    }
+
+    /// Ask to exit with `status`. The first to ask has their way.
+    pub fn request(&self, status: i32) {
+        let mut requested = self.status.lock().unwrap();
+        if requested.is_none() {
+            *requested = Some(status);
+            if let Err(e) = (&self.ours).write_all(&[0]) {
+                warn!("Couldn't wake the accept loop: {}", e);
+            }
+        }
+    }
+
+    /// The status asked for, if any.
+    pub fn requested(&self) -> Option<i32> {
+        *self.status.lock().unwrap()
+    }
+}
+
+impl AsRawFd for Shutdown {
+    fn as_raw_fd(&self) -> RawFd {
+        self.theirs.as_raw_fd()
+    }
+}
+
+/// Kill the client and have us exit, because it was denied `capability`.
+/// Nothing the connections send is passed on from then on.
+fn fail_on_denial(proxy: &Proxy, session: &Session, reason: &str) {
+    error!("Connection {} {}, exiting.", session.id(), reason);
+    if let Some(ref audit) = proxy.audit {
+        audit.record(
+            "fail-on-denial",
+            &[
+                ("conn", session.id().to_string()),
+                ("reason", reason.to_string()),
+            ],
+        );
+    }
+    // Before the client's exit wakes the accept loop.
+    proxy.shutdown.request(EXIT_DENIED);
+    if let Some(pid) = proxy.child_pid {
+        if unsafe { libc::kill(pid, libc::SIGKILL) } != 0 {
+            warn!("Couldn't kill {}: {}", pid, io::Error::last_os_error());
+        }
+    }
+}
+
+/// A filtered client went for a decoy atom. Nothing legitimate knows of
+/// them, so this is as bad as it gets.
+fn honeypot_touched(proxy: &Proxy, session: &Session, decoy: &str) {
+    error!(
+        "Connection {} touched the decoy atom {}, it is trying to escape \
+         the sandbox.",
+        session.id(),
+        decoy
+    );
+    if let Some(ref audit) = proxy.audit {
+        audit.record(
+            "honeypot",
+            &[
+                ("conn", session.id().to_string()),
+                ("atom", decoy.to_string()),
+            ],
+        );
+    }
+    if proxy.fail_on_denial {
+        let reason = format!("touched the decoy atom {}", decoy);
+        fail_on_denial(proxy, session, &reason);
+    }
 }
 
 pub enum ChildInfo {
     Child(Child),
     RawFd(RawFd),
+    /// Neither, for clients that come on their own. We serve them until
+    /// killed.
+    Standalone,
 }
 
-type PidVector = Arc<Mutex<Vec<i32>>>;
+type PidVector = Arc<Mutex<TrustedPids>>;
 
-trait WriteAllNonBlock {
-    /// Similar to write_all, but deals with WouldBlock
-    fn write_all_nonblock(
+trait WriteNonBlock {
+    /// Write as much of `pending` as the socket takes without blocking,
+    /// and remove what was written from it. The file descriptors in `fds`
+    /// go along with the first write and are closed on our side.
+    fn write_nonblock(
         &mut self,
-        write_buff: &[u8],
-        child_stderr_fd: &Option<RawFd>,
+        pending: &mut Vec<u8>,
+        fds: &mut Vec<RawFd>,
     ) -> Result<(), io::Error>;
 }
 
-impl WriteAllNonBlock for UnixStream {
-    fn write_all_nonblock(
+impl<T: Transport + ?Sized> WriteNonBlock for T {
+    fn write_nonblock(
         &mut self,
-        mut write_buff: &[u8],
-        child_stderr_fd: &Option<RawFd>,
+        pending: &mut Vec<u8>,
+        fds: &mut Vec<RawFd>,
     ) -> Result<(), io::Error> {
-        loop {
-            let written = self.write(&write_buff);
-            match written {
+        let mut written = 0;
+        let result = loop {
+            if written == pending.len() {
+                break Ok(());
+            }
+            match self.write_fds(&pending[written..], fds) {
                 Ok(0) => {
-                    return Err(io::Error::new(
+                    break Err(io::Error::new(
                         ErrorKind::WriteZero,
                         "failed to write whole buffer",
                     ))
                 }
-                Ok(n) => write_buff = &write_buff[n..],
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                    // Hold until non-blocking again
-                    let mut select_vec = vec![self.as_raw_fd()];
-                    if child_stderr_fd.is_some() {
-                        let real_child_stderr_fd = child_stderr_fd.unwrap();
-                        select_vec.push(real_child_stderr_fd);
-                    }
-                    if let Err(e) =
-                        select_on_vec(&select_vec, SelectType::Writers)
-                    {
-                        error!("Error during select on write: {}", e);
-                        return Err(io::Error::new(
-                            ErrorKind::WouldBlock,
-                            "Failed to select on handle",
-                        ));
-                    }
+                Ok(n) => {
+                    close_fds(fds);
+                    written += n
                 }
-                Err(e) => return Err(e),
-            }
-            if write_buff.is_empty() {
-                break;
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
             }
-        }
-        Ok(())
+        };
+        pending.drain(..written);
+        result
     }
 }
 
+fn close_fds(fds: &mut Vec<RawFd>) {
+    for fd in fds.drain(..) {
+        nix::unistd::close(fd).ok();
+    }
+}
+
+/// Serve clients until the one we launched exits, and return what to
+/// exit with: its exit status, or 1 if something went wrong.
 pub fn run_unix_socket_loop(
     sockets: SocketConnection,
     listen_socket: UnixListener,
     client_handle: ChildInfo,
-    dumpfile: Option<DumpFile>,
-) {
-    let child_fd = match client_handle {
-        ChildInfo::Child(ref child) => {
-            // We need the stderr fd number from the child.
-            // Given that we wait() on it here and takes a mut ref,
-            // we need to extract that fd now.
-            // We add this to the select() fdset to ensure all threads get
-            // messaged on death.
-            // http://stackoverflow.com/a/8976461/909836
-            match child.stderr {
-                Some(ref stderr) => {
-                    info!("Got a handle for stderr, will monitor for exit.");
-                    Some(stderr.as_raw_fd())
-                }
-                None => {
-                    info!("Couldn't obtain handle to child's stderr.");
-                    None
-                }
-            }
+    proxy: Arc<Proxy>,
+) -> i32 {
+    // The child's output is read by `childoutput::forward`, its exit is
+    // picked up by the accept loop.
+    let (child_fd, child_exit) = match client_handle {
+        ChildInfo::Child(child) => {
+            let exit = ChildExit::watch(child.id() as i32)
+                .expect("Couldn't watch the client for exiting");
+            (None, Some(exit))
         }
-        ChildInfo::RawFd(rawfd) => Some(rawfd),
+        ChildInfo::RawFd(rawfd) => (Some(rawfd), None),
+        ChildInfo::Standalone => (None, None),
     };
 
     let thread = thread::spawn(move || {
-        accept_loop(&sockets, &listen_socket, child_fd, &dumpfile)
+        accept_loop(&sockets, &listen_socket, child_fd, child_exit, &proxy)
     });
 
-    match client_handle {
-        ChildInfo::Child(mut child) => {
-            info!("Waiting for client to exit");
-            child.wait().expect("Client exited abornomally");
+    info!("Waiting for thread to exit");
+    match thread.join() {
+        Ok(status) => {
+            info!("Thread exited normally.");
+            status
         }
-        ChildInfo::RawFd(_) => {
-            info!("Waiting for thread to exit");
-            match thread.join() {
-                Ok(_) => {
-                    info!("Thread exited normally.");
-                }
-                Err(e) => {
-                    error!("Error joining thread: {:?}", e);
-                }
-            }
+        Err(e) => {
+            error!("Error joining thread: {:?}", e);
+            1
         }
     }
 }
@@ -155,23 +339,34 @@ pub fn setup_listen_socket(sockets: &SocketConnection) -> Option<UnixListener> {
 fn accept_loop(
     sockets: &SocketConnection,
     listen_socket: &UnixListener,
-    // This is either the stderr fd (for termination)
-    // or the socketpair fd (also for comms).
+    // The socketpair fd to the process that started us, in --fd mode.
     child_fd: Option<RawFd>,
-    dumpfile: &Option<DumpFile>,
-) {
+    // The client we launched otherwise, we return once it exits.
+    mut child_exit: Option<ChildExit>,
+    proxy: &Arc<Proxy>,
+) -> i32 {
     listen_socket
         .set_nonblocking(true)
         .expect("Couldn't set accept loop to nonblocking.");
 
-    let child_pid_vec = PidVector::new(Mutex::new(Vec::new()));
+    let child_pid_vec = PidVector::new(Mutex::new(TrustedPids::new()));
 
     loop {
+        if let Some(status) = proxy.shutdown.requested() {
+            info!("Shutting down.");
+            return status;
+        }
+
         // XXX: This will break if we are working on standalone mode,
         // need to differentiate this use of child_fd.
         // Check whether the master process is sending us
         // some information.
-        ipc::try_receive_pids(child_fd, &mut child_pid_vec.lock().unwrap());
+        ipc::try_receive_pids(
+            child_fd,
+            &mut child_pid_vec.lock().unwrap(),
+            &proxy.lockdown,
+        );
+        child_pid_vec.lock().unwrap().prune();
 
         // Check whether a new client is connected
         match listen_socket.accept() {
@@ -183,25 +378,49 @@ fn accept_loop(
                     stream,
                     child_fd,
                     child_pid_vec.clone(),
-                    dumpfile.clone(),
+                    proxy.clone(),
                 );
             }
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
             Err(_) => {
                 info!("Error accept()ing on socket.");
-                break;
+                return 1;
             }
         };
 
+        if let Some(ref mut exit) = child_exit {
+            match exit.try_reap() {
+                Ok(None) => (),
+                Ok(Some(exit)) => {
+                    match exit {
+                        Exit::Code(code) => {
+                            info!("Client exited with status {}", code)
+                        }
+                        Exit::Signal(signal) => {
+                            info!("Client was killed by signal {}", signal)
+                        }
+                    }
+                    return exit.status();
+                }
+                Err(e) => {
+                    error!("Couldn't wait for the client: {}", e);
+                    return 1;
+                }
+            }
+        }
+
         let mut select_vec = vec![listen_socket.as_raw_fd()];
         if child_fd.is_some() {
             select_vec.push(child_fd.unwrap());
         }
-        if let Err(e) =
-            select_on_vec(&select_vec, SelectType::ReadersAndWriters)
-        {
+        let mut readers = select_vec.clone();
+        if let Some(ref exit) = child_exit {
+            readers.push(exit.as_raw_fd());
+        }
+        readers.push(proxy.shutdown.as_raw_fd());
+        if let Err(e) = select_on(&readers, &select_vec) {
             error!("Error during select on accept: {}", e);
-            return;
+            return 1;
         }
     }
 }
@@ -211,7 +430,7 @@ fn handle_client(
     client_stream: UnixStream,
     stderr_fd: Option<RawFd>,
     pid_vector: PidVector,
-    dumpfile: Option<DumpFile>,
+    proxy: Arc<Proxy>,
 ) {
     // Incoming connection from client, make our outgoing connection
     // to the original socket.
@@ -229,17 +448,17 @@ fn handle_client(
             server_stream,
             stderr_fd,
             pid_vector,
-            dumpfile,
+            proxy,
         )
     });
 }
 
 fn client_message_loop(
-    mut client_stream: UnixStream,
-    mut server_stream: UnixStream,
+    client_stream: UnixStream,
+    server_stream: UnixStream,
     child_stderr_fd: Option<RawFd>,
     pid_vector: PidVector,
-    dumpfile: Option<DumpFile>,
+    proxy: Arc<Proxy>,
 ) {
     server_stream
         .set_nonblocking(true)
@@ -248,140 +467,316 @@ fn client_message_loop(
         .set_nonblocking(true)
         .expect("Couldn't set sockets to nonblocking");
 
-    // Find the PID of our peer
-    let client_fd = client_stream.as_raw_fd();
-
-    // This is only supported on non-ARM Linux in nix
-    let client_pid;
-    if cfg!(all(target_os = "linux", not(target_arch = "arm"))) {
-        let creds = sockopt::PeerCredentials;
-        let creds_result = getsockopt(client_fd, creds);
-        client_pid = creds_result.unwrap().pid();
-        info!("Client PID is detected as: {}", client_pid);
-    } else {
-        client_pid = 0;
+    let peer = peer_of(&client_stream);
+    info!("Client PID is detected as: {}", peer);
+    if peer == Peer::Unknown
+        && proxy.policy.unknown_peer() == UnknownPeer::Reject
+    {
+        warn!("Rejecting a client with unknown PID.");
+        return;
+    }
+
+    let mut client_stream = transport::wrap(client_stream, proxy.fault_rate);
+    let mut server_stream = transport::wrap(server_stream, proxy.fault_rate);
+
+    let session = proxy.sessions.register();
+    let accepted = Instant::now();
+    info!("Client PID {} is connection {}", peer, session.id());
+    if let Some(ref audit) = proxy.audit {
+        let mut fields = vec![
+            ("conn", session.id().to_string()),
+            ("pid", peer.to_string()),
+        ];
+        if let Peer::Pid(pid) = peer {
+            fields.extend(Fingerprint::of_pid(pid).fields());
+        }
+        audit.record("session-start", &fields);
+    }
+    if let Some(ref registry) = proxy.registry {
+        let name = peer.process_name();
+        registry.client_connected(session.id(), &peer.to_string(), &name);
     }
 
+    let mut capture = Capture::new(proxy.triggers.clone());
+    let mut state = ConnectionState::new();
+    state.seed_atoms(proxy.policy.known_atoms());
+
     // XXX: Some canonical way to avoid the useless init?
     let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    let mut to_server: Vec<u8> = Vec::new();
+    let mut to_client: Vec<u8> = Vec::new();
+    // File descriptors passed along, to go with the next write.
+    let mut fds_to_server = Vec::new();
+    let mut fds_to_client = Vec::new();
+    let mut received = Vec::new();
+    // A client that shuts down its writing side still gets the replies
+    // to what it sent, once the server has them all.
+    let mut client_closed = false;
+    let mut server_shut_down = false;
+    let mut server_closed = false;
 
     loop {
-        let read = match client_stream.read(&mut buffer) {
-            Ok(size) => size,
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => 0,
-            Err(e) => {
-                error!("Read error from socket: {}", e);
-                break;
+        // Each direction gets at most one read per pass, and none while
+        // its output is backed up. Passes go round the connections.
+        let turn = proxy.turns.take();
+        let read = if client_closed || to_server.len() >= WRITE_BUDGET {
+            0
+        } else {
+            match client_stream.read_fds(&mut buffer, &mut received) {
+                Ok(0) => {
+                    info!("Client closed the connection.");
+                    client_closed = true;
+                    0
+                }
+                Ok(size) => size,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => 0,
+                Err(e) => {
+                    error!("Read error from socket: {}", e);
+                    break;
+                }
             }
         };
 
         if read > 0 {
             info!("C->S {} bytes", read);
+            session.summary.lock().unwrap().bytes_to_server += read as u64;
+
+            // Requests split across reads are held back until they're
+            // whole, a BIG-REQUESTS one may take many. So are the file
+            // descriptors that came with them.
+            state.receive_fds(&mut received);
+            let mut framer = analyze::ClientFramer::new(&state, &proxy.policy);
+            let complete = state
+                .client_stream
+                .complete(&buffer[0..read], |m| framer.frame(m));
+            if let Err(e) = framer.check() {
+                warn!("Closing connection {}: {}", session.id(), e);
+                break;
+            }
+            if state.client_stream.pending() > 0 {
+                debug!("Holding back {} bytes", state.client_stream.pending());
+            }
 
-            let filtered_buffer_pair: (Vec<u8>, Vec<u8>);
-            let mut write_buff: &[u8] = &buffer[0..read];
-
-            if !pid_vector.lock().unwrap().contains(&client_pid) {
-                filtered_buffer_pair = analyze::filter_buffer(write_buff);
-                write_buff = &filtered_buffer_pair.0;
-                let reject_buff = filtered_buffer_pair.1;
+            let mut batch: Option<analyze::Filtered> = None;
+            let mut write_buff: &[u8] = &complete;
+
+            if !is_trusted(peer, &pid_vector, &proxy.policy) {
+                state.set_exceptions(session.live_exceptions());
+                let filtered = batch.insert(analyze::filter_buffer(
+                    &mut state,
+                    &proxy.policy,
+                    write_buff,
+                ));
+                write_buff = &filtered.accepted;
+                session.summary.lock().unwrap().add_filtered(filtered);
+                if let Some(ref registry) = proxy.registry {
+                    for &capability in &filtered.denials {
+                        registry.client_denied(session.id(), capability);
+                    }
+                }
+                if proxy.fail_on_denial {
+                    let severe = filtered
+                        .denials
+                        .iter()
+                        .find(|capability| capability.is_high_severity());
+                    if let Some(&capability) = severe {
+                        let reason = format!("was denied {}", capability);
+                        fail_on_denial(&proxy, &session, &reason);
+                    }
+                }
+                if let (Some(companion), Some(window)) =
+                    (proxy.companion.as_ref(), state.main_window())
+                {
+                    for &capability in &filtered.denials {
+                        if capability.is_high_severity() {
+                            companion.mark(
+                                window,
+                                capability,
+                                state.wm_hints(),
+                            );
+                        }
+                    }
+                }
+                for decoy in &filtered.honeypots {
+                    honeypot_touched(&proxy, &session, decoy);
+                }
+                if !filtered.dropped_fds.is_empty() {
+                    warn!(
+                        "Dropping {} file descriptors of denied requests.",
+                        filtered.dropped_fds.len()
+                    );
+                    close_fds(&mut filtered.dropped_fds);
+                }
+                fds_to_server.append(&mut filtered.fds);
 
                 info!("Filtering client-server write after harden.");
+                session.recorder.lock().unwrap().record(&buffer[0..read]);
                 // Log traffic that we filter into the dumpfile
-                if let Some(ref dump) = dumpfile {
-                    match dump.lock().unwrap().write(&buffer) {
+                if let Some(ref dump) = proxy.dumpfile {
+                    match capture.record(
+                        dump,
+                        &session,
+                        &buffer[0..read],
+                        &filtered.denials,
+                        state.byte_order(),
+                    ) {
                         Ok(_) => (),
                         Err(e) => {
                             error!("Could not write dumpfile: {}", e);
                         }
                     }
                 }
+            } else {
+                // Even unfiltered clients may get hardened later on.
+                analyze::track_buffer(
+                    &mut state,
+                    write_buff,
+                    &mut fds_to_server,
+                );
             }
 
-            match server_stream
-                .write_all_nonblock(&write_buff, &child_stderr_fd)
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    info!("Write error on socket: {}", e);
-                }
+            if proxy.shutdown.requested().is_some() {
+                break;
+            }
+            let _allocations = allocations::Span::forwarding();
+            to_server.extend(write_buff);
+            if let Some(filtered) = batch {
+                state.arena.reset(filtered.accepted);
             }
         }
 
-        let read = match server_stream.read(&mut buffer) {
-            Ok(size) => size,
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => 0,
-            Err(e) => {
-                error!("Read error from socket: {}", e);
-                break;
+        let read = if server_closed || to_client.len() >= WRITE_BUDGET {
+            0
+        } else {
+            match server_stream.read_fds(&mut buffer, &mut received) {
+                Ok(0) => {
+                    info!("Server closed the connection.");
+                    server_closed = true;
+                    0
+                }
+                Ok(size) => size,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => 0,
+                Err(e) => {
+                    error!("Read error from socket: {}", e);
+                    break;
+                }
             }
         };
 
         if read > 0 {
             info!("S->C {} bytes", read);
-            let write_buff = &buffer[0..read];
-            match client_stream
-                .write_all_nonblock(&write_buff, &child_stderr_fd)
-            {
-                Ok(_) => (),
-                Err(e) => {
-                    info!("Write error on socket: {}", e);
+            session.summary.lock().unwrap().bytes_to_client += read as u64;
+            let filtered = !is_trusted(peer, &pid_vector, &proxy.policy);
+            let write_buff = server::filter_server_buffer(
+                &mut state,
+                &proxy.policy,
+                &buffer[0..read],
+                filtered,
+            );
+            if let Some(ref capture) = proxy.server_capture {
+                let at = accepted.elapsed();
+                if let Err(e) = capture.record(session.id(), at, &write_buff) {
+                    error!("Could not write server capture: {}", e);
                 }
             }
+            to_client.extend(write_buff);
+            fds_to_client.append(&mut received);
         }
 
-        // Now just block here until anything shows up.
-        if let Err(e) = select_streams(
-            &client_stream,
-            &server_stream,
-            child_stderr_fd,
-            SelectType::Readers,
-        ) {
+        for &mut (ref mut stream, ref mut pending, ref mut fds) in &mut [
+            (&mut server_stream, &mut to_server, &mut fds_to_server),
+            (&mut client_stream, &mut to_client, &mut fds_to_client),
+        ] {
+            if let Err(e) = stream.write_nonblock(pending, fds) {
+                info!("Write error on socket: {}", e);
+                pending.clear();
+                close_fds(fds);
+            }
+        }
+        if client_closed && to_server.is_empty() && !server_shut_down {
+            info!("Passing the client's shutdown on to the server.");
+            if let Err(e) = server_stream.shutdown_write() {
+                info!("Shutdown error on socket: {}", e);
+                break;
+            }
+            server_shut_down = true;
+        }
+        // Whatever the server sent before closing is still the client's.
+        if server_closed && to_client.is_empty() {
+            break;
+        }
+
+        // Now just block here until anything shows up, or until we can
+        // get rid of queued output.
+        drop(turn);
+        let mut readers = Vec::new();
+        let mut writers = Vec::new();
+        if !client_closed && to_server.len() < WRITE_BUDGET {
+            readers.push(client_stream.as_raw_fd());
+        }
+        if !server_closed && to_client.len() < WRITE_BUDGET {
+            readers.push(server_stream.as_raw_fd());
+        }
+        if !to_server.is_empty() {
+            writers.push(server_stream.as_raw_fd());
+        }
+        if !to_client.is_empty() {
+            writers.push(client_stream.as_raw_fd());
+        }
+        if let Some(fd) = child_stderr_fd {
+            readers.push(fd);
+        }
+        if let Err(e) = select_on(&readers, &writers) {
             error!("Error on select: {}", e);
             break;
         }
     }
 
+    // And whatever no request took.
+    state.take_fds(usize::MAX, &mut received);
+    close_fds(&mut fds_to_server);
+    close_fds(&mut fds_to_client);
+    close_fds(&mut received);
+    proxy.sessions.remove(session.id());
+    report_summary(&proxy, &session, peer);
     info!("Leaving client loop in thread.");
 }
 
-fn select_streams(
-    client_stream: &UnixStream,
-    server_stream: &UnixStream,
-    child_stderr_fd: Option<RawFd>,
-    socktype: SelectType,
-) -> Result<(), nix::Error> {
-    let client_stream_fd = client_stream.as_raw_fd();
-    let server_stream_fd = server_stream.as_raw_fd();
-    let mut fd_vec = vec![client_stream_fd as c_int, server_stream_fd as c_int];
-    if child_stderr_fd.is_some() {
-        let real_child_stderr_fd = child_stderr_fd.unwrap();
-        fd_vec.push(real_child_stderr_fd);
+/// Log, audit and tell the parent what a closed connection did.
+fn report_summary(proxy: &Proxy, session: &Session, peer: Peer) {
+    let mut fields = vec![
+        ("conn", session.id().to_string()),
+        ("pid", peer.to_string()),
+    ];
+    fields.extend(session.summary.lock().unwrap().fields());
+    let line: Vec<String> = fields
+        .iter()
+        .map(|&(key, ref value)| format!("{}={}", key, value))
+        .collect();
+    let line = line.join(" ");
+    info!("Session summary: {}", line);
+    if let Some(ref audit) = proxy.audit {
+        audit.record("session-end", &fields);
+    }
+    if let Some(fd) = proxy.parent_fd {
+        ipc::send_summary(fd, &format!("session-end {}", line));
+    }
+    if let Some(ref registry) = proxy.registry {
+        registry.client_closed(session.id(), &line);
     }
-    select_on_vec(&fd_vec, socktype)
 }
 
-fn select_on_vec(
-    fdset_vec: &[c_int],
-    socktype: SelectType,
-) -> Result<(), nix::Error> {
+fn select_on(readers: &[c_int], writers: &[c_int]) -> Result<(), nix::Error> {
     let mut r_fdset = FdSet::new();
     let mut w_fdset = FdSet::new();
     let mut e_fdset = FdSet::new();
-    for fd in fdset_vec {
-        match socktype {
-            SelectType::Readers => {
-                r_fdset.insert(fd.clone());
-            }
-            SelectType::Writers => {
-                w_fdset.insert(fd.clone());
-            }
-            SelectType::ReadersAndWriters => {
-                r_fdset.insert(fd.clone());
-                w_fdset.insert(fd.clone());
-            }
-        };
-        e_fdset.insert(fd.clone());
+    for &fd in readers {
+        r_fdset.insert(fd);
+        e_fdset.insert(fd);
+    }
+    for &fd in writers {
+        w_fdset.insert(fd);
+        e_fdset.insert(fd);
     }
     loop {
         match select(
@@ -404,10 +799,48 @@ fn select_on_vec(
                 //info!(
                 //    "Found {} awoken fds out of {}.",
                 //    count,
-                //    3 * fdset_vec.len()
+                //    readers.len() + writers.len()
                 //);
                 return Ok(());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_peer_trust() {
+        let ours = ::std::process::id() as i32;
+        let pids = PidVector::new(Mutex::new(TrustedPids::new()));
+        pids.lock().unwrap().add(ours).unwrap();
+        let mut policy = Policy::default();
+        assert!(is_trusted(Peer::Pid(ours), &pids, &policy));
+        assert!(!is_trusted(Peer::Pid(ours + 1), &pids, &policy));
+        assert!(!is_trusted(Peer::Unknown, &pids, &policy));
+        policy.set_unknown_peer(UnknownPeer::Trusted);
+        assert!(is_trusted(Peer::Unknown, &pids, &policy));
+
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        if cfg!(all(target_os = "linux", not(target_arch = "arm"))) {
+            let pid = ::std::process::id() as i32;
+            assert_eq!(peer_of(&ours), Peer::Pid(pid));
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Arc::new(Shutdown::new().unwrap());
+        assert_eq!(shutdown.requested(), None);
+        let waiting = shutdown.clone();
+        let waiter = thread::spawn(move || {
+            select_on(&[waiting.as_raw_fd()], &[]).unwrap();
+            waiting.requested()
+        });
+        shutdown.request(EXIT_DENIED);
+        shutdown.request(1);
+        assert_eq!(waiter.join().unwrap(), Some(EXIT_DENIED));
+    }
+}
@@ -0,0 +1,624 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::io::RawFd;
+use std::time::Instant;
+
+use nom::Endianness;
+
+use arena::Arena;
+use atoms;
+use policy::Capability;
+use reassembly::Reassembler;
+
+/// Most requests remembered as waiting for an answer. Past half the
+/// sequence number space they couldn't be told apart anyway.
+const MAX_UNANSWERED: usize = 0x8000;
+
+/// Kinds of resources a client can create.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resource {
+    Window,
+    Pixmap,
+    GContext,
+    ShmSegment,
+    Counter,
+    Alarm,
+    Fence,
+}
+
+/// A paste from another client that this client answers as the owner
+/// of the selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transfer {
+    pub selection: u32,
+    /// Whether the data is sent in chunks following an INCR property.
+    pub incremental: bool,
+    /// Bytes of data sent so far.
+    pub sent: u64,
+}
+
+/// What a request passed on to the server was, as far as it goes into
+/// telling what the server's answer is to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SentRequest {
+    pub opcode: u8,
+    /// The minor opcode of extension requests.
+    pub minor_opcode: u8,
+    /// The property of a GetProperty or ChangeProperty.
+    pub property: Option<u32>,
+}
+
+/// A screen from the server's setup reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Screen {
+    pub root: u32,
+    pub width: u16,
+    pub height: u16,
+    pub root_visual: u32,
+    pub root_depth: u8,
+}
+
+/// How images of a given depth are laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixmapFormat {
+    pub depth: u8,
+    pub bits_per_pixel: u8,
+    pub scanline_pad: u8,
+}
+
+/// What we keep from the server's setup reply.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SetupInfo {
+    /// IDs the client allocates for its resources are the base with
+    /// some bits of the mask set.
+    pub resource_id_base: u32,
+    pub resource_id_mask: u32,
+    pub formats: Vec<PixmapFormat>,
+    pub screens: Vec<Screen>,
+}
+
+impl SetupInfo {
+    /// Whether `id` is from the range the server gave to this client.
+    pub fn in_client_range(&self, id: u32) -> bool {
+        self.resource_id_mask != 0
+            && id & !self.resource_id_mask == self.resource_id_base
+    }
+}
+
+/// Protocol state of a single client connection, as far as the analyzer
+/// needs to know about it.
+pub struct ConnectionState {
+    byte_order: Option<Endianness>,
+    server_setup_done: bool,
+    /// Sequence number of the last request passed on to the server.
+    sequence: u16,
+    /// Requests the server may still answer, oldest first, with what
+    /// they were.
+    unanswered: VecDeque<(u16, SentRequest)>,
+    /// Names asked for in QueryExtension requests, by sequence number.
+    pending_extensions: HashMap<u16, String>,
+    /// Names of the extensions the server reported, by major opcode.
+    extensions: HashMap<u8, String>,
+    /// Whether the client sent BigReqEnable, after which its requests may
+    /// have the extended length field.
+    big_requests: bool,
+    setup: SetupInfo,
+    /// Replies to hand to the client instead of what the server sends,
+    /// by sequence number.
+    fake_replies: HashMap<u16, Vec<u8>>,
+    /// Resources created by the client and not freed yet.
+    resources: HashMap<u32, Resource>,
+    /// Width and height of the client's windows.
+    window_sizes: HashMap<u32, (u16, u16)>,
+    /// The client's windows the window manager has no say over.
+    override_redirect: HashSet<u32>,
+    /// The client's first top-level window that's still around, and the
+    /// WM_HINTS it set on it.
+    main_window: Option<u32>,
+    wm_hints: Option<Vec<u32>>,
+    /// Atom names of InternAtom requests waiting for a reply.
+    pending_atoms: HashMap<u16, String>,
+    /// Names of the atoms the client interned or looked up, and of those
+    /// known from the start.
+    atoms: HashMap<u32, String>,
+    /// Atoms of GetAtomName requests waiting for a reply.
+    atom_name_queries: HashMap<u16, u32>,
+    /// QueryPointer requests on windows the client doesn't own, waiting
+    /// for a reply.
+    pointer_queries: HashSet<u16>,
+    /// RANDR requests about the screen size waiting for a reply, by
+    /// sequence number, with their minor opcode.
+    geometry_queries: HashMap<u16, u8>,
+    /// XC-MISC requests waiting for a reply, with their minor opcode.
+    xid_queries: HashMap<u16, u8>,
+    /// Resource ids XC-MISC handed out, as first id and count.
+    xid_ranges: Vec<(u32, u32)>,
+    /// Capabilities granted for a while on top of the policy.
+    exceptions: Vec<(Capability, Instant)>,
+    /// Selections owned by one of the client's windows.
+    selections: HashSet<u32>,
+    /// GetSelectionOwner requests for the selections of input methods
+    /// waiting for a reply.
+    input_method_queries: HashSet<u16>,
+    /// Windows of input methods: the owners of their selections, and
+    /// the windows they told the client to talk to them on.
+    input_methods: HashSet<u32>,
+    /// Windows the client connected to an input method with, which
+    /// haven't been answered yet.
+    input_method_connects: HashSet<u32>,
+    /// Selection transfers in progress, by requestor window and property.
+    transfers: HashMap<(u32, u32), Transfer>,
+    /// File descriptors the client passed that no request took yet.
+    fds: VecDeque<RawFd>,
+    /// Client to server data that doesn't form a whole message yet.
+    pub client_stream: Reassembler,
+    /// Server to client data that doesn't form a whole message yet.
+    pub server_stream: Reassembler,
+    /// What the requests let through are written to.
+    pub arena: Arena,
+}
+
+impl ConnectionState {
+    pub fn new() -> ConnectionState {
+        ConnectionState {
+            byte_order: None,
+            server_setup_done: false,
+            sequence: 0,
+            unanswered: VecDeque::new(),
+            pending_extensions: HashMap::new(),
+            extensions: HashMap::new(),
+            big_requests: false,
+            setup: SetupInfo::default(),
+            fake_replies: HashMap::new(),
+            resources: HashMap::new(),
+            window_sizes: HashMap::new(),
+            override_redirect: HashSet::new(),
+            main_window: None,
+            wm_hints: None,
+            pending_atoms: HashMap::new(),
+            atoms: HashMap::new(),
+            atom_name_queries: HashMap::new(),
+            pointer_queries: HashSet::new(),
+            geometry_queries: HashMap::new(),
+            xid_queries: HashMap::new(),
+            xid_ranges: Vec::new(),
+            exceptions: Vec::new(),
+            selections: HashSet::new(),
+            input_method_queries: HashSet::new(),
+            input_methods: HashSet::new(),
+            input_method_connects: HashSet::new(),
+            transfers: HashMap::new(),
+            fds: VecDeque::new(),
+            client_stream: Reassembler::new(),
+            server_stream: Reassembler::new(),
+            arena: Arena::new(),
+        }
+    }
+
+    /// Whether the connection setup has been seen (or given up on).
+    pub fn setup_done(&self) -> bool {
+        self.byte_order.is_some()
+    }
+
+    /// Byte order the client uses, LSB first until the setup says otherwise.
+    pub fn byte_order(&self) -> Endianness {
+        self.byte_order.unwrap_or(Endianness::Little)
+    }
+
+    pub fn set_byte_order(&mut self, byte_order: Endianness) {
+        self.byte_order = Some(byte_order);
+    }
+
+    /// Whether the server's reply to the connection setup has been seen.
+    pub fn server_setup_done(&self) -> bool {
+        self.server_setup_done
+    }
+
+    pub fn set_server_setup_done(&mut self) {
+        self.server_setup_done = true;
+    }
+
+    pub fn setup_info(&self) -> &SetupInfo {
+        &self.setup
+    }
+
+    pub fn set_setup_info(&mut self, setup: SetupInfo) {
+        self.setup = setup;
+    }
+
+    pub fn is_root(&self, window: u32) -> bool {
+        self.setup
+            .screens
+            .iter()
+            .any(|screen| screen.root == window)
+    }
+
+    /// Send `reply` to the client in place of the server's reply to the
+    /// request with `sequence`.
+    pub fn fake_reply(&mut self, sequence: u16, reply: Vec<u8>) {
+        self.fake_replies.insert(sequence, reply);
+    }
+
+    pub fn take_fake_reply(&mut self, sequence: u16) -> Option<Vec<u8>> {
+        self.fake_replies.remove(&sequence)
+    }
+
+    /// Remember a resource the client created. Ids that aren't the
+    /// client's to choose are left out, the server refuses them.
+    pub fn add_resource(&mut self, id: u32, resource: Resource) {
+        if self.owns(id) {
+            self.resources.insert(id, resource);
+        }
+    }
+
+    pub fn remove_resource(&mut self, id: u32) {
+        self.resources.remove(&id);
+    }
+
+    pub fn set_window_size(&mut self, window: u32, width: u16, height: u16) {
+        self.window_sizes.insert(window, (width, height));
+    }
+
+    pub fn forget_window_size(&mut self, window: u32) {
+        self.window_sizes.remove(&window);
+    }
+
+    pub fn window_size(&self, window: u32) -> Option<(u16, u16)> {
+        self.window_sizes.get(&window).cloned()
+    }
+
+    /// The window that stands for the client with the window manager.
+    pub fn main_window(&self) -> Option<u32> {
+        self.main_window
+    }
+
+    /// Note that the client created `window` as a child of `parent`.
+    pub fn window_created(&mut self, window: u32, parent: u32) {
+        if self.main_window.is_none() && self.is_root(parent) {
+            self.main_window = Some(window);
+            self.wm_hints = None;
+        }
+    }
+
+    pub fn window_destroyed(&mut self, window: u32) {
+        if self.main_window == Some(window) {
+            self.main_window = None;
+        }
+        self.override_redirect.remove(&window);
+    }
+
+    pub fn set_override_redirect(&mut self, window: u32, enabled: bool) {
+        if enabled {
+            self.override_redirect.insert(window);
+        } else {
+            self.override_redirect.remove(&window);
+        }
+    }
+
+    pub fn is_override_redirect(&self, window: u32) -> bool {
+        self.override_redirect.contains(&window)
+    }
+
+    /// Whether a window of `width` and `height` is as big as a screen.
+    pub fn covers_screen(&self, width: u16, height: u16) -> bool {
+        self.setup
+            .screens
+            .iter()
+            .any(|screen| width >= screen.width && height >= screen.height)
+    }
+
+    pub fn set_wm_hints(&mut self, window: u32, hints: Vec<u32>) {
+        if self.main_window == Some(window) {
+            self.wm_hints = Some(hints);
+        }
+    }
+
+    pub fn wm_hints(&self) -> Option<&[u32]> {
+        self.wm_hints.as_deref()
+    }
+
+    /// Whether the resource `id` belongs to this client: it's from the
+    /// range the server gave it, or one XC-MISC handed out. Other clients
+    /// can't create resources there, and what the client asked to create
+    /// is no proof, so nothing is the client's before the setup reply.
+    pub fn owns(&self, id: u32) -> bool {
+        self.setup.in_client_range(id)
+            || self
+                .xid_ranges
+                .iter()
+                .any(|&(start, count)| id >= start && id - start < count)
+    }
+
+    /// Queue file descriptors the client passed, for the requests they
+    /// came along with to take once those are whole.
+    pub fn receive_fds(&mut self, fds: &mut Vec<RawFd>) {
+        self.fds.extend(fds.drain(..));
+    }
+
+    /// Move the next `count` file descriptors passed to `into`, or as many
+    /// as there are.
+    pub fn take_fds(&mut self, count: usize, into: &mut Vec<RawFd>) {
+        let count = count.min(self.fds.len());
+        into.extend(self.fds.drain(..count));
+    }
+
+    /// Count a request that is passed on to the server and return its
+    /// sequence number.
+    pub fn next_sequence(&mut self) -> u16 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    /// Remember what the request with `sequence` was, to tell what the
+    /// server's replies and errors answer.
+    pub fn sent_request(&mut self, sequence: u16, request: SentRequest) {
+        if self.unanswered.len() >= MAX_UNANSWERED {
+            self.unanswered.pop_front();
+        }
+        self.unanswered.push_back((sequence, request));
+    }
+
+    /// Forget the requests before `sequence`, which the server answered
+    /// with a reply or error. Every message carries the sequence number of
+    /// the last request processed, so they're all done with. The one with
+    /// `sequence` itself may have more replies to come.
+    pub fn answered(&mut self, sequence: u16) {
+        while let Some(&(first, _)) = self.unanswered.front() {
+            if (sequence.wrapping_sub(first) as i16) <= 0 {
+                break;
+            }
+            self.unanswered.pop_front();
+        }
+    }
+
+    /// What the request with `sequence` was, once `answered` was told
+    /// about it.
+    pub fn request(&self, sequence: u16) -> Option<SentRequest> {
+        match self.unanswered.front() {
+            Some(&(first, request)) if first == sequence => Some(request),
+            _ => None,
+        }
+    }
+
+    /// Remember that the request with `sequence` asked for extension `name`.
+    pub fn query_extension(&mut self, sequence: u16, name: &str) {
+        self.pending_extensions.insert(sequence, name.to_string());
+    }
+
+    /// Handle the server's answer to a QueryExtension request.
+    pub fn extension_reply(&mut self, sequence: u16, major_opcode: u8) {
+        if let Some(name) = self.pending_extensions.remove(&sequence) {
+            if major_opcode != 0 {
+                info!("Extension {} has major opcode {}", name, major_opcode);
+                self.extensions.insert(major_opcode, name);
+            }
+        }
+    }
+
+    /// Remember that the request with `sequence` interns atom `name`.
+    pub fn intern_atom(&mut self, sequence: u16, name: &str) {
+        self.pending_atoms.insert(sequence, name.to_string());
+    }
+
+    /// Handle the server's answer to an InternAtom request. `atom` is
+    /// zero if the request failed.
+    pub fn atom_reply(&mut self, sequence: u16, atom: u32) {
+        if let Some(name) = self.pending_atoms.remove(&sequence) {
+            if atom != 0 {
+                self.atoms.insert(atom, name);
+            }
+        }
+    }
+
+    /// Remember that the request with `sequence` asks for the name of
+    /// `atom`.
+    pub fn atom_name_query(&mut self, sequence: u16, atom: u32) {
+        self.atom_name_queries.insert(sequence, atom);
+    }
+
+    /// Learn `name` from the reply to `sequence`, if it answers a
+    /// GetAtomName. Names the client interned are kept as they are.
+    pub fn atom_name_reply(&mut self, sequence: u16, name: &[u8]) {
+        if let Some(&atom) = self.atom_name_queries.get(&sequence) {
+            self.atoms
+                .entry(atom)
+                .or_insert_with(|| String::from_utf8_lossy(name).into_owned());
+        }
+    }
+
+    /// Whether the reply to `sequence` answers a GetAtomName.
+    pub fn take_atom_name_query(&mut self, sequence: u16) -> bool {
+        self.atom_name_queries.remove(&sequence).is_some()
+    }
+
+    /// Remember that the request with `sequence` asks where the pointer
+    /// is relative to someone else's window.
+    pub fn foreign_pointer_query(&mut self, sequence: u16) {
+        self.pointer_queries.insert(sequence);
+    }
+
+    /// Whether the reply to `sequence` answers a foreign QueryPointer.
+    pub fn take_pointer_query(&mut self, sequence: u16) -> bool {
+        self.pointer_queries.remove(&sequence)
+    }
+
+    /// Remember that the request with `sequence` is RANDR request
+    /// `minor_opcode` with the screen size in its reply.
+    pub fn geometry_query(&mut self, sequence: u16, minor_opcode: u8) {
+        self.geometry_queries.insert(sequence, minor_opcode);
+    }
+
+    pub fn take_geometry_query(&mut self, sequence: u16) -> Option<u8> {
+        self.geometry_queries.remove(&sequence)
+    }
+
+    pub fn xid_query(&mut self, sequence: u16, minor_opcode: u8) {
+        self.xid_queries.insert(sequence, minor_opcode);
+    }
+
+    pub fn take_xid_query(&mut self, sequence: u16) -> Option<u8> {
+        self.xid_queries.remove(&sequence)
+    }
+
+    pub fn add_xid_range(&mut self, start: u32, count: u32) {
+        if count > 0 {
+            self.xid_ranges.push((start, count));
+        }
+    }
+
+    pub fn set_exceptions(&mut self, exceptions: Vec<(Capability, Instant)>) {
+        self.exceptions = exceptions;
+    }
+
+    /// Whether `capability` is granted by an exception that hasn't run
+    /// out yet.
+    pub fn has_exception(&self, capability: Capability) -> bool {
+        let now = Instant::now();
+        self.exceptions
+            .iter()
+            .any(|&(granted, until)| granted == capability && until > now)
+    }
+
+    /// Know the names of `atoms` before the client interns them.
+    pub fn seed_atoms(&mut self, atoms: &[(String, u32)]) {
+        for (name, atom) in atoms {
+            self.atoms.insert(*atom, name.clone());
+        }
+    }
+
+    /// Name of `atom`, if it's predefined, known from the start or the
+    /// client interned it or looked it up.
+    pub fn atom_name(&self, atom: u32) -> Option<&str> {
+        match atoms::predefined_name(atom) {
+            Some(name) => Some(name),
+            None => self.atoms.get(&atom).map(|name| name.as_str()),
+        }
+    }
+
+    pub fn set_selection_owned(&mut self, selection: u32, owned: bool) {
+        if owned {
+            self.selections.insert(selection);
+        } else {
+            self.selections.remove(&selection);
+        }
+    }
+
+    /// Whether the client owns `selection`, so reading it only gets the
+    /// client's own data.
+    pub fn owns_selection(&self, selection: u32) -> bool {
+        self.selections.contains(&selection)
+    }
+
+    /// Remember that the request with `sequence` asks who owns the
+    /// selection of an input method.
+    pub fn input_method_query(&mut self, sequence: u16) {
+        self.input_method_queries.insert(sequence);
+    }
+
+    /// Whether the reply to `sequence` names the window of an input
+    /// method.
+    pub fn take_input_method_query(&mut self, sequence: u16) -> bool {
+        self.input_method_queries.remove(&sequence)
+    }
+
+    pub fn add_input_method(&mut self, window: u32) {
+        if window != 0 {
+            self.input_methods.insert(window);
+        }
+    }
+
+    /// Whether `window` is one the client talks to an input method on.
+    pub fn is_input_method(&self, window: u32) -> bool {
+        self.input_methods.contains(&window)
+    }
+
+    /// The client connects to an input method, to be answered on
+    /// `window`.
+    pub fn input_method_connect(&mut self, window: u32) {
+        self.input_method_connects.insert(window);
+    }
+
+    /// An input method answered the connection on `window` with its own
+    /// `server` window. Answers to connections the client didn't make
+    /// are ignored.
+    pub fn input_method_connected(&mut self, window: u32, server: u32) {
+        if self.input_method_connects.remove(&window) {
+            self.add_input_method(server);
+        }
+    }
+
+    /// The server asks the client, as the owner of `selection`, to store
+    /// it in `property` on the `requestor` window.
+    pub fn selection_request(
+        &mut self,
+        requestor: u32,
+        property: u32,
+        selection: u32,
+    ) {
+        let transfer = Transfer {
+            selection,
+            incremental: false,
+            sent: 0,
+        };
+        self.transfers.insert((requestor, property), transfer);
+    }
+
+    pub fn transfer(&self, window: u32, property: u32) -> Option<&Transfer> {
+        self.transfers.get(&(window, property))
+    }
+
+    /// Account for the client storing `size` bytes in `property` of
+    /// `window`. `incr` tells whether that was the INCR property that
+    /// starts a transfer in chunks, its data doesn't count.
+    pub fn transfer_sent(
+        &mut self,
+        window: u32,
+        property: u32,
+        size: u64,
+        incr: bool,
+    ) {
+        let key = (window, property);
+        let done = match self.transfers.get_mut(&key) {
+            Some(transfer) if incr && !transfer.incremental => {
+                debug!(
+                    "Incremental transfer of selection {}",
+                    transfer.selection
+                );
+                transfer.incremental = true;
+                false
+            }
+            Some(transfer) => {
+                transfer.sent += size;
+                !transfer.incremental || size == 0
+            }
+            None => false,
+        };
+        if done {
+            let transfer = self.transfers.remove(&key).unwrap();
+            debug!(
+                "Transfer of selection {} done, {} bytes",
+                transfer.selection, transfer.sent
+            );
+        }
+    }
+
+    /// Name of the extension using `major_opcode`, if we know it.
+    pub fn extension(&self, major_opcode: u8) -> Option<&str> {
+        self.extensions.get(&major_opcode).map(|name| name.as_str())
+    }
+
+    /// Major opcode of extension `name`, if the server reported it.
+    pub fn extension_opcode(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .find(|&(_, extension)| extension == name)
+            .map(|(&opcode, _)| opcode)
+    }
+
+    pub fn big_requests(&self) -> bool {
+        self.big_requests
+    }
+
+    pub fn enable_big_requests(&mut self) {
+        self.big_requests = true;
+    }
+}
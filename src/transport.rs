@@ -0,0 +1,325 @@
+use std::io;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc;
+
+/// Most file descriptors taken in with one read. Xlib and xcb pass no more
+/// than this many with a single request.
+const MAX_FDS: usize = 16;
+
+/// Room for a control message carrying MAX_FDS descriptors, in words so
+/// it's aligned for the header.
+const CONTROL_WORDS: usize = (mem::size_of::<libc::cmsghdr>()
+    + MAX_FDS * mem::size_of::<RawFd>())
+    / mem::size_of::<usize>()
+    + 1;
+
+/// CMSG_ALIGN, which libc doesn't have for us.
+fn cmsg_align(length: usize) -> usize {
+    let align = mem::size_of::<usize>();
+    (length + align - 1) & !(align - 1)
+}
+
+/// A connected, non-blocking byte stream the proxy moves X11 traffic over.
+///
+/// X11 requests and replies can carry file descriptors, for instance
+/// with MIT-SHM and DRI3. Plain reads and writes lose them, so the
+/// client loops use `read_fds` and `write_fds`.
+pub trait Transport: Read + Write + AsRawFd + Send {
+    /// Like `read`, also adding the file descriptors that came along with
+    /// the data to `fds`. They are ours to close.
+    fn read_fds(
+        &mut self,
+        buf: &mut [u8],
+        fds: &mut Vec<RawFd>,
+    ) -> io::Result<usize> {
+        let mut control = [0usize; CONTROL_WORDS];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control) as _;
+        let read = unsafe {
+            libc::recvmsg(
+                self.as_raw_fd(),
+                &mut message,
+                libc::MSG_CMSG_CLOEXEC,
+            )
+        };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let start = control.as_ptr() as *const u8;
+        let end = message.msg_controllen.min(mem::size_of_val(&control));
+        let header = cmsg_align(mem::size_of::<libc::cmsghdr>());
+        let mut offset = 0;
+        while offset + header <= end {
+            let cmsg = unsafe { &*(start.add(offset) as *const libc::cmsghdr) };
+            let length = cmsg.cmsg_len;
+            if length < header || offset + length > end {
+                break;
+            }
+            if cmsg.cmsg_level == libc::SOL_SOCKET
+                && cmsg.cmsg_type == libc::SCM_RIGHTS
+            {
+                let count = (length - header) / mem::size_of::<RawFd>();
+                let data =
+                    unsafe { start.add(offset + header) } as *const RawFd;
+                for i in 0..count {
+                    fds.push(unsafe { ptr::read_unaligned(data.add(i)) });
+                }
+            }
+            offset += cmsg_align(length);
+        }
+        if message.msg_flags & libc::MSG_CTRUNC != 0 {
+            warn!("File descriptors beyond {} were lost.", MAX_FDS);
+        }
+        Ok(read as usize)
+    }
+
+    /// Like `write`, passing `fds` along with the data if any of it is
+    /// written.
+    fn write_fds(&mut self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        if fds.is_empty() {
+            return self.write(buf);
+        }
+        let header = cmsg_align(mem::size_of::<libc::cmsghdr>());
+        let data = mem::size_of_val(fds);
+        let words = (header + cmsg_align(data)) / mem::size_of::<usize>();
+        let mut control = vec![0usize; words];
+        let cmsg = control.as_mut_ptr() as *mut libc::cmsghdr;
+        unsafe {
+            (*cmsg).cmsg_len = (header + data) as _;
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            let target = (cmsg as *mut u8).add(header) as *mut RawFd;
+            ptr::copy_nonoverlapping(fds.as_ptr(), target, fds.len());
+        }
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control[..]) as _;
+        let written = unsafe { libc::sendmsg(self.as_raw_fd(), &message, 0) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(written as usize)
+    }
+
+    /// Tell the peer nothing more is coming, while still reading what it
+    /// sends.
+    fn shutdown_write(&self) -> io::Result<()> {
+        if unsafe { libc::shutdown(self.as_raw_fd(), libc::SHUT_WR) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Transport for UnixStream {}
+
+/// Wrap `stream` for use by a client loop. With a non-zero `fault_rate`
+/// reads and writes fail spuriously, see `FaultyTransport`.
+pub fn wrap(stream: UnixStream, fault_rate: f64) -> Box<dyn Transport> {
+    if fault_rate > 0.0 {
+        Box::new(FaultyTransport::new(stream, fault_rate))
+    } else {
+        Box::new(stream)
+    }
+}
+
+/// Developer tool: a transport that misbehaves in ways the kernel is
+/// allowed to, but rarely does on a local socket. With probability
+/// `fault_rate` each call either returns EAGAIN or only transfers part
+/// of the buffer. Used to soak-test reassembly and backpressure.
+pub struct FaultyTransport<T> {
+    inner: T,
+    fault_rate: f64,
+    rng: u64,
+}
+
+enum Fault {
+    WouldBlock,
+    Short(usize),
+}
+
+impl<T: AsRawFd> FaultyTransport<T> {
+    pub fn new(inner: T, fault_rate: f64) -> FaultyTransport<T> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.subsec_nanos())
+            .unwrap_or(0);
+        let seed = (u64::from(nanos) << 16) ^ inner.as_raw_fd() as u64;
+        FaultyTransport {
+            inner,
+            fault_rate,
+            // xorshift gets stuck at zero.
+            rng: seed | 1,
+        }
+    }
+}
+
+impl<T> FaultyTransport<T> {
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn roll(&mut self) -> f64 {
+        (self.next_random() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Decide whether to misbehave on a call transferring `len` bytes.
+    fn fault(&mut self, len: usize) -> Option<Fault> {
+        let roll = self.roll();
+        if roll >= self.fault_rate {
+            None
+        } else if roll < self.fault_rate / 2.0 || len < 2 {
+            Some(Fault::WouldBlock)
+        } else {
+            let short = 1 + (self.next_random() % (len as u64 - 1)) as usize;
+            Some(Fault::Short(short))
+        }
+    }
+}
+
+fn would_block() -> io::Error {
+    io::Error::new(ErrorKind::WouldBlock, "injected fault")
+}
+
+impl<T: Read> Read for FaultyTransport<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.fault(buf.len()) {
+            None => self.inner.read(buf),
+            Some(Fault::WouldBlock) => Err(would_block()),
+            Some(Fault::Short(len)) => self.inner.read(&mut buf[..len]),
+        }
+    }
+}
+
+impl<T: Write> Write for FaultyTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.fault(buf.len()) {
+            None => self.inner.write(buf),
+            Some(Fault::WouldBlock) => Err(would_block()),
+            Some(Fault::Short(len)) => self.inner.write(&buf[..len]),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for FaultyTransport<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn read_fds(
+        &mut self,
+        buf: &mut [u8],
+        fds: &mut Vec<RawFd>,
+    ) -> io::Result<usize> {
+        match self.fault(buf.len()) {
+            None => self.inner.read_fds(buf, fds),
+            Some(Fault::WouldBlock) => Err(would_block()),
+            Some(Fault::Short(len)) => {
+                self.inner.read_fds(&mut buf[..len], fds)
+            }
+        }
+    }
+
+    fn write_fds(&mut self, buf: &[u8], fds: &[RawFd]) -> io::Result<usize> {
+        match self.fault(buf.len()) {
+            None => self.inner.write_fds(buf, fds),
+            Some(Fault::WouldBlock) => Err(would_block()),
+            Some(Fault::Short(len)) => self.inner.write_fds(&buf[..len], fds),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faulty_transport_loses_nothing() {
+        let (writer, reader) = UnixStream::pair().unwrap();
+        let mut writer = FaultyTransport::new(writer, 0.5);
+        let mut reader = FaultyTransport::new(reader, 0.5);
+        let data: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+
+        let mut sent = 0;
+        while sent < data.len() {
+            match writer.write(&data[sent..]) {
+                Ok(n) => sent += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("write failed: {}", e),
+            }
+        }
+
+        let mut received: Vec<u8> = Vec::new();
+        let mut buffer = [0; 512];
+        while received.len() < data.len() {
+            match reader.read(&mut buffer) {
+                Ok(n) => received.extend(&buffer[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_pass_fds() {
+        let (mut writer, mut reader) = UnixStream::pair().unwrap();
+        let (passed, _other) = UnixStream::pair().unwrap();
+        assert_eq!(writer.write_fds(b"shm", &[passed.as_raw_fd()]).unwrap(), 3);
+        writer.write_fds(b"plain", &[]).unwrap();
+
+        let mut buffer = [0; 16];
+        let mut fds = Vec::new();
+        let n = reader.read_fds(&mut buffer, &mut fds).unwrap();
+        assert_eq!(&buffer[..n], b"shm");
+        assert_eq!(fds.len(), 1);
+        assert!(fds[0] != passed.as_raw_fd());
+        let n = reader.read_fds(&mut buffer, &mut fds).unwrap();
+        assert_eq!(&buffer[..n], b"plain");
+        assert_eq!(fds.len(), 1);
+        unsafe { libc::close(fds[0]) };
+    }
+
+    #[test]
+    fn test_shutdown_write() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"last").unwrap();
+        client.shutdown_write().unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(server.read(&mut buffer).unwrap(), 4);
+        assert_eq!(server.read(&mut buffer).unwrap(), 0);
+        // The reply still makes it back.
+        server.write_all(b"reply").unwrap();
+        assert_eq!(client.read(&mut buffer).unwrap(), 5);
+    }
+}
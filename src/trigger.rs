@@ -0,0 +1,108 @@
+use std::io;
+use std::sync::Arc;
+
+use nom::Endianness;
+
+use analyze;
+use policy::Capability;
+use session::Session;
+use DumpFile;
+
+/// Conditions that start capturing a connection's traffic.
+///
+/// When any trigger is configured, connections are not dumped until one
+/// of them fires. At that point the flight recorder contents of the
+/// connection are written out, followed by everything it sends afterwards.
+pub struct Triggers {
+    atoms: Vec<String>,
+    denials: Vec<Capability>,
+}
+
+impl Triggers {
+    pub fn new(atoms: Vec<String>, denials: Vec<Capability>) -> Triggers {
+        Triggers { atoms, denials }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.atoms.is_empty() && self.denials.is_empty()
+    }
+
+    fn fires(
+        &self,
+        buffer: &[u8],
+        denials: &[Capability],
+        e: Endianness,
+    ) -> bool {
+        let denial = denials
+            .iter()
+            .find(|capability| self.denials.contains(capability));
+        if let Some(capability) = denial {
+            info!("Capture triggered by a request denied {}.", capability);
+            return true;
+        }
+        if self.atoms.is_empty() {
+            return false;
+        }
+        for atom in analyze::interned_atoms(buffer, e) {
+            if self.atoms.contains(&atom) {
+                info!("Capture triggered by atom {}.", atom);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Per-connection capture state.
+pub struct Capture {
+    triggers: Arc<Triggers>,
+    fired: bool,
+}
+
+impl Capture {
+    pub fn new(triggers: Arc<Triggers>) -> Capture {
+        Capture {
+            triggers,
+            fired: false,
+        }
+    }
+
+    /// Dump a chunk of filtered client traffic, if capture is active or
+    /// the chunk causes a trigger to fire. `denials` are the capabilities
+    /// the filter denied requests in it. The chunk must already have been
+    /// added to the session's flight recorder.
+    pub fn record(
+        &mut self,
+        dump: &DumpFile,
+        session: &Session,
+        buffer: &[u8],
+        denials: &[Capability],
+        e: Endianness,
+    ) -> Result<(), io::Error> {
+        if self.fired || self.triggers.is_empty() {
+            return dump.write(buffer);
+        }
+
+        if self.triggers.fires(buffer, denials, e) {
+            self.fired = true;
+            let history = session.recorder.lock().unwrap().contents();
+            dump.write(&history)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fires_on_denial() {
+        let triggers = Triggers::new(Vec::new(), vec![Capability::Screenshot]);
+        let e = Endianness::Little;
+        assert!(!triggers.fires(&[], &[], e));
+        assert!(!triggers.fires(&[], &[Capability::ClipboardPaste], e));
+        let denials = [Capability::ClipboardPaste, Capability::Screenshot];
+        assert!(triggers.fires(&[], &denials, e));
+    }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+use reaper;
+
+/// The PIDs the process that started us trusts, each dropped once its
+/// process exits so that a new one reusing the PID isn't trusted too.
+#[derive(Default)]
+pub struct TrustedPids {
+    entries: Vec<Trusted>,
+}
+
+struct Trusted {
+    pid: i32,
+    liveness: Liveness,
+}
+
+/// What tells whether a trusted PID still is the process we were told of.
+enum Liveness {
+    /// Readable once it has exited.
+    PidFd(RawFd),
+    /// Without pidfds, its start time from /proc/PID/stat, which a process
+    /// reusing the PID won't share.
+    StartTime(u64),
+}
+
+impl Liveness {
+    fn of(pid: i32) -> Result<Liveness, io::Error> {
+        match reaper::pidfd_open(pid) {
+            Ok(fd) => Ok(Liveness::PidFd(fd)),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                start_time(pid).map(Liveness::StartTime)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn alive(&self, pid: i32) -> bool {
+        match *self {
+            Liveness::PidFd(fd) => {
+                let mut poll = libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                unsafe { libc::poll(&mut poll, 1, 0) == 0 }
+            }
+            Liveness::StartTime(started) => {
+                start_time(pid).ok() == Some(started)
+            }
+        }
+    }
+}
+
+impl Drop for Trusted {
+    fn drop(&mut self) {
+        if let Liveness::PidFd(fd) = self.liveness {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// When process `pid` started, in clock ticks after boot.
+fn start_time(pid: i32) -> Result<u64, io::Error> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The name in parentheses may have anything in it, the fields after
+    // it don't. The start time is the 22nd, the 20th after the name.
+    stat.rsplit(')')
+        .next()
+        .and_then(|fields| fields.split_whitespace().nth(19))
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unexpected stat format")
+        })
+}
+
+impl TrustedPids {
+    pub fn new() -> TrustedPids {
+        TrustedPids::default()
+    }
+
+    /// Trust `pid` for as long as its process runs. False if it's trusted
+    /// already.
+    pub fn add(&mut self, pid: i32) -> Result<bool, io::Error> {
+        self.prune();
+        if self.entries.iter().any(|entry| entry.pid == pid) {
+            return Ok(false);
+        }
+        let liveness = Liveness::of(pid)?;
+        self.entries.push(Trusted { pid, liveness });
+        Ok(true)
+    }
+
+    /// Stop trusting `pid`. False if it wasn't.
+    pub fn remove(&mut self, pid: i32) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.pid != pid);
+        self.entries.len() != before
+    }
+
+    pub fn contains(&mut self, pid: i32) -> bool {
+        self.prune();
+        self.entries.iter().any(|entry| entry.pid == pid)
+    }
+
+    /// Drop the PIDs whose processes have exited.
+    pub fn prune(&mut self) {
+        self.entries.retain(|entry| {
+            let alive = entry.liveness.alive(entry.pid);
+            if !alive {
+                info!(
+                    "Trusted PID {} exited, no longer trusting it",
+                    entry.pid
+                );
+            }
+            alive
+        });
+    }
+}
+
+impl fmt::Debug for TrustedPids {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| entry.pid))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_trusted_pids() {
+        let mut pids = TrustedPids::new();
+        let ours = ::std::process::id() as i32;
+        assert!(pids.add(ours).unwrap());
+        assert!(!pids.add(ours).unwrap());
+        assert!(pids.contains(ours));
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id() as i32;
+        assert!(pids.add(pid).unwrap());
+        child.wait().unwrap();
+        assert!(!pids.contains(pid));
+        assert_eq!(format!("{:?}", pids), format!("[{}]", ours));
+
+        // The fallback, with a start time that can't be ours.
+        let started = start_time(ours).unwrap();
+        assert!(Liveness::StartTime(started).alive(ours));
+        assert!(!Liveness::StartTime(started + 1).alive(ours));
+        assert!(pids.remove(ours));
+        assert!(!pids.remove(ours));
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::{Condvar, Mutex};
+
+/// Connections take turns at reading, filtering and writing, one pass of
+/// their loop each, in the order they asked for one.
+///
+/// A pass reads at most one buffer per direction, so a connection that
+/// floods the proxy gets no more than its share while others wait, and
+/// interactive clients stay responsive next to bulk transfers.
+pub struct Turns {
+    tickets: Mutex<Tickets>,
+    changed: Condvar,
+}
+
+struct Tickets {
+    /// Handed to whoever asks next.
+    next: u64,
+    /// Whose turn it is.
+    serving: u64,
+}
+
+/// A connection's turn, until dropped.
+pub struct Turn<'a> {
+    turns: &'a Turns,
+}
+
+impl Turns {
+    pub fn new() -> Turns {
+        Turns {
+            tickets: Mutex::new(Tickets {
+                next: 0,
+                serving: 0,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Wait until the connections that asked before have had their turn.
+    pub fn take(&self) -> Turn<'_> {
+        let mut tickets = self.tickets.lock().unwrap();
+        let ticket = tickets.next;
+        tickets.next += 1;
+        while tickets.serving != ticket {
+            tickets = self.changed.wait(tickets).unwrap();
+        }
+        Turn { turns: self }
+    }
+}
+
+impl<'a> Drop for Turn<'a> {
+    fn drop(&mut self) {
+        self.turns.tickets.lock().unwrap().serving += 1;
+        self.turns.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_turns() {
+        let turns = Arc::new(Turns::new());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let turn = turns.take();
+        let mut waiting = Vec::new();
+        for connection in 0..3 {
+            let (asking, order) = (turns.clone(), order.clone());
+            waiting.push(thread::spawn(move || {
+                let _turn = asking.take();
+                order.lock().unwrap().push(connection);
+            }));
+            // Each asks after the one before.
+            while turns.tickets.lock().unwrap().next < connection + 2 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        assert!(order.lock().unwrap().is_empty());
+        drop(turn);
+        for connection in waiting {
+            connection.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,194 @@
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::{symlink, DirBuilderExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+use socket;
+
+/// Run under this name, rustywin acts as the helper the client calls to
+/// open URLs and files.
+pub const HELPER_NAME: &str = "xdg-open";
+
+/// Tells the helper where to send its requests.
+const SOCKET_VAR: &str = "RUSTYWIN_OPEN_SOCKET";
+
+/// What xdg-open exits with when the action failed.
+const EXIT_FAILED: i32 = 4;
+
+/// Routes the client's attempts to open URLs and files through a prompt
+/// on our terminal, instead of letting it start a browser itself.
+///
+/// The client gets a directory in front of its PATH with a helper named
+/// xdg-open in it, which is us, and BROWSER pointing there too. The
+/// helper passes its argument to the proxy over a socket in the same
+/// directory, and only if the user agrees the real xdg-open is run, from
+/// outside the sandbox.
+pub struct Mediator {
+    dir: PathBuf,
+    listener: UnixListener,
+}
+
+impl Mediator {
+    /// Set up the helper directory and start listening. Requests are only
+    /// handled once `spawn` is called.
+    pub fn new() -> Result<Mediator, io::Error> {
+        let exe = env::current_exe()?;
+        let dir = socket::runtime_dir()?
+            .join(format!("rustywin-open-{}", ::std::process::id()));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        symlink(&exe, dir.join(HELPER_NAME))?;
+        let listener = UnixListener::bind(dir.join("socket"))?;
+        info!("Mediating URLs for the client through {:?}", dir);
+        Ok(Mediator { dir, listener })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Make the client use the helper.
+    pub fn configure(&self, command: &mut Command) {
+        let mut path = self.dir.as_os_str().to_os_string();
+        if let Some(old) = env::var_os("PATH") {
+            path.push(":");
+            path.push(old);
+        }
+        command
+            .env("PATH", path)
+            .env("BROWSER", self.dir.join(HELPER_NAME))
+            // Keeps desktop specific openers from being picked instead.
+            .env("XDG_CURRENT_DESKTOP", "X-Generic")
+            .env(SOCKET_VAR, self.dir.join("socket"));
+    }
+
+    /// Start handling requests, one at a time.
+    pub fn spawn(self) {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => handle_request(stream),
+                    Err(e) => {
+                        error!("Error accepting URL requests: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Remove what `Mediator::new` created in `dir`.
+pub fn cleanup(dir: &Path) {
+    for name in &[HELPER_NAME, "socket"] {
+        fs::remove_file(dir.join(name)).ok();
+    }
+    if let Err(e) = fs::remove_dir(dir) {
+        warn!("Couldn't remove {:?}: {}", dir, e);
+    }
+}
+
+fn handle_request(mut stream: UnixStream) {
+    let mut target = String::new();
+    if let Err(e) = BufReader::new(&mut stream).read_line(&mut target) {
+        warn!("Couldn't read URL request: {}", e);
+        return;
+    }
+    let target = target.trim_end_matches('\n');
+    let allowed = if target.is_empty() || target.chars().any(char::is_control) {
+        warn!("Refusing to open {:?} for the client", target);
+        false
+    } else {
+        match ask(target) {
+            Ok(answer) => answer,
+            Err(e) => {
+                warn!("Couldn't ask about opening {:?}: {}", target, e);
+                false
+            }
+        }
+    };
+    if allowed {
+        info!("Opening {:?} for the client", target);
+        if let Err(e) = Command::new(HELPER_NAME).arg(target).spawn() {
+            warn!("Couldn't run {}: {}", HELPER_NAME, e);
+        }
+    }
+    let reply: &[u8] = if allowed { b"ok\n" } else { b"denied\n" };
+    stream.write_all(reply).ok();
+}
+
+/// Ask on the controlling terminal.
+fn ask(target: &str) -> Result<bool, io::Error> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    write!(tty, "The client wants to open {}. Allow? [y/N] ", target)?;
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer)?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Whether we were started through the helper link. The executable
+/// itself doesn't tell, the link is already resolved there.
+pub fn invoked_as_helper() -> bool {
+    env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .is_some_and(|arg0| arg0.file_name() == Some(HELPER_NAME.as_ref()))
+}
+
+/// The helper side: pass `target` on and wait for the verdict. Returns
+/// the exit code.
+pub fn run_helper(target: Option<String>) -> i32 {
+    let target = match target {
+        Some(target) => target,
+        None => {
+            eprintln!("Usage: {} {{ file | URL }}", HELPER_NAME);
+            return 1;
+        }
+    };
+    let socket = match env::var_os(SOCKET_VAR) {
+        Some(socket) => socket,
+        None => {
+            eprintln!("{} isn't set, nowhere to ask.", SOCKET_VAR);
+            return EXIT_FAILED;
+        }
+    };
+    let result = UnixStream::connect(socket).and_then(|mut stream| {
+        writeln!(stream, "{}", target)?;
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply)?;
+        Ok(reply)
+    });
+    match result {
+        Ok(ref reply) if reply == "ok\n" => 0,
+        Ok(_) => {
+            eprintln!("Opening {} was denied.", target);
+            EXIT_FAILED
+        }
+        Err(e) => {
+            eprintln!("Couldn't ask to open {}: {}", target, e);
+            EXIT_FAILED
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("y\n"));
+        assert!(is_yes(" yes "));
+        assert!(!is_yes("\n"));
+        assert!(!is_yes("no\n"));
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, NativeEndian};
+use libc;
+
+use socket::X11_SOCKET_DIR;
+use socketloop::Proxy;
+
+/// How often the child's file descriptors are looked at.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do about a client that talks to a display server directly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BypassAction {
+    Alert,
+    Kill,
+}
+
+impl BypassAction {
+    pub fn from_name(name: &str) -> Option<BypassAction> {
+        match name {
+            "alert" => Some(BypassAction::Alert),
+            "kill" => Some(BypassAction::Kill),
+            _ => None,
+        }
+    }
+}
+
+// From linux/netlink.h, linux/sock_diag.h and linux/unix_diag.h.
+const NETLINK_SOCK_DIAG: libc::c_int = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_HEADER: usize = 16;
+const UNIX_DIAG_MSG: usize = 16;
+const UDIAG_SHOW_NAME: u32 = 0x1;
+const UDIAG_SHOW_PEER: u32 = 0x4;
+const UNIX_DIAG_NAME: u16 = 0;
+const UNIX_DIAG_PEER: u16 = 2;
+
+/// A Unix socket as the kernel reports it. Abstract names start with a
+/// NUL byte.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UnixSocket {
+    name: Option<Vec<u8>>,
+    peer: Option<u32>,
+}
+
+/// Ask the kernel for all Unix sockets, by inode.
+fn unix_sockets() -> Result<HashMap<u32, UnixSocket>, io::Error> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = dump_unix_sockets(fd);
+    unsafe { libc::close(fd) };
+    result
+}
+
+fn dump_unix_sockets(
+    fd: libc::c_int,
+) -> Result<HashMap<u32, UnixSocket>, io::Error> {
+    let mut request = [0; NLMSG_HEADER + 24];
+    let length = request.len() as u32;
+    NativeEndian::write_u32(&mut request[0..4], length);
+    NativeEndian::write_u16(&mut request[4..6], SOCK_DIAG_BY_FAMILY);
+    NativeEndian::write_u16(&mut request[6..8], NLM_F_REQUEST | NLM_F_DUMP);
+    request[NLMSG_HEADER] = libc::AF_UNIX as u8;
+    // All states, then the inode (any) and what to show.
+    NativeEndian::write_u32(&mut request[20..24], !0);
+    NativeEndian::write_u32(
+        &mut request[28..32],
+        UDIAG_SHOW_NAME | UDIAG_SHOW_PEER,
+    );
+    let sent = unsafe {
+        libc::send(
+            fd,
+            request.as_ptr() as *const libc::c_void,
+            request.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut sockets = HashMap::new();
+    let mut buffer = vec![0u8; 32 * 1024];
+    loop {
+        let n = unsafe {
+            libc::recv(
+                fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if parse_diag(&buffer[..n as usize], &mut sockets)? {
+            return Ok(sockets);
+        }
+    }
+}
+
+/// Add the sockets in one batch of netlink messages to `sockets`.
+/// Returns whether this was the last batch.
+fn parse_diag(
+    mut data: &[u8],
+    sockets: &mut HashMap<u32, UnixSocket>,
+) -> Result<bool, io::Error> {
+    while data.len() >= NLMSG_HEADER {
+        let length = NativeEndian::read_u32(&data[0..4]) as usize;
+        if length < NLMSG_HEADER || length > data.len() {
+            break;
+        }
+        match NativeEndian::read_u16(&data[4..6]) {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let errno = NativeEndian::read_i32(&data[16..20]);
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+            _ => (),
+        }
+        let message = &data[NLMSG_HEADER..length];
+        if message.len() >= UNIX_DIAG_MSG {
+            let inode = NativeEndian::read_u32(&message[4..8]);
+            sockets.insert(inode, parse_attributes(&message[UNIX_DIAG_MSG..]));
+        }
+        data = &data[((length + 3) & !3).min(data.len())..];
+    }
+    Ok(false)
+}
+
+fn parse_attributes(mut data: &[u8]) -> UnixSocket {
+    let mut socket = UnixSocket::default();
+    while data.len() >= 4 {
+        let length = NativeEndian::read_u16(&data[0..2]) as usize;
+        if length < 4 || length > data.len() {
+            break;
+        }
+        let value = &data[4..length];
+        match NativeEndian::read_u16(&data[2..4]) {
+            UNIX_DIAG_NAME => {
+                // Path names may come with their terminating NUL.
+                let mut name = value.to_vec();
+                while name.len() > 1 && name.last() == Some(&0) {
+                    name.pop();
+                }
+                socket.name = Some(name)
+            }
+            UNIX_DIAG_PEER if value.len() >= 4 => {
+                socket.peer = Some(NativeEndian::read_u32(value))
+            }
+            _ => (),
+        }
+        data = &data[((length + 3) & !3).min(data.len())..];
+    }
+    socket
+}
+
+/// Whether a socket name belongs to an X server or Wayland compositor.
+fn is_display_socket(name: &[u8]) -> bool {
+    let path = if name.first() == Some(&0) {
+        &name[1..]
+    } else {
+        name
+    };
+    let path = String::from_utf8_lossy(path);
+    let file_name = path.rsplit('/').next().unwrap_or("");
+    path.starts_with(X11_SOCKET_DIR) || file_name.starts_with("wayland-")
+}
+
+/// The parent pid in the contents of /proc/<pid>/stat. The command name
+/// before it is in parentheses and may contain anything.
+fn parse_ppid(stat: &str) -> Option<i32> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// `pid` and all its descendants.
+fn process_tree(pid: i32) -> Vec<i32> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let child = match entry.file_name().to_str().map(str::parse) {
+                Some(Ok(child)) => child,
+                _ => continue,
+            };
+            let stat = fs::read_to_string(entry.path().join("stat"));
+            if let Some(parent) = stat.ok().as_ref().and_then(|s| parse_ppid(s))
+            {
+                children.entry(parent).or_default().push(child);
+            }
+        }
+    }
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        if let Some(more) = children.get(&tree[i]) {
+            tree.extend(more);
+        }
+        i += 1;
+    }
+    tree
+}
+
+/// The inodes of the sockets open in a process.
+fn socket_inodes(pid: i32) -> Vec<u32> {
+    let entries = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| fs::read_link(e.path()).ok())
+        .filter_map(|link| {
+            let link = link.to_string_lossy().into_owned();
+            if link.starts_with("socket:[") && link.ends_with(']') {
+                link[8..link.len() - 1].parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Watch the launched client and everything it starts for connections
+/// to display servers that don't go through the proxy.
+pub struct Watchdog {
+    child: i32,
+    action: BypassAction,
+    /// The socket the client is supposed to use.
+    spoofed: Vec<u8>,
+    proxy: Arc<Proxy>,
+    reported: HashSet<(i32, u32)>,
+}
+
+impl Watchdog {
+    pub fn new(
+        child: i32,
+        action: BypassAction,
+        spoofed: &str,
+        proxy: Arc<Proxy>,
+    ) -> Watchdog {
+        Watchdog {
+            child,
+            action,
+            spoofed: spoofed.as_bytes().to_vec(),
+            proxy,
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Check on the child until it exits.
+    pub fn spawn(mut self) {
+        thread::spawn(move || {
+            while fs::metadata(format!("/proc/{}", self.child)).is_ok() {
+                if let Err(e) = self.check() {
+                    warn!("Watchdog can't list sockets, stopping: {}", e);
+                    return;
+                }
+                thread::sleep(INTERVAL);
+            }
+        });
+    }
+
+    fn check(&mut self) -> Result<(), io::Error> {
+        let sockets = unix_sockets()?;
+        for pid in process_tree(self.child) {
+            for inode in socket_inodes(pid) {
+                let peer = sockets
+                    .get(&inode)
+                    .and_then(|s| s.peer)
+                    .and_then(|peer| sockets.get(&peer))
+                    .and_then(|peer| peer.name.as_ref());
+                let name = match peer {
+                    Some(name) if *name != self.spoofed => name,
+                    _ => continue,
+                };
+                if is_display_socket(name) && self.reported.insert((pid, inode))
+                {
+                    self.bypass(pid, &String::from_utf8_lossy(name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn bypass(&self, pid: i32, socket: &str) {
+        let socket = socket.replace('\0', "@");
+        error!("Process {} is connected to {} directly!", pid, socket);
+        if let Some(ref audit) = self.proxy.audit {
+            audit.record(
+                "bypass",
+                &[
+                    ("pid", pid.to_string()),
+                    ("socket", socket),
+                    ("action", format!("{:?}", self.action).to_lowercase()),
+                ],
+            );
+        }
+        if self.action == BypassAction::Kill {
+            for &target in &[pid, self.child] {
+                if unsafe { libc::kill(target, libc::SIGKILL) } != 0 {
+                    warn!(
+                        "Couldn't kill {}: {}",
+                        target,
+                        io::Error::last_os_error()
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_diag() {
+        let mut batch = vec![0; 16 + 16];
+        NativeEndian::write_u32(&mut batch[20..24], 42);
+        let name = b"/tmp/.X11-unix/X0";
+        let mut attribute = vec![0; 4];
+        NativeEndian::write_u16(&mut attribute[0..2], 5 + name.len() as u16);
+        attribute.extend(&name[..]);
+        attribute.push(0);
+        attribute.resize((attribute.len() + 3) & !3, 0);
+        batch.extend(&attribute);
+        let mut peer = vec![8, 0, 0, 0, 0, 0, 0, 0];
+        NativeEndian::write_u16(&mut peer[2..4], UNIX_DIAG_PEER);
+        NativeEndian::write_u32(&mut peer[4..8], 7);
+        batch.extend(&peer);
+        let length = batch.len() as u32;
+        NativeEndian::write_u32(&mut batch[0..4], length);
+        NativeEndian::write_u16(&mut batch[4..6], SOCK_DIAG_BY_FAMILY);
+
+        let mut sockets = HashMap::new();
+        assert!(!parse_diag(&batch, &mut sockets).unwrap());
+        assert_eq!(
+            sockets[&42],
+            UnixSocket {
+                name: Some(name.to_vec()),
+                peer: Some(7),
+            }
+        );
+
+        let mut done = vec![0; 20];
+        NativeEndian::write_u32(&mut done[0..4], 20);
+        NativeEndian::write_u16(&mut done[4..6], NLMSG_DONE);
+        assert!(parse_diag(&done, &mut sockets).unwrap());
+    }
+
+    #[test]
+    fn test_display_sockets() {
+        assert!(is_display_socket(b"/tmp/.X11-unix/X0"));
+        assert!(is_display_socket(b"\0/tmp/.X11-unix/X1"));
+        assert!(is_display_socket(b"/run/user/1000/wayland-0"));
+        assert!(!is_display_socket(b"/run/user/1000/bus"));
+        assert_eq!(parse_ppid("12 (a) b) S 34 12 12 0"), Some(34));
+        assert_eq!(parse_ppid("garbage"), None);
+    }
+}
@@ -0,0 +1,251 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use dirs;
+use libc;
+
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+/// An entry of an Xauthority file.
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    family: u16,
+    address: Vec<u8>,
+    number: Vec<u8>,
+    name: Vec<u8>,
+    data: Vec<u8>,
+}
+
+/// Authorization to connect to a local display, as the protocol name and
+/// data to send in the connection setup.
+pub type Credentials = (Vec<u8>, Vec<u8>);
+
+fn authority_path() -> Option<PathBuf> {
+    match env::var_os("XAUTHORITY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::home_dir().map(|home| home.join(".Xauthority")),
+    }
+}
+
+fn hostname() -> Vec<u8> {
+    let mut name = [0u8; 256];
+    let result = unsafe {
+        libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len())
+    };
+    if result != 0 {
+        return Vec::new();
+    }
+    let length = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    name[..length].to_vec()
+}
+
+fn encode_entry(entry: &Entry) -> Vec<u8> {
+    let mut encoded = vec![0; 2];
+    BigEndian::write_u16(&mut encoded, entry.family);
+    for field in &[&entry.address, &entry.number, &entry.name, &entry.data] {
+        let mut length = [0; 2];
+        BigEndian::write_u16(&mut length, field.len() as u16);
+        encoded.extend(&length);
+        encoded.extend(*field);
+    }
+    encoded
+}
+
+/// The entries in the contents of an Xauthority file, up to the first
+/// one that's cut short.
+fn parse_entries(mut data: &[u8]) -> Vec<Entry> {
+    fn field(data: &mut &[u8]) -> Option<Vec<u8>> {
+        if data.len() < 2 {
+            return None;
+        }
+        let length = BigEndian::read_u16(&data[0..2]) as usize;
+        if data.len() < 2 + length {
+            return None;
+        }
+        let field = data[2..2 + length].to_vec();
+        *data = &data[2 + length..];
+        Some(field)
+    }
+
+    let mut entries = Vec::new();
+    while data.len() >= 2 {
+        let family = BigEndian::read_u16(&data[0..2]);
+        data = &data[2..];
+        let entry = (|| {
+            Some(Entry {
+                family,
+                address: field(&mut data)?,
+                number: field(&mut data)?,
+                name: field(&mut data)?,
+                data: field(&mut data)?,
+            })
+        })();
+        match entry {
+            Some(entry) => entries.push(entry),
+            None => break,
+        }
+    }
+    entries
+}
+
+fn find_credentials(
+    entries: Vec<Entry>,
+    host: &[u8],
+    display: usize,
+) -> Option<Credentials> {
+    let number = display.to_string().into_bytes();
+    entries
+        .into_iter()
+        .find(|entry| {
+            let host_matches = entry.family == FAMILY_WILD
+                || (entry.family == FAMILY_LOCAL && entry.address == host);
+            // Like Xlib, take an entry without a number for any display.
+            host_matches
+                && (entry.number.is_empty() || entry.number == number)
+                && entry.name == b"MIT-MAGIC-COOKIE-1"
+        })
+        .map(|entry| (entry.name, entry.data))
+}
+
+/// The cookie for local display `display` from the user's Xauthority
+/// file, if there is one.
+pub fn local_credentials(
+    display: usize,
+) -> Result<Option<Credentials>, io::Error> {
+    let path = match authority_path() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mut contents = Vec::new();
+    match File::open(&path) {
+        Ok(mut file) => file.read_to_end(&mut contents)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(find_credentials(
+        parse_entries(&contents),
+        &hostname(),
+        display,
+    ))
+}
+
+/// Create an Xauthority file at `path`, readable only by us, that gives
+/// `credentials` for every display.
+pub fn write_authority(
+    path: &Path,
+    credentials: &Credentials,
+) -> Result<(), io::Error> {
+    let entry = Entry {
+        family: FAMILY_WILD,
+        address: Vec::new(),
+        number: Vec::new(),
+        name: credentials.0.clone(),
+        data: credentials.1.clone(),
+    };
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(&encode_entry(&entry))
+}
+
+/// Pad `data` out to a multiple of four bytes.
+pub fn pad(data: &mut Vec<u8>) {
+    let padded = (data.len() + 3) & !3;
+    data.resize(padded, 0);
+}
+
+pub fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Set up a connection on `server` to local display `display`, with the
+/// user's credentials for it. Returns what follows the header of the
+/// server's setup reply.
+pub fn connect(
+    server: &mut UnixStream,
+    display: usize,
+) -> Result<Vec<u8>, io::Error> {
+    let (auth_name, auth_data) =
+        local_credentials(display)?.unwrap_or_default();
+    let mut setup = vec![b'l', 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    LittleEndian::write_u16(&mut setup[6..8], auth_name.len() as u16);
+    LittleEndian::write_u16(&mut setup[8..10], auth_data.len() as u16);
+    setup.extend(&auth_name);
+    pad(&mut setup);
+    setup.extend(&auth_data);
+    pad(&mut setup);
+    server.write_all(&setup)?;
+
+    let mut reply = [0; 8];
+    server.read_exact(&mut reply)?;
+    let mut rest = vec![0; 4 * LittleEndian::read_u16(&reply[6..8]) as usize];
+    server.read_exact(&mut rest)?;
+    if reply[0] != 1 {
+        let length = (reply[1] as usize).min(rest.len());
+        return Err(protocol_error(format!(
+            "the server refused us: {}",
+            String::from_utf8_lossy(&rest[..length])
+        )));
+    }
+    Ok(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        family: u16,
+        address: &[u8],
+        number: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut entry = vec![(family >> 8) as u8, family as u8];
+        for field in &[address, number, b"MIT-MAGIC-COOKIE-1", data] {
+            entry.extend(&[0, field.len() as u8]);
+            entry.extend(*field);
+        }
+        entry
+    }
+
+    #[test]
+    fn test_find_credentials() {
+        let mut file = entry(FAMILY_LOCAL, b"other", b"0", b"wrong");
+        file.extend(entry(FAMILY_LOCAL, b"here", b"1", b"one"));
+        file.extend(entry(FAMILY_LOCAL, b"here", b"0", b"zero"));
+        file.extend(&[1, 0, 0]);
+        let entries = parse_entries(&file);
+        assert_eq!(entries.len(), 3);
+        let credentials = find_credentials(entries, b"here", 0);
+        assert_eq!(credentials.unwrap().1, b"zero");
+
+        let file = entry(FAMILY_WILD, b"", b"2", b"wild");
+        let credentials = find_credentials(parse_entries(&file), b"here", 2);
+        assert_eq!(credentials.unwrap().1, b"wild");
+        assert_eq!(find_credentials(parse_entries(&file), b"here", 0), None);
+    }
+
+    #[test]
+    fn test_encode_entry() {
+        let wild = Entry {
+            family: FAMILY_WILD,
+            address: Vec::new(),
+            number: Vec::new(),
+            name: b"MIT-MAGIC-COOKIE-1".to_vec(),
+            data: b"untrusted".to_vec(),
+        };
+        let file = encode_entry(&wild);
+        assert_eq!(file, entry(FAMILY_WILD, b"", b"", b"untrusted"));
+        let credentials = find_credentials(parse_entries(&file), b"here", 3);
+        assert_eq!(credentials.unwrap().1, b"untrusted");
+    }
+}
@@ -0,0 +1,58 @@
+/// The property on the root window listing the running input methods, as
+/// the names of the selections they own.
+pub const SERVERS_PROPERTY: &str = "XIM_SERVERS";
+
+/// What the selection of an input method is called, followed by its name,
+/// like @server=ibus or @server=fcitx. Clients convert it to LOCALES and
+/// TRANSPORT to find out how to talk to it.
+const SERVER_SELECTION_PREFIX: &str = "@server=";
+
+/// The ClientMessage a client connects to an input method with, with its
+/// window in the data. The answer names the input method's window for
+/// the rest of the conversation.
+pub const CONNECT_MESSAGE: &str = "_XIM_XCONNECT";
+
+/// The types of the ClientMessages the X transport of XIM is made of:
+/// connecting, one message in full, and one in pieces.
+const TRANSPORT_MESSAGES: &[&str] =
+    &[CONNECT_MESSAGE, "_XIM_PROTOCOL", "_XIM_MOREDATA"];
+
+/// Messages too long for a ClientMessage go in a property on the input
+/// method's window instead, named this followed by a number. The input
+/// method uses _server followed by a number on ours the same way.
+const TRANSPORT_PROPERTY_PREFIX: &str = "_client";
+
+/// Whether `name` is the selection of an input method.
+pub fn is_server_selection(name: &str) -> bool {
+    name.starts_with(SERVER_SELECTION_PREFIX)
+}
+
+/// Whether `name` is the type of a ClientMessage of the XIM transport.
+pub fn is_transport_message(name: &str) -> bool {
+    TRANSPORT_MESSAGES.contains(&name)
+}
+
+/// Whether `name` is a property a client hands long XIM messages to the
+/// input method in.
+pub fn is_transport_property(name: &str) -> bool {
+    name.starts_with(TRANSPORT_PROPERTY_PREFIX)
+        && name.len() > TRANSPORT_PROPERTY_PREFIX.len()
+        && name[TRANSPORT_PROPERTY_PREFIX.len()..]
+            .bytes()
+            .all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert!(is_server_selection("@server=ibus"));
+        assert!(!is_server_selection("CLIPBOARD"));
+        assert!(is_transport_message("_XIM_MOREDATA"));
+        assert!(is_transport_property("_client12"));
+        assert!(!is_transport_property("_client"));
+        assert!(!is_transport_property("_clients"));
+    }
+}
@@ -0,0 +1,42 @@
+//! Runs real OpenGL and Vulkan clients through rustywin with the gpu
+//! preset, when there's a display and the clients are installed.
+
+use std::env;
+use std::process::{Command, Stdio};
+
+/// Whether `program` is somewhere on $PATH.
+fn installed(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|path| {
+        env::split_paths(&path).any(|dir| dir.join(program).is_file())
+    })
+}
+
+/// Run `client` with `args` through the proxy, unless it can't be, and
+/// tell whether it succeeded. The proxy exits with the client's status.
+fn run_filtered(client: &str, args: &[&str]) -> Option<bool> {
+    if env::var_os("DISPLAY").is_none() || !installed(client) {
+        eprintln!("Skipping {}, no display or not installed.", client);
+        return None;
+    }
+    let status = Command::new(env!("CARGO_BIN_EXE_rustywin"))
+        .args(["run", "--preset", "gpu", client])
+        .args(args)
+        .stdout(Stdio::null())
+        .status()
+        .expect("Couldn't run rustywin");
+    Some(status.success())
+}
+
+#[test]
+fn test_glxinfo() {
+    if let Some(success) = run_filtered("glxinfo", &["-B"]) {
+        assert!(success);
+    }
+}
+
+#[test]
+fn test_vkcube() {
+    if let Some(success) = run_filtered("vkcube", &["--c", "100"]) {
+        assert!(success);
+    }
+}